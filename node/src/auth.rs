@@ -0,0 +1,416 @@
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    commands::Command,
+    config::UserConfig,
+    crypto::{self, ReplayGuard},
+    error::Error,
+};
+
+// node credentials older than this are rejected, younger ones are rejected when their nonce was
+// presented before
+const MAX_CREDENTIAL_AGE_SECS: u64 = 60;
+
+// who a connection is authenticated as
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Identity {
+    #[default]
+    Anonymous,
+    User(String),
+    // another node of the cluster forwarding commands
+    Node(String),
+}
+
+struct User {
+    iterations: u32,
+    salt: String,
+    hash: String,
+    // None allows everything
+    commands: Option<Vec<String>>,
+    key_prefixes: Option<Vec<String>>,
+}
+
+// client accounts and their ACLs, authentication is enabled once a user is configured
+#[derive(Default)]
+pub struct Auth {
+    users: HashMap<String, User>,
+    node_secret: Option<String>,
+    seen: ReplayGuard,
+}
+
+impl Auth {
+    // passwords are configured as pbkdf2-sha256:<iterations>:<salt>:<hex of the derived key>,
+    // nodes authenticate each other with the cluster secret
+    pub fn from_config(
+        users: &HashMap<String, UserConfig>,
+        cluster_secret: Option<String>,
+    ) -> Result<Auth, String> {
+        if !users.is_empty() && cluster_secret.is_none() {
+            return Err("cluster.secret is required to authenticate forwarded commands".into());
+        }
+
+        let mut auth = Auth {
+            users: HashMap::new(),
+            node_secret: cluster_secret,
+            seen: ReplayGuard::default(),
+        };
+
+        for user in users.values() {
+            let invalid = || format!("Invalid password hash for user {}", user.name);
+
+            let parts: Vec<&str> = user.password.split(':').collect();
+            let ["pbkdf2-sha256", iterations, salt, hash] = parts.as_slice() else {
+                return Err(invalid());
+            };
+            let iterations = iterations
+                .parse::<u32>()
+                .ok()
+                .filter(|i| *i > 0)
+                .ok_or_else(invalid)?;
+
+            auth.users.insert(
+                user.name.clone(),
+                User {
+                    iterations,
+                    salt: salt.to_string(),
+                    hash: hash.to_lowercase(),
+                    commands: rule_list(&user.commands)
+                        .map(|c| c.iter().map(|c| c.to_uppercase()).collect()),
+                    key_prefixes: rule_list(&user.keys),
+                },
+            );
+        }
+
+        Ok(auth)
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    pub fn login(&self, name: &str, password: &str) -> Result<Identity, Error> {
+        let invalid = || Error::Unauthorized("Invalid username or password".to_string());
+
+        let user = self.users.get(name).ok_or_else(invalid)?;
+        let hash = hash_password(&user.salt, password, user.iterations);
+        if !crypto::constant_time_eq(hash.as_bytes(), user.hash.as_bytes()) {
+            return Err(invalid());
+        }
+
+        Ok(Identity::User(name.to_string()))
+    }
+
+    pub fn login_node(
+        &self,
+        node_id: &str,
+        timestamp: u64,
+        nonce: &str,
+        mac: &str,
+    ) -> Result<Identity, Error> {
+        let invalid = || Error::Unauthorized("Invalid node credential".to_string());
+
+        let secret = self.node_secret.as_ref().ok_or_else(invalid)?;
+        let now = unix_time();
+        if !crypto::verify_hmac(
+            secret,
+            node_signed(node_id, timestamp, nonce).as_bytes(),
+            mac,
+        ) || now.abs_diff(timestamp) > MAX_CREDENTIAL_AGE_SECS
+            || !self
+                .seen
+                .first_use(nonce, timestamp + MAX_CREDENTIAL_AGE_SECS, now)
+        {
+            return Err(invalid());
+        }
+
+        Ok(Identity::Node(node_id.to_string()))
+    }
+
+    // the credential a node sends ahead of forwarded commands, if authentication is enabled
+    pub fn node_credential(&self, me_id: &str) -> Option<Command> {
+        let secret = self.node_secret.as_ref().filter(|_| self.enabled())?;
+        let timestamp = unix_time();
+        let nonce = crypto::random_nonce();
+        let mac = crypto::hmac_hex(secret, node_signed(me_id, timestamp, &nonce).as_bytes());
+
+        Some(Command::NodeAuth(me_id.to_string(), timestamp, nonce, mac))
+    }
+
+    pub fn authorize(&self, identity: &Identity, cmd: &Command) -> Result<(), Error> {
+        if !self.enabled() {
+            return Ok(());
+        }
+
+        let name = match identity {
+            Identity::Anonymous => {
+                return Err(Error::Unauthorized("Authentication required".to_string()));
+            }
+            Identity::Node(_) => return Ok(()),
+            Identity::User(name) => name,
+        };
+
+        let denied = || {
+            Error::Forbidden(format!(
+                "Permission denied: {} may not run {}",
+                name,
+                cmd.name()
+            ))
+        };
+        let user = self.users.get(name).ok_or_else(denied)?;
+
+        // ranges, queued transactions and replication are only handed over between nodes
+        if matches!(
+            cmd,
+            Command::Split(..)
+                | Command::Raft(_)
+                | Command::Chain(_)
+                | Command::Transaction(_)
+                | Command::Prepare(..)
+                | Command::Commit(_)
+                | Command::Abort(_)
+                | Command::TxStatus(_)
+        ) {
+            return Err(denied());
+        }
+
+        if let Some(commands) = &user.commands
+            && !commands.iter().any(|c| c == cmd.name())
+        {
+            return Err(denied());
+        }
+
+        let Some(prefixes) = &user.key_prefixes else {
+            return Ok(());
+        };
+        let allowed = |key: &str| prefixes.iter().any(|p| key.starts_with(p.as_str()));
+
+        let permitted = match cmd {
+            Command::Put(key, _)
+            | Command::PutIfVersion(key, ..)
+            | Command::UpdateIfVersion(key, ..)
+            | Command::PutEx(key, ..)
+            | Command::Read(key)
+            | Command::ReadVersion(key)
+            | Command::Delete(key)
+            | Command::Expire(key, _)
+            | Command::Append(key, _)
+            | Command::GetSet(key, _)
+            | Command::GetDel(key)
+            | Command::StrLen(key)
+            | Command::Cas(key, ..)
+            | Command::DeleteIf(key, _)
+            | Command::Incr(key, _)
+            | Command::IncrFloat(key, _) => allowed(key),
+            Command::BatchPut(entries) => entries.iter().all(|(key, _)| allowed(key)),
+            Command::Split(_, entries) => entries.iter().all(|entry| allowed(&entry.key)),
+            Command::BatchRead(keys) | Command::BatchDelete(keys) | Command::Watch(keys) => {
+                keys.iter().all(|key| allowed(key))
+            }
+            // every key between two bounds sharing a prefix has that prefix as well
+            Command::ReadKeyByRange(start, end, ..) => prefixes
+                .iter()
+                .any(|p| start.starts_with(p.as_str()) && end.starts_with(p.as_str())),
+            // a scan only sees keys of a permitted prefix when it is limited to one
+            Command::Scan(scan) => allowed(&scan.prefix),
+            Command::Transaction(transaction) | Command::Prepare(_, _, transaction) => transaction
+                .commands
+                .iter()
+                .filter_map(Command::key)
+                .all(allowed),
+            Command::ClusterNodes
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Commit(_)
+            | Command::Abort(_)
+            | Command::TxStatus(_)
+            | Command::Raft(_)
+            | Command::Chain(_)
+            | Command::Protocol(_)
+            | Command::Auth(..)
+            | Command::NodeAuth(..) => true,
+        };
+
+        if permitted {
+            Ok(())
+        } else {
+            Err(Error::Forbidden(format!(
+                "Permission denied: {} may not access these keys",
+                name
+            )))
+        }
+    }
+}
+
+pub fn hash_password(salt: &str, password: &str, iterations: u32) -> String {
+    crypto::pbkdf2_hex(password.as_bytes(), salt.as_bytes(), iterations)
+}
+
+// a comma separated list, empty or `*` for no restriction
+fn rule_list(rules: &str) -> Option<Vec<String>> {
+    match rules.trim() {
+        "" | "*" => None,
+        rules => Some(
+            rules
+                .split(',')
+                .map(|r| r.trim().to_string())
+                .filter(|r| !r.is_empty())
+                .collect(),
+        ),
+    }
+}
+
+fn node_signed(node_id: &str, timestamp: u64, nonce: &str) -> String {
+    format!("NODEAUTH {} {} {}", node_id, timestamp, nonce)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::Scope;
+
+    fn auth() -> Auth {
+        let mut users = HashMap::new();
+        users.insert(
+            "reader".to_string(),
+            UserConfig {
+                name: "reader".into(),
+                password: format!(
+                    "pbkdf2-sha256:1000:salt:{}",
+                    hash_password("salt", "secret", 1000)
+                ),
+                commands: "READ, readrange".into(),
+                keys: "app:".into(),
+            },
+        );
+        users.insert(
+            "admin".to_string(),
+            UserConfig {
+                name: "admin".into(),
+                password: format!(
+                    "pbkdf2-sha256:1:pepper:{}",
+                    hash_password("pepper", "root", 1)
+                ),
+                commands: "*".into(),
+                keys: "".into(),
+            },
+        );
+
+        Auth::from_config(&users, Some("cluster".into())).unwrap()
+    }
+
+    #[test]
+    fn test_login() {
+        let auth = auth();
+
+        assert_eq!(
+            auth.login("reader", "secret"),
+            Ok(Identity::User("reader".into()))
+        );
+        assert!(auth.login("reader", "wrong").is_err());
+        assert!(auth.login("nobody", "secret").is_err());
+    }
+
+    #[test]
+    fn test_password_hashes() {
+        // RFC 7914 test vector of PBKDF2-HMAC-SHA256
+        assert_eq!(
+            hash_password("salt", "passwd", 1),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"
+        );
+
+        let user = |password: &str| {
+            let mut users = HashMap::new();
+            users.insert(
+                "u".to_string(),
+                UserConfig {
+                    name: "u".into(),
+                    password: password.into(),
+                    commands: "".into(),
+                    keys: "".into(),
+                },
+            );
+            Auth::from_config(&users, Some("cluster".into()))
+        };
+        assert!(user("pbkdf2-sha256:1:salt:00").is_ok());
+        assert!(user("pbkdf2-sha256:0:salt:00").is_err());
+        assert!(user("sha256:salt:00").is_err());
+    }
+
+    #[test]
+    fn test_authorize() {
+        let auth = auth();
+        let reader = Identity::User("reader".into());
+        let admin = Identity::User("admin".into());
+        let read = |key: &str| Command::Read(key.to_string());
+
+        assert!(
+            auth.authorize(&Identity::Anonymous, &read("app:1"))
+                .is_err()
+        );
+        assert!(auth.authorize(&reader, &read("app:1")).is_ok());
+        assert!(auth.authorize(&reader, &read("other:1")).is_err());
+        assert!(
+            auth.authorize(&reader, &Command::Delete("app:1".into()))
+                .is_err()
+        );
+        assert!(
+            auth.authorize(
+                &reader,
+                &Command::ReadKeyByRange("app:a".into(), "app:z".into(), None, Scope::Cluster)
+            )
+            .is_ok()
+        );
+        assert!(
+            auth.authorize(
+                &reader,
+                &Command::ReadKeyByRange("a".into(), "z".into(), None, Scope::Cluster)
+            )
+            .is_err()
+        );
+        assert!(
+            auth.authorize(&admin, &Command::Delete("other:1".into()))
+                .is_ok()
+        );
+
+        assert!(
+            Auth::default()
+                .authorize(&Identity::Anonymous, &read("x"))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_node_credential() {
+        let auth = auth();
+
+        let Some(Command::NodeAuth(node_id, timestamp, nonce, mac)) = auth.node_credential("2")
+        else {
+            panic!("expected a node credential");
+        };
+        assert!(auth.login_node("3", timestamp, &nonce, &mac).is_err());
+        assert!(
+            auth.login_node(&node_id, timestamp - 3600, &nonce, &mac)
+                .is_err()
+        );
+        assert_eq!(
+            auth.login_node(&node_id, timestamp, &nonce, &mac),
+            Ok(Identity::Node("2".into()))
+        );
+
+        // a credential is only accepted once
+        assert!(auth.login_node(&node_id, timestamp, &nonce, &mac).is_err());
+
+        assert!(Auth::default().node_credential("2").is_none());
+    }
+}
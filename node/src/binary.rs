@@ -0,0 +1,453 @@
+use std::{
+    io::{self, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, atomic::Ordering},
+};
+
+use crate::{
+    auth::Identity,
+    commands::{Command, Scope, tokenize},
+    error::Error,
+    log::log,
+    networking::{
+        self, NodeContext, POOLED_CLIENT_READ_TIMEOUT, invalid_data, parse_reply, parse_value,
+        read_exactly,
+    },
+};
+
+// every frame starts with this byte, anything else means the connection is out of sync
+const MAGIC: u8 = 0x4b;
+
+// magic, opcode or status, request id, key length, value length, argument
+const HEADER_LEN: usize = 1 + 1 + 4 + 2 + 4 + 8;
+
+const MAX_VALUE_LEN: usize = 64 * 1024 * 1024;
+
+// request opcodes
+const OP_NOOP: u8 = 0x00;
+const OP_GET: u8 = 0x01;
+const OP_PUT: u8 = 0x02;
+const OP_DELETE: u8 = 0x03;
+// the argument is the ttl in seconds
+const OP_EXPIRE: u8 = 0x04;
+// the version is answered in the argument
+const OP_GET_VERSION: u8 = 0x05;
+// the argument is the expected version, 0 for a key that must not exist
+const OP_PUT_IF_VERSION: u8 = 0x06;
+// the value holds the entries, the key is empty
+const OP_BATCH_PUT: u8 = 0x07;
+// the key is the start and the value the end of the range, the entries are answered in the value
+const OP_RANGE: u8 = 0x08;
+// the key is the user and the value the password, the connection runs as that user afterwards
+const OP_AUTH: u8 = 0x09;
+
+// response statuses, errors carry `<CODE> <message>` as the value
+const STATUS_OK: u8 = 0x00;
+const STATUS_NOT_FOUND: u8 = 0x01;
+const STATUS_ERROR: u8 = 0x02;
+// a batch written in part, the value holds the entries that failed with `<CODE> <message>`
+const STATUS_PARTIAL: u8 = 0x03;
+
+// requests and responses share the layout, all integers are big endian:
+// magic u8, opcode (or status) u8, request id u32, key length u16, value length u32, argument u64,
+// followed by the key and the value
+#[derive(Debug, Clone, PartialEq, Default)]
+struct Frame {
+    code: u8,
+    request_id: u32,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    arg: u64,
+}
+
+impl Frame {
+    fn response(request_id: u32, status: u8) -> Frame {
+        Frame {
+            code: status,
+            request_id,
+            ..Frame::default()
+        }
+    }
+
+    fn with_value(self, value: Vec<u8>) -> Frame {
+        Frame { value, ..self }
+    }
+
+    fn error(request_id: u32, error: &Error) -> Frame {
+        Frame::response(request_id, STATUS_ERROR).with_value(error.to_string().into_bytes())
+    }
+
+    // keys longer than a u16 and values longer than a u32 length do not fit a frame
+    fn encode(&self) -> Result<Vec<u8>, Error> {
+        let key_len = u16::try_from(self.key.len()).map_err(|_| too_large("Key"))?;
+        let value_len = u32::try_from(self.value.len()).map_err(|_| too_large("Value"))?;
+
+        let mut out = Vec::with_capacity(HEADER_LEN + self.key.len() + self.value.len());
+        out.push(MAGIC);
+        out.push(self.code);
+        out.extend(self.request_id.to_be_bytes());
+        out.extend(key_len.to_be_bytes());
+        out.extend(value_len.to_be_bytes());
+        out.extend(self.arg.to_be_bytes());
+        out.extend(&self.key);
+        out.extend(&self.value);
+        Ok(out)
+    }
+
+    // a response too large for a frame is answered with an error instead
+    fn encode_or_error(&self) -> Vec<u8> {
+        self.encode().unwrap_or_else(|e| {
+            Frame::error(self.request_id, &e)
+                .encode()
+                .expect("error frames fit a frame")
+        })
+    }
+}
+
+pub fn start_binary(host: &str, port: u16, max_connections: usize, ctx: Arc<NodeContext>) {
+    let addr = format!("{}:{}", host, port);
+    let listener = TcpListener::bind(&addr).expect("Failed to bind binary protocol address");
+
+    log(
+        &format!("Binary protocol listener started on {}", addr),
+        ctx.log_enabled,
+    );
+
+    std::thread::spawn(move || {
+        networking::serve(listener, max_connections, ctx, handle_connection, |e| {
+            Frame::error(0, &Error::Unavailable(e.to_string())).encode_or_error()
+        });
+    });
+}
+
+// requests are answered in order, the request id lets clients match pipelined responses
+fn handle_connection(tcp_stream: TcpStream, ctx: &NodeContext) {
+    let _ = tcp_stream.set_read_timeout(Some(POOLED_CLIENT_READ_TIMEOUT));
+
+    let Some(stream) = networking::accept_client(tcp_stream, ctx) else {
+        return;
+    };
+
+    // responses are collected and written once no pipelined request is pending
+    let mut reader = BufReader::new(stream);
+    let mut pending = Vec::new();
+    let mut identity = Identity::Anonymous;
+
+    loop {
+        let request = match read_frame(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::InvalidData {
+                    let _ = pending.write_all(
+                        &Frame::error(0, &Error::BadRequest(e.to_string())).encode_or_error(),
+                    );
+                }
+                break;
+            }
+        };
+
+        ctx.request_count.fetch_add(1, Ordering::Relaxed);
+        log(
+            &format!(
+                "Received binary request {}: opcode {:#04x}",
+                request.request_id, request.code
+            ),
+            ctx.log_enabled,
+        );
+
+        let response = handle_request(request, &mut identity, ctx);
+        if pending.write_all(&response.encode_or_error()).is_err() {
+            break;
+        }
+
+        // pipelined requests are answered in one go, flush once no request is pending
+        if reader.buffer().is_empty() && networking::flush(reader.get_mut(), &mut pending).is_err()
+        {
+            break;
+        }
+    }
+
+    networking::close(reader.get_mut(), &mut pending);
+}
+
+fn read_frame(reader: &mut impl Read) -> io::Result<Option<Frame>> {
+    let mut header = [0; HEADER_LEN];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    if header[0] != MAGIC {
+        return Err(invalid_data("Invalid magic byte"));
+    }
+
+    let key_len = u16::from_be_bytes([header[6], header[7]]) as usize;
+    let value_len = u32::from_be_bytes(header[8..12].try_into().unwrap()) as usize;
+    if value_len > MAX_VALUE_LEN {
+        return Err(invalid_data("Value too large"));
+    }
+
+    let key = read_exactly(reader, key_len)?;
+    let value = read_exactly(reader, value_len)?;
+
+    Ok(Some(Frame {
+        code: header[1],
+        request_id: u32::from_be_bytes(header[2..6].try_into().unwrap()),
+        key,
+        value,
+        arg: u64::from_be_bytes(header[12..20].try_into().unwrap()),
+    }))
+}
+
+fn handle_request(request: Frame, identity: &mut Identity, ctx: &NodeContext) -> Frame {
+    let id = request.request_id;
+
+    let Ok(key) = String::from_utf8(request.key) else {
+        return Frame::error(id, &bad_request("Keys must be valid UTF-8"));
+    };
+
+    match request.code {
+        OP_NOOP => Frame::response(id, STATUS_OK),
+
+        OP_AUTH => {
+            if !ctx.auth.enabled() {
+                return Frame::error(id, &bad_request("Authentication is not enabled"));
+            }
+            match ctx
+                .auth
+                .login(&key, &String::from_utf8_lossy(&request.value))
+            {
+                Ok(user) => {
+                    *identity = user;
+                    Frame::response(id, STATUS_OK)
+                }
+                Err(e) => {
+                    // write to the stderr regardless of log setting
+                    eprintln!("Failed authentication: {}", e.message());
+                    Frame::error(id, &e)
+                }
+            }
+        }
+
+        OP_GET => match parse_value(networking::execute_as(identity, Command::Read(key), ctx)) {
+            Ok(Some(value)) => Frame::response(id, STATUS_OK).with_value(value),
+            Ok(None) => Frame::response(id, STATUS_NOT_FOUND),
+            Err(e) => Frame::error(id, &e),
+        },
+
+        OP_PUT => status(
+            id,
+            networking::execute_as(identity, Command::Put(key, request.value), ctx),
+        ),
+
+        OP_DELETE => status(
+            id,
+            networking::execute_as(identity, Command::Delete(key), ctx),
+        ),
+
+        OP_EXPIRE => status(
+            id,
+            networking::execute_as(identity, Command::Expire(key, request.arg), ctx),
+        ),
+
+        OP_GET_VERSION => {
+            let response = networking::execute_as(identity, Command::ReadVersion(key), ctx);
+            let reply = match parse_reply(response) {
+                Ok(Some(reply)) => reply,
+                Ok(None) => return Frame::response(id, STATUS_NOT_FOUND),
+                Err(e) => return Frame::error(id, &e),
+            };
+
+            match tokenize(&reply).as_deref() {
+                Ok([version, value]) => match std::str::from_utf8(version).map(str::parse) {
+                    Ok(Ok(version)) => Frame {
+                        arg: version,
+                        ..Frame::response(id, STATUS_OK).with_value(value.clone())
+                    },
+                    _ => Frame::error(id, &invalid_response(&reply)),
+                },
+                _ => Frame::error(id, &invalid_response(&reply)),
+            }
+        }
+
+        OP_PUT_IF_VERSION => status(
+            id,
+            networking::execute_as(
+                identity,
+                Command::PutIfVersion(key, request.value, request.arg),
+                ctx,
+            ),
+        ),
+
+        OP_BATCH_PUT => match decode_entries(&request.value) {
+            Ok(entries) => {
+                let keys: Vec<String> = entries.iter().map(|(key, _)| key.clone()).collect();
+                let response = networking::execute_as(identity, Command::BatchPut(entries), ctx);
+                match parse_reply(response.clone()) {
+                    Ok(Some(reply)) if reply == "OK" => Frame::response(id, STATUS_OK),
+                    Ok(Some(reply)) => Frame::error(id, &invalid_response(&reply)),
+                    Ok(None) => Frame::response(id, STATUS_NOT_FOUND),
+                    Err(Error::Partial(_)) => {
+                        let failed: Vec<(Vec<u8>, Vec<u8>)> = keys
+                            .iter()
+                            .zip(networking::parse_batch(&response, &keys))
+                            .filter_map(|(key, result)| {
+                                let e = result.err()?;
+                                Some((key.clone().into_bytes(), e.to_string().into_bytes()))
+                            })
+                            .collect();
+                        match encode_entries(&failed) {
+                            Ok(value) => Frame::response(id, STATUS_PARTIAL).with_value(value),
+                            Err(e) => Frame::error(id, &e),
+                        }
+                    }
+                    Err(e) => Frame::error(id, &e),
+                }
+            }
+            Err(e) => Frame::error(id, &e),
+        },
+
+        OP_RANGE => {
+            let Ok(end) = String::from_utf8(request.value) else {
+                return Frame::error(id, &bad_request("Keys must be valid UTF-8"));
+            };
+
+            let limit = (request.arg > 0).then_some(request.arg);
+            let range = Command::ReadKeyByRange(key, end, limit, Scope::Cluster);
+            match networking::parse_range(&networking::execute_as(identity, range, ctx)) {
+                Ok(entries) => {
+                    let entries: Vec<(Vec<u8>, Vec<u8>)> = entries
+                        .into_iter()
+                        .map(|(key, value)| (key.into_bytes(), value))
+                        .collect();
+                    match encode_entries(&entries) {
+                        Ok(value) => Frame::response(id, STATUS_OK).with_value(value),
+                        Err(e) => Frame::error(id, &e),
+                    }
+                }
+                Err(e) => Frame::error(id, &e),
+            }
+        }
+
+        opcode => Frame::error(
+            id,
+            &Error::BadRequest(format!("Unknown opcode {:#04x}", opcode)),
+        ),
+    }
+}
+
+// maps a text protocol response without a value onto a status
+fn status(id: u32, response: String) -> Frame {
+    match parse_reply(response) {
+        Ok(Some(_)) => Frame::response(id, STATUS_OK),
+        Ok(None) => Frame::response(id, STATUS_NOT_FOUND),
+        Err(e) => Frame::error(id, &e),
+    }
+}
+
+fn bad_request(message: &str) -> Error {
+    Error::BadRequest(message.to_string())
+}
+
+fn invalid_response(response: &str) -> Error {
+    Error::Internal(format!("Invalid response: {}", response))
+}
+
+// entries are a sequence of: key length u16, key, value length u32, value
+fn encode_entries(entries: &[(Vec<u8>, Vec<u8>)]) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::new();
+    for (key, value) in entries {
+        let key_len = u16::try_from(key.len()).map_err(|_| too_large("Key"))?;
+        let value_len = u32::try_from(value.len()).map_err(|_| too_large("Value"))?;
+
+        out.extend(key_len.to_be_bytes());
+        out.extend(key);
+        out.extend(value_len.to_be_bytes());
+        out.extend(value);
+    }
+    Ok(out)
+}
+
+fn too_large(what: &str) -> Error {
+    Error::BadRequest(format!("{} too large for a frame", what))
+}
+
+fn decode_entries(mut data: &[u8]) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let mut entries = Vec::new();
+
+    while !data.is_empty() {
+        let key = take_chunk(&mut data, 2)?;
+        let value = take_chunk(&mut data, 4)?;
+        let key =
+            String::from_utf8(key.to_vec()).map_err(|_| bad_request("Keys must be valid UTF-8"))?;
+        entries.push((key, value.to_vec()));
+    }
+
+    Ok(entries)
+}
+
+// splits off a chunk preceded by its big endian length of `width` bytes
+fn take_chunk<'a>(data: &mut &'a [u8], width: usize) -> Result<&'a [u8], Error> {
+    let malformed = || bad_request("Malformed entries");
+
+    let (len, rest) = data.split_at_checked(width).ok_or_else(malformed)?;
+    let len = len.iter().fold(0usize, |acc, b| acc << 8 | *b as usize);
+    let (chunk, rest) = rest.split_at_checked(len).ok_or_else(malformed)?;
+
+    *data = rest;
+    Ok(chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_round_trip() {
+        let frame = Frame {
+            code: OP_PUT_IF_VERSION,
+            request_id: 7,
+            key: b"my key".to_vec(),
+            value: b"\x00binary\nvalue".to_vec(),
+            arg: 42,
+        };
+
+        let encoded = frame.encode().unwrap();
+        assert_eq!(encoded.len(), HEADER_LEN + 6 + 13);
+
+        let mut input = encoded.as_slice();
+        assert_eq!(read_frame(&mut input).unwrap(), Some(frame));
+        assert_eq!(read_frame(&mut input).unwrap(), None);
+
+        let mut bad_magic: &[u8] = &[0u8; HEADER_LEN];
+        assert!(read_frame(&mut bad_magic).is_err());
+
+        // a key too long for its length field is rejected instead of truncated
+        let long_key = Frame {
+            key: vec![b'k'; u16::MAX as usize + 1],
+            ..Frame::response(8, STATUS_OK)
+        };
+        assert!(long_key.encode().is_err());
+        let answered = long_key.encode_or_error();
+        let mut input = answered.as_slice();
+        let error = read_frame(&mut input).unwrap().unwrap();
+        assert_eq!((error.code, error.request_id), (STATUS_ERROR, 8));
+    }
+
+    #[test]
+    fn test_entries_round_trip() {
+        let entries = vec![
+            (b"a".to_vec(), b"first value".to_vec()),
+            (b"b".to_vec(), Vec::new()),
+        ];
+
+        let decoded = decode_entries(&encode_entries(&entries).unwrap()).unwrap();
+        assert_eq!(decoded[0], ("a".to_string(), b"first value".to_vec()));
+        assert_eq!(decoded[1], ("b".to_string(), Vec::new()));
+
+        assert!(decode_entries(&[0, 5, b'a']).is_err());
+        assert!(encode_entries(&[(vec![b'k'; u16::MAX as usize + 1], Vec::new())]).is_err());
+    }
+}
@@ -0,0 +1,723 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    commands::{self, ChainMessage, ChainUpdate, Command},
+    config::ClusterNode,
+    error::Error,
+    log::log,
+    networking::{self, NodeContext},
+    partition::Partitioner,
+    storage::{Storage, StoredEntry},
+};
+
+// how often a node looks for chain members gossip marked dead or alive again
+const MEMBER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// how long the sequence number of a deleted or expired key is kept before the members drop it;
+// an older update of the key still on its way would bring it back once it is gone
+const TOMBSTONE_GRACE: Duration = Duration::from_secs(60);
+
+// chain replicated keyspaces: the replicas of a key form a chain in the preference order of the
+// hash ring, writes enter at its head and are acknowledged by its tail, which serves the reads
+#[derive(Default)]
+pub struct Chain {
+    keyspaces: Vec<String>,
+    replicas: usize,
+    // the sequence number of the last update of every key, deleted keys included until every
+    // member of their chain has it
+    seqs: Mutex<HashMap<String, u64>>,
+    // keys deleted or expiring with the update that did it, dropped from `seqs` once due
+    tombstones: Mutex<Vec<(Instant, String, u64)>>,
+    // the members an update could not reach, told to sync at the next check
+    missed: Mutex<HashSet<String>>,
+    // another member could not pass an update to this node, it syncs at the next check
+    stale: AtomicBool,
+    // the keys of this node were pulled from every node gossip takes for alive since it started
+    synced: AtomicBool,
+    // the rounds gossip finished, until the first one the cluster snapshot holds this node only
+    gossip_rounds: Arc<AtomicU64>,
+    // the nodes taken for alive at the last check
+    members: Mutex<Vec<String>>,
+}
+
+impl Chain {
+    pub fn new(keyspaces: Vec<String>, replicas: usize, gossip_rounds: Arc<AtomicU64>) -> Chain {
+        Chain {
+            keyspaces,
+            replicas,
+            gossip_rounds,
+            ..Chain::default()
+        }
+    }
+
+    pub fn covers(&self, key: &str) -> bool {
+        self.keyspaces.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    fn gossiped(&self) -> bool {
+        self.gossip_rounds.load(Ordering::Relaxed) > 0
+    }
+}
+
+// a node serves its chains once gossip told it the members alive and it pulled their keys
+fn ready(ctx: &NodeContext) -> Result<(), Error> {
+    if ctx.chain.gossiped() && ctx.chain.synced.load(Ordering::Relaxed) {
+        Ok(())
+    } else {
+        Err(Error::Unavailable(
+            "Chain replica is still syncing".to_string(),
+        ))
+    }
+}
+
+// the replicas of the key in preference order, the ones gossip marked dead left out
+fn chain(ctx: &NodeContext, key: &str) -> Vec<ClusterNode> {
+    let alive = ctx.cluster_snapshot.lock().unwrap().clone();
+    preference(ctx, key)
+        .into_iter()
+        .filter(|node| node._id == ctx.me_id || alive.contains_key(&node._id))
+        .collect()
+}
+
+// chain replication needs hash partitioning, checked when the node starts
+fn preference(ctx: &NodeContext, key: &str) -> Vec<ClusterNode> {
+    match &ctx.partitioner {
+        Partitioner::Hash(ring) => ring
+            .preference(key, ctx.chain.replicas)
+            .into_iter()
+            .cloned()
+            .collect(),
+        Partitioner::Range(_) => Vec::new(),
+    }
+}
+
+// keys of chain replicated keyspaces are stored by every member of their chain, ranges and
+// scans list them on its head only
+pub fn lists(ctx: &NodeContext, key: &str) -> bool {
+    !ctx.chain.covers(key)
+        || chain(ctx, key)
+            .first()
+            .is_some_and(|head| head._id == ctx.me_id)
+}
+
+// runs a single key command of a chain replicated keyspace: reads on the tail of the chain of
+// the key, writes on its head
+pub fn execute(ctx: &NodeContext, cmd: Command) -> String {
+    let Some(key) = cmd.key() else {
+        return Error::BadRequest(format!("{} has no key", cmd.name())).response();
+    };
+    if let Err(e) = ready(ctx) {
+        return e.response();
+    }
+
+    // versions are counted by every replica on its own
+    let versioned = match &cmd {
+        Command::ReadVersion(_) => true,
+        Command::PutIfVersion(_, _, version) | Command::UpdateIfVersion(_, _, version) => {
+            *version != 0
+        }
+        Command::PutEx(_, _, _, version) => version.is_some_and(|version| version != 0),
+        _ => false,
+    };
+    if versioned {
+        return Error::BadRequest(
+            "Versions are not available in chain replicated keyspaces, use CAS".to_string(),
+        )
+        .response();
+    }
+
+    let members = chain(ctx, key);
+    if matches!(cmd, Command::Read(_) | Command::StrLen(_)) {
+        return read(ctx, &members, cmd);
+    }
+
+    lead(ctx, &members, cmd)
+}
+
+// writes on the first member that can be reached, the head unless gossip has yet to mark it
+// dead; members only hand writes to the ones before them, so a write cannot go round in circles
+fn lead(ctx: &NodeContext, members: &[ClusterNode], cmd: Command) -> String {
+    for node in members {
+        if node._id == ctx.me_id {
+            return write(ctx, &cmd);
+        }
+
+        let write = Command::Chain(ChainMessage::Write(Box::new(cmd.clone())));
+        let response = networking::forward_command(write, node.clone(), None, ctx);
+        if !matches!(
+            networking::parse_reply(response.clone()),
+            Err(Error::Unavailable(_))
+        ) {
+            return response;
+        }
+    }
+
+    no_chain(cmd.key().unwrap_or_default()).response()
+}
+
+// reads on the tail; a tail that is still syncing, or not reachable, hands the read to the
+// member before it
+fn read(ctx: &NodeContext, members: &[ClusterNode], cmd: Command) -> String {
+    for node in members.iter().rev() {
+        let response = if node._id == ctx.me_id {
+            read_local(ctx, &cmd)
+        } else {
+            let read = Command::Chain(ChainMessage::Read(Box::new(cmd.clone())));
+            networking::forward_command(read, node.clone(), None, ctx)
+        };
+
+        if !matches!(
+            networking::parse_reply(response.clone()),
+            Err(Error::Unavailable(_))
+        ) {
+            return response;
+        }
+    }
+
+    no_chain(cmd.key().unwrap_or_default()).response()
+}
+
+fn read_local(ctx: &NodeContext, cmd: &Command) -> String {
+    if let Err(e) = ready(ctx) {
+        return e.response();
+    }
+
+    let mut storage = ctx.storage.lock().unwrap();
+    networking::apply(storage.as_mut(), cmd)
+}
+
+// runs the write on the head and passes the state of the key down the chain, answered once the
+// tail has it
+fn write(ctx: &NodeContext, cmd: &Command) -> String {
+    let key = cmd.key().unwrap_or_default();
+
+    let (answer, update) = {
+        let mut storage = ctx.storage.lock().unwrap();
+        let before = entry(storage.as_ref(), key);
+        let answer = networking::apply(storage.as_mut(), cmd);
+        let after = entry(storage.as_ref(), key);
+
+        // writes that answer an error may still change the key, so the stored state decides;
+        // EXPIRE leaves value and version as they are
+        let changed = match cmd {
+            Command::Expire(..) => !answer.starts_with("Error: "),
+            _ => {
+                before.map(|entry| (entry.value, entry.version))
+                    != after
+                        .as_ref()
+                        .map(|entry| (entry.value.clone(), entry.version))
+            }
+        };
+        if !changed {
+            return answer;
+        }
+
+        let mut seqs = ctx.chain.seqs.lock().unwrap();
+        let seq = next_seq(seqs.get(key).copied().unwrap_or_default());
+        seqs.insert(key.to_string(), seq);
+
+        let update = update_of(seq, key, after);
+        remember(ctx, &update);
+        (answer, update)
+    };
+
+    let acknowledged = pass_on(ctx, &update);
+    if acknowledged == "OK\n" {
+        answer
+    } else {
+        acknowledged
+    }
+}
+
+// the key as stored, None if it does not exist or expired
+fn entry(storage: &dyn Storage, key: &str) -> Option<StoredEntry> {
+    storage.read_entries(key, key).ok()?.pop()
+}
+
+// the state of the key as passed down the chain, its expiry rounded up to whole seconds
+fn update_of(seq: u64, key: &str, entry: Option<StoredEntry>) -> ChainUpdate {
+    let ttl = entry
+        .as_ref()
+        .and_then(|entry| entry.expires_in)
+        .map(|left| left.as_secs() + u64::from(left.subsec_nanos() > 0));
+    ChainUpdate {
+        seq,
+        key: key.to_string(),
+        value: entry.map(|entry| entry.value),
+        ttl,
+    }
+}
+
+// the time in microseconds, so a new head continues after the updates of the old one, or the
+// last update of the key plus one on a clock running behind
+fn next_seq(last: u64) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    now.max(last + 1)
+}
+
+// sends the update to the next member of the chain, skipping members that cannot be reached as
+// the ones gossip marked dead are; "OK\n" once the tail has it, an error if no member after this
+// one could be reached
+fn pass_on(ctx: &NodeContext, update: &ChainUpdate) -> String {
+    let members = chain(ctx, &update.key);
+    let Some(position) = members.iter().position(|node| node._id == ctx.me_id) else {
+        return "OK\n".to_string();
+    };
+    // the tail
+    if position + 1 == members.len() {
+        return "OK\n".to_string();
+    }
+
+    for node in &members[position + 1..] {
+        let cmd = Command::Chain(ChainMessage::Update(update.clone()));
+        let response = networking::forward_command(cmd, node.clone(), None, ctx);
+        match networking::parse_reply(response.clone()) {
+            Err(Error::Unavailable(_)) => {
+                log(
+                    &format!(
+                        "Chain member {} of key '{}' not reachable, passing on to the next one",
+                        node._id, update.key
+                    ),
+                    ctx.log_enabled,
+                );
+                ctx.chain.missed.lock().unwrap().insert(node._id.clone());
+            }
+            _ => return response,
+        }
+    }
+    Error::Unavailable(format!(
+        "No member of the chain of '{}' after {} is available",
+        update.key, ctx.me_id
+    ))
+    .response()
+}
+
+// keeps the update unless a newer one of the key is stored already
+fn install(ctx: &NodeContext, update: &ChainUpdate) -> Result<(), Error> {
+    let mut storage = ctx.storage.lock().unwrap();
+    let mut seqs = ctx.chain.seqs.lock().unwrap();
+    if seqs.get(&update.key).is_some_and(|seq| *seq >= update.seq) {
+        return Ok(());
+    }
+
+    match &update.value {
+        Some(value) => {
+            storage.put(&update.key, value.clone())?;
+            if let Some(ttl) = update.ttl {
+                storage.expire(&update.key, Duration::from_secs(ttl))?;
+            }
+        }
+        None => match storage.delete(&update.key) {
+            Ok(()) | Err(Error::NotFound(_)) => {}
+            Err(e) => return Err(e),
+        },
+    }
+    seqs.insert(update.key.clone(), update.seq);
+    remember(ctx, update);
+    Ok(())
+}
+
+// queues the key of an update deleting it or setting an expiry, to drop its sequence number
+// once the key is gone for the grace period
+fn remember(ctx: &NodeContext, update: &ChainUpdate) {
+    let gone_in = match (&update.value, update.ttl) {
+        (None, _) => Duration::ZERO,
+        (Some(_), Some(ttl)) => Duration::from_secs(ttl),
+        (Some(_), None) => return,
+    };
+    let due = Instant::now() + gone_in + TOMBSTONE_GRACE;
+    ctx.chain
+        .tombstones
+        .lock()
+        .unwrap()
+        .push((due, update.key.clone(), update.seq));
+}
+
+// the head starts dropping the due tombstones of its chains, the other members keep theirs
+// queued until the head dropped them or a newer update replaced them
+fn prune(ctx: &NodeContext) {
+    let now = Instant::now();
+    let due: Vec<(Instant, String, u64)> = {
+        let mut tombstones = ctx.chain.tombstones.lock().unwrap();
+        let (due, waiting) = std::mem::take(&mut *tombstones)
+            .into_iter()
+            .partition(|(at, _, _)| *at <= now);
+        *tombstones = waiting;
+        due
+    };
+
+    for (_, key, seq) in due {
+        if ctx.chain.seqs.lock().unwrap().get(&key) != Some(&seq) {
+            continue;
+        }
+
+        let head = chain(ctx, &key)
+            .first()
+            .is_some_and(|head| head._id == ctx.me_id);
+        if head {
+            match networking::parse_reply(forget(ctx, &key, seq)) {
+                Ok(_) => continue,
+                Err(e) => log(
+                    &format!("Keeping the tombstone of '{}': {}", key, e.message()),
+                    ctx.log_enabled,
+                ),
+            }
+        }
+
+        ctx.chain
+            .tombstones
+            .lock()
+            .unwrap()
+            .push((now + TOMBSTONE_GRACE, key, seq));
+    }
+}
+
+// drops the sequence number of a key gone since the update once every member after this one
+// dropped theirs; refused while a member is left out of the chain or still stores the key, which
+// a sync would hand back to the others
+fn forget(ctx: &NodeContext, key: &str, seq: u64) -> String {
+    let members = chain(ctx, key);
+    if members.len() != preference(ctx, key).len() {
+        return Error::Unavailable(format!("A member of the chain of '{}' is down", key))
+            .response();
+    }
+    let Some(position) = members.iter().position(|node| node._id == ctx.me_id) else {
+        return Error::Unavailable(format!("Not a member of the chain of '{}'", key)).response();
+    };
+
+    {
+        let storage = ctx.storage.lock().unwrap();
+        let known = ctx.chain.seqs.lock().unwrap().get(key).copied();
+        // replaced by a newer update, which has its own tombstone if any
+        if known.is_some_and(|known| known > seq) {
+            return "OK\n".to_string();
+        }
+        if entry(storage.as_ref(), key).is_some() {
+            return Error::Conflict(format!("'{}' is still stored on {}", key, ctx.me_id))
+                .response();
+        }
+    }
+
+    if let Some(next) = members.get(position + 1) {
+        let cmd = Command::Chain(ChainMessage::Forget(seq, key.to_string()));
+        let response = networking::forward_command(cmd, next.clone(), None, ctx);
+        if response != "OK\n" {
+            return response;
+        }
+    }
+
+    let storage = ctx.storage.lock().unwrap();
+    let mut seqs = ctx.chain.seqs.lock().unwrap();
+    if seqs.get(key) == Some(&seq) && entry(storage.as_ref(), key).is_none() {
+        seqs.remove(key);
+    }
+    "OK\n".to_string()
+}
+
+// answers the messages of the other members of a chain
+pub fn handle(ctx: &NodeContext, message: ChainMessage) -> String {
+    match message {
+        ChainMessage::Write(cmd) => {
+            if let Err(e) = ready(ctx) {
+                return e.response();
+            }
+            let mut members = chain(ctx, cmd.key().unwrap_or_default());
+            match members.iter().position(|node| node._id == ctx.me_id) {
+                Some(position) => {
+                    members.truncate(position + 1);
+                    lead(ctx, &members, *cmd)
+                }
+                None => Error::Unavailable(format!(
+                    "Not a member of the chain of '{}'",
+                    cmd.key().unwrap_or_default()
+                ))
+                .response(),
+            }
+        }
+        ChainMessage::Update(update) => match ready(ctx).and_then(|_| install(ctx, &update)) {
+            Ok(()) => pass_on(ctx, &update),
+            Err(e) => e.response(),
+        },
+        ChainMessage::Read(cmd) => read_local(ctx, &cmd),
+        ChainMessage::Sync(node) => sync_response(&updates_for(ctx, &node)),
+        ChainMessage::Missed => {
+            ctx.chain.stale.store(true, Ordering::Relaxed);
+            "OK\n".to_string()
+        }
+        ChainMessage::Forget(seq, key) => forget(ctx, &key, seq),
+    }
+}
+
+// the stored state of every key whose chain includes the node
+fn updates_for(ctx: &NodeContext, node: &str) -> Vec<ChainUpdate> {
+    let storage = ctx.storage.lock().unwrap();
+    let seqs = ctx.chain.seqs.lock().unwrap();
+    seqs.iter()
+        .filter(|(key, _)| preference(ctx, key).iter().any(|n| n._id == node))
+        .map(|(key, seq)| update_of(*seq, key, entry(storage.as_ref(), key)))
+        .collect()
+}
+
+// one `seq key [value]` line per update
+fn sync_response(updates: &[ChainUpdate]) -> String {
+    updates
+        .iter()
+        .map(|update| format!("{}\n", update))
+        .collect()
+}
+
+fn parse_sync(response: &str) -> Result<Vec<ChainUpdate>, Error> {
+    if let Some(e) = response.strip_prefix("Error: ") {
+        return Err(Error::parse(e.trim_end()));
+    }
+
+    response
+        .lines()
+        .map(|line| {
+            let tokens = commands::tokenize(line)?;
+            let parts: Vec<&[u8]> = tokens.iter().map(Vec::as_slice).collect();
+            commands::chain_update(&parts)
+        })
+        .collect()
+}
+
+fn no_chain(key: &str) -> Error {
+    Error::Unavailable(format!("No member of the chain of '{}' is available", key))
+}
+
+// pulls the keys of this node's chains from the other nodes after the first gossip round, when
+// the members gossip takes for alive change and when another member missed this node with an
+// update; a sync that missed a node is retried at the next check. Every sync pulls all keys of
+// the chains of this node, tombstones included, so its cost grows with the keys stored
+pub fn start(ctx: Arc<NodeContext>) {
+    if ctx.chain.keyspaces.is_empty() {
+        return;
+    }
+
+    std::thread::spawn(move || {
+        let mut retry = true;
+        loop {
+            if !ctx.chain.gossiped() {
+                std::thread::sleep(MEMBER_CHECK_INTERVAL);
+                continue;
+            }
+
+            let mut alive: Vec<String> = ctx
+                .cluster_snapshot
+                .lock()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect();
+            alive.sort();
+
+            let mut members = ctx.chain.members.lock().unwrap();
+            let changed = *members != alive;
+            if changed {
+                log(
+                    &format!("Chains reconfigured, members alive: {:?}", alive),
+                    ctx.log_enabled,
+                );
+                *members = alive;
+            }
+            drop(members);
+
+            let stale = ctx.chain.stale.swap(false, Ordering::Relaxed);
+            if changed || stale || retry {
+                retry = !sync(&ctx);
+            }
+
+            tell_missed(&ctx);
+            prune(&ctx);
+            std::thread::sleep(MEMBER_CHECK_INTERVAL);
+        }
+    });
+}
+
+// tells the members an update could not reach to sync, until they got the message
+fn tell_missed(ctx: &NodeContext) {
+    let missed = std::mem::take(&mut *ctx.chain.missed.lock().unwrap());
+    let nodes = ctx.partitioner.nodes();
+
+    for id in missed {
+        let Some(node) = nodes.iter().find(|node| node._id == id) else {
+            continue;
+        };
+        let credential = || ctx.auth.node_credential(&ctx.me_id);
+        let cmd = Command::Chain(ChainMessage::Missed);
+        let told = ctx
+            .peers
+            .request(node, &cmd, None, ctx.tls.as_deref(), credential)
+            .is_ok_and(|response| response == "OK\n");
+        if !told {
+            ctx.chain.missed.lock().unwrap().insert(id);
+        }
+    }
+}
+
+// whether every node gossip takes for alive answered; the ones it takes for dead are left out,
+// they are dead to the chains as well
+fn sync(ctx: &NodeContext) -> bool {
+    let alive = ctx.cluster_snapshot.lock().unwrap().clone();
+    let deadline = ctx.peers.deadline();
+    let mut installed = 0;
+    let mut complete = true;
+
+    for node in ctx.partitioner.nodes() {
+        if node._id == ctx.me_id {
+            continue;
+        }
+
+        let credential = || ctx.auth.node_credential(&ctx.me_id);
+        let cmd = Command::Chain(ChainMessage::Sync(ctx.me_id.clone()));
+        let updates = ctx
+            .peers
+            .request(&node, &cmd, Some(deadline), ctx.tls.as_deref(), credential)
+            .and_then(|response| parse_sync(&response));
+
+        match updates {
+            Ok(updates) => {
+                for update in updates {
+                    if install(ctx, &update).is_ok() {
+                        installed += 1;
+                    }
+                }
+            }
+            Err(e) => {
+                log(
+                    &format!(
+                        "Failed to sync the chains from {}: {}",
+                        node._id,
+                        e.message()
+                    ),
+                    ctx.log_enabled,
+                );
+                complete &= !alive.contains_key(&node._id);
+            }
+        }
+    }
+
+    if complete && !ctx.chain.synced.swap(true, Ordering::Relaxed) {
+        log(
+            &format!("Chains synced, {} updates pulled", installed),
+            ctx.log_enabled,
+        );
+    }
+    complete
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashing::HashRing;
+
+    // a single node with the chain keyspace `h:`, every chain of one member
+    fn single_node() -> NodeContext {
+        let node = ClusterNode {
+            _id: "1".into(),
+            host: "127.0.0.1".into(),
+            port: "0".into(),
+            gossip_port: "0".into(),
+        };
+        NodeContext::new(
+            "1".into(),
+            "memory",
+            false,
+            Partitioner::Hash(HashRing::build(vec![node], 16)),
+            &Arc::new(Mutex::new(HashMap::new())),
+            &Arc::new(Mutex::new(HashMap::new())),
+            None,
+        )
+        .with_chain(Chain::new(vec!["h:".into()], 1, Default::default()))
+    }
+
+    fn update(seq: u64, key: &str, value: Option<&[u8]>) -> ChainUpdate {
+        ChainUpdate {
+            seq,
+            key: key.into(),
+            value: value.map(<[u8]>::to_vec),
+            ttl: None,
+        }
+    }
+
+    #[test]
+    fn test_sync_response_round_trip() {
+        let updates = vec![
+            ChainUpdate {
+                seq: 7,
+                key: "a key".into(),
+                value: Some(b"a\nvalue".to_vec()),
+                ttl: None,
+            },
+            ChainUpdate {
+                seq: 9,
+                key: "gone".into(),
+                value: None,
+                ttl: None,
+            },
+        ];
+        assert_eq!(parse_sync(&sync_response(&updates)).unwrap(), updates);
+        assert_eq!(parse_sync("").unwrap(), []);
+        assert!(parse_sync("Error: UNAVAILABLE down\n").is_err());
+    }
+
+    #[test]
+    fn test_update_of() {
+        let mut storage = crate::storage::StorageBuilder::builder("memory").build();
+        storage.put("key", b"value".to_vec()).unwrap();
+        assert_eq!(
+            update_of(3, "key", entry(storage.as_ref(), "key")),
+            ChainUpdate {
+                seq: 3,
+                key: "key".into(),
+                value: Some(b"value".to_vec()),
+                ttl: None,
+            }
+        );
+
+        storage.expire("key", Duration::from_secs(10)).unwrap();
+        assert_eq!(
+            update_of(4, "key", entry(storage.as_ref(), "key")).ttl,
+            Some(10)
+        );
+        assert_eq!(
+            update_of(5, "gone", entry(storage.as_ref(), "gone")).value,
+            None
+        );
+    }
+
+    #[test]
+    fn test_next_seq() {
+        let now = next_seq(0);
+        assert!(now > 0);
+        assert_eq!(next_seq(u64::MAX - 1), u64::MAX);
+    }
+
+    #[test]
+    fn test_forget_tombstones() {
+        let ctx = single_node();
+        install(&ctx, &update(5, "h:gone", None)).unwrap();
+        install(&ctx, &update(3, "h:kept", Some(b"v"))).unwrap();
+        assert_eq!(ctx.chain.tombstones.lock().unwrap().len(), 1);
+
+        // an older update than the stored one leaves the tombstone
+        assert_eq!(forget(&ctx, "h:gone", 4), "OK\n");
+        assert!(ctx.chain.seqs.lock().unwrap().contains_key("h:gone"));
+
+        assert!(forget(&ctx, "h:kept", 3).starts_with("Error: CONFLICT"));
+        assert_eq!(forget(&ctx, "h:gone", 5), "OK\n");
+        let seqs = ctx.chain.seqs.lock().unwrap();
+        assert!(!seqs.contains_key("h:gone"));
+        assert!(seqs.contains_key("h:kept"));
+    }
+}
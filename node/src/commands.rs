@@ -1,47 +1,1029 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::storage::StoredEntry;
+
 #[derive(Debug, Clone)]
 pub enum Command {
-    Put(String, String),
+    Put(String, Vec<u8>),
+    PutIfVersion(String, Vec<u8>, u64),
+    // like PutIfVersion, but the key keeps its ttl
+    UpdateIfVersion(String, Vec<u8>, u64),
+    // key, value, seconds to live and the version the key must be at, if any; value and ttl are
+    // written at once
+    PutEx(String, Vec<u8>, u64, Option<u64>),
+    // key, expected value and new value, written only if the key holds the expected value
+    Cas(String, Vec<u8>, Vec<u8>),
     Read(String),
-    ReadKeyByRange(String, String),
-    BatchPut(Vec<String>),
+    // appends the suffix to the value, a missing key counts as empty
+    Append(String, Vec<u8>),
+    // writes the value and answers the previous one
+    GetSet(String, Vec<u8>),
+    // deletes the key and answers its value
+    GetDel(String),
+    StrLen(String),
+    ReadVersion(String),
+    // start and end key (both inclusive), the most entries to answer
+    ReadKeyByRange(String, String, Option<u64>, Scope),
+    BatchPut(Vec<(String, Vec<u8>)>),
+    BatchRead(Vec<String>),
     Delete(String),
+    // deleted only if the key holds the expected value
+    DeleteIf(String, Vec<u8>),
+    BatchDelete(Vec<String>),
+    Scan(Scan),
+    // hands the keys from the split key to the end of its range to the receiving node,
+    // along with their entries, versions and expirations; sent between nodes in range
+    // partitioning mode
+    Split(String, Vec<StoredEntry>),
+    Expire(String, u64),
+    // the amount added to the integer value of the key, negative for DECR
+    Incr(String, i64),
+    IncrFloat(String, f64),
+    ClusterNodes,
+    // MULTI queues the following commands of the connection until EXEC runs or DISCARD drops them
+    Multi,
+    Exec,
+    Discard,
+    // EXEC fails if one of the keys is written after WATCH
+    Watch(Vec<String>),
+    // a queued transaction, sent by the node it was queued on to the node owning its keys
+    Transaction(Transaction),
+    // two-phase commit of a transaction spanning nodes: the coordinator prepares every node's
+    // share by transaction id, then commits or aborts them all; a node whose locks time out
+    // asks the coordinator for the status
+    Prepare(String, String, Transaction),
+    Commit(String),
+    Abort(String),
+    TxStatus(String),
+    // sent between the replicas of a shard of a consistent keyspace
+    Raft(RaftMessage),
+    // sent along the replica chain of a key of a chain replicated keyspace
+    Chain(ChainMessage),
+    Protocol(Framing),
+    Auth(String, String),
+    // node id, unix timestamp, random nonce and HMAC of the three, authenticates a connection
+    // between nodes
+    NodeAuth(String, u64, String, String),
+}
+
+impl Command {
+    // the command as named in ACL rules
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Put(..)
+            | Command::PutIfVersion(..)
+            | Command::UpdateIfVersion(..)
+            | Command::PutEx(..)
+            | Command::Cas(..) => "PUT",
+            Command::Read(_) => "READ",
+            Command::Append(..) => "APPEND",
+            Command::GetSet(..) => "GETSET",
+            Command::GetDel(_) => "GETDEL",
+            Command::StrLen(_) => "STRLEN",
+            Command::ReadVersion(_) => "READVERSION",
+            Command::ReadKeyByRange(..) => "READRANGE",
+            Command::BatchPut(_) => "BATCHPUT",
+            Command::BatchRead(_) => "MGET",
+            Command::Delete(_) | Command::DeleteIf(..) => "DELETE",
+            Command::BatchDelete(_) => "BATCHDELETE",
+            Command::Scan(_) => "SCAN",
+            Command::Split(..) => "SPLIT",
+            Command::Expire(..) => "EXPIRE",
+            Command::Incr(..) => "INCR",
+            Command::IncrFloat(..) => "INCRFLOAT",
+            Command::ClusterNodes => "CLUSTER",
+            Command::Multi => "MULTI",
+            Command::Exec => "EXEC",
+            Command::Discard => "DISCARD",
+            Command::Watch(_) => "WATCH",
+            Command::Transaction(_) => "TRANSACTION",
+            Command::Prepare(..) => "PREPARE",
+            Command::Commit(_) => "COMMIT",
+            Command::Abort(_) => "ABORT",
+            Command::TxStatus(_) => "TXSTATUS",
+            Command::Raft(_) => "RAFT",
+            Command::Chain(_) => "CHAIN",
+            Command::Protocol(_) => "PROTOCOL",
+            Command::Auth(..) => "AUTH",
+            Command::NodeAuth(..) => "NODEAUTH",
+        }
+    }
+
+    // the key of a command working on a single key
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Command::Put(key, _)
+            | Command::PutIfVersion(key, ..)
+            | Command::UpdateIfVersion(key, ..)
+            | Command::PutEx(key, ..)
+            | Command::Cas(key, ..)
+            | Command::Read(key)
+            | Command::Append(key, _)
+            | Command::GetSet(key, _)
+            | Command::GetDel(key)
+            | Command::StrLen(key)
+            | Command::ReadVersion(key)
+            | Command::Delete(key)
+            | Command::DeleteIf(key, _)
+            | Command::Expire(key, _)
+            | Command::Incr(key, _)
+            | Command::IncrFloat(key, _) => Some(key),
+            _ => None,
+        }
+    }
+
+    // running the command again leaves the same data behind, so a failed forward can be retried
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            Command::Read(_)
+            | Command::StrLen(_)
+            | Command::ReadVersion(_)
+            | Command::ReadKeyByRange(..)
+            | Command::BatchRead(_)
+            | Command::Scan(_)
+            | Command::ClusterNodes => true,
+            // a replica keeps the update with the highest sequence number
+            Command::Chain(message) => !matches!(message, ChainMessage::Write(_)),
+            // a write applied before the connection failed and sent again can overwrite the
+            // write of another client in between
+            Command::Put(..) | Command::PutEx(..) | Command::BatchPut(_) | Command::Expire(..) => {
+                false
+            }
+            // a repeated conditional put or delete fails although the first one succeeded
+            Command::PutIfVersion(..)
+            | Command::UpdateIfVersion(..)
+            | Command::Cas(..)
+            | Command::Delete(_)
+            | Command::DeleteIf(..)
+            | Command::Append(..)
+            | Command::GetSet(..)
+            | Command::GetDel(_)
+            | Command::BatchDelete(_)
+            | Command::Split(..)
+            | Command::Incr(..)
+            | Command::IncrFloat(..)
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch(_)
+            | Command::Transaction(_)
+            | Command::Prepare(..)
+            | Command::Commit(_)
+            | Command::Abort(_)
+            | Command::TxStatus(_)
+            | Command::Protocol(_)
+            | Command::Auth(..)
+            | Command::NodeAuth(..) => false,
+            // replicas are sent their messages again with the next heartbeat or election, or
+            // the client retries
+            Command::Raft(_) => false,
+        }
+    }
+}
+
+// how responses are delimited on a client connection
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Framing {
+    // responses are written as they are, one request per line (nc friendly)
+    #[default]
+    Line,
+    // every response is preceded by its length in bytes on a line of its own
+    Framed,
+}
+
+// which nodes a range is read from
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Scope {
+    // every node, merged by the node the client is connected to
+    #[default]
+    Cluster,
+    // the node answering the command only, as asked by the node merging a cluster-wide read
+    Local,
+}
+
+// TRANSACTION <watched count> [key version]... [command]..., every command a quoted line
+#[derive(Debug, Clone, Default)]
+pub struct Transaction {
+    // the keys watched by the connection and their versions at the time, 0 for a missing key
+    pub watched: Vec<(String, u64)>,
+    // single key commands, run in order
+    pub commands: Vec<Command>,
+}
+
+// RAFT VOTE|APPEND|SNAPSHOT|SUBMIT <shard> ...
+#[derive(Debug, Clone)]
+pub enum RaftMessage {
+    // RAFT VOTE shard term candidate last_index last_term
+    Vote {
+        shard: u32,
+        term: u64,
+        candidate: String,
+        last_index: u64,
+        last_term: u64,
+    },
+    // RAFT APPEND shard term leader prev_index prev_term commit [term command]..., no entries
+    // for a heartbeat
+    Append {
+        shard: u32,
+        term: u64,
+        leader: String,
+        prev_index: u64,
+        prev_term: u64,
+        commit: u64,
+        entries: Vec<LogEntry>,
+    },
+    // RAFT SNAPSHOT shard term leader last_index last_term [key value version millis]..., every
+    // entry of the shard as applied up to the last index with its expiry, for a replica missing
+    // compacted log entries
+    Snapshot {
+        shard: u32,
+        term: u64,
+        leader: String,
+        last_index: u64,
+        last_term: u64,
+        entries: Vec<StoredEntry>,
+    },
+    // RAFT SUBMIT shard command, run only by the leader of the shard
+    Submit(u32, Box<Command>),
+}
+
+// a command in the log of a shard, written as its term and the quoted command line
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub term: u64,
+    // None for the empty entry a new leader starts its term with, written as ""
+    pub command: Option<Command>,
+}
+
+// CHAIN WRITE|UPDATE|READ|SYNC|MISSED|FORGET ...
+#[derive(Debug, Clone)]
+pub enum ChainMessage {
+    // CHAIN WRITE command, run only by the head of the chain of its key
+    Write(Box<Command>),
+    // CHAIN UPDATE seq key [value [ttl]], the state of a key after a write at the head
+    Update(ChainUpdate),
+    // CHAIN READ command, run only by a member holding every acknowledged write
+    Read(Box<Command>),
+    // CHAIN SYNC node, the updates of every key whose chain includes the node
+    Sync(String),
+    // CHAIN MISSED, sent to a member an update could not reach, which syncs again
+    Missed,
+    // CHAIN FORGET seq key, drops the sequence number of a key gone since that update on every
+    // member, passed down the chain like an update
+    Forget(u64, String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainUpdate {
+    // numbered by the head, a replica keeps the highest one it has seen of every key
+    pub seq: u64,
+    pub key: String,
+    // None for a deleted key
+    pub value: Option<Vec<u8>>,
+    // seconds the key has left to live, if it expires
+    pub ttl: Option<u64>,
+}
+
+// SCAN cursor [MATCH pattern] [PREFIX prefix] [COUNT n] [LOCAL]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scan {
+    // `0` to start, then the cursor answered by the previous call until that is `0` again
+    pub cursor: String,
+    pub pattern: Option<String>,
+    pub prefix: String,
+    // the most keys to answer, fewer may come back before the end
+    pub count: u64,
+    pub scope: Scope,
 }
 
 impl TryFrom<&str> for Command {
-    type Error = String;
+    type Error = Error;
 
     fn try_from(s: &str) -> Result<Self, Self::Error> {
-        let parts: Vec<&str> = s.trim().split_whitespace().collect();
+        let tokens = tokenize(s)?;
+        let parts: Vec<&[u8]> = tokens.iter().map(Vec::as_slice).collect();
         match parts.as_slice() {
-            ["PUT", key, value] => Ok(Command::Put(key.to_string(), value.to_string())),
-            ["READ", key] => Ok(Command::Read(key.to_string())),
-            ["READRANGE", start, end] => {
-                Ok(Command::ReadKeyByRange(start.to_string(), end.to_string()))
+            [b"PUT", key, value] => Ok(Command::Put(text(key)?, value.to_vec())),
+            [b"PUT", key, value, b"IFVERSION", version] => match number(version) {
+                Some(version) => Ok(Command::PutIfVersion(text(key)?, value.to_vec(), version)),
+                None => Err(bad_request("Invalid version")),
+            },
+            [b"PUT", key, value, b"EX", seconds] => match number(seconds) {
+                Some(seconds) => Ok(Command::PutEx(text(key)?, value.to_vec(), seconds, None)),
+                None => Err(bad_request("Invalid expiration")),
+            },
+            [b"PUT", key, value, b"IFVERSION", version, b"EX", seconds] => {
+                match (number(version), number(seconds)) {
+                    (Some(version), Some(seconds)) => Ok(Command::PutEx(
+                        text(key)?,
+                        value.to_vec(),
+                        seconds,
+                        Some(version),
+                    )),
+                    (None, _) => Err(bad_request("Invalid version")),
+                    (_, None) => Err(bad_request("Invalid expiration")),
+                }
+            }
+            [b"PUT", key, value, b"IFVERSION", version, b"KEEPTTL"] => match number(version) {
+                Some(version) => Ok(Command::UpdateIfVersion(
+                    text(key)?,
+                    value.to_vec(),
+                    version,
+                )),
+                None => Err(bad_request("Invalid version")),
+            },
+            // a key without a version does not exist yet
+            [b"PUTIFABSENT", key, value] => {
+                Ok(Command::PutIfVersion(text(key)?, value.to_vec(), 0))
+            }
+            [b"CAS", key, expected, value] => {
+                Ok(Command::Cas(text(key)?, expected.to_vec(), value.to_vec()))
+            }
+            [b"READ", key] => Ok(Command::Read(text(key)?)),
+            [b"APPEND", key, suffix] => Ok(Command::Append(text(key)?, suffix.to_vec())),
+            [b"GETSET", key, value] => Ok(Command::GetSet(text(key)?, value.to_vec())),
+            [b"GETDEL", key] => Ok(Command::GetDel(text(key)?)),
+            [b"STRLEN", key] => Ok(Command::StrLen(text(key)?)),
+            [b"READVERSION", key] => Ok(Command::ReadVersion(text(key)?)),
+            [b"READRANGE", start, end, options @ ..] => range(start, end, options),
+            [b"BATCHPUT", rest @ ..] if rest.is_empty() || rest.len() % 2 != 0 => {
+                Err(bad_request("BATCHPUT expects key value pairs"))
+            }
+            [b"BATCHPUT", rest @ ..] => {
+                let mut entries = Vec::new();
+                for pair in rest.chunks_exact(2) {
+                    entries.push((text(pair[0])?, pair[1].to_vec()));
+                }
+                Ok(Command::BatchPut(entries))
             }
-            ["BATCHPUT", rest @ ..] => Ok(Command::BatchPut(
-                rest.iter().map(|&k| k.to_string()).collect(),
+            [b"MGET" | b"BATCHREAD", keys @ ..] if !keys.is_empty() => {
+                Ok(Command::BatchRead(texts(keys)?))
+            }
+            [b"SCAN", cursor, options @ ..] => scan(cursor, options),
+            [b"SPLIT", _, rest @ ..] if rest.len() % 4 != 0 => Err(bad_request(
+                "SPLIT expects key, value, version and milliseconds to expiry, 0 for none",
+            )),
+            [b"SPLIT", at, rest @ ..] => Ok(Command::Split(text(at)?, stored_entries(rest)?)),
+            [b"DELETE", key] => Ok(Command::Delete(text(key)?)),
+            [b"DELETEIF", key, expected] => Ok(Command::DeleteIf(text(key)?, expected.to_vec())),
+            [b"BATCHDELETE", keys @ ..] if !keys.is_empty() => {
+                Ok(Command::BatchDelete(texts(keys)?))
+            }
+            [b"EXPIRE", key, seconds] => match number(seconds) {
+                Some(seconds) => Ok(Command::Expire(text(key)?, seconds)),
+                None => Err(bad_request("Invalid expiration")),
+            },
+            [b"INCR", key] => Ok(Command::Incr(text(key)?, 1)),
+            [b"DECR", key] => Ok(Command::Incr(text(key)?, -1)),
+            [b"INCR", key, by] => match signed(by) {
+                Some(by) => Ok(Command::Incr(text(key)?, by)),
+                None => Err(bad_request("Invalid increment")),
+            },
+            [b"DECR", key, by] => match signed(by).and_then(i64::checked_neg) {
+                Some(by) => Ok(Command::Incr(text(key)?, by)),
+                None => Err(bad_request("Invalid decrement")),
+            },
+            [b"INCRFLOAT", key, by] => match float(by) {
+                Some(by) => Ok(Command::IncrFloat(text(key)?, by)),
+                None => Err(bad_request("Invalid increment")),
+            },
+            [b"CLUSTER", b"NODES"] => Ok(Command::ClusterNodes),
+            [b"MULTI"] => Ok(Command::Multi),
+            [b"EXEC"] => Ok(Command::Exec),
+            [b"DISCARD"] => Ok(Command::Discard),
+            [b"WATCH", keys @ ..] if !keys.is_empty() => Ok(Command::Watch(texts(keys)?)),
+            [b"TRANSACTION", count, rest @ ..] => {
+                Ok(Command::Transaction(transaction(count, rest)?))
+            }
+            [b"PREPARE", txid, coordinator, count, rest @ ..] => Ok(Command::Prepare(
+                text(txid)?,
+                text(coordinator)?,
+                transaction(count, rest)?,
             )),
-            ["DELETE", key] => Ok(Command::Delete(key.to_string())),
-            _ => Err("Invalid command format".to_string()),
+            [b"COMMIT", txid] => Ok(Command::Commit(text(txid)?)),
+            [b"ABORT", txid] => Ok(Command::Abort(text(txid)?)),
+            [b"TXSTATUS", txid] => Ok(Command::TxStatus(text(txid)?)),
+            [b"RAFT", kind, shard, rest @ ..] => {
+                let shard = number(shard)
+                    .and_then(|shard| u32::try_from(shard).ok())
+                    .ok_or_else(|| bad_request("Invalid shard"))?;
+                Ok(Command::Raft(raft(kind, shard, rest)?))
+            }
+            [b"CHAIN", b"WRITE", line] => Ok(Command::Chain(ChainMessage::Write(Box::new(
+                Command::try_from(text(line)?.as_str())?,
+            )))),
+            [b"CHAIN", b"UPDATE", rest @ ..] => {
+                Ok(Command::Chain(ChainMessage::Update(chain_update(rest)?)))
+            }
+            [b"CHAIN", b"READ", line] => Ok(Command::Chain(ChainMessage::Read(Box::new(
+                Command::try_from(text(line)?.as_str())?,
+            )))),
+            [b"CHAIN", b"SYNC", node] => Ok(Command::Chain(ChainMessage::Sync(text(node)?))),
+            [b"CHAIN", b"MISSED"] => Ok(Command::Chain(ChainMessage::Missed)),
+            [b"CHAIN", b"FORGET", seq, key] => match number(seq) {
+                Some(seq) => Ok(Command::Chain(ChainMessage::Forget(seq, text(key)?))),
+                None => Err(bad_request("Invalid sequence number")),
+            },
+            [b"PROTOCOL", b"LINE"] => Ok(Command::Protocol(Framing::Line)),
+            [b"PROTOCOL", b"FRAMED"] => Ok(Command::Protocol(Framing::Framed)),
+            [b"AUTH", user, password] => Ok(Command::Auth(text(user)?, text(password)?)),
+            [b"NODEAUTH", node_id, timestamp, nonce, mac] => match number(timestamp) {
+                Some(timestamp) => Ok(Command::NodeAuth(
+                    text(node_id)?,
+                    timestamp,
+                    text(nonce)?,
+                    text(mac)?,
+                )),
+                None => Err(bad_request("Invalid timestamp")),
+            },
+            _ => Err(bad_request("Invalid command format")),
         }
     }
 }
 
-impl ToString for Command {
-    fn to_string(&self) -> String {
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Command::Put(key, value) => format!("PUT {} {}", key, value),
-            Command::Read(key) => format!("READ {}", key),
-            Command::ReadKeyByRange(start, end) => format!("READRANGE {} {}", start, end),
+            Command::Put(key, value) => write!(f, "PUT {} {}", quote_str(key), quote(value)),
+            Command::PutIfVersion(key, value, version) => write!(
+                f,
+                "PUT {} {} IFVERSION {}",
+                quote_str(key),
+                quote(value),
+                version
+            ),
+            Command::UpdateIfVersion(key, value, version) => write!(
+                f,
+                "PUT {} {} IFVERSION {} KEEPTTL",
+                quote_str(key),
+                quote(value),
+                version
+            ),
+            Command::PutEx(key, value, seconds, version) => {
+                write!(f, "PUT {} {}", quote_str(key), quote(value))?;
+                if let Some(version) = version {
+                    write!(f, " IFVERSION {}", version)?;
+                }
+                write!(f, " EX {}", seconds)
+            }
+            Command::Cas(key, expected, value) => write!(
+                f,
+                "CAS {} {} {}",
+                quote_str(key),
+                quote(expected),
+                quote(value)
+            ),
+            Command::Read(key) => write!(f, "READ {}", quote_str(key)),
+            Command::Append(key, suffix) => {
+                write!(f, "APPEND {} {}", quote_str(key), quote(suffix))
+            }
+            Command::GetSet(key, value) => write!(f, "GETSET {} {}", quote_str(key), quote(value)),
+            Command::GetDel(key) => write!(f, "GETDEL {}", quote_str(key)),
+            Command::StrLen(key) => write!(f, "STRLEN {}", quote_str(key)),
+            Command::ReadVersion(key) => write!(f, "READVERSION {}", quote_str(key)),
+            Command::ReadKeyByRange(start, end, limit, scope) => {
+                write!(f, "READRANGE {} {}", quote_str(start), quote_str(end))?;
+                if let Some(limit) = limit {
+                    write!(f, " LIMIT {}", limit)?;
+                }
+                if *scope == Scope::Local {
+                    write!(f, " LOCAL")?;
+                }
+                Ok(())
+            }
             Command::BatchPut(entries) => {
-                let mut cmd_str = String::from("BATCHPUT");
-                for entry in entries {
-                    cmd_str.push_str(&format!(" {}", entry));
+                write!(f, "BATCHPUT")?;
+                for (key, value) in entries {
+                    write!(f, " {} {}", quote_str(key), quote(value))?;
+                }
+                Ok(())
+            }
+            Command::BatchRead(keys) => write_keys(f, "MGET", keys),
+            Command::Delete(key) => write!(f, "DELETE {}", quote_str(key)),
+            Command::DeleteIf(key, expected) => {
+                write!(f, "DELETEIF {} {}", quote_str(key), quote(expected))
+            }
+            Command::BatchDelete(keys) => write_keys(f, "BATCHDELETE", keys),
+            Command::Split(at, entries) => {
+                write!(f, "SPLIT {}", quote_str(at))?;
+                write_entries(f, entries)
+            }
+            Command::Scan(scan) => {
+                write!(f, "SCAN {}", quote_str(&scan.cursor))?;
+                if let Some(pattern) = &scan.pattern {
+                    write!(f, " MATCH {}", quote_str(pattern))?;
+                }
+                if !scan.prefix.is_empty() {
+                    write!(f, " PREFIX {}", quote_str(&scan.prefix))?;
+                }
+                write!(f, " COUNT {}", scan.count)?;
+                if scan.scope == Scope::Local {
+                    write!(f, " LOCAL")?;
+                }
+                Ok(())
+            }
+            Command::Expire(key, seconds) => write!(f, "EXPIRE {} {}", quote_str(key), seconds),
+            Command::Incr(key, by) => write!(f, "INCR {} {}", quote_str(key), by),
+            Command::IncrFloat(key, by) => write!(f, "INCRFLOAT {} {}", quote_str(key), by),
+            Command::ClusterNodes => write!(f, "CLUSTER NODES"),
+            Command::Multi => write!(f, "MULTI"),
+            Command::Exec => write!(f, "EXEC"),
+            Command::Discard => write!(f, "DISCARD"),
+            Command::Watch(keys) => write_keys(f, "WATCH", keys),
+            Command::Transaction(transaction) => {
+                write!(f, "TRANSACTION")?;
+                write_transaction(f, transaction)
+            }
+            Command::Prepare(txid, coordinator, transaction) => {
+                write!(f, "PREPARE {} {}", quote_str(txid), quote_str(coordinator))?;
+                write_transaction(f, transaction)
+            }
+            Command::Commit(txid) => write!(f, "COMMIT {}", quote_str(txid)),
+            Command::Abort(txid) => write!(f, "ABORT {}", quote_str(txid)),
+            Command::TxStatus(txid) => write!(f, "TXSTATUS {}", quote_str(txid)),
+            Command::Raft(message) => write_raft(f, message),
+            Command::Chain(ChainMessage::Write(cmd)) => {
+                write!(f, "CHAIN WRITE {}", quote_str(&cmd.to_string()))
+            }
+            Command::Chain(ChainMessage::Update(update)) => {
+                write!(f, "CHAIN UPDATE {}", update)
+            }
+            Command::Chain(ChainMessage::Read(cmd)) => {
+                write!(f, "CHAIN READ {}", quote_str(&cmd.to_string()))
+            }
+            Command::Chain(ChainMessage::Sync(node)) => write!(f, "CHAIN SYNC {}", quote_str(node)),
+            Command::Chain(ChainMessage::Missed) => write!(f, "CHAIN MISSED"),
+            Command::Chain(ChainMessage::Forget(seq, key)) => {
+                write!(f, "CHAIN FORGET {} {}", seq, quote_str(key))
+            }
+            Command::Protocol(Framing::Line) => write!(f, "PROTOCOL LINE"),
+            Command::Protocol(Framing::Framed) => write!(f, "PROTOCOL FRAMED"),
+            Command::Auth(user, password) => {
+                write!(f, "AUTH {} {}", quote_str(user), quote_str(password))
+            }
+            Command::NodeAuth(node_id, timestamp, nonce, mac) => {
+                write!(
+                    f,
+                    "NODEAUTH {} {} {} {}",
+                    quote_str(node_id),
+                    timestamp,
+                    nonce,
+                    mac
+                )
+            }
+        }
+    }
+}
+
+// keys are text, values may be any bytes
+fn text(token: &[u8]) -> Result<String, Error> {
+    String::from_utf8(token.to_vec()).map_err(|_| bad_request("Keys must be valid UTF-8"))
+}
+
+// READRANGE start end [LIMIT n] [LOCAL]
+fn range(start: &[u8], end: &[u8], mut options: &[&[u8]]) -> Result<Command, Error> {
+    let mut limit = None;
+    let mut scope = Scope::Cluster;
+
+    loop {
+        match options {
+            [] => break,
+            [b"LIMIT", n, rest @ ..] => {
+                limit = Some(number(n).ok_or_else(|| bad_request("Invalid limit"))?);
+                options = rest;
+            }
+            [b"LOCAL", rest @ ..] => {
+                scope = Scope::Local;
+                options = rest;
+            }
+            _ => return Err(bad_request("Invalid command format")),
+        }
+    }
+
+    Ok(Command::ReadKeyByRange(
+        text(start)?,
+        text(end)?,
+        limit,
+        scope,
+    ))
+}
+
+// SCAN cursor [MATCH pattern] [PREFIX prefix] [COUNT n] [LOCAL], 10 keys by default
+fn scan(cursor: &[u8], mut options: &[&[u8]]) -> Result<Command, Error> {
+    let mut scan = Scan {
+        cursor: text(cursor)?,
+        pattern: None,
+        prefix: String::new(),
+        count: 10,
+        scope: Scope::Cluster,
+    };
+
+    loop {
+        match options {
+            [] => break,
+            [b"MATCH", pattern, rest @ ..] => {
+                scan.pattern = Some(text(pattern)?);
+                options = rest;
+            }
+            [b"PREFIX", prefix, rest @ ..] => {
+                scan.prefix = text(prefix)?;
+                options = rest;
+            }
+            [b"COUNT", n, rest @ ..] => {
+                scan.count = number(n)
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| bad_request("Invalid count"))?;
+                options = rest;
+            }
+            [b"LOCAL", rest @ ..] => {
+                scan.scope = Scope::Local;
+                options = rest;
+            }
+            _ => return Err(bad_request("Invalid command format")),
+        }
+    }
+
+    Ok(Command::Scan(scan))
+}
+
+fn transaction(count: &[u8], rest: &[&[u8]]) -> Result<Transaction, Error> {
+    let count = number(count).ok_or_else(|| bad_request("Invalid watched key count"))? as usize;
+    let (watched, commands) = rest
+        .split_at_checked(count.saturating_mul(2))
+        .ok_or_else(|| bad_request("Invalid watched key count"))?;
+
+    let mut transaction = Transaction::default();
+    for pair in watched.chunks_exact(2) {
+        let version = number(pair[1]).ok_or_else(|| bad_request("Invalid version"))?;
+        transaction.watched.push((text(pair[0])?, version));
+    }
+    for line in commands {
+        let cmd = Command::try_from(text(line)?.as_str())?;
+        if cmd.key().is_none() {
+            return Err(bad_request("Transactions hold single key commands only"));
+        }
+        transaction.commands.push(cmd);
+    }
+    Ok(transaction)
+}
+
+fn write_transaction(f: &mut fmt::Formatter<'_>, transaction: &Transaction) -> fmt::Result {
+    write!(f, " {}", transaction.watched.len())?;
+    for (key, version) in &transaction.watched {
+        write!(f, " {} {}", quote_str(key), version)?;
+    }
+    for cmd in &transaction.commands {
+        write!(f, " {}", quote_str(&cmd.to_string()))?;
+    }
+    Ok(())
+}
+
+// `key value version milliseconds` per entry, 0 milliseconds for a key that does not expire
+fn stored_entries(tokens: &[&[u8]]) -> Result<Vec<StoredEntry>, Error> {
+    let mut entries = Vec::new();
+    for entry in tokens.chunks_exact(4) {
+        let (Some(version), Some(millis)) = (number(entry[2]), number(entry[3])) else {
+            return Err(bad_request("Invalid stored entry"));
+        };
+        entries.push(StoredEntry {
+            key: text(entry[0])?,
+            value: entry[1].to_vec(),
+            version,
+            expires_in: (millis > 0).then(|| Duration::from_millis(millis)),
+        });
+    }
+    Ok(entries)
+}
+
+fn write_entries(f: &mut fmt::Formatter<'_>, entries: &[StoredEntry]) -> fmt::Result {
+    for entry in entries {
+        // a key about to expire still needs an expiry
+        let millis = entry
+            .expires_in
+            .map_or(0, |ttl| ttl.as_millis().clamp(1, u64::MAX as u128) as u64);
+        write!(
+            f,
+            " {} {} {} {}",
+            quote_str(&entry.key),
+            quote(&entry.value),
+            entry.version,
+            millis
+        )?;
+    }
+    Ok(())
+}
+
+fn raft(kind: &[u8], shard: u32, rest: &[&[u8]]) -> Result<RaftMessage, Error> {
+    let index = |token: &[u8]| number(token).ok_or_else(|| bad_request("Invalid index"));
+    match (kind, rest) {
+        (b"VOTE", [term, candidate, last_index, last_term]) => Ok(RaftMessage::Vote {
+            shard,
+            term: index(term)?,
+            candidate: text(candidate)?,
+            last_index: index(last_index)?,
+            last_term: index(last_term)?,
+        }),
+        (b"APPEND", [term, leader, prev_index, prev_term, commit, entries @ ..])
+            if entries.len() % 2 == 0 =>
+        {
+            let mut log = Vec::new();
+            for pair in entries.chunks_exact(2) {
+                let command = match text(pair[1])?.as_str() {
+                    "" => None,
+                    line => Some(Command::try_from(line)?),
+                };
+                log.push(LogEntry {
+                    term: index(pair[0])?,
+                    command,
+                });
+            }
+            Ok(RaftMessage::Append {
+                shard,
+                term: index(term)?,
+                leader: text(leader)?,
+                prev_index: index(prev_index)?,
+                prev_term: index(prev_term)?,
+                commit: index(commit)?,
+                entries: log,
+            })
+        }
+        (b"SNAPSHOT", [term, leader, last_index, last_term, entries @ ..])
+            if entries.len() % 4 == 0 =>
+        {
+            Ok(RaftMessage::Snapshot {
+                shard,
+                term: index(term)?,
+                leader: text(leader)?,
+                last_index: index(last_index)?,
+                last_term: index(last_term)?,
+                entries: stored_entries(entries)?,
+            })
+        }
+        (b"SUBMIT", [line]) => Ok(RaftMessage::Submit(
+            shard,
+            Box::new(Command::try_from(text(line)?.as_str())?),
+        )),
+        _ => Err(bad_request("Invalid command format")),
+    }
+}
+
+// seq key [value [ttl]]
+pub fn chain_update(tokens: &[&[u8]]) -> Result<ChainUpdate, Error> {
+    let (seq, key, value, ttl) = match tokens {
+        [seq, key] => (seq, key, None, None),
+        [seq, key, value] => (seq, key, Some(value.to_vec()), None),
+        [seq, key, value, ttl] => (
+            seq,
+            key,
+            Some(value.to_vec()),
+            Some(number(ttl).ok_or_else(|| bad_request("Invalid expiration"))?),
+        ),
+        _ => return Err(bad_request("Invalid command format")),
+    };
+
+    Ok(ChainUpdate {
+        seq: number(seq).ok_or_else(|| bad_request("Invalid sequence number"))?,
+        key: text(key)?,
+        value,
+        ttl,
+    })
+}
+
+impl fmt::Display for ChainUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.seq, quote_str(&self.key))?;
+        if let Some(value) = &self.value {
+            write!(f, " {}", quote(value))?;
+        }
+        if let Some(ttl) = self.ttl {
+            write!(f, " {}", ttl)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_raft(f: &mut fmt::Formatter<'_>, message: &RaftMessage) -> fmt::Result {
+    match message {
+        RaftMessage::Vote {
+            shard,
+            term,
+            candidate,
+            last_index,
+            last_term,
+        } => write!(
+            f,
+            "RAFT VOTE {} {} {} {} {}",
+            shard,
+            term,
+            quote_str(candidate),
+            last_index,
+            last_term
+        ),
+        RaftMessage::Append {
+            shard,
+            term,
+            leader,
+            prev_index,
+            prev_term,
+            commit,
+            entries,
+        } => {
+            write!(
+                f,
+                "RAFT APPEND {} {} {} {} {} {}",
+                shard,
+                term,
+                quote_str(leader),
+                prev_index,
+                prev_term,
+                commit
+            )?;
+            for entry in entries {
+                let line = entry.command.as_ref().map(Command::to_string);
+                write!(
+                    f,
+                    " {} {}",
+                    entry.term,
+                    quote_str(line.as_deref().unwrap_or_default())
+                )?;
+            }
+            Ok(())
+        }
+        RaftMessage::Snapshot {
+            shard,
+            term,
+            leader,
+            last_index,
+            last_term,
+            entries,
+        } => {
+            write!(
+                f,
+                "RAFT SNAPSHOT {} {} {} {} {}",
+                shard,
+                term,
+                quote_str(leader),
+                last_index,
+                last_term
+            )?;
+            write_entries(f, entries)
+        }
+        RaftMessage::Submit(shard, cmd) => {
+            write!(f, "RAFT SUBMIT {} {}", shard, quote_str(&cmd.to_string()))
+        }
+    }
+}
+
+fn texts(tokens: &[&[u8]]) -> Result<Vec<String>, Error> {
+    tokens.iter().map(|token| text(token)).collect()
+}
+
+fn write_keys(f: &mut fmt::Formatter<'_>, name: &str, keys: &[String]) -> fmt::Result {
+    write!(f, "{}", name)?;
+    for key in keys {
+        write!(f, " {}", quote_str(key))?;
+    }
+    Ok(())
+}
+
+fn bad_request(message: &str) -> Error {
+    Error::BadRequest(message.to_string())
+}
+
+fn number(token: &[u8]) -> Option<u64> {
+    std::str::from_utf8(token).ok()?.parse().ok()
+}
+
+fn signed(token: &[u8]) -> Option<i64> {
+    std::str::from_utf8(token).ok()?.parse().ok()
+}
+
+// infinity and NaN would make the value unusable
+fn float(token: &[u8]) -> Option<f64> {
+    let value: f64 = std::str::from_utf8(token).ok()?.parse().ok()?;
+    value.is_finite().then_some(value)
+}
+
+// splits a line into whitespace separated tokens, a token in double quotes may contain
+// whitespace and the escapes \" \\ \n \r \t \0 and \xNN, unquoted tokens are taken as they are
+pub fn tokenize(line: &str) -> Result<Vec<Vec<u8>>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let Some(first) = chars.next() else {
+            return Ok(tokens);
+        };
+
+        let mut token = Vec::new();
+        if first != '"' {
+            push_char(&mut token, first);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                push_char(&mut token, c);
+            }
+            tokens.push(token);
+            continue;
+        }
+
+        loop {
+            match chars.next() {
+                None => return Err(bad_request("Unterminated quote")),
+                Some('"') => break,
+                Some('\\') => match chars.next() {
+                    Some('"') => token.push(b'"'),
+                    Some('\\') => token.push(b'\\'),
+                    Some('n') => token.push(b'\n'),
+                    Some('r') => token.push(b'\r'),
+                    Some('t') => token.push(b'\t'),
+                    Some('0') => token.push(0),
+                    Some('x') => {
+                        let hex: String = chars.by_ref().take(2).collect();
+                        match u8::from_str_radix(&hex, 16) {
+                            Ok(byte) if hex.len() == 2 => token.push(byte),
+                            _ => return Err(bad_request("Invalid escape sequence")),
+                        }
+                    }
+                    _ => return Err(bad_request("Invalid escape sequence")),
+                },
+                Some(c) => push_char(&mut token, c),
+            }
+        }
+
+        if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+            return Err(bad_request("Unexpected character after closing quote"));
+        }
+        tokens.push(token);
+    }
+}
+
+fn push_char(token: &mut Vec<u8>, c: char) {
+    token.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+}
+
+// answered for a value that does not exist where that is not an error, such as the previous value
+// of a new key
+pub const NIL: &str = "(nil)";
+
+// answered by EXEC for a transaction without commands
+pub const EMPTY: &str = "(empty)";
+
+// renders bytes as a single token, values that would not survive tokenizing as they are
+// (empty, whitespace, control characters, quotes, backslashes, invalid UTF-8) are quoted
+// a token starting like an error answer or reading as `NIL` is quoted as well, so a line of
+// stored keys and values never reads as one
+pub fn quote(value: &[u8]) -> String {
+    if let Ok(s) = std::str::from_utf8(value)
+        && !s.is_empty()
+        && !s.contains(needs_quoting)
+        && !s.starts_with("Error:")
+        && s != NIL
+    {
+        return s.to_string();
+    }
+
+    let mut quoted = String::from("\"");
+    for chunk in value.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                '\n' => quoted.push_str("\\n"),
+                '\r' => quoted.push_str("\\r"),
+                '\t' => quoted.push_str("\\t"),
+                c if c.is_control() => {
+                    for byte in c.encode_utf8(&mut [0; 4]).bytes() {
+                        quoted.push_str(&format!("\\x{:02x}", byte));
+                    }
                 }
-                cmd_str
+                c => quoted.push(c),
             }
-            Command::Delete(key) => format!("DELETE {}", key),
         }
+        for byte in chunk.invalid() {
+            quoted.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn needs_quoting(c: char) -> bool {
+    c.is_whitespace() || c.is_control() || c == '"' || c == '\\'
+}
+
+pub fn quote_str(value: &str) -> String {
+    quote(value.as_bytes())
+}
+
+// reverses `quote` for a response holding a single value
+pub fn unquote(s: &str) -> Result<Vec<u8>, Error> {
+    let mut tokens = tokenize(s)?;
+    match tokens.len() {
+        1 => Ok(tokens.remove(0)),
+        _ => Err(bad_request("Invalid value")),
     }
 }
 
@@ -53,7 +1035,9 @@ mod tests {
     fn test_command_from_str_put() {
         let cmd_result = Command::try_from("PUT key value");
 
-        assert!(matches!(cmd_result, Ok(Command::Put(ref k, ref v)) if k == "key" && v == "value"));
+        assert!(
+            matches!(cmd_result, Ok(Command::Put(ref k, ref v)) if k == "key" && v == b"value")
+        );
     }
 
     #[test]
@@ -63,13 +1047,201 @@ mod tests {
         assert!(matches!(cmd_result, Ok(Command::Read(ref k)) if k == "key"));
     }
 
+    #[test]
+    fn test_command_from_str_read_version() {
+        assert!(matches!(
+            Command::try_from("READVERSION key"),
+            Ok(Command::ReadVersion(ref k)) if k == "key"
+        ));
+    }
+
+    #[test]
+    fn test_command_from_str_value_operations() {
+        assert!(matches!(
+            Command::try_from("APPEND log \"a line\\n\""),
+            Ok(Command::Append(ref k, ref s)) if k == "log" && s == b"a line\n"
+        ));
+        assert!(
+            matches!(Command::try_from("GETSET k v"), Ok(Command::GetSet(ref k, ref v)) if k == "k" && v == b"v")
+        );
+        assert!(matches!(Command::try_from("GETDEL k"), Ok(Command::GetDel(ref k)) if k == "k"));
+        assert!(matches!(Command::try_from("STRLEN k"), Ok(Command::StrLen(ref k)) if k == "k"));
+        assert!(Command::try_from("APPEND k").is_err());
+    }
+
+    #[test]
+    fn test_command_from_str_transaction() {
+        assert!(matches!(Command::try_from("MULTI"), Ok(Command::Multi)));
+        assert!(
+            matches!(Command::try_from("WATCH a b"), Ok(Command::Watch(ref k)) if k.len() == 2)
+        );
+        assert!(Command::try_from("WATCH").is_err());
+
+        let transaction = Command::Transaction(Transaction {
+            watched: vec![("a key".into(), 3)],
+            commands: vec![
+                Command::Put("a key".into(), b"a value".to_vec()),
+                Command::Incr("b".into(), 1),
+            ],
+        });
+        let line = transaction.to_string();
+        match Command::try_from(line.as_str()) {
+            Ok(Command::Transaction(parsed)) => {
+                assert_eq!(parsed.watched, [("a key".to_string(), 3)]);
+                assert_eq!(parsed.commands.len(), 2);
+                assert_eq!(parsed.commands[0].to_string(), "PUT \"a key\" \"a value\"");
+                assert_eq!(parsed.commands[1].to_string(), "INCR b 1");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            Command::try_from("PREPARE 1-42-0 1 0 \"INCR b 1\""),
+            Ok(Command::Prepare(ref txid, ref coordinator, ref t)) if txid == "1-42-0" && coordinator == "1" && t.commands.len() == 1
+        ));
+        assert!(matches!(
+            Command::try_from("COMMIT 1-42-0"),
+            Ok(Command::Commit(_))
+        ));
+        assert!(Command::try_from("TRANSACTION 0 \"CLUSTER NODES\"").is_err());
+        assert!(Command::try_from("TRANSACTION 2 a 1").is_err());
+    }
+
+    #[test]
+    fn test_command_from_str_raft() {
+        let append = Command::Raft(RaftMessage::Append {
+            shard: 3,
+            term: 2,
+            leader: "1".into(),
+            prev_index: 4,
+            prev_term: 1,
+            commit: 4,
+            entries: vec![
+                LogEntry {
+                    term: 2,
+                    command: None,
+                },
+                LogEntry {
+                    term: 2,
+                    command: Some(Command::Put("a key".into(), b"a value".to_vec())),
+                },
+            ],
+        });
+        let line = append.to_string();
+        assert_eq!(
+            line,
+            "RAFT APPEND 3 2 1 4 1 4 2 \"\" 2 \"PUT \\\"a key\\\" \\\"a value\\\"\""
+        );
+        match Command::try_from(line.as_str()) {
+            Ok(Command::Raft(RaftMessage::Append { entries, .. })) => {
+                assert!(entries[0].command.is_none());
+                assert_eq!(
+                    entries[1].command.as_ref().unwrap().to_string(),
+                    "PUT \"a key\" \"a value\""
+                );
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert!(matches!(
+            Command::try_from("RAFT VOTE 0 5 2 10 4"),
+            Ok(Command::Raft(RaftMessage::Vote { term: 5, ref candidate, last_index: 10, .. })) if candidate == "2"
+        ));
+        assert!(matches!(
+            Command::try_from("RAFT SNAPSHOT 1 5 2 10 4 k v 3 1500"),
+            Ok(Command::Raft(RaftMessage::Snapshot { ref entries, .. }))
+                if entries.len() == 1 && entries[0].expires_in == Some(Duration::from_millis(1500))
+        ));
+        assert!(Command::try_from("RAFT SNAPSHOT 1 5 2 10 4 k v").is_err());
+        assert!(matches!(
+            Command::try_from("RAFT SUBMIT 1 \"INCR k 1\""),
+            Ok(Command::Raft(RaftMessage::Submit(1, _)))
+        ));
+        assert!(Command::try_from("RAFT APPEND 1 5 2 10 4 10 2").is_err());
+        assert!(Command::try_from("RAFT VOTE x 5 2 10 4").is_err());
+    }
+
+    #[test]
+    fn test_command_from_str_conditional() {
+        let cmd_result = Command::try_from("PUT key value IFVERSION 7");
+
+        assert!(
+            matches!(cmd_result, Ok(Command::PutIfVersion(ref k, ref v, 7)) if k == "key" && v == b"value")
+        );
+        assert!(Command::try_from("PUT key value IFVERSION x").is_err());
+        assert!(matches!(
+            Command::try_from("PUT key value EX 10"),
+            Ok(Command::PutEx(ref k, _, 10, None)) if k == "key"
+        ));
+        assert_eq!(
+            Command::try_from("PUT key value IFVERSION 0 EX 10")
+                .unwrap()
+                .to_string(),
+            "PUT key value IFVERSION 0 EX 10"
+        );
+        assert!(Command::try_from("PUT key value EX x").is_err());
+        assert_eq!(
+            Command::try_from("PUT key value IFVERSION 7 KEEPTTL")
+                .unwrap()
+                .to_string(),
+            "PUT key value IFVERSION 7 KEEPTTL"
+        );
+        assert!(matches!(
+            Command::try_from("PUTIFABSENT key value"),
+            Ok(Command::PutIfVersion(ref k, _, 0)) if k == "key"
+        ));
+        assert!(matches!(
+            Command::try_from("CAS key old new"),
+            Ok(Command::Cas(ref k, ref e, ref v)) if k == "key" && e == b"old" && v == b"new"
+        ));
+        assert!(matches!(
+            Command::try_from("DELETEIF key old"),
+            Ok(Command::DeleteIf(ref k, ref e)) if k == "key" && e == b"old"
+        ));
+        assert_eq!(
+            Command::try_from("CAS key \"an old\" new")
+                .unwrap()
+                .to_string(),
+            "CAS key \"an old\" new"
+        );
+        assert!(Command::try_from("CAS key old").is_err());
+    }
+
     #[test]
     fn test_command_from_str_read_range() {
         let cmd_result = Command::try_from("READRANGE startkey endkey");
 
         assert!(
-            matches!(cmd_result, Ok(Command::ReadKeyByRange(ref start, ref end)) if start == "startkey" && end == "endkey")
+            matches!(cmd_result, Ok(Command::ReadKeyByRange(ref start, ref end, None, Scope::Cluster)) if start == "startkey" && end == "endkey")
         );
+
+        let cmd = Command::try_from("READRANGE a z LIMIT 10 LOCAL").unwrap();
+        assert!(matches!(
+            cmd,
+            Command::ReadKeyByRange(_, _, Some(10), Scope::Local)
+        ));
+        assert_eq!(cmd.to_string(), "READRANGE a z LIMIT 10 LOCAL");
+
+        assert!(Command::try_from("READRANGE a z LIMIT").is_err());
+        assert!(Command::try_from("READRANGE a z LIMIT ten").is_err());
+    }
+
+    #[test]
+    fn test_command_from_str_scan() {
+        let cmd = Command::try_from("SCAN 0 MATCH \"user:*\" COUNT 100").unwrap();
+        assert!(matches!(
+            cmd,
+            Command::Scan(ref scan) if scan.cursor == "0" && scan.pattern.as_deref() == Some("user:*") && scan.count == 100
+        ));
+
+        let cmd = Command::try_from("SCAN 2:6b PREFIX user: LOCAL").unwrap();
+        assert!(matches!(
+            cmd,
+            Command::Scan(ref scan) if scan.prefix == "user:" && scan.scope == Scope::Local
+        ));
+        assert_eq!(cmd.to_string(), "SCAN 2:6b PREFIX user: COUNT 10 LOCAL");
+
+        assert!(Command::try_from("SCAN 0 COUNT 0").is_err());
+        assert!(Command::try_from("SCAN").is_err());
     }
 
     #[test]
@@ -77,8 +1249,47 @@ mod tests {
         let cmd_result = Command::try_from("BATCHPUT key1 value1 key2 value2");
 
         assert!(
-            matches!(cmd_result, Ok(Command::BatchPut(ref kvs)) if *kvs == vec!["key1", "value1", "key2", "value2"])
+            matches!(cmd_result, Ok(Command::BatchPut(ref kvs)) if *kvs == [("key1".to_string(), b"value1".to_vec()), ("key2".to_string(), b"value2".to_vec())])
+        );
+
+        assert!(Command::try_from("BATCHPUT key1 value1 key2").is_err());
+        assert!(Command::try_from("BATCHPUT").is_err());
+    }
+
+    #[test]
+    fn test_command_from_str_batch_read_and_delete() {
+        let keys = vec!["key1".to_string(), "a key".to_string()];
+
+        let cmd = Command::BatchRead(keys.clone());
+        assert_eq!(cmd.to_string(), "MGET key1 \"a key\"");
+        assert!(
+            matches!(Command::try_from("BATCHREAD key1 \"a key\""), Ok(Command::BatchRead(ref k)) if *k == keys)
         );
+
+        let cmd = Command::BatchDelete(keys.clone());
+        assert!(
+            matches!(Command::try_from(cmd.to_string().as_str()), Ok(Command::BatchDelete(ref k)) if *k == keys)
+        );
+
+        assert!(Command::try_from("MGET").is_err());
+        assert!(Command::try_from("BATCHDELETE").is_err());
+    }
+
+    #[test]
+    fn test_command_from_str_split() {
+        let cmd = Command::try_from("SPLIT m m 1 7 0 n \"2 3\" 9 1500").unwrap();
+        let Command::Split(ref at, ref entries) = cmd else {
+            panic!("not a split: {:?}", cmd);
+        };
+        assert_eq!(at, "m");
+        assert_eq!(entries[0].version, 7);
+        assert_eq!(entries[0].expires_in, None);
+        assert_eq!(entries[1].value, b"2 3");
+        assert_eq!(entries[1].expires_in, Some(Duration::from_millis(1500)));
+        assert_eq!(cmd.to_string(), "SPLIT m m 1 7 0 n \"2 3\" 9 1500");
+
+        assert!(Command::try_from("SPLIT m m 1").is_err());
+        assert!(Command::try_from("SPLIT m m 1 x 0").is_err());
     }
 
     #[test]
@@ -87,4 +1298,105 @@ mod tests {
 
         assert!(matches!(cmd_result, Ok(Command::Delete(ref k)) if k == "mykey"));
     }
+
+    #[test]
+    fn test_command_from_str_expire() {
+        let cmd_result = Command::try_from("EXPIRE mykey 10");
+
+        assert!(matches!(cmd_result, Ok(Command::Expire(ref k, 10)) if k == "mykey"));
+        assert!(Command::try_from("EXPIRE mykey soon").is_err());
+    }
+
+    #[test]
+    fn test_command_from_str_incr() {
+        assert!(matches!(Command::try_from("INCR n"), Ok(Command::Incr(ref k, 1)) if k == "n"));
+        assert!(matches!(
+            Command::try_from("DECR n 5"),
+            Ok(Command::Incr(_, -5))
+        ));
+        assert!(
+            matches!(Command::try_from("INCRFLOAT n 0.5"), Ok(Command::IncrFloat(_, by)) if by == 0.5)
+        );
+        assert_eq!(
+            Command::try_from("DECR n 2").unwrap().to_string(),
+            "INCR n -2"
+        );
+        assert!(Command::try_from("INCR n one").is_err());
+        assert!(Command::try_from("DECR n -9223372036854775808").is_err());
+        assert!(Command::try_from("INCRFLOAT n inf").is_err());
+    }
+
+    #[test]
+    fn test_command_from_str_cluster_nodes() {
+        let cmd_result = Command::try_from("CLUSTER NODES");
+
+        assert!(matches!(cmd_result, Ok(Command::ClusterNodes)));
+        assert_eq!(Command::ClusterNodes.to_string(), "CLUSTER NODES");
+    }
+
+    #[test]
+    fn test_command_from_str_protocol() {
+        assert!(matches!(
+            Command::try_from("PROTOCOL FRAMED"),
+            Ok(Command::Protocol(Framing::Framed))
+        ));
+        assert!(matches!(
+            Command::try_from("PROTOCOL LINE\n"),
+            Ok(Command::Protocol(Framing::Line))
+        ));
+        assert!(Command::try_from("PROTOCOL BINARY").is_err());
+    }
+
+    #[test]
+    fn test_command_from_str_auth() {
+        assert!(matches!(
+            Command::try_from("AUTH alice \"s3cret pass\""),
+            Ok(Command::Auth(ref u, ref p)) if u == "alice" && p == "s3cret pass"
+        ));
+        assert!(matches!(
+            Command::try_from("NODEAUTH 2 1700000000 0f1e abcd"),
+            Ok(Command::NodeAuth(ref id, 1700000000, ref nonce, ref mac))
+                if id == "2" && nonce == "0f1e" && mac == "abcd"
+        ));
+        assert!(Command::try_from("NODEAUTH 2 now 0f1e abcd").is_err());
+        assert!(Command::try_from("NODEAUTH 2 1700000000 abcd").is_err());
+    }
+
+    #[test]
+    fn test_command_from_str_quoted() {
+        let cmd_result = Command::try_from(r#"PUT "my key" "my value\n\x00\"" "#);
+
+        assert!(
+            matches!(cmd_result, Ok(Command::Put(ref k, ref v)) if k == "my key" && v == b"my value\n\0\"")
+        );
+        assert!(Command::try_from(r#"PUT key "unterminated"#).is_err());
+        assert!(Command::try_from(r#"PUT key "bad\q""#).is_err());
+        assert!(Command::try_from(r#"PUT key "a"b"#).is_err());
+        assert!(Command::try_from("PUT key my value").is_err());
+    }
+
+    #[test]
+    fn test_quote_round_trip() {
+        assert_eq!(quote(b"plain"), "plain");
+        assert_eq!(quote(b""), r#""""#);
+        assert_eq!(quote(b"a b\n"), r#""a b\n""#);
+        assert_eq!(quote(b"\xff\x01"), r#""\xff\x01""#);
+        assert_eq!(quote(NIL.as_bytes()), r#""(nil)""#);
+
+        for value in [
+            &b"my value"[..],
+            b"",
+            b"\"quoted\" \\ \r\t",
+            b"\xff\x00bin",
+            "caf\u{e9}".as_bytes(),
+        ] {
+            assert_eq!(unquote(&quote(value)).unwrap(), value);
+        }
+
+        let cmd = Command::BatchPut(vec![("a key".to_string(), b"a value".to_vec())]);
+        assert!(matches!(
+            Command::try_from(cmd.to_string().as_str()),
+            Ok(Command::BatchPut(ref entries)) if entries[0].0 == "a key" && entries[0].1 == b"a value"
+        ));
+    }
 }
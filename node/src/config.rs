@@ -8,6 +8,15 @@ pub struct ClusterNode {
     pub gossip_port: String,
 }
 
+// a client account: salted password hash and the commands and key prefixes it may use
+#[derive(Debug, Clone)]
+pub struct UserConfig {
+    pub name: String,
+    pub password: String,
+    pub commands: String,
+    pub keys: String,
+}
+
 #[derive(Debug, Clone)]
 pub struct NodeConfig {
     pub host: String,
@@ -15,7 +24,37 @@ pub struct NodeConfig {
     pub storage: String,
     pub log_enabled: String,
     pub me: String,
+    pub max_connections: String,
+    pub resp_port: String,
+    pub http_port: String,
+    pub memcached_port: String,
+    pub binary_port: String,
+    pub tls_cert: String,
+    pub tls_key: String,
+    pub tls_ca: String,
+    pub users: HashMap<String, UserConfig>,
+    pub forward_connect_timeout_ms: String,
+    pub forward_read_timeout_ms: String,
+    pub forward_write_timeout_ms: String,
+    pub forward_retries: String,
+    pub forward_pool_size: String,
+    pub forward_deadline_ms: String,
+    pub partition_mode: String,
+    pub partition_splits: String,
+    pub partition_split_keys: String,
+    pub partition_split_rate: String,
+    pub transaction_log: String,
+    pub transaction_lock_timeout_ms: String,
+    pub raft_keyspaces: String,
+    pub raft_shards: String,
+    pub raft_replicas: String,
+    pub raft_snapshot_entries: String,
+    pub raft_log: String,
+    pub chain_keyspaces: String,
+    pub chain_replicas: String,
     pub cluster: HashMap<String, ClusterNode>,
+    pub cluster_secret: String,
+    pub gossip_encryption: String,
 }
 
 pub struct NodeConfigBuilder {
@@ -31,7 +70,37 @@ impl NodeConfigBuilder {
                 storage: "".into(),
                 log_enabled: "".into(),
                 me: "".into(),
+                max_connections: "".into(),
+                resp_port: "".into(),
+                http_port: "".into(),
+                memcached_port: "".into(),
+                binary_port: "".into(),
+                tls_cert: "".into(),
+                tls_key: "".into(),
+                tls_ca: "".into(),
+                users: HashMap::new(),
+                forward_connect_timeout_ms: "".into(),
+                forward_read_timeout_ms: "".into(),
+                forward_write_timeout_ms: "".into(),
+                forward_retries: "".into(),
+                forward_pool_size: "".into(),
+                forward_deadline_ms: "".into(),
+                partition_mode: "".into(),
+                partition_splits: "".into(),
+                partition_split_keys: "".into(),
+                partition_split_rate: "".into(),
+                transaction_log: "".into(),
+                transaction_lock_timeout_ms: "".into(),
+                raft_keyspaces: "".into(),
+                raft_shards: "".into(),
+                raft_replicas: "".into(),
+                raft_snapshot_entries: "".into(),
+                raft_log: "".into(),
+                chain_keyspaces: "".into(),
+                chain_replicas: "".into(),
                 cluster: HashMap::new(),
+                cluster_secret: "".into(),
+                gossip_encryption: "".into(),
             },
         }
     }
@@ -85,6 +154,313 @@ impl NodeConfigBuilder {
         }
     }
 
+    pub fn with_max_connections(&self, max_connections: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                max_connections,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_resp_port(&self, resp_port: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                resp_port,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_http_port(&self, http_port: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                http_port,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_memcached_port(&self, memcached_port: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                memcached_port,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_binary_port(&self, binary_port: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                binary_port,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_tls_cert(&self, tls_cert: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                tls_cert,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_tls_key(&self, tls_key: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                tls_key,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_tls_ca(&self, tls_ca: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                tls_ca,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_cluster_secret(&self, cluster_secret: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                cluster_secret,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_gossip_encryption(&self, gossip_encryption: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                gossip_encryption,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_forward_connect_timeout_ms(
+        &self,
+        forward_connect_timeout_ms: String,
+    ) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                forward_connect_timeout_ms,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_forward_read_timeout_ms(
+        &self,
+        forward_read_timeout_ms: String,
+    ) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                forward_read_timeout_ms,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_forward_write_timeout_ms(
+        &self,
+        forward_write_timeout_ms: String,
+    ) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                forward_write_timeout_ms,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_forward_retries(&self, forward_retries: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                forward_retries,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_forward_pool_size(&self, forward_pool_size: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                forward_pool_size,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_forward_deadline_ms(&self, forward_deadline_ms: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                forward_deadline_ms,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_partition_mode(&self, partition_mode: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                partition_mode,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_partition_splits(&self, partition_splits: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                partition_splits,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_partition_split_keys(&self, partition_split_keys: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                partition_split_keys,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_partition_split_rate(&self, partition_split_rate: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                partition_split_rate,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_transaction_log(&self, transaction_log: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                transaction_log,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_transaction_lock_timeout_ms(
+        &self,
+        transaction_lock_timeout_ms: String,
+    ) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                transaction_lock_timeout_ms,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_raft_keyspaces(&self, raft_keyspaces: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                raft_keyspaces,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_raft_shards(&self, raft_shards: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                raft_shards,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_raft_replicas(&self, raft_replicas: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                raft_replicas,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_raft_snapshot_entries(&self, raft_snapshot_entries: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                raft_snapshot_entries,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_raft_log(&self, raft_log: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                raft_log,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_chain_keyspaces(&self, chain_keyspaces: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                chain_keyspaces,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_chain_replicas(&self, chain_replicas: String) -> NodeConfigBuilder {
+        Self {
+            config: NodeConfig {
+                chain_replicas,
+                ..self.config.clone()
+            },
+        }
+    }
+
+    pub fn with_user_password(&self, name: &str, password: String) -> NodeConfigBuilder {
+        self.with_user(name, |user| user.password = password)
+    }
+
+    pub fn with_user_commands(&self, name: &str, commands: String) -> NodeConfigBuilder {
+        self.with_user(name, |user| user.commands = commands)
+    }
+
+    pub fn with_user_keys(&self, name: &str, keys: String) -> NodeConfigBuilder {
+        self.with_user(name, |user| user.keys = keys)
+    }
+
+    fn with_user<F>(&self, name: &str, update: F) -> NodeConfigBuilder
+    where
+        F: FnOnce(&mut UserConfig),
+    {
+        let mut users = self.config.users.clone();
+
+        let entry = users.entry(name.to_string()).or_insert(UserConfig {
+            name: name.to_string(),
+            password: "".into(),
+            commands: "".into(),
+            keys: "".into(),
+        });
+        update(entry);
+
+        Self {
+            config: NodeConfig {
+                users,
+                ..self.config.clone()
+            },
+        }
+    }
+
     pub fn with_cluster_host(&self, node_id: &str, node_host: String) -> NodeConfigBuilder {
         let mut cluster = self.config.cluster.clone();
 
@@ -98,7 +474,7 @@ impl NodeConfigBuilder {
 
         Self {
             config: NodeConfig {
-                cluster: cluster,
+                cluster,
                 ..self.config.clone()
             },
         }
@@ -117,7 +493,7 @@ impl NodeConfigBuilder {
 
         Self {
             config: NodeConfig {
-                cluster: cluster,
+                cluster,
                 ..self.config.clone()
             },
         }
@@ -136,7 +512,7 @@ impl NodeConfigBuilder {
 
         Self {
             config: NodeConfig {
-                cluster: cluster,
+                cluster,
                 ..self.config.clone()
             },
         }
@@ -155,7 +531,37 @@ impl NodeConfig {
             storage: "memory".into(),
             log_enabled: "true".into(),
             me: "1".into(),
+            max_connections: "64".into(),
+            resp_port: "".into(),
+            http_port: "".into(),
+            memcached_port: "".into(),
+            binary_port: "".into(),
+            tls_cert: "".into(),
+            tls_key: "".into(),
+            tls_ca: "".into(),
+            users: HashMap::new(),
+            forward_connect_timeout_ms: "".into(),
+            forward_read_timeout_ms: "".into(),
+            forward_write_timeout_ms: "".into(),
+            forward_retries: "".into(),
+            forward_pool_size: "".into(),
+            forward_deadline_ms: "".into(),
+            partition_mode: "".into(),
+            partition_splits: "".into(),
+            partition_split_keys: "".into(),
+            partition_split_rate: "".into(),
+            transaction_log: "".into(),
+            transaction_lock_timeout_ms: "".into(),
+            raft_keyspaces: "".into(),
+            raft_shards: "".into(),
+            raft_replicas: "".into(),
+            raft_snapshot_entries: "".into(),
+            raft_log: "".into(),
+            chain_keyspaces: "".into(),
+            chain_replicas: "".into(),
             cluster: HashMap::new(),
+            cluster_secret: "".into(),
+            gossip_encryption: "false".into(),
         }
     }
 }
@@ -179,6 +585,101 @@ pub fn load_config(path: &str) -> Result<NodeConfig, std::io::Error> {
                     config_builder = config_builder.with_log_enabled(value.trim().to_string())
                 }
                 "me" => config_builder = config_builder.with_me(value.trim().to_string()),
+                "max_connections" => {
+                    config_builder = config_builder.with_max_connections(value.trim().to_string())
+                }
+                "resp_port" => {
+                    config_builder = config_builder.with_resp_port(value.trim().to_string())
+                }
+                "http_port" => {
+                    config_builder = config_builder.with_http_port(value.trim().to_string())
+                }
+                "memcached_port" => {
+                    config_builder = config_builder.with_memcached_port(value.trim().to_string())
+                }
+                "binary_port" => {
+                    config_builder = config_builder.with_binary_port(value.trim().to_string())
+                }
+                "tls.cert" => {
+                    config_builder = config_builder.with_tls_cert(value.trim().to_string())
+                }
+                "tls.key" => config_builder = config_builder.with_tls_key(value.trim().to_string()),
+                "tls.ca" => config_builder = config_builder.with_tls_ca(value.trim().to_string()),
+                "cluster.secret" => {
+                    config_builder = config_builder.with_cluster_secret(value.trim().to_string())
+                }
+                "cluster.gossip_encryption" => {
+                    config_builder = config_builder.with_gossip_encryption(value.trim().to_string())
+                }
+                "forward.connect_timeout_ms" => {
+                    config_builder =
+                        config_builder.with_forward_connect_timeout_ms(value.trim().to_string())
+                }
+                "forward.read_timeout_ms" => {
+                    config_builder =
+                        config_builder.with_forward_read_timeout_ms(value.trim().to_string())
+                }
+                "forward.write_timeout_ms" => {
+                    config_builder =
+                        config_builder.with_forward_write_timeout_ms(value.trim().to_string())
+                }
+                "forward.retries" => {
+                    config_builder = config_builder.with_forward_retries(value.trim().to_string())
+                }
+                "forward.pool_size" => {
+                    config_builder = config_builder.with_forward_pool_size(value.trim().to_string())
+                }
+                "forward.deadline_ms" => {
+                    config_builder =
+                        config_builder.with_forward_deadline_ms(value.trim().to_string())
+                }
+
+                "partition.mode" => {
+                    config_builder = config_builder.with_partition_mode(value.trim().to_string())
+                }
+                "partition.splits" => {
+                    config_builder = config_builder.with_partition_splits(value.trim().to_string())
+                }
+                "partition.split_keys" => {
+                    config_builder =
+                        config_builder.with_partition_split_keys(value.trim().to_string())
+                }
+                "partition.split_rate" => {
+                    config_builder =
+                        config_builder.with_partition_split_rate(value.trim().to_string())
+                }
+
+                "transaction.log" => {
+                    config_builder = config_builder.with_transaction_log(value.trim().to_string())
+                }
+                "transaction.lock_timeout_ms" => {
+                    config_builder =
+                        config_builder.with_transaction_lock_timeout_ms(value.trim().to_string())
+                }
+
+                "raft.keyspaces" => {
+                    config_builder = config_builder.with_raft_keyspaces(value.trim().to_string())
+                }
+                "raft.shards" => {
+                    config_builder = config_builder.with_raft_shards(value.trim().to_string())
+                }
+                "raft.replicas" => {
+                    config_builder = config_builder.with_raft_replicas(value.trim().to_string())
+                }
+                "raft.snapshot_entries" => {
+                    config_builder =
+                        config_builder.with_raft_snapshot_entries(value.trim().to_string())
+                }
+                "raft.log" => {
+                    config_builder = config_builder.with_raft_log(value.trim().to_string())
+                }
+
+                "chain.keyspaces" => {
+                    config_builder = config_builder.with_chain_keyspaces(value.trim().to_string())
+                }
+                "chain.replicas" => {
+                    config_builder = config_builder.with_chain_replicas(value.trim().to_string())
+                }
 
                 key if key.starts_with("cluster.node.") && key.ends_with(".host") => {
                     let node_id = key.split('.').collect::<Vec<&str>>()[2];
@@ -198,6 +699,23 @@ pub fn load_config(path: &str) -> Result<NodeConfig, std::io::Error> {
                         config_builder.with_cluster_gossip(node_id, value.trim().to_string())
                 }
 
+                key if key.starts_with("auth.user.") && key.ends_with(".password") => {
+                    let name = key.split('.').collect::<Vec<&str>>()[2];
+                    config_builder =
+                        config_builder.with_user_password(name, value.trim().to_string())
+                }
+
+                key if key.starts_with("auth.user.") && key.ends_with(".commands") => {
+                    let name = key.split('.').collect::<Vec<&str>>()[2];
+                    config_builder =
+                        config_builder.with_user_commands(name, value.trim().to_string())
+                }
+
+                key if key.starts_with("auth.user.") && key.ends_with(".keys") => {
+                    let name = key.split('.').collect::<Vec<&str>>()[2];
+                    config_builder = config_builder.with_user_keys(name, value.trim().to_string())
+                }
+
                 _ => panic!("invalid config"),
             }
         }
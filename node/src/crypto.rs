@@ -0,0 +1,173 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use chacha20poly1305::{
+    ChaCha20Poly1305, KeyInit, Nonce,
+    aead::{Aead, AeadCore, OsRng},
+};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LEN: usize = 12;
+
+pub fn hmac_hex(secret: &str, data: &[u8]) -> String {
+    let mut mac =
+        <HmacSha256 as Mac>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key");
+    mac.update(data);
+    to_hex(&mac.finalize().into_bytes())
+}
+
+// constant time comparison of the expected and the received MAC
+pub fn verify_hmac(secret: &str, data: &[u8], mac_hex: &str) -> bool {
+    let Some(expected) = from_hex(mac_hex) else {
+        return false;
+    };
+
+    let mut mac =
+        <HmacSha256 as Mac>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key");
+    mac.update(data);
+    mac.verify_slice(&expected).is_ok()
+}
+
+// PBKDF2-HMAC-SHA256 with a 32 byte output, slow on purpose to resist guessing
+pub fn pbkdf2_hex(password: &[u8], salt: &[u8], iterations: u32) -> String {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, &mut key);
+    to_hex(&key)
+}
+
+// compares secrets without revealing through timing how much of them matched
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// encrypts with a key derived from the secret, returns nonce || ciphertext
+pub fn encrypt(secret: &str, plaintext: &[u8]) -> Vec<u8> {
+    let cipher = ChaCha20Poly1305::new(&Sha256::digest(secret.as_bytes()));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let mut out = nonce.to_vec();
+    out.extend(
+        cipher
+            .encrypt(&nonce, plaintext)
+            .expect("encryption of an in-memory buffer cannot fail"),
+    );
+    out
+}
+
+pub fn decrypt(secret: &str, data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < NONCE_LEN {
+        return Err("Ciphertext too short".to_string());
+    }
+
+    let cipher = ChaCha20Poly1305::new(&Sha256::digest(secret.as_bytes()));
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Decryption failed".to_string())
+}
+
+// a random value to tell messages signed in the same second apart
+pub fn random_nonce() -> String {
+    to_hex(&ChaCha20Poly1305::generate_nonce(&mut OsRng))
+}
+
+// the nonces of the signed messages accepted lately, a message presenting one of them again is
+// a replay; nonces are forgotten once messages carrying them are too old to be accepted anyway
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    seen: Mutex<HashMap<String, u64>>,
+}
+
+impl ReplayGuard {
+    // true the first time a nonce is presented, `expires` is the unix time from which the
+    // message is rejected for its age
+    pub fn first_use(&self, nonce: &str, expires: u64, now: u64) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, until| *until >= now);
+        seen.insert(nonce.to_string(), expires).is_none()
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// decodes %XX escapes, as used in URLs and gossip messages
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(b) = s
+                .get(i + 1..i + 3)
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+        {
+            out.push(b);
+            i += 3;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|h| u8::from_str_radix(h, 16).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_verify() {
+        let mac = hmac_hex("secret", b"OK:1");
+
+        assert!(verify_hmac("secret", b"OK:1", &mac));
+        assert!(!verify_hmac("secret", b"OK:2", &mac));
+        assert!(!verify_hmac("other", b"OK:1", &mac));
+        assert!(!verify_hmac("secret", b"OK:1", "zz"));
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let sealed = encrypt("secret", b"OK:1");
+
+        assert_ne!(&sealed[NONCE_LEN..], b"OK:1");
+        assert_eq!(decrypt("secret", &sealed).unwrap(), b"OK:1");
+        assert!(decrypt("other", &sealed).is_err());
+    }
+
+    #[test]
+    fn test_replay_guard() {
+        let guard = ReplayGuard::default();
+        let nonce = random_nonce();
+
+        assert_ne!(nonce, random_nonce());
+        assert!(guard.first_use(&nonce, 160, 100));
+        assert!(!guard.first_use(&nonce, 160, 150));
+        assert!(guard.first_use(&random_nonce(), 160, 150));
+
+        // forgotten once the message has expired
+        assert!(guard.first_use(&nonce, 260, 200));
+    }
+
+    #[test]
+    fn test_hex_roundtrip() {
+        assert_eq!(to_hex(&[0, 15, 255]), "000fff");
+        assert_eq!(from_hex("000fff").unwrap(), vec![0, 15, 255]);
+        assert!(from_hex("0").is_none());
+    }
+}
@@ -0,0 +1,108 @@
+use std::fmt;
+
+// errors answered to clients: the code is stable and meant to be matched on,
+// the message is meant for humans and may change
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    // the key does not exist or expired
+    NotFound(String),
+    // the command could not be parsed or is not valid here
+    BadRequest(String),
+    // a condition of the command did not hold, e.g. a version mismatch
+    Conflict(String),
+    // the client did not authenticate or its credentials are wrong
+    Unauthorized(String),
+    // the ACL of the client does not allow the command or the key
+    Forbidden(String),
+    // another node could not be reached, or this one is too busy
+    Unavailable(String),
+    // another node did not answer in time
+    Timeout(String),
+    // a command spanning nodes succeeded on some of them only
+    Partial(String),
+    Internal(String),
+}
+
+impl Error {
+    pub fn not_found() -> Error {
+        Error::NotFound("Key not found".to_string())
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::NotFound(_) => "NOT_FOUND",
+            Error::BadRequest(_) => "BAD_REQUEST",
+            Error::Conflict(_) => "CONFLICT",
+            Error::Unauthorized(_) => "UNAUTHORIZED",
+            Error::Forbidden(_) => "FORBIDDEN",
+            Error::Unavailable(_) => "UNAVAILABLE",
+            Error::Timeout(_) => "TIMEOUT",
+            Error::Partial(_) => "PARTIAL",
+            Error::Internal(_) => "INTERNAL",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Error::NotFound(message)
+            | Error::BadRequest(message)
+            | Error::Conflict(message)
+            | Error::Unauthorized(message)
+            | Error::Forbidden(message)
+            | Error::Unavailable(message)
+            | Error::Timeout(message)
+            | Error::Partial(message)
+            | Error::Internal(message) => message,
+        }
+    }
+
+    // the error as a text protocol response: `Error: <CODE> <message>`
+    pub fn response(&self) -> String {
+        format!("Error: {}\n", self)
+    }
+
+    // reads `<CODE> <message>` back, an unknown code is kept in an internal error
+    pub fn parse(s: &str) -> Error {
+        let (code, message) = s.split_once(' ').unwrap_or((s, ""));
+        let message = message.to_string();
+
+        match code {
+            "NOT_FOUND" => Error::NotFound(message),
+            "BAD_REQUEST" => Error::BadRequest(message),
+            "CONFLICT" => Error::Conflict(message),
+            "UNAUTHORIZED" => Error::Unauthorized(message),
+            "FORBIDDEN" => Error::Forbidden(message),
+            "UNAVAILABLE" => Error::Unavailable(message),
+            "TIMEOUT" => Error::Timeout(message),
+            "PARTIAL" => Error::Partial(message),
+            "INTERNAL" => Error::Internal(message),
+            _ => Error::Internal(s.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.message())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_round_trip() {
+        let error = Error::Unavailable("Failed to connect to 2: refused".into());
+        assert_eq!(
+            error.response(),
+            "Error: UNAVAILABLE Failed to connect to 2: refused\n"
+        );
+        assert_eq!(Error::parse(&error.to_string()), error);
+        assert_eq!(Error::parse("NOT_FOUND Key not found"), Error::not_found());
+        assert_eq!(
+            Error::parse("something else"),
+            Error::Internal("something else".into())
+        );
+    }
+}
@@ -1,26 +1,269 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     io::{Read, Write},
-    sync::{Arc, Mutex},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crate::{config::ClusterNode, log::log};
+use crate::{
+    config::ClusterNode,
+    crypto::{self, ReplayGuard},
+    log::log,
+    tls::{Stream, TlsConfig},
+};
+
+// gossip messages older than this are rejected, younger ones are rejected when their nonce
+// was seen before
+const MAX_MESSAGE_AGE_SECS: u64 = 60;
+
+// a peer that does not complete its message in time is dropped, so it cannot stall the listener
+const GOSSIP_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// protection of gossip messages on the wire, without a secret messages are sent in plain text,
+// with TLS only nodes presenting a certificate of the cluster CA are accepted
+#[derive(Debug, Clone, Default)]
+pub struct GossipSecurity {
+    pub secret: Option<String>,
+    pub encrypt: bool,
+    pub tls: Option<Arc<TlsConfig>>,
+    // shared by the clones of the listener
+    pub seen: Arc<ReplayGuard>,
+}
+
+impl GossipSecurity {
+    // frame layout: KAVA2 <timestamp> <nonce> <P|E> <hmac> <payload>
+    // the HMAC covers timestamp, nonce, mode and payload, encrypted payloads are hex encoded
+    fn seal(&self, message: &str) -> String {
+        let Some(secret) = &self.secret else {
+            return message.to_string();
+        };
+
+        let (mode, payload) = if self.encrypt {
+            (
+                "E",
+                crypto::to_hex(&crypto::encrypt(secret, message.as_bytes())),
+            )
+        } else {
+            ("P", message.to_string())
+        };
+
+        let timestamp = unix_time();
+        let nonce = crypto::random_nonce();
+        let signed = format!("{} {} {} {}", timestamp, nonce, mode, payload);
+        let mac = crypto::hmac_hex(secret, signed.as_bytes());
+
+        format!("KAVA2 {} {} {} {} {}", timestamp, nonce, mode, mac, payload)
+    }
+
+    fn open(&self, frame: &str) -> Result<String, String> {
+        let Some(secret) = &self.secret else {
+            return Ok(frame.to_string());
+        };
+
+        let parts: Vec<&str> = frame.splitn(6, ' ').collect();
+        let ["KAVA2", timestamp, nonce, mode, mac, payload] = parts.as_slice() else {
+            return Err("unauthenticated message".to_string());
+        };
+
+        let signed = format!("{} {} {} {}", timestamp, nonce, mode, payload);
+        if !crypto::verify_hmac(secret, signed.as_bytes(), mac) {
+            return Err("invalid HMAC".to_string());
+        }
+
+        let timestamp: u64 = timestamp
+            .parse()
+            .map_err(|_| "invalid timestamp".to_string())?;
+        let now = unix_time();
+        if now.abs_diff(timestamp) > MAX_MESSAGE_AGE_SECS {
+            return Err("message expired".to_string());
+        }
+        if !self
+            .seen
+            .first_use(nonce, timestamp + MAX_MESSAGE_AGE_SECS, now)
+        {
+            return Err("replayed message".to_string());
+        }
+
+        match *mode {
+            "P" => Ok(payload.to_string()),
+            "E" => {
+                let ciphertext =
+                    crypto::from_hex(payload).ok_or_else(|| "invalid payload".to_string())?;
+                let plaintext = crypto::decrypt(secret, &ciphertext)?;
+                String::from_utf8(plaintext).map_err(|_| "invalid payload".to_string())
+            }
+            _ => Err("unknown mode".to_string()),
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// application state advertised by a node about itself (load, version, ...)
+// the (generation, version) pair orders updates: the generation is fixed at
+// node startup, the version grows with every local update
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ApplicationState {
+    pub generation: u64,
+    pub version: u64,
+    pub values: BTreeMap<String, String>,
+}
+
+pub type ApplicationStates = Arc<Mutex<HashMap<String, ApplicationState>>>;
+
+impl ApplicationState {
+    fn is_newer_than(&self, other: &ApplicationState) -> bool {
+        (self.generation, self.version) > (other.generation, other.version)
+    }
+
+    fn encode(&self, node_id: &str) -> String {
+        let values = self
+            .values
+            .iter()
+            .map(|(k, v)| format!("{}={}", escape(k), escape(v)))
+            .collect::<Vec<String>>()
+            .join(";");
+
+        format!(
+            "STATE {} {} {} {}",
+            node_id, self.generation, self.version, values
+        )
+    }
+
+    fn decode(line: &str) -> Option<(String, ApplicationState)> {
+        let parts: Vec<&str> = line.split(' ').collect();
+        match parts.as_slice() {
+            ["STATE", node_id, generation, version, values] => {
+                let mut state = ApplicationState {
+                    generation: generation.parse().ok()?,
+                    version: version.parse().ok()?,
+                    values: BTreeMap::new(),
+                };
+
+                for pair in values.split(';').filter(|p| !p.is_empty()) {
+                    let (k, v) = pair.split_once('=')?;
+                    state
+                        .values
+                        .insert(crypto::percent_decode(k), crypto::percent_decode(v));
+                }
+
+                Some((node_id.to_string(), state))
+            }
+            _ => None,
+        }
+    }
+}
+
+// updates a single application state entry of the local node,
+// the change is picked up by the next gossip round
+pub fn update_application_state(
+    app_states: &ApplicationStates,
+    node_id: &str,
+    key: &str,
+    value: String,
+) {
+    let mut states = app_states.lock().unwrap();
+    let state = states
+        .entry(node_id.to_string())
+        .or_insert_with(|| ApplicationState {
+            generation: unix_time(),
+            ..Default::default()
+        });
+
+    if state.values.get(key) != Some(&value) {
+        state.values.insert(key.to_string(), value);
+        state.version += 1;
+    }
+}
+
+// builds a gossip message: the heartbeat line followed by all known application states
+fn gossip_message(me_id: &str, app_states: &ApplicationStates) -> String {
+    let mut message = format!("OK:{}", me_id);
+
+    for (node_id, state) in app_states.lock().unwrap().iter() {
+        message.push('\n');
+        message.push_str(&state.encode(node_id));
+    }
+
+    message
+}
+
+// merges received application states, the local node's own state is never overwritten
+fn merge_application_states(buffer: &str, me_id: &str, app_states: &ApplicationStates) {
+    let mut states = app_states.lock().unwrap();
+
+    for (node_id, state) in buffer.lines().skip(1).filter_map(ApplicationState::decode) {
+        if node_id == me_id {
+            continue;
+        }
+
+        let newer = states
+            .get(&node_id)
+            .map(|known| state.is_newer_than(known))
+            .unwrap_or(true);
+
+        if newer {
+            states.insert(node_id, state);
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '%' | ' ' | ';' | '=' | '\n' | '\r' => {
+                let mut buf = [0u8; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    escaped.push_str(&format!("%{:02X}", b));
+                }
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
 
+// answers the number of rounds the talker finished, a round gossips to every other node once; the
+// cluster snapshot holds this node only until the first one
 pub fn start_gossip(
     cluster_snapshot: &Arc<Mutex<HashMap<String, String>>>,
+    app_states: &ApplicationStates,
     cluster_nodes: Vec<ClusterNode>,
+    security: GossipSecurity,
     me: String,
     me_id: String,
     log_enabled: bool,
-) {
+) -> Arc<AtomicU64> {
+    let rounds = Arc::new(AtomicU64::new(0));
+    let rounds_talker = rounds.clone();
+
     log(
         &format!("Starting gossip with cluster nodes: {:?}", cluster_nodes),
         log_enabled,
     );
 
+    if security.secret.is_none() && security.tls.is_none() {
+        eprintln!("Gossip is not authenticated, set cluster.secret to protect cluster membership");
+    }
+
+    let security_talker = security.clone();
+    let security_listener = security;
+
     let me_cloned = me.clone();
     let cluster_snapshot_talker = cluster_snapshot.clone();
     let cluster_snapshot_listener = cluster_snapshot.clone();
+    let app_states_talker = app_states.clone();
+    let app_states_listener = app_states.clone();
+    let me_id_listener = me_id.clone();
 
     let me_gossip = cluster_nodes
         .iter()
@@ -41,16 +284,26 @@ pub fn start_gossip(
                 .collect::<Vec<ClusterNode>>();
 
             for node in rest {
-                let connect_result =
-                    std::net::TcpStream::connect(format!("{}:{}", node.host, node.gossip_port));
+                let connect_result = Stream::connect_peer(
+                    &format!("{}:{}", node.host, node.gossip_port),
+                    &node.host,
+                    security_talker.tls.as_deref(),
+                );
 
                 match connect_result {
                     Ok(mut stream) => {
                         log(&format!("Gossip sending to {}", node._id), log_enabled);
 
-                        let message = format!("OK:{}", me_id);
+                        let message =
+                            security_talker.seal(&gossip_message(&me_id, &app_states_talker));
 
-                        if let Err(_e) = stream.write_all(message.as_bytes()) {
+                        // wait for the listener to close, dropping a TLS connection with unread
+                        // handshake data resets it before the message is read
+                        let sent = stream
+                            .write_all(message.as_bytes())
+                            .and_then(|_| stream.finish())
+                            .and_then(|_| stream.read_to_end(&mut Vec::new()));
+                        if let Err(_e) = sent {
                             log(
                                 &format!("Removing {} from cluster snapshot", node._id),
                                 log_enabled,
@@ -85,6 +338,7 @@ pub fn start_gossip(
 
                 std::thread::sleep(std::time::Duration::from_secs(10));
             }
+            rounds_talker.fetch_add(1, Ordering::Relaxed);
         }
     });
 
@@ -104,37 +358,78 @@ pub fn start_gossip(
 
         for stream in listener.incoming() {
             match stream {
-                Ok(mut tcp_stream) => {
-                    let mut buffer = String::new();
-                    if let Ok(_) = tcp_stream.read_to_string(&mut buffer) {
+                Ok(tcp_stream) => {
+                    let peer = tcp_stream
+                        .peer_addr()
+                        .map(|a| a.to_string())
+                        .unwrap_or_else(|_| "unknown".to_string());
+
+                    let _ = tcp_stream.set_read_timeout(Some(GOSSIP_READ_TIMEOUT));
+                    let mut stream =
+                        match Stream::accept_peer(tcp_stream, security_listener.tls.as_deref()) {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                eprintln!("Rejected gossip message from {}: {}", peer, e);
+                                continue;
+                            }
+                        };
+
+                    let mut frame = String::new();
+                    let received = stream.read_to_string(&mut frame);
+                    let _ = stream.finish();
+
+                    if let Err(e) = received {
+                        if security_listener.tls.is_some() {
+                            eprintln!("Rejected gossip message from {}: {}", peer, e);
+                        }
+                    } else {
+                        // write to the stderr regardless of log setting
+                        let buffer = match security_listener.open(&frame) {
+                            Ok(buffer) => buffer,
+                            Err(e) => {
+                                eprintln!("Rejected gossip message from {}: {}", peer, e);
+                                continue;
+                            }
+                        };
+
                         log(&format!("Gossip received: {}", buffer), log_enabled);
-                        if buffer.starts_with("OK:") {
-                            let parts: Vec<&str> = buffer.splitn(2, ':').collect();
-                            if parts.len() == 2 {
-                                let node_id = parts[1].to_string();
-                                let addr = cluster_nodes_listener
-                                    .iter()
-                                    .find(|node| node._id == node_id)
-                                    .map(|node| format!("{}:{}", node.host, node.port))
-                                    .unwrap_or_else(|| "unknown".to_string());
-
-                                cluster_snapshot_listener
-                                    .lock()
-                                    .unwrap()
-                                    .insert(node_id.clone(), addr.clone());
-                                log(
-                                    &format!("Updated cluster snapshot: {} -> {}", node_id, addr),
-                                    log_enabled,
+                        let heartbeat = buffer.lines().next().unwrap_or_default();
+                        if let Some(node_id) = heartbeat.strip_prefix("OK:") {
+                            let node_id = node_id.to_string();
+                            let Some(addr) = cluster_nodes_listener
+                                .iter()
+                                .find(|node| node._id == node_id)
+                                .map(|node| format!("{}:{}", node.host, node.port))
+                            else {
+                                eprintln!(
+                                    "Rejected gossip message from {}: unknown node {}",
+                                    peer, node_id
                                 );
+                                continue;
+                            };
 
-                                log(
-                                    &format!(
-                                        "Cluster snapshot: {:?}",
-                                        cluster_snapshot_listener.lock().unwrap()
-                                    ),
-                                    log_enabled,
-                                );
-                            }
+                            cluster_snapshot_listener
+                                .lock()
+                                .unwrap()
+                                .insert(node_id.clone(), addr.clone());
+                            log(
+                                &format!("Updated cluster snapshot: {} -> {}", node_id, addr),
+                                log_enabled,
+                            );
+
+                            merge_application_states(
+                                &buffer,
+                                &me_id_listener,
+                                &app_states_listener,
+                            );
+
+                            log(
+                                &format!(
+                                    "Cluster snapshot: {:?}",
+                                    cluster_snapshot_listener.lock().unwrap()
+                                ),
+                                log_enabled,
+                            );
                         }
                     }
                 }
@@ -144,4 +439,121 @@ pub fn start_gossip(
             }
         }
     });
+
+    rounds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_application_state_encode_decode() {
+        let mut state = ApplicationState {
+            generation: 42,
+            version: 3,
+            values: BTreeMap::new(),
+        };
+        state.values.insert("key_count".into(), "10".into());
+        state.values.insert("note".into(), "a b;c=d%".into());
+
+        let line = state.encode("2");
+        let (node_id, decoded) = ApplicationState::decode(&line).unwrap();
+
+        assert_eq!(node_id, "2");
+        assert_eq!(decoded, state);
+    }
+
+    #[test]
+    fn test_update_application_state_bumps_version() {
+        let app_states: ApplicationStates = Arc::new(Mutex::new(HashMap::new()));
+
+        update_application_state(&app_states, "1", "key_count", "1".into());
+        update_application_state(&app_states, "1", "key_count", "1".into());
+        update_application_state(&app_states, "1", "key_count", "2".into());
+
+        let states = app_states.lock().unwrap();
+        assert_eq!(states["1"].version, 2);
+        assert_eq!(states["1"].values["key_count"], "2");
+    }
+
+    #[test]
+    fn test_merge_application_states_keeps_newest_and_own_state() {
+        let app_states: ApplicationStates = Arc::new(Mutex::new(HashMap::new()));
+        update_application_state(&app_states, "1", "key_count", "5".into());
+
+        let remote: ApplicationStates = Arc::new(Mutex::new(HashMap::new()));
+        update_application_state(&remote, "2", "key_count", "7".into());
+        remote.lock().unwrap().insert(
+            "1".into(),
+            ApplicationState {
+                generation: u64::MAX,
+                version: 1,
+                values: BTreeMap::new(),
+            },
+        );
+
+        merge_application_states(&gossip_message("2", &remote), "1", &app_states);
+
+        let states = app_states.lock().unwrap();
+        assert_eq!(states["1"].values["key_count"], "5");
+        assert_eq!(states["2"].values["key_count"], "7");
+
+        let stale = ApplicationState {
+            generation: 0,
+            version: 100,
+            values: BTreeMap::new(),
+        };
+        assert!(!stale.is_newer_than(&states["2"]));
+    }
+
+    #[test]
+    fn test_gossip_security_seal_open() {
+        for encrypt in [false, true] {
+            let security = GossipSecurity {
+                secret: Some("secret".into()),
+                encrypt,
+                ..Default::default()
+            };
+
+            let frame = security.seal("OK:1\nSTATE 1 1 1 version=0.1.0");
+            assert!(frame.starts_with("KAVA2 "));
+            assert_eq!(encrypt, !frame.contains("OK:1"));
+            assert_eq!(
+                security.open(&frame).unwrap(),
+                "OK:1\nSTATE 1 1 1 version=0.1.0"
+            );
+        }
+    }
+
+    #[test]
+    fn test_gossip_security_rejects_unauthenticated() {
+        let security = GossipSecurity {
+            secret: Some("secret".into()),
+            encrypt: false,
+            ..Default::default()
+        };
+        let other = GossipSecurity {
+            secret: Some("other".into()),
+            encrypt: false,
+            ..Default::default()
+        };
+
+        assert!(security.open("OK:1").is_err());
+        assert!(security.open(&other.seal("OK:1")).is_err());
+
+        let tampered = security.seal("OK:1").replace("OK:1", "OK:2");
+        assert!(security.open(&tampered).is_err());
+
+        let mac = crypto::hmac_hex("secret", b"0 00 P OK:1");
+        assert!(
+            security
+                .open(&format!("KAVA2 0 00 P {} OK:1", mac))
+                .is_err()
+        );
+
+        let frame = security.seal("OK:1");
+        assert!(security.open(&frame).is_ok());
+        assert!(security.clone().open(&frame).is_err());
+    }
 }
@@ -46,8 +46,38 @@ impl HashRing {
         Some(&self.vnodes[0].node)
     }
 
+    // up to `n` distinct nodes met walking the ring from the key, the primary first
+    pub fn preference(&self, key: &str, n: usize) -> Vec<&ClusterNode> {
+        let key_hash = Self::hash(key);
+        let start = self.vnodes.partition_point(|vnode| vnode.token < key_hash);
+
+        let mut nodes: Vec<&ClusterNode> = Vec::new();
+        for i in 0..self.vnodes.len() {
+            if nodes.len() == n {
+                break;
+            }
+            let node = &self.vnodes[(start + i) % self.vnodes.len()].node;
+            if !nodes.iter().any(|n| n._id == node._id) {
+                nodes.push(node);
+            }
+        }
+        nodes
+    }
+
+    // distinct cluster nodes on the ring, in order of node id
+    pub fn nodes(&self) -> Vec<&ClusterNode> {
+        let mut nodes: Vec<&ClusterNode> = Vec::new();
+        for vnode in &self.vnodes {
+            if !nodes.iter().any(|n| n._id == vnode.node._id) {
+                nodes.push(&vnode.node);
+            }
+        }
+        nodes.sort_by(|a, b| a._id.cmp(&b._id));
+        nodes
+    }
+
     // TODO this is just for demonstration, replace with a better hash function (Murmur hash)
-    fn hash(key: &str) -> u32 {
+    pub fn hash(key: &str) -> u32 {
         use std::collections::hash_map::DefaultHasher;
         use std::hash::{Hash, Hasher};
 
@@ -56,3 +86,30 @@ impl HashRing {
         (hasher.finish() % (u32::MAX as u64)) as u32
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preference() {
+        let nodes = ["1", "2", "3"]
+            .iter()
+            .map(|id| ClusterNode {
+                _id: id.to_string(),
+                host: "localhost".into(),
+                port: "0".into(),
+                gossip_port: "0".into(),
+            })
+            .collect();
+        let ring = HashRing::build(nodes, 16);
+
+        for key in ["a", "b", "c", "d"] {
+            let preference = ring.preference(key, 2);
+            assert_eq!(preference.len(), 2);
+            assert_eq!(preference[0]._id, ring.primary(key).unwrap()._id);
+            assert_ne!(preference[0]._id, preference[1]._id);
+        }
+        assert_eq!(ring.preference("a", 5).len(), 3);
+    }
+}
@@ -0,0 +1,555 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, atomic::Ordering},
+};
+
+use serde_json::{Map, Value, json};
+
+use crate::{
+    auth::Identity,
+    commands::{Command, Scope},
+    crypto::{percent_decode, to_hex},
+    error::Error,
+    log::log,
+    networking::{self, CLIENT_READ_TIMEOUT, NodeContext, parse_reply, parse_value, read_exactly},
+};
+
+// upper bound for request bodies and the request head
+const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+const MAX_HEADERS: usize = 100;
+const MAX_LINE_LEN: usize = 8 * 1024;
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    body: Body,
+}
+
+#[derive(Debug)]
+enum Body {
+    Json(Value),
+    // a stored value as it is, for clients asking for application/octet-stream
+    Octets(Vec<u8>),
+}
+
+impl Response {
+    fn ok(body: Value) -> Response {
+        Response {
+            status: 200,
+            body: Body::Json(body),
+        }
+    }
+
+    // errors of the HTTP layer itself, the code follows from the status
+    fn error(status: u16, message: &str) -> Response {
+        let code = match status {
+            400 | 405 => "BAD_REQUEST",
+            401 => "UNAUTHORIZED",
+            404 => "NOT_FOUND",
+            _ => "INTERNAL",
+        };
+
+        Response {
+            status,
+            body: Body::Json(json!({ "error": message, "code": code })),
+        }
+    }
+
+    // maps a text protocol error onto a status code
+    fn from_error(error: &Error) -> Response {
+        let status = match error {
+            Error::NotFound(_) => 404,
+            Error::BadRequest(_) => 400,
+            Error::Conflict(_) => 409,
+            Error::Unauthorized(_) => 401,
+            Error::Forbidden(_) => 403,
+            Error::Unavailable(_) | Error::Partial(_) => 503,
+            Error::Timeout(_) => 504,
+            Error::Internal(_) => 500,
+        };
+
+        Response {
+            status,
+            body: Body::Json(json!({ "error": error.message(), "code": error.code() })),
+        }
+    }
+}
+
+pub fn start_http(host: &str, port: u16, max_connections: usize, ctx: Arc<NodeContext>) {
+    let addr = format!("{}:{}", host, port);
+    let listener = TcpListener::bind(&addr).expect("Failed to bind HTTP address");
+
+    log(
+        &format!("HTTP listener started on {}", addr),
+        ctx.log_enabled,
+    );
+
+    std::thread::spawn(move || {
+        networking::serve(listener, max_connections, ctx, handle_connection, |e| {
+            let body = json!({ "error": e, "code": "UNAVAILABLE" }).to_string();
+            format!(
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .into_bytes()
+        });
+    });
+}
+
+fn handle_connection(tcp_stream: TcpStream, ctx: &NodeContext) {
+    let _ = tcp_stream.set_read_timeout(Some(CLIENT_READ_TIMEOUT));
+
+    let Some(stream) = networking::accept_client(tcp_stream, ctx) else {
+        return;
+    };
+
+    // responses are collected and written once no pipelined request is pending
+    let mut reader = BufReader::new(stream);
+    let mut pending = Vec::new();
+
+    loop {
+        let (response, keep_alive) = match read_request(&mut reader) {
+            Ok(Some(request)) => {
+                ctx.request_count.fetch_add(1, Ordering::Relaxed);
+                log(
+                    &format!("Received HTTP request: {} {}", request.method, request.path),
+                    ctx.log_enabled,
+                );
+
+                let keep_alive = !request
+                    .headers
+                    .get("connection")
+                    .is_some_and(|c| c.eq_ignore_ascii_case("close"));
+
+                (route(&request, ctx), keep_alive)
+            }
+            Ok(None) => break,
+            Err(response) => (response, false),
+        };
+
+        if write_response(&mut pending, &response, keep_alive).is_err() || !keep_alive {
+            break;
+        }
+
+        if reader.buffer().is_empty() && networking::flush(reader.get_mut(), &mut pending).is_err()
+        {
+            break;
+        }
+    }
+
+    networking::close(reader.get_mut(), &mut pending);
+}
+
+// the request, None at the end of the stream, or the response to a request that cannot be read
+fn read_request(reader: &mut impl BufRead) -> Result<Option<Request>, Response> {
+    let bad = |e: io::Error| Response::error(400, &e.to_string());
+
+    let request_line = match networking::read_line(reader, MAX_LINE_LEN) {
+        Ok(Some(line)) => line,
+        Ok(None) => return Ok(None),
+        Err(e) if networking::is_line_too_long(&e) => {
+            return Err(Response::error(400, "Request line too long"));
+        }
+        Err(e) => return Err(bad(e)),
+    };
+
+    let parts: Vec<&str> = request_line.split_whitespace().collect();
+    let [method, target, _version] = parts.as_slice() else {
+        return Err(Response::error(400, "Malformed request line"));
+    };
+
+    let mut headers = HashMap::new();
+    loop {
+        let line = match networking::read_line(reader, MAX_LINE_LEN) {
+            Ok(line) => line.unwrap_or_default(),
+            Err(e) if networking::is_line_too_long(&e) => {
+                return Err(Response::error(431, "Request header too large"));
+            }
+            Err(e) => return Err(bad(e)),
+        };
+
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+
+        if headers.len() >= MAX_HEADERS {
+            return Err(Response::error(431, "Too many headers"));
+        }
+
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(Response::error(400, "Malformed header"));
+        };
+        headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+    }
+
+    if headers.contains_key("transfer-encoding") {
+        return Err(Response::error(
+            400,
+            "Chunked request bodies are not supported",
+        ));
+    }
+
+    let body_len = match headers.get("content-length") {
+        Some(len) => match len.parse::<usize>() {
+            Ok(len) if len <= MAX_BODY_LEN => len,
+            _ => return Err(Response::error(400, "Invalid Content-Length")),
+        },
+        None => 0,
+    };
+
+    let body = read_exactly(reader, body_len).map_err(bad)?;
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    Ok(Some(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: parse_query(query),
+        headers,
+        body,
+    }))
+}
+
+fn write_response(
+    writer: &mut impl Write,
+    response: &Response,
+    keep_alive: bool,
+) -> io::Result<()> {
+    let (content_type, body) = match &response.body {
+        Body::Json(body) => ("application/json", body.to_string().into_bytes()),
+        Body::Octets(body) => ("application/octet-stream", body.clone()),
+    };
+
+    // clients are asked for credentials when authentication is enabled
+    let challenge = if response.status == 401 {
+        "WWW-Authenticate: Basic realm=\"kavadb\"\r\n"
+    } else {
+        ""
+    };
+
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Connection: {}\r\n\r\n",
+        response.status,
+        reason(response.status),
+        content_type,
+        body.len(),
+        challenge,
+        if keep_alive { "keep-alive" } else { "close" },
+    )?;
+    writer.write_all(&body)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        409 => "Conflict",
+        431 => "Request Header Fields Too Large",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+fn route(request: &Request, ctx: &NodeContext) -> Response {
+    let segments: Vec<&str> = request.path.trim_start_matches('/').split('/').collect();
+
+    let identity = match authenticate(request, ctx) {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+    let id = &identity;
+
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["kv"]) => read_range(request, id, ctx),
+        ("POST", ["kv", "_batch"]) => batch_put(request, id, ctx),
+        ("GET", ["kv", key]) => read(&percent_decode(key), request, id, ctx),
+        ("PUT", ["kv", key]) => put(&percent_decode(key), request, id, ctx),
+        ("DELETE", ["kv", key]) => delete(&percent_decode(key), id, ctx),
+        (_, ["kv"]) | (_, ["kv", _]) => Response::error(405, "Method not allowed"),
+        _ => Response::error(404, "Not found"),
+    }
+}
+
+// every request carries its credentials as HTTP basic authentication
+fn authenticate(request: &Request, ctx: &NodeContext) -> Result<Identity, Response> {
+    let Some(authorization) = request.headers.get("authorization") else {
+        return Ok(Identity::Anonymous);
+    };
+
+    let invalid = || Response::error(401, "Invalid username or password");
+    let credentials = authorization
+        .strip_prefix("Basic ")
+        .and_then(|encoded| from_base64(encoded.trim()))
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .ok_or_else(invalid)?;
+    let (user, password) = credentials.split_once(':').ok_or_else(invalid)?;
+
+    ctx.auth.login(user, password).map_err(|e| {
+        // write to the stderr regardless of log setting
+        eprintln!("Failed authentication: {}", e.message());
+        invalid()
+    })
+}
+
+fn from_base64(encoded: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = Vec::new();
+    let (mut bits, mut count) = (0u32, 0);
+    for c in encoded.trim_end_matches('=').bytes() {
+        let digit = ALPHABET.iter().position(|&a| a == c)? as u32;
+        bits = bits << 6 | digit;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn validate(key: &str) -> Result<(), Response> {
+    if key.is_empty() {
+        return Err(Response::error(400, "Keys must not be empty"));
+    }
+    Ok(())
+}
+
+// values that are not valid UTF-8 are given as hex in "value_hex" instead of "value"
+fn entry(key: &str, value: &[u8]) -> Value {
+    match std::str::from_utf8(value) {
+        Ok(value) => json!({ "key": key, "value": value }),
+        Err(_) => json!({ "key": key, "value_hex": to_hex(value) }),
+    }
+}
+
+fn read(key: &str, request: &Request, identity: &Identity, ctx: &NodeContext) -> Response {
+    if let Err(response) = validate(key) {
+        return response;
+    }
+
+    let octets = request
+        .headers
+        .get("accept")
+        .is_some_and(|a| a.starts_with("application/octet-stream"));
+
+    match parse_value(networking::execute_as(
+        identity,
+        Command::Read(key.to_string()),
+        ctx,
+    )) {
+        Ok(Some(value)) if octets => Response {
+            status: 200,
+            body: Body::Octets(value),
+        },
+        Ok(Some(value)) => Response::ok(entry(key, &value)),
+        Ok(None) => Response::error(404, "Key not found"),
+        Err(e) => Response::from_error(&e),
+    }
+}
+
+// the value is the request body, or the "value" field of a JSON body
+fn put(key: &str, request: &Request, identity: &Identity, ctx: &NodeContext) -> Response {
+    let is_json = request
+        .headers
+        .get("content-type")
+        .is_some_and(|t| t.starts_with("application/json"));
+
+    let value = if is_json {
+        match serde_json::from_slice::<Value>(&request.body) {
+            Ok(Value::Object(object)) => match object.get("value") {
+                Some(Value::String(value)) => value.clone().into_bytes(),
+                _ => return Response::error(400, "Expected a string field \"value\""),
+            },
+            _ => return Response::error(400, "Expected a JSON object"),
+        }
+    } else {
+        request.body.clone()
+    };
+
+    if let Err(response) = validate(key) {
+        return response;
+    }
+
+    match parse_reply(networking::execute_as(
+        identity,
+        Command::Put(key.to_string(), value.clone()),
+        ctx,
+    )) {
+        Ok(_) => Response::ok(entry(key, &value)),
+        Err(e) => Response::from_error(&e),
+    }
+}
+
+fn delete(key: &str, identity: &Identity, ctx: &NodeContext) -> Response {
+    if let Err(response) = validate(key) {
+        return response;
+    }
+
+    match parse_reply(networking::execute_as(
+        identity,
+        Command::Delete(key.to_string()),
+        ctx,
+    )) {
+        Ok(Some(_)) => Response::ok(json!({ "key": key, "deleted": true })),
+        Ok(None) => Response::error(404, "Key not found"),
+        Err(e) => Response::from_error(&e),
+    }
+}
+
+// the body is a JSON object of string keys and values
+fn batch_put(request: &Request, identity: &Identity, ctx: &NodeContext) -> Response {
+    let entries: Map<String, Value> = match serde_json::from_slice(&request.body) {
+        Ok(Value::Object(entries)) if !entries.is_empty() => entries,
+        _ => return Response::error(400, "Expected a non-empty JSON object"),
+    };
+
+    let mut pairs = Vec::with_capacity(entries.len());
+    for (key, value) in &entries {
+        let Value::String(value) = value else {
+            return Response::error(400, "Values must be strings");
+        };
+        if let Err(response) = validate(key) {
+            return response;
+        }
+        pairs.push((key.clone(), value.clone().into_bytes()));
+    }
+
+    let keys: Vec<String> = entries.keys().cloned().collect();
+    let response = networking::execute_as(identity, Command::BatchPut(pairs), ctx);
+    let e = match parse_reply(response.clone()) {
+        Ok(_) => return Response::ok(json!({ "result": "OK" })),
+        Err(e) => e,
+    };
+
+    // a partial failure lists the keys that were not written along with their error
+    let mut failed = Map::new();
+    for (key, result) in keys.iter().zip(networking::parse_batch(&response, &keys)) {
+        if let Err(e) = result {
+            failed.insert(
+                key.clone(),
+                json!({ "error": e.message(), "code": e.code() }),
+            );
+        }
+    }
+
+    let mut error = Response::from_error(&e);
+    if let (Error::Partial(_), Body::Json(Value::Object(body))) = (&e, &mut error.body) {
+        body.insert("failed".to_string(), Value::Object(failed));
+    }
+    error
+}
+
+fn read_range(request: &Request, identity: &Identity, ctx: &NodeContext) -> Response {
+    let (Some(start), Some(end)) = (request.query.get("start"), request.query.get("end")) else {
+        return Response::error(400, "Query parameters start and end are required");
+    };
+
+    let limit = match request.query.get("limit").map(|limit| limit.parse()) {
+        None => None,
+        Some(Ok(limit)) => Some(limit),
+        Some(Err(_)) => return Response::error(400, "Query parameter limit must be a number"),
+    };
+
+    let range = Command::ReadKeyByRange(start.clone(), end.clone(), limit, Scope::Cluster);
+    let items = match networking::parse_range(&networking::execute_as(identity, range, ctx)) {
+        Ok(items) => items,
+        Err(e) => return Response::from_error(&e),
+    };
+
+    let items: Vec<Value> = items.iter().map(|(key, value)| entry(key, value)).collect();
+
+    Response::ok(json!({ "items": items }))
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (
+                percent_decode(&name.replace('+', " ")),
+                percent_decode(&value.replace('+', " ")),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_request() {
+        let mut input: &[u8] =
+            b"PUT /kv/my%20key?x=1 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nvalueGET /kv HTTP/1.1\r\n\r\n";
+
+        let request = read_request(&mut input).unwrap().unwrap();
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/kv/my%20key");
+        assert_eq!(request.query["x"], "1");
+        assert_eq!(request.headers["host"], "localhost");
+        assert_eq!(request.body, b"value");
+
+        let request = read_request(&mut input).unwrap().unwrap();
+        assert_eq!(request.method, "GET");
+        assert!(request.body.is_empty());
+
+        assert!(read_request(&mut input).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_request_rejects_chunked_body() {
+        let mut input: &[u8] = b"POST /kv/_batch HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+        assert!(read_request(&mut input).is_err());
+    }
+
+    #[test]
+    fn test_read_request_bounds_the_head() {
+        let header = format!("X-Long: {}\r\n", "a".repeat(MAX_LINE_LEN));
+        let input = format!("GET /kv HTTP/1.1\r\n{}\r\n", header);
+        assert_eq!(read_request(&mut input.as_bytes()).unwrap_err().status, 431);
+
+        let input = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE_LEN));
+        assert_eq!(read_request(&mut input.as_bytes()).unwrap_err().status, 400);
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = parse_query("start=a%2Fb&end=z+z&flag");
+
+        assert_eq!(query["start"], "a/b");
+        assert_eq!(query["end"], "z z");
+        assert_eq!(query["flag"], "");
+    }
+
+    #[test]
+    fn test_from_base64() {
+        assert_eq!(from_base64("dXNlcjpwYXNz").unwrap(), b"user:pass");
+        assert_eq!(from_base64("YTpi").unwrap(), b"a:b");
+        assert_eq!(from_base64("YQ==").unwrap(), b"a");
+        assert!(from_base64("not base64!").is_none());
+    }
+}
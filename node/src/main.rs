@@ -1,19 +1,46 @@
 use std::collections::HashMap;
 use std::env;
+use std::str::FromStr;
+use std::time::Duration;
 
+use crate::auth::Auth;
+use crate::binary::start_binary;
+use crate::chain::Chain;
 use crate::config::{NodeConfig, load_config};
-use crate::gossip::start_gossip;
+use crate::gossip::{ApplicationStates, GossipSecurity, start_gossip};
 use crate::hashing::HashRing;
-use crate::networking::start_node;
+use crate::http::start_http;
+use crate::memcached::start_memcached;
+use crate::networking::{NodeContext, start_node};
+use crate::partition::{Partitioner, RangeTable, SplitLimits};
+use crate::peers::{ForwardConfig, PeerPool};
+use crate::raft::Raft;
+use crate::resp::start_resp;
+use crate::tls::TlsConfig;
+use crate::twophase::TwoPhase;
 use std::sync::{Arc, Mutex};
 
+mod auth;
+mod binary;
+mod chain;
 mod commands;
 mod config;
+mod crypto;
+mod error;
 mod gossip;
 mod hashing;
+mod http;
 mod log;
+mod memcached;
 mod networking;
+mod partition;
+mod peers;
+mod pool;
+mod raft;
+mod resp;
 mod storage;
+mod tls;
+mod twophase;
 
 fn main() {
     // assume that kava.conf is in the current directory
@@ -45,12 +72,24 @@ fn main() {
 
     let storage_type = config.storage;
 
+    let max_connections: usize = if config.max_connections.is_empty() {
+        64
+    } else {
+        match config.max_connections.parse() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                eprintln!("Invalid max_connections: {}", config.max_connections);
+                std::process::exit(1);
+            }
+        }
+    };
+
     let log_enabled = config.log_enabled.to_lowercase() == "true";
 
     log::log(
         &format!(
-            "Starting server on {}:{}, with id: [{}] with storage: {}, logging: {}, config: {}",
-            host, port_num, config.me, storage_type, log_enabled, config_file
+            "Starting server on {}:{}, with id: [{}] with storage: {}, logging: {}, max connections: {}, config: {}",
+            host, port_num, config.me, storage_type, log_enabled, max_connections, config_file
         ),
         log_enabled,
     );
@@ -58,8 +97,8 @@ fn main() {
     let cluster_nodes_config = config.cluster;
 
     let cluster_nodes: Vec<String> = cluster_nodes_config
-        .iter()
-        .map(|(_, v)| format!("{}:{}", v.host, v.port))
+        .values()
+        .map(|v| format!("{}:{}", v.host, v.port))
         .collect();
 
     for node in &cluster_nodes {
@@ -81,23 +120,311 @@ fn main() {
         .unwrap()
         .insert(config.me.clone(), format!("{}:{}", host, port_num));
 
-    let ring = HashRing::build(cluster_nodes_config.values().cloned().collect(), 128);
+    let app_states: ApplicationStates = Arc::new(Mutex::new(HashMap::new()));
+
+    // TLS is enabled by configuring a certificate, the CA is needed to verify the other nodes
+    let tls = if config.tls_cert.is_empty() && config.tls_key.is_empty() {
+        None
+    } else {
+        match TlsConfig::load(&config.tls_cert, &config.tls_key, &config.tls_ca) {
+            Ok(tls) => Some(Arc::new(tls)),
+            Err(e) => {
+                eprintln!("Invalid TLS configuration: {}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+
+    let cluster_secret = Some(config.cluster_secret.clone()).filter(|s| !s.is_empty());
+
+    // authentication is enabled once a user is configured
+    let auth = match Auth::from_config(&config.users, cluster_secret.clone()) {
+        Ok(auth) => auth,
+        Err(e) => {
+            eprintln!("Invalid authentication configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let auth_enabled = auth.enabled();
+
+    let gossip_encryption = config.gossip_encryption.to_lowercase() == "true";
+    if gossip_encryption && cluster_secret.is_none() {
+        eprintln!("Invalid gossip configuration: gossip_encryption requires cluster.secret");
+        std::process::exit(1);
+    }
+
+    let gossip_security = GossipSecurity {
+        secret: cluster_secret,
+        encrypt: gossip_encryption,
+        tls: tls.clone(),
+        ..Default::default()
+    };
+
+    // timeouts and retries of commands forwarded to other nodes, in milliseconds
+    let defaults = ForwardConfig::default();
+    let mut forward = ForwardConfig {
+        connect_timeout: Duration::from_millis(setting(
+            "forward.connect_timeout_ms",
+            &config.forward_connect_timeout_ms,
+            defaults.connect_timeout.as_millis() as u64,
+        )),
+        read_timeout: Duration::from_millis(setting(
+            "forward.read_timeout_ms",
+            &config.forward_read_timeout_ms,
+            defaults.read_timeout.as_millis() as u64,
+        )),
+        write_timeout: Duration::from_millis(setting(
+            "forward.write_timeout_ms",
+            &config.forward_write_timeout_ms,
+            defaults.write_timeout.as_millis() as u64,
+        )),
+        retries: setting("forward.retries", &config.forward_retries, defaults.retries),
+        pool_size: setting(
+            "forward.pool_size",
+            &config.forward_pool_size,
+            defaults.pool_size,
+        ),
+        deadline: Duration::from_millis(setting(
+            "forward.deadline_ms",
+            &config.forward_deadline_ms,
+            defaults.deadline.as_millis() as u64,
+        )),
+    };
+    if forward.connect_timeout.is_zero()
+        || forward.read_timeout.is_zero()
+        || forward.write_timeout.is_zero()
+        || forward.deadline.is_zero()
+    {
+        eprintln!("Invalid forward timeouts: must be greater than 0");
+        std::process::exit(1);
+    }
+
+    // every idle connection a peer keeps to this node holds one of its workers, all peers
+    // together may hold half of them, the rest is left for clients; with too many peers for one
+    // each, connections are closed after every command
+    let peers = cluster_nodes.len().saturating_sub(1).max(1);
+    let pool_cap = max_connections / 2 / peers;
+    if forward.pool_size > pool_cap {
+        log::log(
+            &format!(
+                "Keeping {} idle connections per peer instead of {}, half of the {} workers for {} peers",
+                pool_cap, forward.pool_size, max_connections, peers
+            ),
+            log_enabled,
+        );
+        forward.pool_size = pool_cap;
+    }
+
+    // the coordinator of a transaction spanning nodes logs its decisions, participants give up
+    // their locks after the timeout
+    let lock_timeout = Duration::from_millis(setting(
+        "transaction.lock_timeout_ms",
+        &config.transaction_lock_timeout_ms,
+        10_000,
+    ));
+    if lock_timeout.is_zero() {
+        eprintln!("Invalid transaction.lock_timeout_ms: must be greater than 0");
+        std::process::exit(1);
+    }
+    let transaction_log = if config.transaction_log.is_empty() {
+        format!("kava-{}.txlog", config.me)
+    } else {
+        config.transaction_log.clone()
+    };
+    let two_phase = match TwoPhase::open(&transaction_log, lock_timeout) {
+        Ok(two_phase) => two_phase,
+        Err(e) => {
+            eprintln!("Failed to open transaction log {}: {}", transaction_log, e);
+            std::process::exit(1);
+        }
+    };
 
-    start_gossip(
+    // keys of consistent keyspaces are replicated by Raft groups, all nodes need the same
+    // settings
+    let keyspaces: Vec<String> = config
+        .raft_keyspaces
+        .split(',')
+        .map(|prefix| prefix.trim().to_string())
+        .filter(|prefix| !prefix.is_empty())
+        .collect();
+    let shards = setting("raft.shards", &config.raft_shards, 8);
+    let replicas = setting("raft.replicas", &config.raft_replicas, 3);
+    if shards == 0 || replicas == 0 {
+        eprintln!("Invalid raft settings: shards and replicas must be greater than 0");
+        std::process::exit(1);
+    }
+    let raft_log = if config.raft_log.is_empty() {
+        format!("kava-{}.raft", config.me)
+    } else {
+        config.raft_log.clone()
+    };
+    let raft = if keyspaces.is_empty() {
+        Raft::default()
+    } else {
+        match Raft::open(
+            &config.me,
+            cluster_nodes_config.values().cloned().collect(),
+            keyspaces.clone(),
+            shards,
+            replicas,
+            setting("raft.snapshot_entries", &config.raft_snapshot_entries, 1000),
+            &raft_log,
+        ) {
+            Ok(raft) => raft,
+            Err(e) => {
+                eprintln!("Failed to open the Raft journals {}.*: {}", raft_log, e);
+                std::process::exit(1);
+            }
+        }
+    };
+
+    // keys of chain replicated keyspaces are written along the preference list of the hash ring,
+    // all nodes need the same settings
+    let chain_keyspaces: Vec<String> = config
+        .chain_keyspaces
+        .split(',')
+        .map(|prefix| prefix.trim().to_string())
+        .filter(|prefix| !prefix.is_empty())
+        .collect();
+    let chain_replicas = setting("chain.replicas", &config.chain_replicas, 3);
+    if chain_replicas == 0 {
+        eprintln!("Invalid chain.replicas: must be greater than 0");
+        std::process::exit(1);
+    }
+    if !chain_keyspaces.is_empty() && !matches!(config.partition_mode.as_str(), "" | "hash") {
+        eprintln!("Invalid chain.keyspaces: chains need partition.mode=hash");
+        std::process::exit(1);
+    }
+    if let Some(prefix) = chain_keyspaces.iter().find(|prefix| {
+        keyspaces
+            .iter()
+            .any(|other| prefix.starts_with(other.as_str()) || other.starts_with(prefix.as_str()))
+    }) {
+        eprintln!(
+            "Invalid chain.keyspaces: {} overlaps a keyspace of raft.keyspaces",
+            prefix
+        );
+        std::process::exit(1);
+    }
+
+    // range partitioning keeps neighbouring keys together, all nodes need the same settings
+    let nodes = cluster_nodes_config.values().cloned().collect();
+    let partitioner = match config.partition_mode.as_str() {
+        "" | "hash" => Partitioner::Hash(HashRing::build(nodes, 128)),
+        "range" => {
+            let defaults = SplitLimits::default();
+            let limits = SplitLimits {
+                max_keys: setting(
+                    "partition.split_keys",
+                    &config.partition_split_keys,
+                    defaults.max_keys,
+                ),
+                max_rate: setting(
+                    "partition.split_rate",
+                    &config.partition_split_rate,
+                    defaults.max_rate,
+                ),
+            };
+            let splits: Vec<String> = config
+                .partition_splits
+                .split(',')
+                .map(|split| split.trim().to_string())
+                .filter(|split| !split.is_empty())
+                .collect();
+            Partitioner::Range(RangeTable::new(nodes, &splits, limits))
+        }
+        mode => {
+            eprintln!("Invalid partition.mode: {} (expected hash or range)", mode);
+            std::process::exit(1);
+        }
+    };
+
+    let gossip_rounds = start_gossip(
         &cluster_snapshot,
+        &app_states,
         cluster_nodes_config.into_values().collect(),
+        gossip_security,
         format!("{}:{}", host, port_num),
         config.me.clone(),
         log_enabled,
     );
 
-    start_node(
-        &host,
-        port_num,
+    let ctx = NodeContext::new(
         config.me.clone(),
-        storage_type,
+        &storage_type,
         log_enabled,
-        &ring,
+        partitioner,
         &cluster_snapshot,
-    );
+        &app_states,
+        tls,
+    )
+    .with_auth(auth)
+    .with_peers(PeerPool::new(forward))
+    .with_two_phase(two_phase)
+    .with_raft(raft)
+    .with_chain(Chain::new(chain_keyspaces, chain_replicas, gossip_rounds));
+
+    let ctx = Arc::new(ctx);
+
+    if !config.resp_port.is_empty() {
+        match config.resp_port.parse() {
+            Ok(resp_port) => start_resp(&host, resp_port, max_connections, ctx.clone()),
+            Err(_) => {
+                eprintln!("Invalid RESP port number: {}", config.resp_port);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if !config.http_port.is_empty() {
+        match config.http_port.parse() {
+            Ok(http_port) => start_http(&host, http_port, max_connections, ctx.clone()),
+            Err(_) => {
+                eprintln!("Invalid HTTP port number: {}", config.http_port);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if !config.memcached_port.is_empty() && auth_enabled {
+        // write to the stderr regardless of log setting
+        eprintln!("The memcached listener is disabled: its text protocol has no authentication");
+    } else if !config.memcached_port.is_empty() {
+        match config.memcached_port.parse() {
+            Ok(memcached_port) => {
+                start_memcached(&host, memcached_port, max_connections, ctx.clone())
+            }
+            Err(_) => {
+                eprintln!("Invalid memcached port number: {}", config.memcached_port);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if !config.binary_port.is_empty() {
+        match config.binary_port.parse() {
+            Ok(binary_port) => start_binary(&host, binary_port, max_connections, ctx.clone()),
+            Err(_) => {
+                eprintln!("Invalid binary port number: {}", config.binary_port);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    start_node(&host, port_num, max_connections, ctx);
+}
+
+// an optional numeric setting, the default when it is not configured
+fn setting<T: FromStr>(name: &str, value: &str, default: T) -> T {
+    if value.is_empty() {
+        return default;
+    }
+
+    match value.parse() {
+        Ok(value) => value,
+        Err(_) => {
+            eprintln!("Invalid {}: {}", name, value);
+            std::process::exit(1);
+        }
+    }
 }
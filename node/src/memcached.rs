@@ -0,0 +1,613 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, atomic::Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    commands::{Command, tokenize},
+    error::Error,
+    log::log,
+    networking::{
+        self, NodeContext, POOLED_CLIENT_READ_TIMEOUT, invalid_data, parse_reply, parse_value,
+        read_exactly,
+    },
+};
+
+const MAX_KEY_LEN: usize = 250;
+const MAX_VALUE_LEN: usize = 1024 * 1024;
+// a command line, long enough for a `get` of a few hundred keys
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+// exptime values up to 30 days are relative, larger ones are unix timestamps
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+// read-modify-write operations retry this often when the key changes concurrently
+const MAX_CAS_RETRIES: usize = 16;
+
+#[derive(Debug, PartialEq)]
+enum Expiry {
+    Never,
+    Expired,
+    After(u64),
+}
+
+impl Expiry {
+    fn from_exptime(exptime: i64) -> Expiry {
+        if exptime == 0 {
+            return Expiry::Never;
+        }
+
+        let seconds = if exptime > MAX_RELATIVE_EXPTIME {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or_default();
+            exptime - now
+        } else {
+            exptime
+        };
+
+        if seconds <= 0 {
+            Expiry::Expired
+        } else {
+            Expiry::After(seconds as u64)
+        }
+    }
+}
+
+pub fn start_memcached(host: &str, port: u16, max_connections: usize, ctx: Arc<NodeContext>) {
+    let addr = format!("{}:{}", host, port);
+    let listener = TcpListener::bind(&addr).expect("Failed to bind memcached address");
+
+    log(
+        &format!("Memcached listener started on {}", addr),
+        ctx.log_enabled,
+    );
+
+    std::thread::spawn(move || {
+        networking::serve(listener, max_connections, ctx, handle_connection, |e| {
+            format!("SERVER_ERROR {}\r\n", e).into_bytes()
+        });
+    });
+}
+
+fn handle_connection(tcp_stream: TcpStream, ctx: &NodeContext) {
+    let _ = tcp_stream.set_read_timeout(Some(POOLED_CLIENT_READ_TIMEOUT));
+
+    let Some(stream) = networking::accept_client(tcp_stream, ctx) else {
+        return;
+    };
+
+    // responses are collected and written once no pipelined request is pending
+    let mut reader = BufReader::new(stream);
+    let mut pending = Vec::new();
+
+    loop {
+        let line = match networking::read_line(&mut reader, MAX_LINE_LEN) {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::InvalidData {
+                    let _ = pending.write_all(format!("CLIENT_ERROR {}\r\n", e).as_bytes());
+                }
+                break;
+            }
+        };
+
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.is_empty() {
+            continue;
+        }
+
+        if tokens[0] == "quit" {
+            break;
+        }
+
+        ctx.request_count.fetch_add(1, Ordering::Relaxed);
+        log(
+            &format!("Received memcached command: {}", line.trim_end()),
+            ctx.log_enabled,
+        );
+
+        let noreply = tokens.last() == Some(&"noreply");
+        let response = match handle_command(&tokens, &mut reader, ctx) {
+            Ok(response) => response,
+            Err(e) => {
+                let _ = pending.write_all(format!("CLIENT_ERROR {}\r\n", e).as_bytes());
+                break;
+            }
+        };
+
+        if !noreply && pending.write_all(&response).is_err() {
+            break;
+        }
+
+        // pipelined requests are answered in one go, flush once no request is pending
+        if reader.buffer().is_empty() && networking::flush(reader.get_mut(), &mut pending).is_err()
+        {
+            break;
+        }
+    }
+
+    networking::close(reader.get_mut(), &mut pending);
+}
+
+// answers one command, an Err means the connection is out of sync and must be closed
+fn handle_command(
+    tokens: &[&str],
+    reader: &mut impl BufRead,
+    ctx: &NodeContext,
+) -> io::Result<Vec<u8>> {
+    let args = match tokens.last() {
+        Some(&"noreply") => &tokens[1..tokens.len() - 1],
+        _ => &tokens[1..],
+    };
+
+    let response = match (tokens[0], args) {
+        ("get", keys) if !keys.is_empty() => return Ok(get(keys, false, ctx)),
+        ("gets", keys) if !keys.is_empty() => return Ok(get(keys, true, ctx)),
+
+        ("set" | "add" | "replace", [key, flags, exptime, bytes]) => {
+            let data = read_data(reader, bytes)?;
+            match check_flags(flags) {
+                Ok(()) => store(tokens[0], key, exptime, data, None, ctx),
+                Err(response) => response,
+            }
+        }
+        ("cas", [key, flags, exptime, bytes, cas_unique]) => {
+            let data = read_data(reader, bytes)?;
+            match (check_flags(flags), cas_unique.parse::<u64>()) {
+                (Err(response), _) => response,
+                (Ok(()), Ok(cas_unique)) => store("cas", key, exptime, data, Some(cas_unique), ctx),
+                (Ok(()), Err(_)) => "CLIENT_ERROR bad command line format\r\n".to_string(),
+            }
+        }
+
+        ("delete", [key]) => delete(key, ctx),
+        ("incr", [key, delta]) => incr_decr(key, delta, true, ctx),
+        ("decr", [key, delta]) => incr_decr(key, delta, false, ctx),
+        ("touch", [key, exptime]) => touch(key, exptime, ctx),
+        ("version", []) => format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")),
+
+        _ => "ERROR\r\n".to_string(),
+    };
+
+    Ok(response.into_bytes())
+}
+
+// reads the data block of a storage command: <bytes> followed by \r\n
+fn read_data(reader: &mut impl BufRead, bytes: &str) -> io::Result<Vec<u8>> {
+    let len = match bytes.parse::<usize>() {
+        Ok(len) if len <= MAX_VALUE_LEN => len,
+        _ => return Err(invalid_data("bad data chunk")),
+    };
+
+    let mut data = read_exactly(reader, len + 2)?;
+    if !data.ends_with(b"\r\n") {
+        return Err(invalid_data("bad data chunk"));
+    }
+    data.truncate(len);
+    Ok(data)
+}
+
+// values are stored as they are, shared with the other protocols, so there is no room for flags
+fn check_flags(flags: &str) -> Result<(), String> {
+    match flags.parse::<u32>() {
+        Ok(0) => Ok(()),
+        Ok(_) => Err("CLIENT_ERROR client flags are not supported\r\n".to_string()),
+        Err(_) => Err("CLIENT_ERROR bad command line format\r\n".to_string()),
+    }
+}
+
+fn valid_key(key: &str) -> bool {
+    key.len() <= MAX_KEY_LEN && !key.chars().any(|c| c.is_control() || c.is_whitespace())
+}
+
+fn get(keys: &[&str], with_cas: bool, ctx: &NodeContext) -> Vec<u8> {
+    let mut response = Vec::new();
+
+    for key in keys {
+        if !valid_key(key) {
+            return b"CLIENT_ERROR bad command line format\r\n".to_vec();
+        }
+
+        let read = if with_cas {
+            read_versioned(key, ctx).map(|read| read.map(|(value, version)| (value, Some(version))))
+        } else {
+            parse_value(networking::execute(Command::Read(key.to_string()), ctx))
+                .map(|read| read.map(|value| (value, None)))
+        };
+
+        match read {
+            Ok(Some((value, version))) => {
+                let header = match version {
+                    Some(version) => format!("VALUE {} 0 {} {}\r\n", key, value.len(), version),
+                    None => format!("VALUE {} 0 {}\r\n", key, value.len()),
+                };
+                response.extend(header.as_bytes());
+                response.extend(&value);
+                response.extend(b"\r\n");
+            }
+            Ok(None) => {}
+            Err(e) => return failure(e).into_bytes(),
+        }
+    }
+
+    response.extend(b"END\r\n");
+    response
+}
+
+fn store(
+    command: &str,
+    key: &str,
+    exptime: &str,
+    value: Vec<u8>,
+    cas_unique: Option<u64>,
+    ctx: &NodeContext,
+) -> String {
+    let (Ok(exptime), true) = (exptime.parse::<i64>(), valid_key(key)) else {
+        return "CLIENT_ERROR bad command line format\r\n".to_string();
+    };
+    let expiry = Expiry::from_exptime(exptime);
+
+    let stored = match (command, cas_unique) {
+        ("set", _) => parse_reply(networking::execute(
+            write_command(key, value, None, &expiry),
+            ctx,
+        ))
+        .map(|_| "STORED"),
+
+        // version 0 only matches a missing key
+        ("add", _) => match put_if_version(key, value, 0, &expiry, ctx) {
+            Ok(()) => Ok("STORED"),
+            Err(Error::Conflict(_)) => Ok("NOT_STORED"),
+            Err(e) => Err(e),
+        },
+
+        ("replace", _) => {
+            update(key, Some(&expiry), ctx, |_| Ok(value.clone())).map(|updated| match updated {
+                Some(_) => "STORED",
+                None => "NOT_STORED",
+            })
+        }
+
+        ("cas", Some(cas_unique)) if cas_unique > 0 => {
+            match put_if_version(key, value, cas_unique, &expiry, ctx) {
+                Ok(()) => Ok("STORED"),
+                Err(Error::Conflict(_)) => Ok("EXISTS"),
+                Err(Error::NotFound(_)) => Ok("NOT_FOUND"),
+                Err(e) => Err(e),
+            }
+        }
+        ("cas", _) => read_versioned(key, ctx).map(|current| match current {
+            Some(_) => "EXISTS",
+            None => "NOT_FOUND",
+        }),
+
+        _ => return "ERROR\r\n".to_string(),
+    };
+
+    match stored {
+        Ok(outcome) => format!("{}\r\n", outcome),
+        Err(e) => failure(e),
+    }
+}
+
+fn delete(key: &str, ctx: &NodeContext) -> String {
+    match parse_reply(networking::execute(Command::Delete(key.to_string()), ctx)) {
+        Ok(Some(_)) => "DELETED\r\n".to_string(),
+        Ok(None) => "NOT_FOUND\r\n".to_string(),
+        Err(e) => format!("SERVER_ERROR {}\r\n", e),
+    }
+}
+
+// incr wraps around at 2^64, decr stops at 0
+fn incr_decr(key: &str, delta: &str, incr: bool, ctx: &NodeContext) -> String {
+    let Ok(delta) = delta.parse::<u64>() else {
+        return "CLIENT_ERROR invalid numeric delta argument\r\n".to_string();
+    };
+
+    // the key keeps its expiration, as in memcached
+    let updated = update(key, None, ctx, |value| {
+        let Some(current) = std::str::from_utf8(value)
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+        else {
+            return Err(Error::BadRequest(
+                "cannot increment or decrement non-numeric value".to_string(),
+            ));
+        };
+
+        Ok(if incr {
+            current.wrapping_add(delta)
+        } else {
+            current.saturating_sub(delta)
+        }
+        .to_string()
+        .into_bytes())
+    });
+
+    match updated {
+        Ok(Some(value)) => format!("{}\r\n", String::from_utf8_lossy(&value)),
+        Ok(None) => "NOT_FOUND\r\n".to_string(),
+        Err(e) => failure(e),
+    }
+}
+
+fn touch(key: &str, exptime: &str, ctx: &NodeContext) -> String {
+    let Ok(exptime) = exptime.parse::<i64>() else {
+        return "CLIENT_ERROR bad command line format\r\n".to_string();
+    };
+
+    let result = match Expiry::from_exptime(exptime) {
+        // rewriting the value clears the expiration
+        Expiry::Never => {
+            update(key, Some(&Expiry::Never), ctx, |value| Ok(value.to_vec())).map(|v| v.is_some())
+        }
+        expiry => apply_expiry(key, expiry, ctx),
+    };
+
+    match result {
+        Ok(true) => "TOUCHED\r\n".to_string(),
+        Ok(false) => "NOT_FOUND\r\n".to_string(),
+        Err(e) => failure(e),
+    }
+}
+
+// false if the key does not exist
+fn apply_expiry(key: &str, expiry: Expiry, ctx: &NodeContext) -> Result<bool, Error> {
+    let cmd = match expiry {
+        Expiry::Never => return Ok(true),
+        Expiry::Expired => Command::Delete(key.to_string()),
+        Expiry::After(seconds) => Command::Expire(key.to_string(), seconds),
+    };
+
+    parse_reply(networking::execute(cmd, ctx)).map(|reply| reply.is_some())
+}
+
+// a request the listener cannot serve is the client's error, anything else the server's
+fn failure(e: Error) -> String {
+    match e {
+        Error::BadRequest(e) => format!("CLIENT_ERROR {}\r\n", e),
+        e => format!("SERVER_ERROR {}\r\n", e),
+    }
+}
+
+// versions are counted by every replica of a consistent or chain replicated keyspace on its own,
+// so its keys have no cas unique
+fn check_versioned(key: &str, ctx: &NodeContext) -> Result<(), Error> {
+    if ctx.raft.consistent(key) {
+        return Err(Error::BadRequest(
+            "cas uniques are not available in consistent keyspaces".to_string(),
+        ));
+    }
+    if ctx.chain.covers(key) {
+        return Err(Error::BadRequest(
+            "cas uniques are not available in chain replicated keyspaces".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn read_versioned(key: &str, ctx: &NodeContext) -> Result<Option<(Vec<u8>, u64)>, Error> {
+    check_versioned(key, ctx)?;
+    let invalid = |response: &str| Error::Internal(format!("Invalid response: {}", response));
+
+    let response = parse_reply(networking::execute(
+        Command::ReadVersion(key.to_string()),
+        ctx,
+    ))?;
+
+    match response {
+        Some(response) => match tokenize(&response)?.as_slice() {
+            [version, value] => {
+                let version = std::str::from_utf8(version)
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .ok_or_else(|| invalid(&response))?;
+                Ok(Some((value.clone(), version)))
+            }
+            _ => Err(invalid(&response)),
+        },
+        None => Ok(None),
+    }
+}
+
+// writes the value and its expiration at once, only if the key is at the version if one is given
+fn write_command(key: &str, value: Vec<u8>, version: Option<u64>, expiry: &Expiry) -> Command {
+    let key = key.to_string();
+    match (expiry, version) {
+        (Expiry::Never, None) => Command::Put(key, value),
+        (Expiry::Never, Some(version)) => Command::PutIfVersion(key, value, version),
+        (Expiry::After(seconds), version) => Command::PutEx(key, value, *seconds, version),
+        // stored and gone right away, as a write that expired already
+        (Expiry::Expired, version) => Command::PutEx(key, value, 0, version),
+    }
+}
+
+// version 0, a key that must not exist yet, works in every keyspace
+fn put_if_version(
+    key: &str,
+    value: Vec<u8>,
+    version: u64,
+    expiry: &Expiry,
+    ctx: &NodeContext,
+) -> Result<(), Error> {
+    if version != 0 {
+        check_versioned(key, ctx)?;
+    }
+    write_versioned(write_command(key, value, Some(version), expiry), ctx)
+}
+
+fn write_versioned(cmd: Command, ctx: &NodeContext) -> Result<(), Error> {
+    match parse_reply(networking::execute(cmd, ctx))? {
+        Some(_) => Ok(()),
+        None => Err(Error::not_found()),
+    }
+}
+
+// optimistic read-modify-write on the owning node, None if the key does not exist; the new value
+// is written with the expiry, or keeps the expiration of the key without one
+fn update<F>(
+    key: &str,
+    expiry: Option<&Expiry>,
+    ctx: &NodeContext,
+    modify: F,
+) -> Result<Option<Vec<u8>>, Error>
+where
+    F: Fn(&[u8]) -> Result<Vec<u8>, Error>,
+{
+    for _ in 0..MAX_CAS_RETRIES {
+        let Some((value, version)) = read_versioned(key, ctx)? else {
+            return Ok(None);
+        };
+
+        let new_value = modify(&value)?;
+        let cmd = match expiry {
+            Some(expiry) => write_command(key, new_value.clone(), Some(version), expiry),
+            None => Command::UpdateIfVersion(key.to_string(), new_value.clone(), version),
+        };
+
+        match write_versioned(cmd, ctx) {
+            Ok(()) => return Ok(Some(new_value)),
+            Err(Error::Conflict(_)) => continue,
+            Err(Error::NotFound(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(Error::Conflict("too much contention on key".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chain::Chain, config::ClusterNode, hashing::HashRing, partition::Partitioner, raft::Raft,
+    };
+    use std::{collections::HashMap, sync::Mutex};
+
+    // a single node with the consistent keyspace `c:` and the chain keyspace `h:`, answering
+    // commands without a listener
+    fn single_node() -> NodeContext {
+        let node = ClusterNode {
+            _id: "1".into(),
+            host: "127.0.0.1".into(),
+            port: "0".into(),
+            gossip_port: "0".into(),
+        };
+        NodeContext::new(
+            "1".into(),
+            "memory",
+            false,
+            Partitioner::Hash(HashRing::build(vec![node.clone()], 16)),
+            &Arc::new(Mutex::new(HashMap::new())),
+            &Arc::new(Mutex::new(HashMap::new())),
+            None,
+        )
+        .with_raft(Raft::new("1", vec![node], vec!["c:".into()], 1, 1, 100))
+        .with_chain(Chain::new(vec!["h:".into()], 1, Default::default()))
+    }
+
+    fn send(command: &str, data: &[u8], ctx: &NodeContext) -> String {
+        let tokens: Vec<&str> = command.split_whitespace().collect();
+        let mut reader = data;
+        String::from_utf8(handle_command(&tokens, &mut reader, ctx).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_expiry_from_exptime() {
+        assert_eq!(Expiry::from_exptime(0), Expiry::Never);
+        assert_eq!(Expiry::from_exptime(-1), Expiry::Expired);
+        assert_eq!(Expiry::from_exptime(60), Expiry::After(60));
+        assert_eq!(
+            Expiry::from_exptime(MAX_RELATIVE_EXPTIME + 1),
+            Expiry::Expired
+        );
+
+        let in_a_minute = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+            + 60;
+        assert!(matches!(
+            Expiry::from_exptime(in_a_minute),
+            Expiry::After(59..=60)
+        ));
+    }
+
+    #[test]
+    fn test_read_data() {
+        let mut input: &[u8] = b"value\r\nhello world\r\nvalueXX";
+
+        assert_eq!(read_data(&mut input, "5").unwrap(), b"value");
+        assert_eq!(read_data(&mut input, "11").unwrap(), b"hello world");
+        assert!(read_data(&mut input, "5").is_err());
+        assert!(read_data(&mut input, "x").is_err());
+    }
+
+    #[test]
+    fn test_valid_key() {
+        assert!(valid_key("user:42"));
+        assert!(!valid_key("user 42"));
+        assert!(!valid_key(&"k".repeat(MAX_KEY_LEN + 1)));
+    }
+
+    #[test]
+    fn test_versions_only_outside_consistent_keyspaces() {
+        let ctx = single_node();
+
+        assert_eq!(send("set k 0 0 1", b"v\r\n", &ctx), "STORED\r\n");
+        assert_eq!(send("get k", b"", &ctx), "VALUE k 0 1\r\nv\r\nEND\r\n");
+        assert_eq!(send("gets k", b"", &ctx), "VALUE k 0 1 1\r\nv\r\nEND\r\n");
+        assert_eq!(send("touch k 100", b"", &ctx), "TOUCHED\r\n");
+        assert_eq!(send("touch x 100", b"", &ctx), "NOT_FOUND\r\n");
+        assert_eq!(
+            send("set k 7 0 1", b"w\r\n", &ctx),
+            "CLIENT_ERROR client flags are not supported\r\n"
+        );
+        assert_eq!(send("get k", b"", &ctx), "VALUE k 0 1\r\nv\r\nEND\r\n");
+
+        let error = "CLIENT_ERROR cas uniques are not available in consistent keyspaces\r\n";
+        assert_eq!(send("gets c:1", b"", &ctx), error);
+        assert_eq!(send("cas c:1 0 0 1 1", b"v\r\n", &ctx), error);
+        assert_eq!(send("replace c:1 0 0 1", b"v\r\n", &ctx), error);
+
+        let error = "CLIENT_ERROR cas uniques are not available in chain replicated keyspaces\r\n";
+        assert_eq!(send("gets h:1", b"", &ctx), error);
+        assert_eq!(send("cas h:1 0 0 1 1", b"v\r\n", &ctx), error);
+    }
+
+    #[test]
+    fn test_store_with_expiration() {
+        let ctx = single_node();
+
+        assert_eq!(send("set e 0 100 1", b"v\r\n", &ctx), "STORED\r\n");
+        assert_eq!(send("add a 0 100 1", b"v\r\n", &ctx), "STORED\r\n");
+        assert_eq!(send("add a 0 100 1", b"w\r\n", &ctx), "NOT_STORED\r\n");
+        assert_eq!(send("set gone 0 -1 1", b"v\r\n", &ctx), "STORED\r\n");
+        assert_eq!(send("get gone", b"", &ctx), "END\r\n");
+
+        let entries = ctx.storage.lock().unwrap().read_entries("a", "e").unwrap();
+        assert_eq!(entries.len(), 2);
+        for entry in entries {
+            assert!(entry.expires_in.is_some_and(|left| left.as_secs() >= 99));
+        }
+    }
+
+    #[test]
+    fn test_incr_keeps_expiration() {
+        let ctx = single_node();
+
+        assert_eq!(send("set n 0 100 1", b"5\r\n", &ctx), "STORED\r\n");
+        assert_eq!(send("incr n 3", b"", &ctx), "8\r\n");
+        assert_eq!(send("decr n 10", b"", &ctx), "0\r\n");
+
+        let storage = ctx.storage.lock().unwrap();
+        let entry = storage.read_entries("n", "n").unwrap().pop().unwrap();
+        assert_eq!(entry.value, b"0");
+        assert!(entry.expires_in.is_some());
+    }
+}
@@ -1,258 +1,154 @@
 use std::{
-    collections::HashMap,
-    io::{Read, Write},
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{self, BufRead, BufReader, Read, Write},
     net::{TcpListener, TcpStream},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
 };
 
 use crate::{
-    commands::{self, Command},
+    auth::{Auth, Identity},
+    chain::{self, Chain},
+    commands::{self, ChainMessage, Command, Framing, RaftMessage, Scan, Scope, Transaction},
     config::ClusterNode,
-    hashing::HashRing,
+    crypto,
+    error::Error,
+    gossip::{ApplicationStates, update_application_state},
     log::{self, log},
-    storage::StorageBuilder,
+    partition::{Handover, Partitioner, RangeTable},
+    peers::PeerPool,
+    pool::ThreadPool,
+    raft::{self, Raft},
+    storage::{Storage, StorageBuilder, StoredEntry},
+    tls::{Stream, TlsConfig},
+    twophase::{Decision, TwoPhase},
 };
 use std::sync::{Arc, Mutex};
 
-pub fn start_node(
-    host: &str,
-    port: u16,
-    me_id: String,
-    storage_type: String,
-    log_enabled: bool,
-    ring: &HashRing,
-    cluster_snapshot: &Arc<Mutex<HashMap<String, String>>>,
-) {
+// how often the node refreshes the load information it advertises via gossip
+const LOAD_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+// how often ranges are checked for splitting in range partitioning mode
+const RANGE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// how often decisions of transactions spanning nodes are delivered again and timed out
+// locks are resolved
+const TRANSACTION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// an idle client connection is closed after this long, releasing its worker
+pub const CLIENT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+// upper bound for a request line of the text protocol; requests between nodes carry whole key
+// ranges and snapshots on a single line
+pub const MAX_LINE_LEN: usize = 512 * 1024 * 1024;
+
+// clients of the Redis, memcached and binary protocols keep pooled connections open for longer
+pub const POOLED_CLIENT_READ_TIMEOUT: Duration = Duration::from_secs(300);
+
+// shared state of a running node, handed to every connection worker
+pub struct NodeContext {
+    pub me_id: String,
+    pub log_enabled: bool,
+    pub partitioner: Partitioner,
+    pub storage: Arc<Mutex<Box<dyn Storage>>>,
+    pub cluster_snapshot: Arc<Mutex<HashMap<String, String>>>,
+    pub app_states: ApplicationStates,
+    pub request_count: Arc<AtomicU64>,
+    pub tls: Option<Arc<TlsConfig>>,
+    pub auth: Auth,
+    pub peers: PeerPool,
+    pub two_phase: TwoPhase,
+    pub raft: Raft,
+    pub chain: Chain,
+}
+
+impl NodeContext {
+    pub fn new(
+        me_id: String,
+        storage_type: &str,
+        log_enabled: bool,
+        partitioner: Partitioner,
+        cluster_snapshot: &Arc<Mutex<HashMap<String, String>>>,
+        app_states: &ApplicationStates,
+        tls: Option<Arc<TlsConfig>>,
+    ) -> NodeContext {
+        NodeContext {
+            me_id,
+            log_enabled,
+            partitioner,
+            storage: Arc::new(Mutex::new(StorageBuilder::builder(storage_type).build())),
+            cluster_snapshot: cluster_snapshot.clone(),
+            app_states: app_states.clone(),
+            request_count: Arc::new(AtomicU64::new(0)),
+            tls,
+            auth: Auth::default(),
+            peers: PeerPool::default(),
+            two_phase: TwoPhase::default(),
+            raft: Raft::default(),
+            chain: Chain::default(),
+        }
+    }
+
+    pub fn with_auth(self, auth: Auth) -> NodeContext {
+        NodeContext { auth, ..self }
+    }
+
+    pub fn with_peers(self, peers: PeerPool) -> NodeContext {
+        NodeContext { peers, ..self }
+    }
+
+    pub fn with_two_phase(self, two_phase: TwoPhase) -> NodeContext {
+        NodeContext { two_phase, ..self }
+    }
+
+    pub fn with_raft(self, raft: Raft) -> NodeContext {
+        NodeContext { raft, ..self }
+    }
+
+    pub fn with_chain(self, chain: Chain) -> NodeContext {
+        NodeContext { chain, ..self }
+    }
+}
+
+pub fn start_node(host: &str, port: u16, max_connections: usize, ctx: Arc<NodeContext>) {
     let addr = format!("{}:{}", host, port);
     let listener = TcpListener::bind(&addr).expect("Failed to bind address");
 
-    let storage = Arc::new(Mutex::new(StorageBuilder::builder(&storage_type).build()));
+    start_load_reporter(&ctx);
+    start_transaction_recovery(ctx.clone());
+    raft::start(ctx.clone());
+    chain::start(ctx.clone());
+    if let Partitioner::Range(_) = ctx.partitioner {
+        start_range_splitter(ctx.clone());
+    }
+
+    serve(listener, max_connections, ctx, handle_connection, |e| {
+        Error::Unavailable(e.to_string()).response().into_bytes()
+    });
+}
+
+// accepts connections and hands them to a bounded pool of workers running the handler,
+// connections beyond the workers wait in a queue of the same size, the rest is turned away
+pub fn serve(
+    listener: TcpListener,
+    max_connections: usize,
+    ctx: Arc<NodeContext>,
+    handler: fn(TcpStream, &NodeContext),
+    busy_response: fn(&str) -> Vec<u8>,
+) {
+    let pool = ThreadPool::new(max_connections, max_connections);
 
     for stream in listener.incoming() {
         match stream {
-            Ok(mut tcp_stream) => {
-                let mut buffer = String::new();
-                if let Ok(_) = tcp_stream.read_to_string(&mut buffer) {
-                    let command = commands::Command::try_from(buffer.as_str());
-                    match command {
-                        Ok(cmd) => {
-                            log::log(&format!("Received command: {:?}", cmd), log_enabled);
-
-                            match cmd {
-                                // handling PUT command with consistent hashing
-                                commands::Command::Put(ref key, ref value) => {
-                                    let primary = ring.primary(&key);
-
-                                    log(
-                                        &format!(
-                                            "Primary node for key '{}': {:?}",
-                                            key,
-                                            primary.unwrap()._id
-                                        ),
-                                        log_enabled,
-                                    );
-
-                                    if primary.unwrap()._id != me_id {
-                                        let response = forward_command(
-                                            cmd.clone(),
-                                            primary.unwrap().clone(),
-                                            log_enabled,
-                                            cluster_snapshot,
-                                        );
-
-                                        let _ = tcp_stream.write_all(response.as_bytes());
-                                    } else {
-                                        let res = storage.lock().unwrap().put(key, value.clone());
-                                        let response = match res {
-                                            Ok(_) => "OK\n".to_string(),
-                                            Err(e) => format!("Error: {}\n", e),
-                                        };
-
-                                        let _ = tcp_stream.write_all(response.as_bytes());
-                                    }
-                                }
-
-                                // handling READ command with consistent hashing
-                                commands::Command::Read(ref key) => {
-                                    let primary = ring.primary(&key);
-
-                                    log(
-                                        &format!(
-                                            "Primary node for key '{}': {:?}",
-                                            key,
-                                            primary.unwrap()._id
-                                        ),
-                                        log_enabled,
-                                    );
-
-                                    if primary.unwrap()._id != me_id {
-                                        let response = forward_command(
-                                            cmd.clone(),
-                                            primary.unwrap().clone(),
-                                            log_enabled,
-                                            cluster_snapshot,
-                                        );
-
-                                        let _ = tcp_stream.write_all(response.as_bytes());
-                                    } else {
-                                        let res = storage.lock().unwrap().read(&key);
-                                        let response = match res {
-                                            Ok(value) => format!("{}\n", value),
-                                            Err(e) => format!("Error: {}\n", e),
-                                        };
-
-                                        let _ = tcp_stream.write_all(response.as_bytes());
-                                    }
-                                }
-
-                                // TODO handling READRANGE command with consistent hashing
-                                commands::Command::ReadKeyByRange(start, end) => {
-                                    let res =
-                                        storage.lock().unwrap().read_key_by_range(&start, &end);
-                                    let response = match res {
-                                        Ok(pairs) => {
-                                            let mut resp = String::new();
-                                            for (k, v) in pairs {
-                                                resp.push_str(&format!("{} {}\n", k, v));
-                                            }
-                                            resp
-                                        }
-                                        Err(e) => format!("Error: {}\n", e),
-                                    };
-
-                                    let _ = tcp_stream.write_all(response.as_bytes());
-                                }
-
-                                commands::Command::BatchPut(entries) => {
-                                    let mut kv_pairs = Vec::new();
-                                    let mut iter = entries.into_iter();
-
-                                    while let (Some(k), Some(v)) = (iter.next(), iter.next()) {
-                                        kv_pairs.push((k, v));
-                                    }
-
-                                    let distribution: HashMap<String, Vec<(String, String)>> =
-                                        kv_pairs.into_iter().fold(
-                                            HashMap::new(),
-                                            |mut acc, (k, v)| {
-                                                let primary = ring.primary(&k).unwrap()._id.clone();
-                                                acc.entry(primary)
-                                                    .or_insert_with(Vec::new)
-                                                    .push((k, v));
-                                                acc
-                                            },
-                                        );
-
-                                    let values_for_me =
-                                        distribution.get(&me_id).cloned().unwrap_or_default();
-
-                                    let res_me = storage.lock().unwrap().batch_put(values_for_me);
-
-                                    let mut responses = Vec::new();
-
-                                    for (node_id, entries) in distribution.into_iter() {
-                                        if node_id != me_id {
-                                            let primary_node = cluster_snapshot
-                                                .lock()
-                                                .unwrap()
-                                                .get(&node_id)
-                                                .cloned();
-                                            if let Some(addr) = primary_node {
-                                                let parts: Vec<&str> = addr.split(':').collect();
-                                                if parts.len() == 2 {
-                                                    let node = ClusterNode {
-                                                        _id: node_id.clone(),
-                                                        host: parts[0].to_string(),
-                                                        port: parts[1]
-                                                            .parse()
-                                                            .unwrap_or("0".to_string()),
-                                                        gossip_port: "0".to_string(),
-                                                    };
-                                                    let cmd = Command::BatchPut(
-                                                        entries
-                                                            .clone()
-                                                            .into_iter()
-                                                            .flat_map(|(k, v)| vec![k, v])
-                                                            .collect(),
-                                                    );
-                                                    let response = forward_command(
-                                                        cmd,
-                                                        node,
-                                                        log_enabled,
-                                                        cluster_snapshot,
-                                                    );
-                                                    log::log(
-                                                        &format!(
-                                                            "Response from node {}: {}",
-                                                            node_id, response
-                                                        ),
-                                                        log_enabled,
-                                                    );
-                                                    responses.push(response);
-                                                }
-                                            }
-                                        }
-                                    }
-
-                                    let response_me = match res_me {
-                                        Ok(_) => "OK\n".to_string(),
-                                        Err(e) => format!("Error: {}\n", e),
-                                    };
-
-                                    responses.push(response_me);
-
-                                    let final_response = responses.iter().all(|r| r == "OK\n");
-
-                                    let partial_response =
-                                        responses.iter().any(|r| r.starts_with("OK"));
-
-                                    let response = if final_response {
-                                        "OK\n".to_string()
-                                    } else if partial_response {
-                                        "Partial OK\n".to_string()
-                                    } else {
-                                        format!("Error: {}\n", responses.join("; "))
-                                    };
-
-                                    let _ = tcp_stream.write_all(response.as_bytes());
-                                }
-
-                                // handling DELETE command with consistent hashing
-                                commands::Command::Delete(ref key) => {
-                                    let primary = ring.primary(&key);
-
-                                    log(
-                                        &format!(
-                                            "Primary node for key '{}': {:?}",
-                                            key,
-                                            primary.unwrap()._id
-                                        ),
-                                        log_enabled,
-                                    );
-
-                                    if primary.unwrap()._id != me_id {
-                                        let response = forward_command(
-                                            cmd.clone(),
-                                            primary.unwrap().clone(),
-                                            log_enabled,
-                                            cluster_snapshot,
-                                        );
-
-                                        let _ = tcp_stream.write_all(response.as_bytes());
-                                    } else {
-                                        let res = storage.lock().unwrap().delete(&key);
-                                        let response = match res {
-                                            Ok(_) => "OK\n".to_string(),
-                                            Err(e) => format!("Error: {}\n", e),
-                                        };
-
-                                        let _ = tcp_stream.write_all(response.as_bytes());
-                                    }
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to parse command: {}", e); // write to the stderr regardless of log setting
-                        }
+            Ok(tcp_stream) => {
+                let rejected = tcp_stream.try_clone();
+                let worker_ctx = ctx.clone();
+
+                if let Err(e) = pool.try_execute(move || handler(tcp_stream, &worker_ctx)) {
+                    // write to the stderr regardless of log setting
+                    eprintln!("Rejecting client: {}", e);
+                    // a client expecting a TLS handshake could not read a plain answer
+                    if let (Ok(mut stream), None) = (rejected, &ctx.tls) {
+                        let _ = stream.write_all(&busy_response(&e));
                     }
                 }
             }
@@ -263,61 +159,2279 @@ pub fn start_node(
     }
 }
 
-fn forward_command(
-    cmd: Command,
-    node: ClusterNode,
-    log_enabled: bool,
-    _cluster_snapshot: &Arc<Mutex<HashMap<String, String>>>,
+// per connection state of a client
+#[derive(Default)]
+struct Session {
+    framing: Framing,
+    identity: Identity,
+    // the commands queued since MULTI, None outside a transaction
+    queued: Option<Queued>,
+    // the keys watched for the next EXEC and their versions at the time
+    watched: Vec<(String, u64)>,
+}
+
+#[derive(Default)]
+struct Queued {
+    commands: Vec<Command>,
+    // a command was rejected, EXEC discards the transaction
+    failed: bool,
+}
+
+// serves one client connection: one request per line, answered in order,
+// so a client can keep the connection open and pipeline its requests
+fn handle_connection(tcp_stream: TcpStream, ctx: &NodeContext) {
+    let _ = tcp_stream.set_read_timeout(Some(CLIENT_READ_TIMEOUT));
+
+    let Some(stream) = accept_client(tcp_stream, ctx) else {
+        return;
+    };
+
+    // responses are collected and written once no pipelined request is pending
+    let mut reader = BufReader::new(stream);
+    let mut pending = Vec::new();
+    let mut session = Session::default();
+
+    loop {
+        let line = match read_line(&mut reader, MAX_LINE_LEN) {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::InvalidData {
+                    let response = Error::BadRequest(e.to_string()).response();
+                    write_response(&mut pending, session.framing, &response);
+                }
+                break;
+            }
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        ctx.request_count.fetch_add(1, Ordering::Relaxed);
+
+        let response = match commands::Command::try_from(line.as_str()) {
+            Ok(cmd) => {
+                // keep credentials out of the log
+                // and the heartbeats of consistent keyspaces, arriving several times a second
+                let shown = match &cmd {
+                    Command::Auth(user, _) => Some(format!("Auth({:?}, ..)", user)),
+                    Command::NodeAuth(node_id, ..) => Some(format!("NodeAuth({:?}, ..)", node_id)),
+                    Command::Raft(RaftMessage::Submit(..)) => Some(format!("{:?}", cmd)),
+                    Command::Raft(_) => None,
+                    Command::Chain(ChainMessage::Sync(_)) => None,
+                    cmd => Some(format!("{:?}", cmd)),
+                };
+                if let Some(shown) = shown {
+                    log::log(&format!("Received command: {}", shown), ctx.log_enabled);
+                }
+
+                handle_command(cmd, &mut session, ctx)
+            }
+            Err(e) => {
+                eprintln!("Failed to parse command: {}", e.message()); // write to the stderr regardless of log setting
+                if let Some(queued) = session.queued.as_mut() {
+                    queued.failed = true;
+                }
+                e.response()
+            }
+        };
+
+        write_response(&mut pending, session.framing, &response);
+
+        // pipelined requests are answered in one go, flush once no request is pending
+        if reader.buffer().is_empty() && flush(reader.get_mut(), &mut pending).is_err() {
+            break;
+        }
+    }
+
+    close(reader.get_mut(), &mut pending);
+}
+
+// a connection on any of the client listeners, TLS once the node has a certificate
+pub fn accept_client(tcp_stream: TcpStream, ctx: &NodeContext) -> Option<Stream> {
+    match Stream::accept_client(tcp_stream, ctx.tls.as_deref()) {
+        Ok(stream) => Some(stream),
+        Err(e) => {
+            eprintln!("Failed to accept client: {}", e);
+            None
+        }
+    }
+}
+
+// reads `len` bytes, the buffer grows as the data arrives so a client announcing large
+// lengths alone allocates nothing; fails if the stream ends before
+pub fn read_exactly(reader: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.by_ref().take(len as u64).read_to_end(&mut data)?;
+    if data.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(data)
+}
+
+// reads a line of up to `max` bytes, line break included, None at the end of the stream; a longer
+// line is invalid data, so a client cannot make the node buffer without end
+pub fn read_line(reader: &mut impl BufRead, max: usize) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    reader.take(max as u64).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.len() == max && !line.ends_with(b"\n") {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, LineTooLong));
+    }
+
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| invalid_data("line is not valid UTF-8"))
+}
+
+// the error of `read_line` for a line over the limit
+#[derive(Debug)]
+pub struct LineTooLong;
+
+impl std::fmt::Display for LineTooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line too long")
+    }
+}
+
+impl std::error::Error for LineTooLong {}
+
+pub fn is_line_too_long(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<LineTooLong>())
+}
+
+pub fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn write_response(out: &mut Vec<u8>, framing: Framing, response: &str) {
+    if framing == Framing::Framed {
+        out.extend(format!("{}\n", response.len()).as_bytes());
+    }
+
+    out.extend(response.as_bytes());
+}
+
+pub fn flush(stream: &mut Stream, pending: &mut Vec<u8>) -> std::io::Result<()> {
+    stream.write_all(pending)?;
+    pending.clear();
+    stream.flush()
+}
+
+// writes what is left and ends the connection
+pub fn close(stream: &mut Stream, pending: &mut Vec<u8>) {
+    if flush(stream, pending).is_ok() {
+        let _ = stream.finish();
+    }
+}
+
+// connection level commands change the session, everything else is executed
+fn handle_command(cmd: Command, session: &mut Session, ctx: &NodeContext) -> String {
+    match cmd {
+        commands::Command::Protocol(framing) => {
+            session.framing = framing;
+            "OK\n".to_string()
+        }
+        commands::Command::Auth(user, password) => {
+            if !ctx.auth.enabled() {
+                return Error::BadRequest("Authentication is not enabled".to_string()).response();
+            }
+            login(session, ctx.auth.login(&user, &password))
+        }
+        commands::Command::NodeAuth(node_id, timestamp, nonce, mac) => {
+            if !ctx.auth.enabled() {
+                return "OK\n".to_string();
+            }
+            login(
+                session,
+                ctx.auth.login_node(&node_id, timestamp, &nonce, &mac),
+            )
+        }
+        commands::Command::Multi => {
+            if session.queued.is_some() {
+                return Error::BadRequest("MULTI calls can not be nested".to_string()).response();
+            }
+            session.queued = Some(Queued::default());
+            "OK\n".to_string()
+        }
+        commands::Command::Discard => match session.queued.take() {
+            Some(_) => {
+                session.watched.clear();
+                "OK\n".to_string()
+            }
+            None => Error::BadRequest("DISCARD without MULTI".to_string()).response(),
+        },
+        commands::Command::Exec => exec(session, ctx),
+        commands::Command::Watch(keys) => watch(session, keys, ctx),
+        cmd if session.queued.is_some() => queue(session, cmd, ctx),
+        cmd => execute_as(&session.identity, cmd, ctx),
+    }
+}
+
+// remembers the versions of the keys, EXEC fails if one of them changes in the meantime
+fn watch(session: &mut Session, keys: Vec<String>, ctx: &NodeContext) -> String {
+    if session.queued.is_some() {
+        return Error::BadRequest("WATCH inside MULTI is not allowed".to_string()).response();
+    }
+    if let Err(e) = ctx
+        .auth
+        .authorize(&session.identity, &Command::Watch(keys.clone()))
+        .and_then(|_| single_key_only(ctx, keys.iter().map(String::as_str)))
+    {
+        return e.response();
+    }
+
+    for key in keys {
+        match current_version(ctx, &key) {
+            Ok(version) => session.watched.push((key, version)),
+            Err(e) => return e.response(),
+        }
+    }
+    "OK\n".to_string()
+}
+
+// the version of the key on its primary, 0 for a missing key
+fn current_version(ctx: &NodeContext, key: &str) -> Result<u64, Error> {
+    match parse_reply(execute(Command::ReadVersion(key.to_string()), ctx))? {
+        Some(reply) => reply
+            .split(' ')
+            .next()
+            .and_then(|version| version.parse().ok())
+            .ok_or_else(|| Error::Internal(format!("Invalid version reply: {}", reply))),
+        None => Ok(0),
+    }
+}
+
+// queues a command of the transaction; a rejected command fails the whole transaction, as the
+// client may not check every answer before EXEC
+fn queue(session: &mut Session, cmd: Command, ctx: &NodeContext) -> String {
+    let Some(queued) = session.queued.as_mut() else {
+        return execute_as(&session.identity, cmd, ctx);
+    };
+
+    let checked = ctx
+        .auth
+        .authorize(&session.identity, &cmd)
+        .and_then(|_| match cmd.key() {
+            Some(key) => single_key_only(ctx, [key].into_iter()),
+            None => Err(Error::BadRequest(format!(
+                "{} cannot be used in a transaction",
+                cmd.name()
+            ))),
+        });
+
+    match checked {
+        Ok(()) => {
+            queued.commands.push(cmd);
+            "QUEUED\n".to_string()
+        }
+        Err(e) => {
+            queued.failed = true;
+            e.response()
+        }
+    }
+}
+
+// runs the queued commands, one answer line per command: on the node owning all of their keys,
+// or by two-phase commit across the nodes owning them
+fn exec(session: &mut Session, ctx: &NodeContext) -> String {
+    let Some(queued) = session.queued.take() else {
+        return Error::BadRequest("EXEC without MULTI".to_string()).response();
+    };
+    let watched = std::mem::take(&mut session.watched);
+
+    if queued.failed {
+        return Error::BadRequest("Transaction discarded because of previous errors".to_string())
+            .response();
+    }
+
+    let transaction = Transaction {
+        watched,
+        commands: queued.commands,
+    };
+    let mut shares = transaction_shares(ctx, &transaction);
+    let response = match shares.len() {
+        // nothing was queued or watched
+        0 => String::new(),
+        1 => {
+            let (node, _) = shares.remove(0);
+            if node._id == ctx.me_id {
+                local_transaction(ctx, &transaction)
+            } else {
+                forward_command(Command::Transaction(transaction), node, None, ctx)
+            }
+        }
+        _ => two_phase_commit(ctx, shares, transaction.commands.len()),
+    };
+
+    // clients wait for a line even if no command was queued
+    if response.is_empty() {
+        return format!("{}\n", commands::EMPTY);
+    }
+    response
+}
+
+fn transaction_keys<'a>(watched: &'a [(String, u64)], commands: &'a [Command]) -> Vec<&'a str> {
+    watched
+        .iter()
+        .map(|(key, _)| key.as_str())
+        .chain(commands.iter().filter_map(Command::key))
+        .collect()
+}
+
+// the share of the transaction of every node owning one of its keys, in order of appearance,
+// with the positions of the share's commands in the transaction
+fn transaction_shares(
+    ctx: &NodeContext,
+    transaction: &Transaction,
+) -> Vec<(ClusterNode, (Transaction, Vec<usize>))> {
+    let mut shares = Vec::new();
+
+    for (key, version) in &transaction.watched {
+        let (share, _) = share_of(ctx, &mut shares, key);
+        share.watched.push((key.clone(), *version));
+    }
+    for (position, cmd) in transaction.commands.iter().enumerate() {
+        if let Some(key) = cmd.key() {
+            let (share, positions) = share_of(ctx, &mut shares, key);
+            share.commands.push(cmd.clone());
+            positions.push(position);
+        }
+    }
+    shares
+}
+
+fn share_of<'a>(
+    ctx: &NodeContext,
+    shares: &'a mut Vec<(ClusterNode, (Transaction, Vec<usize>))>,
+    key: &str,
+) -> &'a mut (Transaction, Vec<usize>) {
+    let primary = ctx.partitioner.primary(key).unwrap();
+    let index = match shares.iter().position(|(node, _)| node._id == primary._id) {
+        Some(index) => index,
+        None => {
+            shares.push((primary, Default::default()));
+            shares.len() - 1
+        }
+    };
+    &mut shares[index].1
+}
+
+// what keeps a transaction from running on this node under the storage lock: keys that moved
+// to another node or are locked by another transaction, or watched keys written since WATCH
+fn check_transaction(
+    ctx: &NodeContext,
+    storage: &dyn Storage,
+    transaction: &Transaction,
+) -> Result<(), Error> {
+    for key in transaction_keys(&transaction.watched, &transaction.commands) {
+        // ranges are split under the storage lock, the keys may have moved since EXEC
+        if ctx
+            .partitioner
+            .primary(key)
+            .is_none_or(|node| node._id != ctx.me_id)
+        {
+            return Err(Error::Unavailable(
+                "Transaction keys moved to another node".to_string(),
+            ));
+        }
+        if let Some(txid) = ctx.two_phase.locked_by(key) {
+            return Err(locked(key, &txid));
+        }
+    }
+
+    for (key, version) in &transaction.watched {
+        let current = match storage.read_versioned(key) {
+            Ok((_, current)) => current,
+            Err(Error::NotFound(_)) => 0,
+            Err(e) => return Err(e),
+        };
+        if current != *version {
+            return Err(Error::Conflict(format!("Watched key '{}' changed", key)));
+        }
+    }
+    Ok(())
+}
+
+fn locked(key: &str, txid: &str) -> Error {
+    Error::Conflict(format!("Key '{}' is locked by transaction {}", key, txid))
+}
+
+// runs the commands of a transaction under one storage lock, unless a watched key was written
+// since WATCH; a failing command does not undo the others
+fn local_transaction(ctx: &NodeContext, transaction: &Transaction) -> String {
+    let mut storage = ctx.storage.lock().unwrap();
+    if let Err(e) = check_transaction(ctx, storage.as_ref(), transaction) {
+        return e.response();
+    }
+    if transaction.commands.is_empty() {
+        return format!("{}\n", commands::EMPTY);
+    }
+    run_commands(ctx, storage.as_mut(), &transaction.commands)
+}
+
+fn run_commands(ctx: &NodeContext, storage: &mut dyn Storage, commands: &[Command]) -> String {
+    let mut response = String::new();
+    for cmd in commands {
+        if let Some(key) = cmd.key() {
+            ctx.partitioner.record(key);
+        }
+        response.push_str(&apply(storage, cmd));
+    }
+    response
+}
+
+// runs a transaction spanning nodes with this node as coordinator: every node prepares its share
+// and locks its keys, then all commit if all could prepare, or all abort; the decision is written
+// to the transaction log first, so it reaches every node even if this one stops in between
+fn two_phase_commit(
+    ctx: &NodeContext,
+    shares: Vec<(ClusterNode, (Transaction, Vec<usize>))>,
+    count: usize,
 ) -> String {
-    let node_clone = node.clone();
-    let addr = format!("{}:{}", node_clone.host, node_clone.port);
+    let txid = ctx.two_phase.begin(&ctx.me_id);
+    let participants: Vec<String> = shares.iter().map(|(node, _)| node._id.clone()).collect();
+    if let Err(e) = ctx
+        .two_phase
+        .record(&txid, Decision::Pending, &participants)
+    {
+        ctx.two_phase.delivered(&txid);
+        return e.response();
+    }
 
     log(
-        &format!(
-            "Command goes to the node [{}] at address: {}",
-            node_clone._id, addr
-        ),
-        log_enabled,
+        &format!("Preparing transaction {} on {:?}", txid, participants),
+        ctx.log_enabled,
     );
 
-    match TcpStream::connect(&addr) {
-        Ok(mut stream) => {
-            let command_str = format!("{}\n", cmd.to_string());
+    let (nodes, positions): (Vec<(ClusterNode, Transaction)>, Vec<Vec<usize>>) = shares
+        .into_iter()
+        .map(|(node, (share, positions))| ((node, share), positions))
+        .unzip();
+    let prepared = scatter(ctx, nodes.clone(), |node, share, deadline| {
+        let response = if node._id == ctx.me_id {
+            prepare(ctx, &txid, &ctx.me_id, share)
+        } else {
+            let cmd = Command::Prepare(txid.clone(), ctx.me_id.clone(), share);
+            forward_command(cmd, node, Some(deadline), ctx)
+        };
+        parse_reply(response).map(|_| ())
+    });
 
-            log(
-                &format!(
-                    "Forwarding command to node [{}]: {}, address: {}",
-                    node_clone._id,
-                    command_str.trim(),
-                    addr
-                ),
-                log_enabled,
-            );
+    let mut failure = prepared.into_iter().find_map(Result::err);
+    let mut decision = match failure {
+        None => Decision::Commit,
+        Some(_) => Decision::Abort,
+    };
+    if let Err(e) = ctx.two_phase.record(&txid, decision, &participants) {
+        // without a durable decision the transaction can only be aborted
+        decision = Decision::Abort;
+        let _ = ctx.two_phase.record(&txid, decision, &participants);
+        failure = Some(e);
+    }
+
+    let outcomes = scatter(ctx, nodes, |node, _, deadline| {
+        deliver(ctx, &txid, decision, node, Some(deadline))
+    });
+    if outcomes.iter().all(Result::is_ok) {
+        ctx.two_phase.done(&txid);
+    }
+    ctx.two_phase.delivered(&txid);
+
+    if let Some(e) = failure {
+        return e.response();
+    }
+
+    // the answers of every node's commands in the order they were queued; a node that missed
+    // the decision gets it again later, its commands are answered with the error until then
+    let mut answers = vec![String::new(); count];
+    for (outcome, positions) in outcomes.into_iter().zip(positions) {
+        let lines: Vec<String> = match outcome {
+            Ok(lines) => lines,
+            Err(e) => vec![e.response(); positions.len()],
+        };
+        for (i, position) in positions.into_iter().enumerate() {
+            answers[position] = lines.get(i).cloned().unwrap_or_else(|| {
+                Error::Internal("The node did not answer the command".to_string()).response()
+            });
+        }
+    }
+    answers.concat()
+}
+
+// hands the decision on a transaction to a node, answered by its commands' answer lines when
+// committed; a node that took the decision before answers the same
+fn deliver(
+    ctx: &NodeContext,
+    txid: &str,
+    decision: Decision,
+    node: ClusterNode,
+    deadline: Option<Instant>,
+) -> Result<Vec<String>, Error> {
+    let response = match (node._id == ctx.me_id, decision) {
+        (true, Decision::Commit) => commit(ctx, txid),
+        (true, _) => abort(ctx, txid),
+        (false, Decision::Commit) => {
+            forward_command(Command::Commit(txid.to_string()), node, deadline, ctx)
+        }
+        (false, _) => forward_command(Command::Abort(txid.to_string()), node, deadline, ctx),
+    };
+
+    // the status line keeps the answers of the commands apart from errors of the delivery, a
+    // node not knowing the transaction has not taken the decision
+    match response.split_once('\n') {
+        Some(("OK", lines)) => Ok(lines.lines().map(|line| format!("{}\n", line)).collect()),
+        _ => match response.strip_prefix("Error: ") {
+            Some(e) => Err(Error::parse(e.lines().next().unwrap_or_default())),
+            None => Err(Error::Internal(format!(
+                "Invalid answer to {}: {}",
+                decision.as_str(),
+                response.trim()
+            ))),
+        },
+    }
+}
+
+// locks the keys of this node's share of a transaction until the coordinator decides
+fn prepare(ctx: &NodeContext, txid: &str, coordinator: &str, share: Transaction) -> String {
+    let storage = ctx.storage.lock().unwrap();
+    if let Err(e) = check_transaction(ctx, storage.as_ref(), &share) {
+        return e.response();
+    }
+
+    let keys = transaction_keys(&share.watched, &share.commands)
+        .into_iter()
+        .map(String::from)
+        .collect();
+    ctx.two_phase.prepare(txid, coordinator, share, keys);
+    "OK\n".to_string()
+}
+
+// runs this node's share of a prepared transaction, answered by `OK` and a line per command;
+// a share committed before is answered the same again
+fn commit(ctx: &NodeContext, txid: &str) -> String {
+    let mut storage = ctx.storage.lock().unwrap();
+    if let Some(share) = ctx.two_phase.take(txid) {
+        let answers = run_commands(ctx, storage.as_mut(), &share.commands);
+        ctx.two_phase
+            .decided(txid, Decision::Commit, answers.clone());
+        return format!("OK\n{}", answers);
+    }
+
+    match ctx.two_phase.outcome(txid) {
+        Some((Decision::Commit, answers)) => format!("OK\n{}", answers),
+        Some(_) => Error::Conflict(format!("Transaction {} was aborted", txid)).response(),
+        None => Error::NotFound(format!("Unknown transaction {}", txid)).response(),
+    }
+}
+
+// releases the locks of this node's share; a share that was never prepared has nothing to undo
+fn abort(ctx: &NodeContext, txid: &str) -> String {
+    let _storage = ctx.storage.lock().unwrap();
+    if ctx.two_phase.take(txid).is_some() {
+        ctx.two_phase.decided(txid, Decision::Abort, String::new());
+        return "OK\n".to_string();
+    }
+
+    match ctx.two_phase.outcome(txid) {
+        Some((Decision::Commit, _)) => {
+            Error::Conflict(format!("Transaction {} was committed", txid)).response()
+        }
+        _ => "OK\n".to_string(),
+    }
+}
 
-            if let Err(e) = stream.write_all(command_str.as_bytes()) {
-                return format!("Failed to send command to {}: {}", node_clone._id, e);
+fn login(session: &mut Session, result: Result<Identity, Error>) -> String {
+    match result {
+        Ok(identity) => {
+            session.identity = identity;
+            "OK\n".to_string()
+        }
+        Err(e) => {
+            // write to the stderr regardless of log setting
+            eprintln!("Failed authentication: {}", e.message());
+            e.response()
+        }
+    }
+}
+
+// executes a client command after checking the ACL of the connection's identity
+pub fn execute_as(identity: &Identity, cmd: Command, ctx: &NodeContext) -> String {
+    match ctx.auth.authorize(identity, &cmd) {
+        Ok(()) => execute(cmd, ctx),
+        Err(e) => e.response(),
+    }
+}
+
+// executes a client command, routing it to the owning node(s) where needed
+pub fn execute(cmd: Command, ctx: &NodeContext) -> String {
+    match cmd {
+        // handling READRANGE command on every node, merged here
+        commands::Command::ReadKeyByRange(start, end, limit, Scope::Cluster) => {
+            read_range(ctx, start, end, limit)
+        }
+
+        // handling READRANGE command for the node merging a cluster-wide read
+        commands::Command::ReadKeyByRange(start, end, limit, Scope::Local) => {
+            match local_range(ctx, &start, &end, limit) {
+                Ok(entries) => range_response(&entries),
+                Err(e) => e.response(),
             }
+        }
 
-            if let Err(e) = stream.shutdown(std::net::Shutdown::Write) {
-                return format!("Failed to shutdown write side: {}", e);
+        // handling SCAN command by walking the nodes in ring order
+        commands::Command::Scan(scan) if scan.scope == Scope::Cluster => cluster_scan(ctx, scan),
+
+        commands::Command::Scan(scan) => match local_scan(ctx, &scan) {
+            Ok((cursor, keys)) => scan_response(&cursor, &keys),
+            Err(e) => e.response(),
+        },
+
+        commands::Command::BatchPut(entries) => {
+            match single_key_only(ctx, entries.iter().map(|(key, _)| key.as_str())) {
+                Ok(()) => batch_put(ctx, entries),
+                Err(e) => e.response(),
             }
+        }
 
-            let mut response = String::new();
-            match stream.read_to_string(&mut response) {
-                Ok(_) => {
-                    log(
-                        &format!("Response from {}: {}", node._id, response),
-                        log_enabled,
-                    );
-                    response
-                }
-                Err(e) => {
-                    format!("Failed to read response from {}: {}", node_clone._id, e)
-                }
+        // handling SPLIT command, this node takes over the keys from the split key on
+        commands::Command::Split(at, entries) => take_over(ctx, &at, entries),
+
+        commands::Command::BatchRead(keys) => {
+            match single_key_only(ctx, keys.iter().map(String::as_str)) {
+                Ok(()) => batch_read(ctx, keys),
+                Err(e) => e.response(),
             }
         }
-        Err(e) => {
-            format!("Failed to connect to {}: {}", node_clone._id, e)
+
+        commands::Command::BatchDelete(keys) => {
+            match single_key_only(ctx, keys.iter().map(String::as_str)) {
+                Ok(()) => batch_delete(ctx, keys),
+                Err(e) => e.response(),
+            }
+        }
+
+        // handling a transaction queued on another node, this node owns its keys
+        commands::Command::Transaction(transaction) => local_transaction(ctx, &transaction),
+
+        // handling the two-phase commit messages of a transaction spanning nodes
+        commands::Command::Prepare(txid, coordinator, transaction) => {
+            prepare(ctx, &txid, &coordinator, transaction)
+        }
+        commands::Command::Commit(txid) => commit(ctx, &txid),
+        commands::Command::Abort(txid) => abort(ctx, &txid),
+        commands::Command::TxStatus(txid) => format!("{}\n", ctx.two_phase.status(&txid).as_str()),
+
+        // handling the messages between the replicas of a consistent keyspace shard
+        commands::Command::Raft(message) => raft::handle(ctx, message),
+
+        // handling the messages along the replica chain of a key
+        commands::Command::Chain(message) => chain::handle(ctx, message),
+
+        // cluster membership and application state, answered locally
+        commands::Command::ClusterNodes => cluster_nodes(ctx),
+
+        // single key commands of consistent keyspaces run through the log of their shard
+        cmd if cmd.key().is_some_and(|key| ctx.raft.consistent(key)) => raft::execute(ctx, cmd),
+
+        // and the ones of chain replicated keyspaces along the chain of their key
+        cmd if cmd.key().is_some_and(|key| ctx.chain.covers(key)) => chain::execute(ctx, cmd),
+
+        // single key commands run on the primary of the key
+        cmd => match cmd.key() {
+            Some(key) => on_primary(ctx, key, &cmd, |storage| apply(storage, &cmd)),
+            None => Error::BadRequest(format!(
+                "{} is only valid on a client connection",
+                cmd.name()
+            ))
+            .response(),
+        },
+    }
+}
+
+// runs a single key command against the storage of its primary, locked by the caller
+pub fn apply(storage: &mut dyn Storage, cmd: &Command) -> String {
+    match cmd {
+        // handling PUT command
+        commands::Command::Put(key, value) => match storage.put(key, value.clone()) {
+            Ok(_) => "OK\n".to_string(),
+            Err(e) => e.response(),
+        },
+
+        // handling PUT ... IFVERSION command
+        commands::Command::PutIfVersion(key, value, version) => {
+            match storage.put_if_version(key, value.clone(), *version) {
+                Ok(_) => "OK\n".to_string(),
+                Err(e) => e.response(),
+            }
+        }
+
+        // handling PUT ... IFVERSION ... KEEPTTL command; a mismatch fails the way PUT ... IFVERSION
+        // does, a key that must not exist yet has no ttl to keep
+        commands::Command::UpdateIfVersion(key, value, version) => {
+            let written = match storage.read_versioned(key) {
+                Ok((_, current)) if current == *version => storage.update(key, value.clone()),
+                _ => storage.put_if_version(key, value.clone(), *version),
+            };
+            match written {
+                Ok(_) => "OK\n".to_string(),
+                Err(e) => e.response(),
+            }
+        }
+
+        // handling PUT ... EX command, the value and its ttl written under the same lock
+        commands::Command::PutEx(key, value, seconds, version) => {
+            let written = match version {
+                Some(version) => storage.put_if_version(key, value.clone(), *version),
+                None => storage.put(key, value.clone()),
+            };
+            match written.and_then(|_| storage.expire(key, Duration::from_secs(*seconds))) {
+                Ok(_) => "OK\n".to_string(),
+                Err(e) => e.response(),
+            }
+        }
+
+        // handling CAS command
+        commands::Command::Cas(key, expected, value) => {
+            match compare_and_swap(storage, key, expected, value.clone()) {
+                Ok(_) => "OK\n".to_string(),
+                Err(e) => e.response(),
+            }
+        }
+
+        // handling READVERSION command, answers `<version> <value>`
+        commands::Command::ReadVersion(key) => match storage.read_versioned(key) {
+            Ok((value, version)) => format!("{} {}\n", version, commands::quote(&value)),
+            Err(e) => e.response(),
+        },
+
+        // handling READ command
+        commands::Command::Read(key) => match storage.read(key) {
+            Ok(value) => format!("{}\n", commands::quote(&value)),
+            Err(e) => e.response(),
+        },
+
+        // handling APPEND command, answers the new length of the value; the key keeps its ttl
+        commands::Command::Append(key, suffix) => {
+            let mut value = match storage.read(key) {
+                Ok(value) => value,
+                Err(Error::NotFound(_)) => Vec::new(),
+                Err(e) => return e.response(),
+            };
+            value.extend_from_slice(suffix);
+            let length = value.len();
+
+            match storage.update(key, value) {
+                Ok(_) => format!("{}\n", length),
+                Err(e) => e.response(),
+            }
+        }
+
+        // handling GETSET command, a missing previous value is answered as NIL
+        commands::Command::GetSet(key, value) => {
+            let previous = match storage.read(key) {
+                Ok(previous) => commands::quote(&previous),
+                Err(Error::NotFound(_)) => commands::NIL.to_string(),
+                Err(e) => return e.response(),
+            };
+
+            match storage.put(key, value.clone()) {
+                Ok(_) => format!("{}\n", previous),
+                Err(e) => e.response(),
+            }
+        }
+
+        // handling GETDEL command
+        commands::Command::GetDel(key) => {
+            let value = match storage.read(key) {
+                Ok(value) => value,
+                Err(e) => return e.response(),
+            };
+
+            match storage.delete(key) {
+                Ok(_) => format!("{}\n", commands::quote(&value)),
+                Err(e) => e.response(),
+            }
         }
+
+        // handling STRLEN command, a missing key has length 0
+        commands::Command::StrLen(key) => match storage.read(key) {
+            Ok(value) => format!("{}\n", value.len()),
+            Err(Error::NotFound(_)) => "0\n".to_string(),
+            Err(e) => e.response(),
+        },
+
+        // handling DELETE command
+        commands::Command::Delete(key) => match storage.delete(key) {
+            Ok(_) => "OK\n".to_string(),
+            Err(e) => e.response(),
+        },
+
+        // handling DELETEIF command
+        commands::Command::DeleteIf(key, expected) => match delete_if(storage, key, expected) {
+            Ok(_) => "OK\n".to_string(),
+            Err(e) => e.response(),
+        },
+
+        // handling EXPIRE command
+        commands::Command::Expire(key, seconds) => {
+            match storage.expire(key, Duration::from_secs(*seconds)) {
+                Ok(_) => "OK\n".to_string(),
+                Err(e) => e.response(),
+            }
+        }
+
+        // handling INCR and DECR commands, answers the new value
+        commands::Command::Incr(key, by) => match increment(storage, key, *by) {
+            Ok(value) => format!("{}\n", value),
+            Err(e) => e.response(),
+        },
+
+        // handling INCRFLOAT command, answers the new value
+        commands::Command::IncrFloat(key, by) => match increment_float(storage, key, *by) {
+            Ok(value) => format!("{}\n", value),
+            Err(e) => e.response(),
+        },
+
+        cmd => Error::BadRequest(format!("{} is not a single key command", cmd.name())).response(),
+    }
+}
+
+// interprets a text protocol response for the other protocols:
+// the value, None for a missing key, or the error
+pub fn parse_reply(response: String) -> Result<Option<String>, Error> {
+    let response = response.strip_suffix('\n').unwrap_or(&response);
+
+    // errors may be followed by details, such as the per-key results of a batch
+    let first_line = |e: &str| Error::parse(e.lines().next().unwrap_or_default());
+    match response.strip_prefix("Error: ").map(first_line) {
+        Some(Error::NotFound(_)) => Ok(None),
+        Some(e) => Err(e),
+        None => Ok(Some(response.to_string())),
+    }
+}
+
+// like `parse_reply` for responses holding a single, possibly quoted, value or `NIL`
+pub fn parse_value(response: String) -> Result<Option<Vec<u8>>, Error> {
+    match parse_reply(response)? {
+        Some(value) if value == commands::NIL => Ok(None),
+        Some(value) => commands::unquote(&value).map(Some),
+        None => Ok(None),
+    }
+}
+
+// keys of replicated keyspaces are written one at a time, through the log of their shard or
+// along their chain
+fn single_key_only<'a>(
+    ctx: &NodeContext,
+    mut keys: impl Iterator<Item = &'a str>,
+) -> Result<(), Error> {
+    match keys.find(|key| ctx.raft.consistent(key) || ctx.chain.covers(key)) {
+        Some(key) => Err(Error::BadRequest(format!(
+            "Key '{}' of a replicated keyspace needs a single key command",
+            key
+        ))),
+        None => Ok(()),
+    }
+}
+
+// keys of replicated keyspaces are stored by several nodes, ranges and scans list them once
+fn listed_here(ctx: &NodeContext, key: &str) -> bool {
+    ctx.raft.lists(key) && chain::lists(ctx, key)
+}
+
+// runs the command on the primary node of the key: locally under the storage lock,
+// or by forwarding it to the primary without holding the lock
+fn on_primary<F>(ctx: &NodeContext, key: &str, cmd: &Command, local: F) -> String
+where
+    F: FnOnce(&mut dyn Storage) -> String,
+{
+    let primary = ctx.partitioner.primary(key).unwrap();
+
+    log(
+        &format!("Primary node for key '{}': {:?}", key, primary._id),
+        ctx.log_enabled,
+    );
+
+    if primary._id != ctx.me_id {
+        forward_command(cmd.clone(), primary, None, ctx)
+    } else {
+        let mut storage = ctx.storage.lock().unwrap();
+
+        // ranges are split under the storage lock, the key may have moved while waiting for it
+        if let Some(owner) = ctx.partitioner.primary(key)
+            && owner._id != ctx.me_id
+        {
+            drop(storage);
+            return forward_command(cmd.clone(), owner, None, ctx);
+        }
+
+        if let Some(txid) = ctx.two_phase.locked_by(key) {
+            return locked(key, &txid).response();
+        }
+
+        ctx.partitioner.record(key);
+        local(storage.as_mut())
+    }
+}
+
+// writes the value only if the key holds the expected one; runs under the storage lock, so no
+// other write can come between the comparison and the write
+fn compare_and_swap(
+    storage: &mut dyn Storage,
+    key: &str,
+    expected: &[u8],
+    value: Vec<u8>,
+) -> Result<(), Error> {
+    if storage.read(key)? != expected {
+        return Err(Error::Conflict("Value mismatch".to_string()));
+    }
+    storage.put(key, value)
+}
+
+// deletes the key only if it holds the expected value, like `compare_and_swap`
+fn delete_if(storage: &mut dyn Storage, key: &str, expected: &[u8]) -> Result<(), Error> {
+    if storage.read(key)? != expected {
+        return Err(Error::Conflict("Value mismatch".to_string()));
+    }
+    storage.delete(key)
+}
+
+// adds to the integer value of the key, a missing key counts as 0 and the ttl is kept; runs under
+// the storage lock, so concurrent increments of a key are never lost
+fn increment(storage: &mut dyn Storage, key: &str, by: i64) -> Result<i64, Error> {
+    let current = match storage.read(key) {
+        Ok(value) => std::str::from_utf8(&value)
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .ok_or_else(|| Error::BadRequest("Value is not an integer".to_string()))?,
+        Err(Error::NotFound(_)) => 0,
+        Err(e) => return Err(e),
+    };
+
+    let value = current
+        .checked_add(by)
+        .ok_or_else(|| Error::BadRequest("Increment would overflow".to_string()))?;
+    storage.update(key, value.to_string().into_bytes())?;
+    Ok(value)
+}
+
+// like `increment` for floating point values, integer values are taken as floats
+fn increment_float(storage: &mut dyn Storage, key: &str, by: f64) -> Result<f64, Error> {
+    let current = match storage.read(key) {
+        Ok(value) => std::str::from_utf8(&value)
+            .ok()
+            .and_then(|value| value.parse::<f64>().ok())
+            .filter(|value| value.is_finite())
+            .ok_or_else(|| Error::BadRequest("Value is not a number".to_string()))?,
+        Err(Error::NotFound(_)) => 0.0,
+        Err(e) => return Err(e),
+    };
+
+    let value = current + by;
+    if !value.is_finite() {
+        return Err(Error::BadRequest("Increment would overflow".to_string()));
+    }
+    storage.update(key, value.to_string().into_bytes())?;
+    Ok(value)
+}
+
+// writes the entries on their primary nodes, answered by `batch_response`
+fn batch_put(ctx: &NodeContext, entries: Vec<(String, Vec<u8>)>) -> String {
+    let results = on_primaries(
+        ctx,
+        entries,
+        |storage, entries| {
+            let count = entries.len();
+            vec![storage.batch_put(entries); count]
+        },
+        Command::BatchPut,
+        parse_batch,
+    );
+    batch_response(&results)
+}
+
+// reads the keys on their primary nodes, answered by `read_response`
+fn batch_read(ctx: &NodeContext, keys: Vec<String>) -> String {
+    let results = on_primaries(
+        ctx,
+        keys.into_iter().map(|key| (key, ())).collect(),
+        |storage, keys| keys.iter().map(|(key, _)| storage.read(key)).collect(),
+        |keys| Command::BatchRead(keys.into_iter().map(|(key, _)| key).collect()),
+        parse_values,
+    );
+    read_response(&results)
+}
+
+// deletes the keys on their primary nodes, answered by `batch_response`
+fn batch_delete(ctx: &NodeContext, keys: Vec<String>) -> String {
+    let results = on_primaries(
+        ctx,
+        keys.into_iter().map(|key| (key, ())).collect(),
+        |storage, keys| keys.iter().map(|(key, _)| storage.delete(key)).collect(),
+        |keys| Command::BatchDelete(keys.into_iter().map(|(key, _)| key).collect()),
+        parse_batch,
+    );
+    batch_response(&results)
+}
+
+// reads the range from the nodes covering it at once and merges the entries in key order; a key found on
+// several nodes, e.g. left behind by a ring change, is answered with the value of its primary
+fn read_range(ctx: &NodeContext, start: String, end: String, limit: Option<u64>) -> String {
+    if start > end {
+        return Error::BadRequest("The range starts after its end".to_string()).response();
+    }
+
+    let nodes = ctx
+        .partitioner
+        .nodes_for_range(&start, &end)
+        .into_iter()
+        .map(|node| (node, ()))
+        .collect();
+
+    let parts = scatter(ctx, nodes, |node, _, deadline| {
+        let entries = if node._id == ctx.me_id {
+            local_range(ctx, &start, &end, limit)
+        } else {
+            let cmd = Command::ReadKeyByRange(start.clone(), end.clone(), limit, Scope::Local);
+            parse_range(&forward_command(cmd, node.clone(), Some(deadline), ctx))
+        };
+        (node, entries)
+    });
+
+    let mut merged: BTreeMap<String, (Vec<u8>, bool)> = BTreeMap::new();
+    for (node, entries) in parts {
+        // a range missing the keys of a node would look complete, so it fails as a whole
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(e) => return e.response(),
+        };
+
+        for (key, value) in entries {
+            let on_primary = ctx
+                .partitioner
+                .primary(&key)
+                .is_some_and(|p| p._id == node._id);
+            if on_primary || !merged.contains_key(&key) {
+                merged.insert(key, (value, on_primary));
+            }
+        }
+    }
+
+    let limit = limit.map_or(usize::MAX, |limit| limit as usize);
+    let entries: Vec<(String, Vec<u8>)> = merged
+        .into_iter()
+        .take(limit)
+        .map(|(key, (value, _))| (key, value))
+        .collect();
+    range_response(&entries)
+}
+
+// the entries of the range stored on this node in key order, each node answers up to the
+// limit so the merged range is complete up to it as well
+fn local_range(
+    ctx: &NodeContext,
+    start: &str,
+    end: &str,
+    limit: Option<u64>,
+) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let mut entries = ctx.storage.lock().unwrap().read_key_by_range(start, end)?;
+    entries.retain(|(key, _)| listed_here(ctx, key));
+    entries.sort();
+    if let Some(limit) = limit {
+        entries.truncate(limit as usize);
+    }
+    Ok(entries)
+}
+
+// scans the nodes one after the other in ring order until enough keys are found, the cursor is
+// `<node id>:<cursor on that node>` so the walk continues on the node it stopped at
+fn cluster_scan(ctx: &NodeContext, scan: Scan) -> String {
+    let nodes = ctx.partitioner.nodes();
+
+    // a node that left since the cursor was answered is skipped, node ids keep their order
+    let (mut index, mut cursor) = match scan.cursor.rsplit_once(':') {
+        _ if scan.cursor == "0" => (0, "0".to_string()),
+        Some((id, cursor)) => match nodes.iter().position(|node| node._id.as_str() >= id) {
+            Some(index) if nodes[index]._id == id => (index, cursor.to_string()),
+            Some(index) => (index, "0".to_string()),
+            None => (nodes.len(), "0".to_string()),
+        },
+        None => return Error::BadRequest("Invalid cursor".to_string()).response(),
+    };
+
+    let mut keys = Vec::new();
+    while index < nodes.len() && (keys.len() as u64) < scan.count {
+        let node = &nodes[index];
+        let local = Scan {
+            cursor,
+            count: scan.count - keys.len() as u64,
+            scope: Scope::Local,
+            ..scan.clone()
+        };
+
+        let result = if node._id == ctx.me_id {
+            local_scan(ctx, &local)
+        } else {
+            parse_scan(&forward_command(
+                Command::Scan(local),
+                node.clone(),
+                None,
+                ctx,
+            ))
+        };
+        let (next, found) = match result {
+            Ok(result) => result,
+            Err(e) => return e.response(),
+        };
+
+        keys.extend(found);
+        cursor = next;
+        if cursor == "0" {
+            index += 1;
+        }
+    }
+
+    let cursor = match nodes.get(index) {
+        Some(node) => format!("{}:{}", node._id, cursor),
+        None => "0".to_string(),
+    };
+    scan_response(&cursor, &keys)
+}
+
+// scans the keys stored on this node, the cursor is the last key answered in hex
+fn local_scan(ctx: &NodeContext, scan: &Scan) -> Result<(String, Vec<String>), Error> {
+    let after = match scan.cursor.as_str() {
+        "0" => None,
+        cursor => crypto::from_hex(cursor)
+            .and_then(|key| String::from_utf8(key).ok())
+            .map(Some)
+            .ok_or_else(|| Error::BadRequest("Invalid cursor".to_string()))?,
+    };
+
+    let (mut keys, done) = ctx.storage.lock().unwrap().scan(
+        after.as_deref(),
+        &scan.prefix,
+        scan.pattern.as_deref(),
+        scan.count as usize,
+    )?;
+
+    let cursor = match keys.last() {
+        Some(last) if !done => crypto::to_hex(last.as_bytes()),
+        _ => "0".to_string(),
+    };
+    keys.retain(|key| listed_here(ctx, key));
+    Ok((cursor, keys))
+}
+
+// runs a batch on the primary nodes of its keys, all of them at once: the share of this node
+// through `local` under the storage lock, the share of another node forwarded as the command
+// `remote` makes of it and read back by `parse`; the results come back per key in request order
+fn on_primaries<T, V, L, C, P>(
+    ctx: &NodeContext,
+    items: Vec<(String, T)>,
+    local: L,
+    remote: C,
+    parse: P,
+) -> Vec<(String, Result<V, Error>)>
+where
+    T: Send,
+    V: Send + Clone,
+    L: Fn(&mut dyn Storage, Vec<(String, T)>) -> Vec<Result<V, Error>> + Sync,
+    C: Fn(Vec<(String, T)>) -> Command + Sync,
+    P: Fn(&str, &[String]) -> Vec<Result<V, Error>> + Sync,
+{
+    let keys: Vec<String> = items.iter().map(|(key, _)| key.clone()).collect();
+
+    // a key given twice is run once with its last item, and answered alike both times
+    let mut seen = HashSet::new();
+    let mut items: Vec<(String, T)> = items
+        .into_iter()
+        .rev()
+        .filter(|(key, _)| seen.insert(key.clone()))
+        .collect();
+    items.reverse();
+
+    let parts = scatter(ctx, by_primary(ctx, items), |node, items, deadline| {
+        if node._id != ctx.me_id {
+            let node_keys: Vec<String> = items.iter().map(|(key, _)| key.clone()).collect();
+            let response = forward_command(remote(items), node, Some(deadline), ctx);
+            let results = parse(&response, &node_keys);
+            return node_keys.into_iter().zip(results).collect::<Vec<_>>();
+        }
+
+        // keys locked by a transaction spanning nodes are left alone
+        let mut storage = ctx.storage.lock().unwrap();
+        let mut outcomes = Vec::new();
+        let mut free = Vec::new();
+        for (key, item) in items {
+            match ctx.two_phase.locked_by(&key) {
+                Some(txid) => {
+                    let error = locked(&key, &txid);
+                    outcomes.push((key, Err(error)));
+                }
+                None => {
+                    ctx.partitioner.record(&key);
+                    free.push((key, item));
+                }
+            }
+        }
+
+        let free_keys: Vec<String> = free.iter().map(|(key, _)| key.clone()).collect();
+        outcomes.extend(free_keys.into_iter().zip(local(storage.as_mut(), free)));
+        outcomes
+    });
+
+    let outcomes: HashMap<String, Result<V, Error>> = parts.into_iter().flatten().collect();
+    keys.into_iter()
+        .map(|key| {
+            let outcome = outcomes[&key].clone();
+            (key, outcome)
+        })
+        .collect()
+}
+
+// groups keyed items by the primary node of their key, keeping their order within a node;
+// routed by the ring itself, so a node missing from the gossip view fails as unavailable
+fn by_primary<T>(
+    ctx: &NodeContext,
+    items: Vec<(String, T)>,
+) -> Vec<(ClusterNode, Vec<(String, T)>)> {
+    let mut groups: Vec<(ClusterNode, Vec<(String, T)>)> = Vec::new();
+    for (key, item) in items {
+        let primary = ctx.partitioner.primary(&key).unwrap();
+        match groups.iter_mut().find(|(node, _)| node._id == primary._id) {
+            Some((_, group)) => group.push((key, item)),
+            None => groups.push((primary.clone(), vec![(key, item)])),
+        }
+    }
+    groups
+}
+
+// runs the part of every node at once and returns the results in the same order; `run` gets
+// a deadline shared by all parts, so the slowest node bounds the latency of the whole command
+fn scatter<T, R, F>(ctx: &NodeContext, parts: Vec<(ClusterNode, T)>, run: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(ClusterNode, T, Instant) -> R + Sync,
+{
+    let deadline = ctx.peers.deadline();
+    let run = &run;
+
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = parts
+            .into_iter()
+            .map(|(node, part)| scope.spawn(move || run(node, part, deadline)))
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+// `OK` if every key succeeded, the error if all failed alike, otherwise a PARTIAL error
+// followed by one line per key in request order: `<key> OK` or `<key> <CODE> <message>`
+pub fn batch_response(results: &[(String, Result<(), Error>)]) -> String {
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    if failed == 0 {
+        return "OK\n".to_string();
+    }
+
+    if let Some((_, Err(first))) = results.first()
+        && results
+            .iter()
+            .all(|(_, result)| result.as_ref().err() == Some(first))
+    {
+        return first.response();
+    }
+
+    let mut response =
+        Error::Partial(format!("{} of {} entries failed", failed, results.len())).response();
+    for (key, result) in results {
+        response.push_str(&key_line(key, result.as_ref().map(|_| b"OK".as_slice())));
+    }
+    response
+}
+
+// one line per key in request order: `<key> <value>`, or `<key> <CODE> <message>` for a key
+// that is missing or could not be read
+pub fn read_response(results: &[(String, Result<Vec<u8>, Error>)]) -> String {
+    results
+        .iter()
+        .map(|(key, result)| key_line(key, result.as_deref()))
+        .collect()
+}
+
+// the cursor to continue from on the first line, then one key per line
+pub fn scan_response(cursor: &str, keys: &[String]) -> String {
+    let mut response = format!("{}\n", commands::quote_str(cursor));
+    for key in keys {
+        response.push_str(&format!("{}\n", commands::quote_str(key)));
+    }
+    response
+}
+
+// reads a `scan_response` back
+pub fn parse_scan(response: &str) -> Result<(String, Vec<String>), Error> {
+    if let Some(e) = response.strip_prefix("Error: ") {
+        return Err(Error::parse(e.lines().next().unwrap_or_default()));
+    }
+
+    let mut tokens = Vec::new();
+    for line in response.lines() {
+        let token = commands::unquote(line)?;
+        let token = String::from_utf8(token)
+            .map_err(|_| Error::Internal(format!("Invalid key in {}", line)))?;
+        tokens.push(token);
+    }
+
+    if tokens.is_empty() {
+        return Err(Error::Internal("Missing cursor".to_string()));
+    }
+    let cursor = tokens.remove(0);
+    Ok((cursor, tokens))
+}
+
+// one line per entry: `<key> <value>`
+pub fn range_response(entries: &[(String, Vec<u8>)]) -> String {
+    entries
+        .iter()
+        .map(|(key, value)| key_line(key, Ok(value)))
+        .collect()
+}
+
+// reads a `range_response` back
+pub fn parse_range(response: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    if let Some(e) = response.strip_prefix("Error: ") {
+        return Err(Error::parse(e.lines().next().unwrap_or_default()));
+    }
+
+    let mut entries = Vec::new();
+    for line in response.lines() {
+        match commands::tokenize(line)?.as_slice() {
+            [key, value] => match String::from_utf8(key.clone()) {
+                Ok(key) => entries.push((key, value.clone())),
+                Err(_) => return Err(Error::Internal(format!("Invalid key in {}", line))),
+            },
+            _ => return Err(Error::Internal(format!("Invalid range entry {}", line))),
+        }
+    }
+    Ok(entries)
+}
+
+fn key_line(key: &str, result: Result<&[u8], &Error>) -> String {
+    match result {
+        Ok(value) => format!("{} {}\n", commands::quote_str(key), commands::quote(value)),
+        Err(e) => format!(
+            "{} {} {}\n",
+            commands::quote_str(key),
+            e.code(),
+            commands::quote_str(e.message())
+        ),
+    }
+}
+
+// reads a `batch_response` back, one result for each of the keys it was sent for
+pub fn parse_batch(response: &str, keys: &[String]) -> Vec<Result<(), Error>> {
+    let mut lines = response.lines();
+    let first = lines.next().unwrap_or_default();
+
+    match first.strip_prefix("Error: ") {
+        None => vec![Ok(()); keys.len()],
+        Some(e) if !e.starts_with("PARTIAL ") => vec![Err(Error::parse(e)); keys.len()],
+        Some(_) => parse_key_lines(lines, keys)
+            .into_iter()
+            .map(|result| result.map(|_| ()))
+            .collect(),
+    }
+}
+
+// reads a `read_response` back, one result for each of the keys it was sent for
+pub fn parse_values(response: &str, keys: &[String]) -> Vec<Result<Vec<u8>, Error>> {
+    match response.strip_prefix("Error: ") {
+        Some(e) => vec![Err(Error::parse(e.lines().next().unwrap_or_default())); keys.len()],
+        None => parse_key_lines(response.lines(), keys),
+    }
+}
+
+fn parse_key_lines<'a>(
+    lines: impl Iterator<Item = &'a str>,
+    keys: &[String],
+) -> Vec<Result<Vec<u8>, Error>> {
+    let per_key: HashMap<String, Result<Vec<u8>, Error>> = lines
+        .filter_map(|line| {
+            let tokens = commands::tokenize(line).ok()?;
+            let key = String::from_utf8(tokens.first()?.clone()).ok()?;
+            let outcome = match &tokens[1..] {
+                [value] => Ok(value.clone()),
+                [code, message] => Err(Error::parse(&format!(
+                    "{} {}",
+                    String::from_utf8_lossy(code),
+                    String::from_utf8_lossy(message)
+                ))),
+                _ => return None,
+            };
+            Some((key, outcome))
+        })
+        .collect();
+
+    keys.iter()
+        .map(|key| {
+            per_key
+                .get(key)
+                .cloned()
+                .unwrap_or_else(|| Err(Error::Internal(format!("Missing result for {}", key))))
+        })
+        .collect()
+}
+
+// stores the entries of a range handed over by its owner, then routes the range here
+fn take_over(ctx: &NodeContext, at: &str, entries: Vec<StoredEntry>) -> String {
+    let Partitioner::Range(table) = &ctx.partitioner else {
+        return Error::BadRequest("SPLIT needs range partitioning".to_string()).response();
+    };
+
+    {
+        let mut storage = ctx.storage.lock().unwrap();
+        // a key written here since the range moved is newer than the one handed over
+        let entries = entries
+            .into_iter()
+            .filter(|entry| matches!(storage.read(&entry.key), Err(Error::NotFound(_))))
+            .collect();
+        if let Err(e) = storage.restore(entries) {
+            return e.response();
+        }
+        table.split(at, &ctx.me_id);
+    }
+    publish_ranges(ctx, table);
+
+    log(
+        &format!("Took over the keys from '{}' in range partitioning", at),
+        ctx.log_enabled,
+    );
+    "OK\n".to_string()
+}
+
+fn publish_ranges(ctx: &NodeContext, table: &RangeTable) {
+    update_application_state(&ctx.app_states, &ctx.me_id, "ranges", table.encode());
+}
+
+// in range partitioning mode, periodically adopts the splits other nodes made and splits a range
+// of this node that holds too many keys or gets too many requests
+fn start_range_splitter(ctx: Arc<NodeContext>) {
+    std::thread::spawn(move || {
+        let Partitioner::Range(table) = &ctx.partitioner else {
+            return;
+        };
+        publish_ranges(&ctx, table);
+
+        loop {
+            std::thread::sleep(RANGE_CHECK_INTERVAL);
+
+            let states = ctx.app_states.lock().unwrap().clone();
+            for (node_id, state) in states {
+                if node_id != ctx.me_id
+                    && let Some(ranges) = state.values.get("ranges")
+                {
+                    table.merge(ranges);
+                }
+            }
+
+            split_range(&ctx, table);
+            publish_ranges(&ctx, table);
+        }
+    });
+}
+
+// splits the first range of this node over the limits at its middle key and hands the upper
+// half to the node storing the fewest keys
+fn split_range(ctx: &NodeContext, table: &RangeTable) {
+    for handover in table.take_handovers() {
+        let entries = range_entries(
+            ctx,
+            ctx.storage.lock().unwrap().as_ref(),
+            &handover.at,
+            handover.end.as_deref(),
+        );
+        hand_over(ctx, table, handover, entries);
+    }
+
+    let load = table.take_load();
+
+    for (start, end) in table.owned_by(&ctx.me_id) {
+        let entries = range_entries(
+            ctx,
+            ctx.storage.lock().unwrap().as_ref(),
+            &start,
+            end.as_deref(),
+        );
+
+        let requests = load.get(&start).copied().unwrap_or_default();
+        let rate = requests as f64 / RANGE_CHECK_INTERVAL.as_secs_f64();
+        if entries.len() < 2
+            || (entries.len() <= table.limits.max_keys && rate <= table.limits.max_rate)
+        {
+            continue;
+        }
+
+        let Some(target) = least_loaded(ctx) else {
+            return;
+        };
+
+        let handover = Handover {
+            at: entries[entries.len() / 2].key.clone(),
+            end,
+            target,
+        };
+        // from here on the keys are routed to the target; writes waiting for the storage lock
+        // see the split, so the keys read under it are all the range has here
+        let moved = {
+            let storage = ctx.storage.lock().unwrap();
+            table.split(&handover.at, &handover.target._id);
+            range_entries(ctx, storage.as_ref(), &handover.at, handover.end.as_deref())
+        };
+
+        log(
+            &format!(
+                "Split the range from '{}' at '{}': {} keys moved to {} ({:.2} requests/s)",
+                start,
+                handover.at,
+                moved.len(),
+                handover.target._id,
+                rate
+            ),
+            ctx.log_enabled,
+        );
+        hand_over(ctx, table, handover, moved);
+        return;
+    }
+}
+
+// the keys stored here from start up to end, keys of consistent keyspaces are placed by their
+// shard, not by the range table
+fn range_entries(
+    ctx: &NodeContext,
+    storage: &dyn Storage,
+    start: &str,
+    end: Option<&str>,
+) -> Vec<StoredEntry> {
+    let last = end.map_or_else(|| char::MAX.to_string(), str::to_string);
+    let mut entries = storage.read_entries(start, &last).unwrap_or_default();
+    entries.retain(|entry| {
+        end.is_none_or(|end| entry.key.as_str() < end) && !ctx.raft.consistent(&entry.key)
+    });
+    entries
+}
+
+// sends the keys of a split off range to its new owner and deletes them here once it took them;
+// the range is routed to the owner already, so no key of it is written here in the meantime
+fn hand_over(ctx: &NodeContext, table: &RangeTable, handover: Handover, entries: Vec<StoredEntry>) {
+    let keys: Vec<String> = entries.iter().map(|entry| entry.key.clone()).collect();
+    let response = forward_command(
+        Command::Split(handover.at.clone(), entries),
+        handover.target.clone(),
+        None,
+        ctx,
+    );
+    if response != "OK\n" {
+        // write to the stderr regardless of log setting
+        eprintln!(
+            "Failed to hand the keys from '{}' over to {}, trying again: {}",
+            handover.at,
+            handover.target._id,
+            response.trim()
+        );
+        table.postpone(handover);
+        return;
+    }
+
+    let mut storage = ctx.storage.lock().unwrap();
+    for key in keys {
+        let _ = storage.delete(&key);
+    }
+}
+
+// the other node advertising the fewest keys via gossip
+fn least_loaded(ctx: &NodeContext) -> Option<ClusterNode> {
+    let states = ctx.app_states.lock().unwrap().clone();
+    let key_count = |node: &ClusterNode| {
+        states
+            .get(&node._id)
+            .and_then(|state| state.values.get("key_count"))
+            .and_then(|count| count.parse::<u64>().ok())
+            .unwrap_or_default()
+    };
+
+    ctx.partitioner
+        .nodes()
+        .into_iter()
+        .filter(|node| node._id != ctx.me_id)
+        .min_by_key(key_count)
+}
+
+// delivers the decisions on transactions this node coordinated until every participant has
+// them, also after a restart, and resolves transactions prepared here whose locks timed out
+fn start_transaction_recovery(ctx: Arc<NodeContext>) {
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(TRANSACTION_CHECK_INTERVAL);
+
+            for (txid, record) in ctx.two_phase.unresolved() {
+                let nodes: Vec<(ClusterNode, ())> = ctx
+                    .partitioner
+                    .nodes()
+                    .into_iter()
+                    .filter(|node| record.participants.contains(&node._id))
+                    .map(|node| (node, ()))
+                    .collect();
+
+                let outcomes = scatter(&ctx, nodes, |node, _, deadline| {
+                    deliver(&ctx, &txid, record.decision, node, Some(deadline))
+                });
+                if outcomes.iter().all(Result::is_ok) {
+                    log(
+                        &format!(
+                            "Delivered {} of transaction {}",
+                            record.decision.as_str(),
+                            txid
+                        ),
+                        ctx.log_enabled,
+                    );
+                    ctx.two_phase.done(&txid);
+                }
+            }
+
+            for (txid, coordinator) in ctx.two_phase.expired() {
+                resolve_expired(&ctx, &txid, &coordinator);
+            }
+        }
+    });
+}
+
+// asks the coordinator for the decision on a transaction whose locks timed out; one it cannot
+// reach is aborted to release the locks, although the coordinator may have committed it
+fn resolve_expired(ctx: &NodeContext, txid: &str, coordinator: &str) {
+    let status = if coordinator == ctx.me_id {
+        Ok(ctx.two_phase.status(txid))
+    } else {
+        match ctx
+            .partitioner
+            .nodes()
+            .into_iter()
+            .find(|node| node._id == coordinator)
+        {
+            Some(node) => {
+                let cmd = Command::TxStatus(txid.to_string());
+                let response = forward_command(cmd, node, Some(ctx.peers.deadline()), ctx);
+                parse_reply(response).and_then(|status| {
+                    status
+                        .as_deref()
+                        .and_then(Decision::parse)
+                        .ok_or_else(|| Error::Internal(format!("Invalid status: {:?}", status)))
+                })
+            }
+            None => Err(Error::NotFound(format!("Unknown node {}", coordinator))),
+        }
+    };
+
+    match status {
+        Ok(Decision::Pending) => ctx.two_phase.extend(txid),
+        Ok(Decision::Commit) => {
+            commit(ctx, txid);
+        }
+        Ok(Decision::Abort) => {
+            abort(ctx, txid);
+        }
+        Err(e) => {
+            // write to the stderr regardless of log setting
+            eprintln!(
+                "Aborting transaction {} after its locks timed out, coordinator {} did not answer: {}",
+                txid, coordinator, e
+            );
+            abort(ctx, txid);
+        }
+    }
+}
+
+// periodically publishes the node's load information as gossip application state
+fn start_load_reporter(ctx: &NodeContext) {
+    let me_id = ctx.me_id.clone();
+    let storage = ctx.storage.clone();
+    let request_count = ctx.request_count.clone();
+    let app_states = ctx.app_states.clone();
+
+    update_application_state(
+        &app_states,
+        &me_id,
+        "version",
+        env!("CARGO_PKG_VERSION").to_string(),
+    );
+
+    std::thread::spawn(move || {
+        let mut last_report = Instant::now();
+
+        loop {
+            let (key_count, storage_bytes) = {
+                let storage = storage.lock().unwrap();
+                (storage.key_count(), storage.size_bytes())
+            };
+
+            let elapsed = last_report.elapsed().as_secs_f64().max(f64::EPSILON);
+            let request_rate = request_count.swap(0, Ordering::Relaxed) as f64 / elapsed;
+            last_report = Instant::now();
+
+            update_application_state(&app_states, &me_id, "key_count", key_count.to_string());
+            update_application_state(
+                &app_states,
+                &me_id,
+                "storage_bytes",
+                storage_bytes.to_string(),
+            );
+            update_application_state(
+                &app_states,
+                &me_id,
+                "request_rate",
+                format!("{:.2}", request_rate),
+            );
+
+            std::thread::sleep(LOAD_REPORT_INTERVAL);
+        }
+    });
+}
+
+// one line per cluster node: id, address, link state and advertised application state
+fn cluster_nodes(ctx: &NodeContext) -> String {
+    let snapshot = ctx.cluster_snapshot.lock().unwrap().clone();
+    let states = ctx.app_states.lock().unwrap().clone();
+
+    let mut response = String::new();
+    for node in ctx.partitioner.nodes() {
+        let status = if node._id == ctx.me_id {
+            "myself"
+        } else if snapshot.contains_key(&node._id) {
+            "connected"
+        } else {
+            "disconnected"
+        };
+
+        response.push_str(&format!(
+            "{} {}:{} {}",
+            node._id, node.host, node.port, status
+        ));
+
+        if let Some(state) = states.get(&node._id) {
+            for (k, v) in &state.values {
+                response.push_str(&format!(" {}={}", k, v));
+            }
+        }
+
+        response.push('\n');
+    }
+
+    response
+}
+
+// sends the command to the node, with a deadline the node is given up on once it passes
+pub fn forward_command(
+    cmd: Command,
+    node: ClusterNode,
+    deadline: Option<Instant>,
+    ctx: &NodeContext,
+) -> String {
+    let log_enabled = ctx.log_enabled;
+    let addr = format!("{}:{}", node.host, node.port);
+
+    log(
+        &format!(
+            "Forwarding command to node [{}]: {}, address: {}",
+            node._id, cmd, addr
+        ),
+        log_enabled,
+    );
+
+    // pooled connections authenticate once, when they are opened
+    let credential = || ctx.auth.node_credential(&ctx.me_id);
+
+    match ctx
+        .peers
+        .request(&node, &cmd, deadline, ctx.tls.as_deref(), credential)
+    {
+        Ok(response) => {
+            log(
+                &format!("Response from {}: {}", node._id, response),
+                log_enabled,
+            );
+            response
+        }
+        Err(e) => {
+            // write to the stderr regardless of log setting
+            eprintln!("{}", e.message());
+            e.response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hashing::HashRing, partition::SplitLimits};
+    use std::io::Read;
+
+    // a single node cluster serving the text protocol on a free local port
+    fn start_single_node() -> (Arc<NodeContext>, String) {
+        let node = ClusterNode {
+            _id: "1".into(),
+            host: "127.0.0.1".into(),
+            port: "0".into(),
+            gossip_port: "0".into(),
+        };
+        let ctx = Arc::new(NodeContext::new(
+            "1".into(),
+            "memory",
+            false,
+            Partitioner::Hash(HashRing::build(vec![node], 16)),
+            &Arc::new(Mutex::new(HashMap::new())),
+            &Arc::new(Mutex::new(HashMap::new())),
+            None,
+        ));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let served = ctx.clone();
+        std::thread::spawn(move || {
+            serve(listener, 2, served, handle_connection, |e| {
+                e.as_bytes().to_vec()
+            })
+        });
+        (ctx, addr)
+    }
+
+    // sends the requests in one write and reads every answer until the node closes
+    fn one_shot(addr: &str, requests: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(requests.as_bytes()).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_read_line_bounded() {
+        let mut input: &[u8] = b"READ a\nREAD b";
+        assert_eq!(read_line(&mut input, 16).unwrap().unwrap(), "READ a\n");
+        assert_eq!(read_line(&mut input, 16).unwrap().unwrap(), "READ b");
+        assert!(read_line(&mut input, 16).unwrap().is_none());
+
+        let mut input: &[u8] = b"PUT key a-value-too-long\n";
+        let e = read_line(&mut input, 16).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(is_line_too_long(&e));
+        assert!(!is_line_too_long(&invalid_data("line too long")));
+    }
+
+    #[test]
+    fn test_pipelined_requests() {
+        let (_ctx, addr) = start_single_node();
+
+        // answered in order on one connection, the nc style half-close still works
+        assert_eq!(
+            one_shot(&addr, "PUT a 1\nREAD a\n\nREAD missing\nPUT a 2\nREAD a\n"),
+            "OK\n1\nError: NOT_FOUND Key not found\nOK\n2\n"
+        );
+
+        // the connection stays open between requests
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        for (request, expected) in [("READ a\n", "2\n"), ("PUT b x\n", "OK\n")] {
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, expected);
+        }
+
+        // framed answers carry their length, from the answer switching on
+        assert_eq!(
+            one_shot(&addr, "PROTOCOL FRAMED\nREAD b\nREAD missing\n"),
+            "3\nOK\n2\nx\n31\nError: NOT_FOUND Key not found\n"
+        );
+    }
+
+    #[test]
+    fn test_read_modify_writes() {
+        let (ctx, addr) = start_single_node();
+
+        // a new key has no previous value, a stored "(nil)" is told apart by its quotes
+        assert_eq!(
+            one_shot(&addr, "GETSET g 1\nGETSET g (nil)\nREAD g\nGETSET g 2\n"),
+            "(nil)\n1\n\"(nil)\"\n\"(nil)\"\n"
+        );
+        assert_eq!(parse_value("(nil)\n".to_string()), Ok(None));
+        assert_eq!(
+            parse_value("\"(nil)\"\n".to_string()),
+            Ok(Some(b"(nil)".to_vec()))
+        );
+
+        assert_eq!(
+            one_shot(&addr, "PUT s a\nEXPIRE s 60\nAPPEND s b\nREAD s\n"),
+            "OK\nOK\n2\nab\n"
+        );
+        let entries = ctx.storage.lock().unwrap().read_entries("s", "s").unwrap();
+        assert!(entries[0].expires_in.is_some());
+    }
+
+    #[test]
+    fn test_transactions() {
+        let (_ctx, addr) = start_single_node();
+
+        assert_eq!(
+            one_shot(&addr, "MULTI\nPUT t 1\nREAD t\nEXEC\n"),
+            "OK\nQUEUED\nQUEUED\nOK\n1\n"
+        );
+        assert_eq!(one_shot(&addr, "MULTI\nEXEC\n"), "OK\n(empty)\n");
+        assert_eq!(
+            one_shot(&addr, "WATCH t\nMULTI\nEXEC\n"),
+            "OK\nOK\n(empty)\n"
+        );
+    }
+
+    #[test]
+    fn test_decisions_are_remembered() {
+        let (ctx, _addr) = start_single_node();
+        let node = ctx.partitioner.nodes().remove(0);
+        let share = Transaction {
+            watched: vec![],
+            commands: vec![
+                Command::Put("k".into(), b"v".to_vec()),
+                Command::Read("k".into()),
+            ],
+        };
+
+        assert_eq!(prepare(&ctx, "t1", &ctx.me_id, share), "OK\n");
+        assert_eq!(commit(&ctx, "t1"), "OK\nOK\nv\n");
+        // a decision delivered again is answered the same, a contradicting one fails
+        assert_eq!(
+            deliver(&ctx, "t1", Decision::Commit, node.clone(), None),
+            Ok(vec!["OK\n".to_string(), "v\n".to_string()])
+        );
+        assert!(abort(&ctx, "t1").starts_with("Error: CONFLICT"));
+
+        // nothing to undo for an unknown transaction, but it was not committed either
+        assert_eq!(abort(&ctx, "t2"), "OK\n");
+        assert!(matches!(
+            deliver(&ctx, "t2", Decision::Commit, node, None),
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_range_bounds() {
+        let (_ctx, addr) = start_single_node();
+
+        assert_eq!(
+            one_shot(&addr, "PUT a 1\nREADRANGE z a\n"),
+            "OK\nError: BAD_REQUEST The range starts after its end\n"
+        );
+    }
+
+    // two nodes in range partitioning mode, node 1 owns every key at first
+    fn start_range_nodes(max_keys: usize) -> Vec<Arc<NodeContext>> {
+        let listeners: Vec<TcpListener> = (0..2)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let nodes: Vec<ClusterNode> = listeners
+            .iter()
+            .enumerate()
+            .map(|(i, listener)| ClusterNode {
+                _id: (i + 1).to_string(),
+                host: "127.0.0.1".into(),
+                port: listener.local_addr().unwrap().port().to_string(),
+                gossip_port: "0".into(),
+            })
+            .collect();
+
+        listeners
+            .into_iter()
+            .zip(&nodes)
+            .map(|(listener, node)| {
+                let limits = SplitLimits {
+                    max_keys,
+                    ..SplitLimits::default()
+                };
+                let ctx = Arc::new(NodeContext::new(
+                    node._id.clone(),
+                    "memory",
+                    false,
+                    Partitioner::Range(RangeTable::new(nodes.clone(), &[], limits)),
+                    &Arc::new(Mutex::new(HashMap::new())),
+                    &Arc::new(Mutex::new(HashMap::new())),
+                    None,
+                ));
+                let served = ctx.clone();
+                std::thread::spawn(move || {
+                    serve(listener, 8, served, handle_connection, |e| {
+                        e.as_bytes().to_vec()
+                    })
+                });
+                ctx
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_split_keeps_keys_written_meanwhile() {
+        let nodes = start_range_nodes(10);
+        let Partitioner::Range(table) = &nodes[0].partitioner else {
+            unreachable!();
+        };
+        for i in 0..40 {
+            let put = Command::Put(format!("k{:02}", i), b"old".to_vec());
+            assert_eq!(execute(put, &nodes[0]), "OK\n");
+        }
+
+        // the written keys sort after the moved half, so they fall into the range on its way
+        let writer = {
+            let ctx = nodes[0].clone();
+            std::thread::spawn(move || {
+                for i in 0..200 {
+                    let put = Command::Put(format!("w{:03}", i), b"new".to_vec());
+                    assert_eq!(execute(put, &ctx), "OK\n");
+                }
+            })
+        };
+        split_range(&nodes[0], table);
+        writer.join().unwrap();
+
+        assert_eq!(table.primary("z").unwrap()._id, "2");
+        for ctx in &nodes {
+            for i in 0..40 {
+                let read = Command::Read(format!("k{:02}", i));
+                assert_eq!(execute(read, ctx), "old\n");
+            }
+            for i in 0..200 {
+                let read = Command::Read(format!("w{:03}", i));
+                assert_eq!(execute(read, ctx), "new\n");
+            }
+        }
+        assert!(nodes[0].storage.lock().unwrap().read("w199").is_err());
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        assert_eq!(parse_reply("value\n".into()), Ok(Some("value".into())));
+        assert_eq!(
+            parse_reply("Error: NOT_FOUND Key not found\n".into()),
+            Ok(None)
+        );
+        assert_eq!(
+            parse_reply("Error: UNAVAILABLE Failed to connect to 2: refused\n".into()),
+            Err(Error::Unavailable("Failed to connect to 2: refused".into()))
+        );
+        assert_eq!(
+            parse_value("\"a value\"\n".into()),
+            Ok(Some(b"a value".to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_conditional_writes() {
+        let mut storage = StorageBuilder::builder("memory").build();
+        let storage = storage.as_mut();
+        storage.put("k", b"old".to_vec()).unwrap();
+
+        let mismatch = Err(Error::Conflict("Value mismatch".into()));
+        assert_eq!(
+            compare_and_swap(storage, "k", b"other", b"new".to_vec()),
+            mismatch
+        );
+        assert_eq!(
+            compare_and_swap(storage, "k", b"old", b"new".to_vec()),
+            Ok(())
+        );
+        assert_eq!(storage.read("k"), Ok(b"new".to_vec()));
+        assert_eq!(
+            compare_and_swap(storage, "none", b"", b"new".to_vec()),
+            Err(Error::not_found())
+        );
+
+        assert_eq!(delete_if(storage, "k", b"old"), mismatch);
+        assert_eq!(delete_if(storage, "k", b"new"), Ok(()));
+        assert_eq!(storage.read("k"), Err(Error::not_found()));
+    }
+
+    #[test]
+    fn test_versioned_writes() {
+        let (_ctx, addr) = start_single_node();
+
+        assert_eq!(
+            one_shot(
+                &addr,
+                "PUT k a IFVERSION 0\nPUT k b IFVERSION 0\nREADVERSION k\n"
+            ),
+            "OK\nError: CONFLICT Key exists\n1 a\n"
+        );
+        assert_eq!(
+            one_shot(
+                &addr,
+                "PUT k b IFVERSION 2\nPUT k b IFVERSION 1\nREAD k\nPUT missing c IFVERSION 1\n"
+            ),
+            "Error: CONFLICT Version mismatch\nOK\nb\nError: NOT_FOUND Key not found\n"
+        );
+    }
+
+    #[test]
+    fn test_increment() {
+        let mut storage = StorageBuilder::builder("memory").build();
+        let storage = storage.as_mut();
+
+        assert_eq!(increment(storage, "n", 5), Ok(5));
+        assert_eq!(increment(storage, "n", -7), Ok(-2));
+        assert_eq!(storage.read("n"), Ok(b"-2".to_vec()));
+        assert_eq!(increment_float(storage, "n", 0.5), Ok(-1.5));
+        assert!(matches!(
+            increment(storage, "n", 1),
+            Err(Error::BadRequest(_))
+        ));
+
+        storage
+            .put("max", i64::MAX.to_string().into_bytes())
+            .unwrap();
+        assert!(matches!(
+            increment(storage, "max", 1),
+            Err(Error::BadRequest(_))
+        ));
+        assert_eq!(storage.read("max"), Ok(i64::MAX.to_string().into_bytes()));
+
+        storage.put("text", b"ten".to_vec()).unwrap();
+        assert!(matches!(
+            increment_float(storage, "text", 1.0),
+            Err(Error::BadRequest(_))
+        ));
+        // a counter with an expiry keeps it
+        storage.expire("n", Duration::from_secs(60)).unwrap();
+        assert_eq!(increment_float(storage, "n", 1.5), Ok(0.0));
+        assert_eq!(increment(storage, "n", 1), Ok(1));
+        let entries = storage.read_entries("n", "n").unwrap();
+        assert!(entries[0].expires_in.is_some());
+    }
+
+    #[test]
+    fn test_batch_put_repeated_keys() {
+        let (ctx, addr) = start_single_node();
+
+        // the last value of a key given twice is written
+        assert_eq!(one_shot(&addr, "BATCHPUT a 1 b 2 a 3\nREAD a\n"), "OK\n3\n");
+
+        // and a key failing is reported failed every time it is given
+        let transaction = Transaction {
+            watched: vec![],
+            commands: vec![],
+        };
+        ctx.two_phase
+            .prepare("t1", "1", transaction, vec!["a".into()]);
+        let keys = ["a".to_string(), "b".to_string(), "a".to_string()];
+        let response = one_shot(&addr, "BATCHPUT a 4 b 5 a 6\n");
+        let results = parse_batch(&response, &keys);
+        assert!(matches!(results[0], Err(Error::Conflict(_))));
+        assert_eq!(results[1], Ok(()));
+        assert!(matches!(results[2], Err(Error::Conflict(_))));
+    }
+
+    #[test]
+    fn test_batch_response_round_trip() {
+        let keys = vec!["a".to_string(), "b c".to_string(), "d".to_string()];
+        let unavailable = Error::Unavailable("Failed to connect to 3: refused".into());
+
+        let response = batch_response(&[
+            (keys[0].clone(), Ok(())),
+            (keys[1].clone(), Err(unavailable.clone())),
+            (keys[2].clone(), Ok(())),
+        ]);
+        assert_eq!(
+            response,
+            "Error: PARTIAL 1 of 3 entries failed\na OK\n\"b c\" UNAVAILABLE \"Failed to connect to 3: refused\"\nd OK\n"
+        );
+        assert_eq!(
+            parse_reply(response.clone()),
+            Err(Error::Partial("1 of 3 entries failed".into()))
+        );
+        assert_eq!(
+            parse_batch(&response, &keys),
+            vec![Ok(()), Err(unavailable.clone()), Ok(())]
+        );
+
+        let all_failed: Vec<_> = keys
+            .iter()
+            .map(|k| (k.clone(), Err(unavailable.clone())))
+            .collect();
+        assert_eq!(batch_response(&all_failed), unavailable.response());
+        assert_eq!(parse_batch("OK\n", &keys), vec![Ok(()); 3]);
+    }
+
+    #[test]
+    fn test_range_response_round_trip() {
+        let entries = vec![
+            ("a".to_string(), b"1".to_vec()),
+            ("b key".to_string(), b"a value".to_vec()),
+        ];
+        let response = range_response(&entries);
+        assert_eq!(response, "a 1\n\"b key\" \"a value\"\n");
+        assert_eq!(parse_range(&response), Ok(entries));
+        assert_eq!(parse_range(""), Ok(vec![]));
+
+        // stored data looking like an error answer is still read as data
+        let entries = vec![("Error:".to_string(), b"Error:".to_vec())];
+        let response = range_response(&entries);
+        assert_eq!(response, "\"Error:\" \"Error:\"\n");
+        assert_eq!(parse_range(&response), Ok(entries));
+        assert_eq!(
+            parse_range("Error: TIMEOUT Failed to read response from 3\n"),
+            Err(Error::Timeout("Failed to read response from 3".into()))
+        );
+    }
+
+    #[test]
+    fn test_read_response_round_trip() {
+        let keys = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let results = vec![
+            Ok(b"a value".to_vec()),
+            Err(Error::not_found()),
+            Err(Error::Timeout(
+                "Failed to read response from 3: timed out".into(),
+            )),
+        ];
+
+        let response = read_response(
+            &keys
+                .iter()
+                .cloned()
+                .zip(results.clone())
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(
+            response,
+            "a \"a value\"\nb NOT_FOUND \"Key not found\"\nc TIMEOUT \"Failed to read response from 3: timed out\"\n"
+        );
+        assert_eq!(parse_values(&response, &keys), results);
+
+        let stored_keys = vec!["Error:".to_string()];
+        let response = read_response(&[(stored_keys[0].clone(), Ok(b"NOT_FOUND".to_vec()))]);
+        assert_eq!(
+            parse_values(&response, &stored_keys),
+            vec![Ok(b"NOT_FOUND".to_vec())]
+        );
+
+        let forbidden = Error::Forbidden("Permission denied".into());
+        assert_eq!(
+            parse_values(&forbidden.response(), &keys),
+            vec![Err(forbidden); 3]
+        );
     }
 }
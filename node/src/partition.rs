@@ -0,0 +1,315 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound::{Included, Unbounded},
+    sync::{Mutex, RwLock},
+};
+
+use crate::{config::ClusterNode, crypto, hashing::HashRing};
+
+// how keys are assigned to nodes
+pub enum Partitioner {
+    // consistent hashing: keys spread evenly, but a range touches every node
+    Hash(HashRing),
+    // contiguous key ranges: a range only touches the nodes covering it
+    Range(RangeTable),
+}
+
+impl Partitioner {
+    pub fn primary(&self, key: &str) -> Option<ClusterNode> {
+        match self {
+            Partitioner::Hash(ring) => ring.primary(key).cloned(),
+            Partitioner::Range(table) => table.primary(key),
+        }
+    }
+
+    // distinct cluster nodes, in order of node id
+    pub fn nodes(&self) -> Vec<ClusterNode> {
+        match self {
+            Partitioner::Hash(ring) => ring.nodes().into_iter().cloned().collect(),
+            Partitioner::Range(table) => table.nodes.clone(),
+        }
+    }
+
+    // counts a request to a key stored on this node, ranges split when they get hot
+    pub fn record(&self, key: &str) {
+        if let Partitioner::Range(table) = self {
+            table.record(key);
+        }
+    }
+
+    // the nodes that may hold keys between start and end, both inclusive
+    pub fn nodes_for_range(&self, start: &str, end: &str) -> Vec<ClusterNode> {
+        match self {
+            Partitioner::Hash(_) => self.nodes(),
+            Partitioner::Range(table) => table.covering(start, end),
+        }
+    }
+}
+
+// a range of keys from its first key up to the first key of the next one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Partition {
+    pub owner: String,
+    // raised with every split, the higher version of a first key wins when tables are merged
+    pub version: u64,
+}
+
+// the keys of a range split off to another node, they stay here until the node took them
+#[derive(Debug, Clone)]
+pub struct Handover {
+    pub at: String,
+    // the first key of the next range at the time of the split, None at the end
+    pub end: Option<String>,
+    pub target: ClusterNode,
+}
+
+// a range is split once it holds more keys or gets more requests per second than this
+#[derive(Debug, Clone)]
+pub struct SplitLimits {
+    pub max_keys: usize,
+    pub max_rate: f64,
+}
+
+impl Default for SplitLimits {
+    fn default() -> Self {
+        SplitLimits {
+            max_keys: 100_000,
+            max_rate: 1000.0,
+        }
+    }
+}
+
+// the ranges of the cluster by their first key, the first one starts at the empty key
+pub struct RangeTable {
+    pub limits: SplitLimits,
+    nodes: Vec<ClusterNode>,
+    partitions: RwLock<BTreeMap<String, Partition>>,
+    // requests per range since the last split check, by first key
+    load: Mutex<HashMap<String, u64>>,
+    // splits whose keys the new owner did not take, handed over again at the next check
+    handovers: Mutex<Vec<Handover>>,
+}
+
+impl RangeTable {
+    // the ranges between the split keys go to the nodes in order of node id, in turns
+    pub fn new(mut nodes: Vec<ClusterNode>, splits: &[String], limits: SplitLimits) -> RangeTable {
+        nodes.sort_by(|a, b| a._id.cmp(&b._id));
+
+        let mut starts = vec![String::new()];
+        starts.extend(splits.iter().cloned());
+        starts.sort();
+        starts.dedup();
+
+        let mut partitions = BTreeMap::new();
+        for (i, start) in starts.into_iter().enumerate() {
+            let owner = nodes[i % nodes.len()]._id.clone();
+            partitions.insert(start, Partition { owner, version: 1 });
+        }
+
+        RangeTable {
+            limits,
+            nodes,
+            partitions: RwLock::new(partitions),
+            load: Mutex::new(HashMap::new()),
+            handovers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn primary(&self, key: &str) -> Option<ClusterNode> {
+        let (_, partition) = self.partition_of(key);
+        self.node(&partition.owner)
+    }
+
+    fn node(&self, id: &str) -> Option<ClusterNode> {
+        self.nodes.iter().find(|node| node._id == id).cloned()
+    }
+
+    // the first key and the partition the key falls into
+    pub fn partition_of(&self, key: &str) -> (String, Partition) {
+        let partitions = self.partitions.read().unwrap();
+        let (start, partition) = partitions
+            .range::<str, _>((Unbounded, Included(key)))
+            .next_back()
+            .expect("the first range starts at the empty key");
+        (start.clone(), partition.clone())
+    }
+
+    fn covering(&self, start: &str, end: &str) -> Vec<ClusterNode> {
+        let (first, _) = self.partition_of(start);
+        let partitions = self.partitions.read().unwrap();
+
+        let mut owners: Vec<&str> = Vec::new();
+        for (_, partition) in partitions.range::<str, _>((Included(first.as_str()), Included(end)))
+        {
+            if !owners.contains(&partition.owner.as_str()) {
+                owners.push(&partition.owner);
+            }
+        }
+        owners.iter().filter_map(|id| self.node(id)).collect()
+    }
+
+    // the ranges owned by the node: first key and first key of the next range, None at the end
+    pub fn owned_by(&self, id: &str) -> Vec<(String, Option<String>)> {
+        let partitions = self.partitions.read().unwrap();
+        let starts: Vec<&String> = partitions.keys().collect();
+
+        let mut owned = Vec::new();
+        for (i, start) in starts.iter().enumerate() {
+            if partitions[*start].owner == id {
+                owned.push(((*start).clone(), starts.get(i + 1).map(|s| (*s).clone())));
+            }
+        }
+        owned
+    }
+
+    // counts a request to the range of the key, for finding hot ranges
+    pub fn record(&self, key: &str) {
+        let (start, _) = self.partition_of(key);
+        *self.load.lock().unwrap().entry(start).or_default() += 1;
+    }
+
+    // the requests per range since the last call
+    pub fn take_load(&self) -> HashMap<String, u64> {
+        std::mem::take(&mut *self.load.lock().unwrap())
+    }
+
+    // keeps a handover the new owner did not take for the next check
+    pub fn postpone(&self, handover: Handover) {
+        self.handovers.lock().unwrap().push(handover);
+    }
+
+    // the postponed handovers, taken for another attempt
+    pub fn take_handovers(&self) -> Vec<Handover> {
+        std::mem::take(&mut *self.handovers.lock().unwrap())
+    }
+
+    // hands the keys from `at` to the end of its range to `owner`; every node applying the same
+    // split to the same table ends up with the same versions, false if `at` is a first key already
+    pub fn split(&self, at: &str, owner: &str) -> bool {
+        let mut partitions = self.partitions.write().unwrap();
+        let Some((start, partition)) = partitions
+            .range_mut::<str, _>((Unbounded, Included(at)))
+            .next_back()
+        else {
+            return false;
+        };
+        if start == at {
+            return false;
+        }
+
+        partition.version += 1;
+        let version = partition.version;
+        partitions.insert(
+            at.to_string(),
+            Partition {
+                owner: owner.to_string(),
+                version,
+            },
+        );
+        true
+    }
+
+    // `<first key in hex>/<owner>/<version>` per range, comma separated, for gossip
+    pub fn encode(&self) -> String {
+        self.partitions
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(start, p)| {
+                format!(
+                    "{}/{}/{}",
+                    crypto::to_hex(start.as_bytes()),
+                    p.owner,
+                    p.version
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+
+    // takes over the ranges another node knows in a newer version, true if anything changed
+    pub fn merge(&self, encoded: &str) -> bool {
+        let mut partitions = self.partitions.write().unwrap();
+        let mut changed = false;
+
+        for entry in encoded.split(',').filter(|e| !e.is_empty()) {
+            let Some((start, partition)) = decode_partition(entry) else {
+                continue;
+            };
+
+            let newer = partitions
+                .get(&start)
+                .is_none_or(|known| partition.version > known.version);
+            if newer {
+                partitions.insert(start, partition);
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+fn decode_partition(entry: &str) -> Option<(String, Partition)> {
+    let mut parts = entry.split('/');
+    let (start, owner, version) = (parts.next()?, parts.next()?, parts.next()?);
+
+    let start = String::from_utf8(crypto::from_hex(start)?).ok()?;
+    let partition = Partition {
+        owner: owner.to_string(),
+        version: version.parse().ok()?,
+    };
+    Some((start, partition))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: &str) -> ClusterNode {
+        ClusterNode {
+            _id: id.into(),
+            host: "127.0.0.1".into(),
+            port: format!("300{}", id),
+            gossip_port: format!("400{}", id),
+        }
+    }
+
+    #[test]
+    fn test_ranges_route_and_cover() {
+        let splits = ["g".to_string(), "n".to_string()];
+        let table = RangeTable::new(
+            vec![node("2"), node("1"), node("3")],
+            &splits,
+            SplitLimits::default(),
+        );
+
+        assert_eq!(table.primary("apple").unwrap()._id, "1");
+        assert_eq!(table.primary("g").unwrap()._id, "2");
+        assert_eq!(table.primary("zebra").unwrap()._id, "3");
+
+        let ids = |nodes: Vec<ClusterNode>| nodes.into_iter().map(|n| n._id).collect::<Vec<_>>();
+        assert_eq!(ids(table.covering("h", "k")), ["2"]);
+        assert_eq!(ids(table.covering("b", "h")), ["1", "2"]);
+        assert_eq!(
+            table.owned_by("2"),
+            [("g".to_string(), Some("n".to_string()))]
+        );
+    }
+
+    #[test]
+    fn test_split_and_merge() {
+        let table = RangeTable::new(vec![node("1"), node("2")], &[], SplitLimits::default());
+        let other = RangeTable::new(vec![node("1"), node("2")], &[], SplitLimits::default());
+
+        assert!(table.split("m", "2"));
+        assert!(!table.split("m", "2"));
+        assert_eq!(table.primary("z").unwrap()._id, "2");
+        assert_eq!(other.primary("z").unwrap()._id, "1");
+
+        assert!(other.merge(&table.encode()));
+        assert!(!other.merge(&table.encode()));
+        assert_eq!(other.encode(), table.encode());
+        assert_eq!(other.primary("z").unwrap()._id, "2");
+        assert_eq!(other.primary("a").unwrap()._id, "1");
+    }
+}
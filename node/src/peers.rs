@@ -0,0 +1,429 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use socket2::{SockRef, TcpKeepalive};
+
+use crate::{
+    commands::{Command, Framing},
+    config::ClusterNode,
+    error::Error,
+    tls::{Stream, TlsConfig},
+};
+
+// idle connections are dropped before the peer's client read timeout closes them
+const IDLE_TIMEOUT: Duration = Duration::from_secs(20);
+
+// the kernel probes idle connections, so a peer that went away is noticed before reuse
+const KEEPALIVE_TIME: Duration = Duration::from_secs(10);
+
+// delay before the first retry, doubled for every further one
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+// timeouts and retries of commands forwarded to other nodes
+#[derive(Debug, Clone)]
+pub struct ForwardConfig {
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    // further attempts for idempotent commands, other commands are sent once
+    pub retries: u32,
+    // idle connections kept open per peer
+    pub pool_size: usize,
+    // time for all nodes to answer a command spanning several of them
+    pub deadline: Duration,
+}
+
+impl Default for ForwardConfig {
+    fn default() -> Self {
+        ForwardConfig {
+            connect_timeout: Duration::from_secs(1),
+            read_timeout: Duration::from_secs(5),
+            write_timeout: Duration::from_secs(5),
+            retries: 2,
+            pool_size: 4,
+            deadline: Duration::from_secs(10),
+        }
+    }
+}
+
+struct Connection {
+    reader: BufReader<Stream>,
+    idle_since: Instant,
+}
+
+// persistent connections to the other nodes, switched to the framed text protocol
+// so a response can be read without the peer closing the connection
+#[derive(Default)]
+pub struct PeerPool {
+    config: ForwardConfig,
+    idle: Mutex<HashMap<String, Vec<Connection>>>,
+}
+
+impl PeerPool {
+    pub fn new(config: ForwardConfig) -> PeerPool {
+        PeerPool {
+            config,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // the deadline for a command spanning nodes that starts now
+    pub fn deadline(&self) -> Instant {
+        Instant::now() + self.config.deadline
+    }
+
+    // sends the command to the node and returns its response, `credential` is called
+    // for every new connection and sent ahead of the first command; with a deadline,
+    // timeouts are shortened and retries given up so the response arrives before it
+    pub fn request<F>(
+        &self,
+        node: &ClusterNode,
+        cmd: &Command,
+        deadline: Option<Instant>,
+        tls: Option<&TlsConfig>,
+        credential: F,
+    ) -> Result<String, Error>
+    where
+        F: Fn() -> Option<Command>,
+    {
+        let attempts = if cmd.is_idempotent() {
+            self.config.retries + 1
+        } else {
+            1
+        };
+        let mut backoff = RETRY_BACKOFF;
+
+        for attempt in 1.. {
+            match self.try_request(node, cmd, deadline, tls, &credential) {
+                Ok(response) => return Ok(response),
+                Err(e) if attempt >= attempts => return Err(e),
+                Err(e) if deadline.is_some_and(|d| Instant::now() + backoff >= d) => return Err(e),
+                Err(_) => {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+            }
+        }
+        unreachable!()
+    }
+
+    fn try_request<F>(
+        &self,
+        node: &ClusterNode,
+        cmd: &Command,
+        deadline: Option<Instant>,
+        tls: Option<&TlsConfig>,
+        credential: &F,
+    ) -> Result<String, Error>
+    where
+        F: Fn() -> Option<Command>,
+    {
+        let timeouts = Timeouts::until(&self.config, deadline).ok_or_else(|| {
+            Error::Timeout(format!("Deadline passed before sending to {}", node._id))
+        })?;
+
+        let addr = format!("{}:{}", node.host, node.port);
+        let mut connection = match self.checkout(&addr) {
+            Some(connection) => connection,
+            None => self.connect(node, &addr, &timeouts, tls, credential)?,
+        };
+        timeouts.apply(connection.reader.get_ref().tcp());
+
+        let stream = connection.reader.get_mut();
+        stream
+            .write_all(format!("{}\n", cmd).as_bytes())
+            .and_then(|_| stream.flush())
+            .map_err(|e| failure(format!("Failed to send command to {}", node._id), e))?;
+
+        let response = read_framed(&mut connection.reader)
+            .map_err(|e| failure(format!("Failed to read response from {}", node._id), e))?;
+
+        self.checkin(&addr, connection);
+        Ok(response)
+    }
+
+    // an idle connection to the address that is still open
+    fn checkout(&self, addr: &str) -> Option<Connection> {
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.get_mut(addr)?;
+
+        while let Some(connection) = connections.pop() {
+            if connection.idle_since.elapsed() < IDLE_TIMEOUT
+                && connection.reader.buffer().is_empty()
+                && is_open(connection.reader.get_ref().tcp())
+            {
+                return Some(connection);
+            }
+        }
+        None
+    }
+
+    fn checkin(&self, addr: &str, mut connection: Connection) {
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.entry(addr.to_string()).or_default();
+
+        if connections.len() < self.config.pool_size {
+            connection.idle_since = Instant::now();
+            connections.push(connection);
+        }
+    }
+
+    fn connect<F>(
+        &self,
+        node: &ClusterNode,
+        addr: &str,
+        timeouts: &Timeouts,
+        tls: Option<&TlsConfig>,
+        credential: &F,
+    ) -> Result<Connection, Error>
+    where
+        F: Fn() -> Option<Command>,
+    {
+        let context = format!("Failed to connect to {}", node._id);
+        let failed = |e: io::Error| failure(context.clone(), e);
+        let refused = |message: &str| Error::Unavailable(format!("{}: {}", context, message));
+
+        let socket_addr = addr
+            .to_socket_addrs()
+            .map_err(failed)?
+            .next()
+            .ok_or_else(|| refused(&format!("Unknown address {}", addr)))?;
+        let tcp_stream =
+            TcpStream::connect_timeout(&socket_addr, timeouts.connect).map_err(failed)?;
+
+        timeouts.apply(&tcp_stream);
+        let _ = tcp_stream.set_nodelay(true);
+        let _ = SockRef::from(&tcp_stream)
+            .set_tcp_keepalive(&TcpKeepalive::new().with_time(KEEPALIVE_TIME));
+
+        let stream = Stream::peer(tcp_stream, &node.host, tls).map_err(|e| refused(&e))?;
+        let mut reader = BufReader::new(stream);
+
+        // the credential is answered line by line, everything after PROTOCOL FRAMED is framed
+        let credential = credential();
+        let mut handshake = String::new();
+        if let Some(credential) = &credential {
+            handshake.push_str(&format!("{}\n", credential));
+        }
+        handshake.push_str(&format!("{}\n", Command::Protocol(Framing::Framed)));
+
+        let stream = reader.get_mut();
+        stream
+            .write_all(handshake.as_bytes())
+            .and_then(|_| stream.flush())
+            .map_err(failed)?;
+
+        if credential.is_some() {
+            let mut line = String::new();
+            reader.read_line(&mut line).map_err(failed)?;
+            if line != "OK\n" {
+                return Err(Error::Unavailable(format!(
+                    "Failed to authenticate with {}: {}",
+                    node._id,
+                    line.trim()
+                )));
+            }
+        }
+
+        match read_framed(&mut reader) {
+            Ok(response) if response == "OK\n" => Ok(Connection {
+                reader,
+                idle_since: Instant::now(),
+            }),
+            Ok(response) => Err(refused(response.trim())),
+            Err(e) => Err(failed(e)),
+        }
+    }
+}
+
+// socket timeouts of one attempt: the configured ones, cut short by the deadline
+struct Timeouts {
+    connect: Duration,
+    read: Duration,
+    write: Duration,
+}
+
+impl Timeouts {
+    // None once the deadline has passed
+    fn until(config: &ForwardConfig, deadline: Option<Instant>) -> Option<Timeouts> {
+        let left = match deadline {
+            Some(deadline) => deadline.checked_duration_since(Instant::now())?,
+            None => Duration::MAX,
+        };
+        if left.is_zero() {
+            return None;
+        }
+
+        Some(Timeouts {
+            connect: config.connect_timeout.min(left),
+            read: config.read_timeout.min(left),
+            write: config.write_timeout.min(left),
+        })
+    }
+
+    // pooled connections are set again for every request, the deadline differs
+    fn apply(&self, stream: &TcpStream) {
+        let _ = stream.set_read_timeout(Some(self.read));
+        let _ = stream.set_write_timeout(Some(self.write));
+    }
+}
+
+// a peer that did not answer in time timed out, any other failure makes it unavailable
+fn failure(context: String, e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+            Error::Timeout(format!("{}: {}", context, e))
+        }
+        _ => Error::Unavailable(format!("{}: {}", context, e)),
+    }
+}
+
+// a response preceded by its length in bytes on a line of its own
+fn read_framed(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Connection closed",
+        ));
+    }
+
+    let len: usize = line.trim().parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid frame length: {}", line.trim()),
+        )
+    })?;
+
+    let mut response = vec![0; len];
+    reader.read_exact(&mut response)?;
+    String::from_utf8(response).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// a connection the peer closed, or that has unexpected data pending, cannot be reused
+fn is_open(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+
+    let pending = stream.peek(&mut [0; 1]);
+    let open = matches!(pending, Err(e) if e.kind() == io::ErrorKind::WouldBlock);
+
+    stream.set_nonblocking(false).is_ok() && open
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        net::TcpListener,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    // a peer answering every command with its line, framed; `hang` never answers
+    fn fake_peer(hang: bool) -> (ClusterNode, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let counter = accepted.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                thread::spawn(move || {
+                    let mut writer = stream.try_clone().unwrap();
+                    for line in BufReader::new(stream).lines() {
+                        let Ok(line) = line else { break };
+                        if hang {
+                            continue;
+                        }
+                        let response = if line == "PROTOCOL FRAMED" {
+                            "OK\n".to_string()
+                        } else {
+                            format!("{}\n", line)
+                        };
+                        let _ = write!(writer, "{}\n{}", response.len(), response);
+                    }
+                });
+            }
+        });
+
+        let node = ClusterNode {
+            _id: "2".into(),
+            host: "127.0.0.1".into(),
+            port: port.to_string(),
+            gossip_port: "0".into(),
+        };
+        (node, accepted)
+    }
+
+    #[test]
+    fn test_connections_are_reused() {
+        let (node, accepted) = fake_peer(false);
+        let pool = PeerPool::new(ForwardConfig::default());
+
+        let read = Command::Read("key".into());
+        assert_eq!(
+            pool.request(&node, &read, None, None, || None).unwrap(),
+            "READ key\n"
+        );
+        let delete = Command::Delete("other key".into());
+        assert_eq!(
+            pool.request(&node, &delete, None, None, || None).unwrap(),
+            "DELETE \"other key\"\n"
+        );
+
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_hung_peer_times_out_and_only_idempotent_commands_are_retried() {
+        let (node, accepted) = fake_peer(true);
+        let pool = PeerPool::new(ForwardConfig {
+            read_timeout: Duration::from_millis(100),
+            retries: 1,
+            ..ForwardConfig::default()
+        });
+
+        let started = Instant::now();
+        let read = Command::Read("key".into());
+        let error = pool.request(&node, &read, None, None, || None).unwrap_err();
+        assert!(matches!(error, Error::Timeout(_)), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        let delete = Command::Delete("key".into());
+        assert!(pool.request(&node, &delete, None, None, || None).is_err());
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+
+        let put = Command::Put("key".into(), b"value".to_vec());
+        assert!(pool.request(&node, &put, None, None, || None).is_err());
+        assert_eq!(accepted.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_deadline_cuts_timeouts_short() {
+        let (node, _) = fake_peer(true);
+        let pool = PeerPool::new(ForwardConfig::default());
+
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(200);
+        let read = Command::Read("key".into());
+        let error = pool
+            .request(&node, &read, Some(deadline), None, || None)
+            .unwrap_err();
+        assert!(matches!(error, Error::Timeout(_)), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
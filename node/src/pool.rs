@@ -0,0 +1,104 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+    },
+    thread,
+};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// fixed number of worker threads fed through a bounded queue,
+// jobs are rejected instead of piling up when all workers are busy and the queue is full
+pub struct ThreadPool {
+    sender: SyncSender<Job>,
+}
+
+impl ThreadPool {
+    pub fn new(workers: usize, queue_size: usize) -> ThreadPool {
+        assert!(workers > 0, "thread pool needs at least one worker");
+
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for id in 0..workers {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("worker-{}", id))
+                .spawn(move || Self::work(receiver))
+                .expect("Failed to spawn worker thread");
+        }
+
+        ThreadPool { sender }
+    }
+
+    // hands the job to an idle worker or the queue, fails if the pool is saturated
+    pub fn try_execute<F>(&self, job: F) -> Result<(), String>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.sender.try_send(Box::new(job)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err("Server busy".to_string()),
+            Err(TrySendError::Disconnected(_)) => Err("Thread pool stopped".to_string()),
+        }
+    }
+
+    fn work(receiver: Arc<Mutex<Receiver<Job>>>) {
+        loop {
+            let job = receiver.lock().unwrap().recv();
+            match job {
+                Ok(job) => job(),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    #[test]
+    fn test_thread_pool_runs_jobs_concurrently() {
+        let pool = ThreadPool::new(2, 0);
+        let (started_tx, started_rx) = channel();
+        let (release_tx, release_rx) = channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+
+        for _ in 0..2 {
+            let started_tx = started_tx.clone();
+            let release_rx = release_rx.clone();
+            let job = move || {
+                started_tx.send(()).unwrap();
+                let _ = release_rx.lock().unwrap().recv();
+            };
+
+            // workers may not be parked in recv yet, retry until one picks the job up
+            let job = Arc::new(Mutex::new(Some(job)));
+            loop {
+                let pending = job.clone();
+                let accepted = pool.try_execute(move || {
+                    if let Some(job) = pending.lock().unwrap().take() {
+                        job();
+                    }
+                });
+                if accepted.is_ok() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        started_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        started_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+
+        // both workers are blocked, a third job is rejected
+        assert!(pool.try_execute(|| {}).is_err());
+
+        release_tx.send(()).unwrap();
+        release_tx.send(()).unwrap();
+    }
+}
@@ -0,0 +1,1605 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, hash_map::DefaultHasher},
+    fs::{self, File, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{self, BufRead, BufReader, Write},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    commands::{self, Command, LogEntry, RaftMessage},
+    config::ClusterNode,
+    error::Error,
+    hashing::HashRing,
+    log::log,
+    networking::{self, NodeContext},
+    storage::{Storage, StoredEntry},
+};
+
+// how often a leader sends its log, empty when nothing is new, to the other replicas
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+
+// a follower not hearing from a leader for this long, plus up to the same again at random so
+// replicas rarely start an election together, becomes a candidate
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(1000);
+
+// how long a replica has to answer a vote, append or snapshot
+const MESSAGE_TIMEOUT: Duration = Duration::from_millis(500);
+
+// how long a client waits for its command to be committed and applied
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how often a replica checks its timers when nothing happens
+const TICK: Duration = Duration::from_millis(20);
+
+// the most log entries sent in one append
+const MAX_ENTRIES: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+// the Raft state of one replica of a shard; the log holds the entries after the last snapshot,
+// the entry at `log[i]` has the index `snapshot_index + 1 + i`
+pub struct Group {
+    me: String,
+    peers: Vec<String>,
+    pub role: Role,
+    pub term: u64,
+    voted_for: Option<String>,
+    pub leader: Option<String>,
+    log: Vec<LogEntry>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    // the next entry to send to every peer and the last one it is known to store, leader only
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    votes: HashSet<String>,
+    election_deadline: Instant,
+    heartbeat_deadline: Instant,
+    // entries were proposed since the last append was sent
+    proposed: bool,
+    // the first log entry not written to the journal yet
+    unsaved: Option<u64>,
+    // the entries proposed by clients of this node, by index and term, and their answers once
+    // applied
+    waiting: HashSet<(u64, u64)>,
+    answers: HashMap<(u64, u64), String>,
+}
+
+impl Group {
+    pub fn new(me: &str, peers: Vec<String>, now: Instant) -> Group {
+        Group {
+            me: me.to_string(),
+            peers,
+            role: Role::Follower,
+            term: 0,
+            voted_for: None,
+            leader: None,
+            log: Vec::new(),
+            snapshot_index: 0,
+            snapshot_term: 0,
+            commit_index: 0,
+            last_applied: 0,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            votes: HashSet::new(),
+            election_deadline: now + election_timeout(me),
+            heartbeat_deadline: now,
+            proposed: false,
+            unsaved: None,
+            waiting: HashSet::new(),
+            answers: HashMap::new(),
+        }
+    }
+
+    // takes over the state read back from the journal, the entries up to the snapshot are
+    // applied by restoring its keys
+    fn restore(&mut self, saved: Saved) {
+        self.term = saved.term;
+        self.voted_for = saved.voted_for;
+        self.log = saved.log;
+        self.snapshot_index = saved.snapshot_index;
+        self.snapshot_term = saved.snapshot_term;
+        self.commit_index = saved.snapshot_index;
+        self.last_applied = saved.snapshot_index;
+    }
+
+    // appends to the log, the entry is written to the journal with the next save
+    fn append(&mut self, entry: LogEntry) {
+        self.log.push(entry);
+        let index = self.last_index();
+        self.unsaved = Some(self.unsaved.map_or(index, |unsaved| unsaved.min(index)));
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.snapshot_index + self.log.len() as u64
+    }
+
+    // None for an index compacted into the snapshot or past the end of the log
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        let position = index.checked_sub(self.snapshot_index + 1)?;
+        self.log.get(position as usize).map(|entry| entry.term)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index()).unwrap_or_default()
+    }
+
+    // the replicas, this one included, needed to elect a leader or commit an entry
+    pub fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    pub fn election_due(&self, now: Instant) -> bool {
+        self.role != Role::Leader && now >= self.election_deadline
+    }
+
+    pub fn replication_due(&self, now: Instant) -> bool {
+        self.role == Role::Leader && (self.proposed || now >= self.heartbeat_deadline)
+    }
+
+    // a newer term from another replica turns this one into a follower of it
+    fn observe(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.role = Role::Follower;
+            self.voted_for = None;
+            self.leader = None;
+        }
+    }
+
+    fn follow(&mut self, leader: &str, now: Instant) {
+        self.role = Role::Follower;
+        self.leader = Some(leader.to_string());
+        self.election_deadline = now + election_timeout(&self.me);
+    }
+
+    // becomes a candidate of the next term, voting for itself; answers the vote request
+    pub fn start_election(&mut self, shard: u32, now: Instant) -> RaftMessage {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.me.clone());
+        self.leader = None;
+        self.votes = HashSet::from([self.me.clone()]);
+        self.election_deadline = now + election_timeout(&self.me);
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+        }
+
+        RaftMessage::Vote {
+            shard,
+            term: self.term,
+            candidate: self.me.clone(),
+            last_index: self.last_index(),
+            last_term: self.last_term(),
+        }
+    }
+
+    // grants the vote to the first candidate of the term whose log is at least as complete
+    pub fn handle_vote(
+        &mut self,
+        term: u64,
+        candidate: &str,
+        last_index: u64,
+        last_term: u64,
+        now: Instant,
+    ) -> (u64, bool) {
+        self.observe(term);
+
+        let up_to_date = (last_term, last_index) >= (self.last_term(), self.last_index());
+        let granted = term == self.term
+            && up_to_date
+            && self.voted_for.as_deref().is_none_or(|v| v == candidate);
+        if granted {
+            self.voted_for = Some(candidate.to_string());
+            self.election_deadline = now + election_timeout(&self.me);
+        }
+        (self.term, granted)
+    }
+
+    pub fn on_vote(&mut self, from: &str, term: u64, granted: bool) {
+        self.observe(term);
+        if self.role != Role::Candidate || term != self.term || !granted {
+            return;
+        }
+
+        self.votes.insert(from.to_string());
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+        }
+    }
+
+    // starts the term with an empty entry: committing it commits the entries of earlier terms,
+    // and tells the leader its commit index is current
+    fn become_leader(&mut self) {
+        self.role = Role::Leader;
+        self.leader = Some(self.me.clone());
+        self.append(LogEntry {
+            term: self.term,
+            command: None,
+        });
+        for peer in &self.peers {
+            self.next_index.insert(peer.clone(), self.last_index());
+            self.match_index.insert(peer.clone(), 0);
+        }
+        self.proposed = true;
+        self.advance_commit();
+    }
+
+    // appends the command to the log of a leader, answers its index and term
+    pub fn propose(&mut self, cmd: Command) -> Option<(u64, u64)> {
+        if self.role != Role::Leader {
+            return None;
+        }
+
+        self.append(LogEntry {
+            term: self.term,
+            command: Some(cmd),
+        });
+        self.proposed = true;
+        let proposal = (self.last_index(), self.term);
+        self.waiting.insert(proposal);
+        self.advance_commit();
+        Some(proposal)
+    }
+
+    pub fn replication_started(&mut self, now: Instant) {
+        self.proposed = false;
+        self.heartbeat_deadline = now + HEARTBEAT_INTERVAL;
+    }
+
+    // the entries the peer is missing, None when some of them are only in the snapshot
+    pub fn append_for(&self, shard: u32, peer: &str) -> Option<RaftMessage> {
+        let next = self
+            .next_index
+            .get(peer)
+            .copied()
+            .unwrap_or(self.last_index() + 1);
+        let prev_index = next - 1;
+        let prev_term = self.term_at(prev_index)?;
+        let start = (prev_index - self.snapshot_index) as usize;
+        let end = self.log.len().min(start + MAX_ENTRIES);
+
+        Some(RaftMessage::Append {
+            shard,
+            term: self.term,
+            leader: self.me.clone(),
+            prev_index,
+            prev_term,
+            commit: self.commit_index,
+            entries: self.log[start..end].to_vec(),
+        })
+    }
+
+    // stores the entries if they continue the log, replacing conflicting ones; answers the term,
+    // whether they were stored and the last index known to match the leader's log
+    #[allow(clippy::too_many_arguments)]
+    pub fn handle_append(
+        &mut self,
+        term: u64,
+        leader: &str,
+        mut prev_index: u64,
+        mut prev_term: u64,
+        commit: u64,
+        mut entries: Vec<LogEntry>,
+        now: Instant,
+    ) -> (u64, bool, u64) {
+        self.observe(term);
+        if term < self.term {
+            return (self.term, false, self.last_index());
+        }
+        self.follow(leader, now);
+
+        // entries up to the snapshot are committed and therefore the same
+        if prev_index < self.snapshot_index {
+            let skipped = ((self.snapshot_index - prev_index) as usize).min(entries.len());
+            entries.drain(..skipped);
+            prev_index += skipped as u64;
+            if prev_index < self.snapshot_index {
+                return (self.term, true, prev_index);
+            }
+            prev_term = self.snapshot_term;
+        }
+
+        if self.term_at(prev_index) != Some(prev_term) {
+            // the leader goes back to the end of this log, or one entry further
+            let hint = self.last_index().min(prev_index.saturating_sub(1));
+            return (self.term, false, hint);
+        }
+
+        let mut index = prev_index;
+        for entry in entries {
+            index += 1;
+            match self.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    self.log
+                        .truncate((index - self.snapshot_index - 1) as usize);
+                    self.append(entry);
+                }
+                None => self.append(entry),
+            }
+        }
+
+        if commit > self.commit_index {
+            self.commit_index = commit.min(index);
+        }
+        (self.term, true, index)
+    }
+
+    pub fn on_append(&mut self, peer: &str, term: u64, success: bool, matched: u64) {
+        self.observe(term);
+        if self.role != Role::Leader || term != self.term {
+            return;
+        }
+
+        if success {
+            let known = self.match_index.entry(peer.to_string()).or_default();
+            *known = (*known).max(matched);
+            self.next_index.insert(peer.to_string(), *known + 1);
+            self.advance_commit();
+        } else {
+            let next = self.next_index.entry(peer.to_string()).or_insert(1);
+            *next = (*next - 1).min(matched + 1).max(1);
+        }
+    }
+
+    // commits the last entry of the current term a quorum stores, and every entry before it
+    fn advance_commit(&mut self) {
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            let stored = 1 + self
+                .match_index
+                .values()
+                .filter(|matched| **matched >= index)
+                .count();
+            if self.term_at(index) == Some(self.term) && stored >= self.quorum() {
+                self.commit_index = index;
+                return;
+            }
+        }
+    }
+
+    // the committed entries not applied yet, counted as applied from here on
+    pub fn committed(&mut self) -> Vec<(u64, LogEntry)> {
+        let from = self.last_applied + 1;
+        let entries = (from..=self.commit_index)
+            .map(|index| {
+                let position = (index - self.snapshot_index - 1) as usize;
+                (index, self.log[position].clone())
+            })
+            .collect();
+        self.last_applied = self.last_applied.max(self.commit_index);
+        entries
+    }
+
+    // keeps the answer of an applied entry for the client waiting on it
+    pub fn answer(&mut self, index: u64, term: u64, answer: String) {
+        if self.waiting.remove(&(index, term)) {
+            self.answers.insert((index, term), answer);
+        }
+    }
+
+    // drops the applied entries from the log once it holds more than `max` of them, the
+    // storage holds their outcome; answers whether it did
+    pub fn compact(&mut self, max: usize) -> bool {
+        if self.log.len() <= max || self.last_applied <= self.snapshot_index {
+            return false;
+        }
+
+        let term = self.term_at(self.last_applied).unwrap_or_default();
+        self.log
+            .drain(..(self.last_applied - self.snapshot_index) as usize);
+        self.snapshot_index = self.last_applied;
+        self.snapshot_term = term;
+        true
+    }
+
+    // takes over a snapshot of the leader, answers the term and whether the storage has to be
+    // replaced with its entries
+    pub fn install(
+        &mut self,
+        term: u64,
+        leader: &str,
+        last_index: u64,
+        last_term: u64,
+        now: Instant,
+    ) -> (u64, bool) {
+        self.observe(term);
+        if term < self.term {
+            return (self.term, false);
+        }
+        self.follow(leader, now);
+
+        if last_index <= self.last_applied {
+            return (self.term, false);
+        }
+
+        // entries after the snapshot are kept if the log continues it
+        if self.term_at(last_index) == Some(last_term) {
+            self.log
+                .drain(..(last_index - self.snapshot_index) as usize);
+        } else {
+            self.log.clear();
+        }
+        self.snapshot_index = last_index;
+        self.snapshot_term = last_term;
+        self.commit_index = self.commit_index.max(last_index);
+        self.last_applied = last_index;
+        (self.term, true)
+    }
+
+    pub fn on_install(&mut self, peer: &str, term: u64, last_index: u64) {
+        self.on_append(peer, term, true, last_index);
+    }
+}
+
+// the state of a replica read back from its journal
+#[derive(Debug, Default)]
+struct Saved {
+    term: u64,
+    voted_for: Option<String>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    // the keys of the shard as applied up to the snapshot
+    snapshot: Vec<StoredEntry>,
+    log: Vec<LogEntry>,
+}
+
+// the term, vote and log of a replica, written before it answers a vote or an append so a
+// restarted replica keeps its promises; an append-only file of `TERM`, `ENTRY` and `SNAPSHOT`
+// lines, rewritten when the log is compacted
+#[derive(Default)]
+struct Journal {
+    path: String,
+    file: Option<File>,
+    term: u64,
+    voted_for: Option<String>,
+}
+
+impl Journal {
+    // reads the journal back and rewrites it with the state read; a line torn by a crash was
+    // never acknowledged and ends the journal
+    fn open(path: &str) -> io::Result<(Journal, Saved)> {
+        let mut saved = Saved::default();
+        if let Ok(file) = File::open(path) {
+            for line in BufReader::new(file).lines() {
+                if read_line(&mut saved, &line?).is_none() {
+                    break;
+                }
+            }
+        }
+
+        let mut journal = Journal {
+            path: path.to_string(),
+            ..Default::default()
+        };
+        journal
+            .write_all(&saved)
+            .map_err(|e| io::Error::other(e.message().to_string()))?;
+        Ok((journal, saved))
+    }
+
+    // writes the changes of the group since the last save
+    fn save(&mut self, group: &mut Group) -> Result<(), Error> {
+        let unsaved = group.unsaved.take();
+        let Some(file) = self.file.as_mut() else {
+            return Ok(());
+        };
+
+        let mut lines = String::new();
+        if (group.term, &group.voted_for) != (self.term, &self.voted_for) {
+            lines.push_str(&term_line(group.term, group.voted_for.as_deref()));
+        }
+        if let Some(from) = unsaved {
+            for index in from.max(group.snapshot_index + 1)..=group.last_index() {
+                let position = (index - group.snapshot_index - 1) as usize;
+                lines.push_str(&entry_line(index, &group.log[position]));
+            }
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        file.write_all(lines.as_bytes())
+            .and_then(|_| file.sync_data())
+            .map_err(journal_error)?;
+        self.term = group.term;
+        self.voted_for = group.voted_for.clone();
+        Ok(())
+    }
+
+    // replaces the journal after the log was compacted into the given keys
+    fn rewrite(&mut self, group: &mut Group, snapshot: &[StoredEntry]) -> Result<(), Error> {
+        group.unsaved = None;
+        if self.file.is_none() {
+            return Ok(());
+        }
+
+        self.write_all(&Saved {
+            term: group.term,
+            voted_for: group.voted_for.clone(),
+            snapshot_index: group.snapshot_index,
+            snapshot_term: group.snapshot_term,
+            snapshot: snapshot.to_vec(),
+            log: group.log.clone(),
+        })
+    }
+
+    // written next to the journal and renamed, a crash leaves either journal behind
+    fn write_all(&mut self, saved: &Saved) -> Result<(), Error> {
+        let mut text = term_line(saved.term, saved.voted_for.as_deref());
+        text.push_str(&format!(
+            "SNAPSHOT {} {}",
+            saved.snapshot_index, saved.snapshot_term
+        ));
+        for entry in &saved.snapshot {
+            text.push_str(&format!(
+                " {} {} {} {}",
+                commands::quote_str(&entry.key),
+                commands::quote(&entry.value),
+                entry.version,
+                expires_at(entry.expires_in)
+            ));
+        }
+        text.push('\n');
+        for (i, entry) in saved.log.iter().enumerate() {
+            text.push_str(&entry_line(saved.snapshot_index + 1 + i as u64, entry));
+        }
+
+        let compacted = format!("{}.tmp", self.path);
+        let write = || -> io::Result<File> {
+            let mut file = File::create(&compacted)?;
+            file.write_all(text.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&compacted, &self.path)?;
+            OpenOptions::new().append(true).open(&self.path)
+        };
+        self.file = Some(write().map_err(journal_error)?);
+        self.term = saved.term;
+        self.voted_for = saved.voted_for.clone();
+        Ok(())
+    }
+}
+
+fn journal_error(e: io::Error) -> Error {
+    Error::Internal(format!("Failed to write the Raft journal: {}", e))
+}
+
+fn term_line(term: u64, voted_for: Option<&str>) -> String {
+    format!(
+        "TERM {} {}\n",
+        term,
+        commands::quote_str(voted_for.unwrap_or_default())
+    )
+}
+
+// the time in milliseconds since the unix epoch, the journal keeps expirations as points in time
+// so they still hold after a restart
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// 0 for a key that does not expire
+fn expires_at(expires_in: Option<Duration>) -> u64 {
+    expires_in.map_or(0, |ttl| unix_millis() + (ttl.as_millis() as u64).max(1))
+}
+
+// the command as in an append, empty for the entry starting a term
+fn entry_line(index: u64, entry: &LogEntry) -> String {
+    let command = entry.command.as_ref().map(Command::to_string);
+    format!(
+        "ENTRY {} {} {}\n",
+        index,
+        entry.term,
+        commands::quote_str(command.as_deref().unwrap_or_default())
+    )
+}
+
+// applies a line of the journal, None for a line that can not be read
+fn read_line(saved: &mut Saved, line: &str) -> Option<()> {
+    let tokens = commands::tokenize(line).ok()?;
+    let tokens: Vec<&[u8]> = tokens.iter().map(Vec::as_slice).collect();
+    let number = |token: &[u8]| std::str::from_utf8(token).ok()?.parse::<u64>().ok();
+    let text = |token: &[u8]| String::from_utf8(token.to_vec()).ok();
+
+    match tokens.as_slice() {
+        [b"TERM", term, voted_for] => {
+            saved.term = number(term)?;
+            saved.voted_for = Some(text(voted_for)?).filter(|id| !id.is_empty());
+        }
+        [b"SNAPSHOT", index, term, entries @ ..] if entries.len() % 4 == 0 => {
+            saved.snapshot_index = number(index)?;
+            saved.snapshot_term = number(term)?;
+            saved.snapshot.clear();
+            let now = unix_millis();
+            for entry in entries.chunks_exact(4) {
+                let (key, expires_at) = (text(entry[0])?, number(entry[3])?);
+                // a key that expired while the replica was down is left out
+                if expires_at != 0 && expires_at <= now {
+                    continue;
+                }
+                saved.snapshot.push(StoredEntry {
+                    key,
+                    value: entry[1].to_vec(),
+                    version: number(entry[2])?,
+                    expires_in: (expires_at != 0).then(|| Duration::from_millis(expires_at - now)),
+                });
+            }
+            saved.log.clear();
+        }
+        // an entry replaces the ones from its index on
+        [b"ENTRY", index, term, command] => {
+            let position = number(index)?.checked_sub(saved.snapshot_index + 1)? as usize;
+            if position > saved.log.len() {
+                return None;
+            }
+            let command = match text(command)?.as_str() {
+                "" => None,
+                line => Some(Command::try_from(line).ok()?),
+            };
+            saved.log.truncate(position);
+            saved.log.push(LogEntry {
+                term: number(term)?,
+                command,
+            });
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+// the replicas this node runs for one shard
+pub struct Shard {
+    id: u32,
+    peers: Vec<ClusterNode>,
+    group: Mutex<Group>,
+    // signalled when entries are proposed, committed or applied
+    changed: Condvar,
+    journal: Mutex<Journal>,
+    // the keys of the snapshot read back from the journal, stored when the replica starts
+    restored: Mutex<Vec<StoredEntry>>,
+}
+
+impl Shard {
+    // writes the changes of the group to the journal, under the group lock
+    fn save(&self, group: &mut Group) -> Result<(), Error> {
+        let result = self.journal.lock().unwrap().save(group);
+        if let Err(e) = &result {
+            // write to the stderr regardless of log setting
+            eprintln!("Shard {}: {}", self.id, e);
+        }
+        result
+    }
+}
+
+// strongly consistent keyspaces: their keys are hashed into shards, every shard is replicated
+// by a Raft group on `replicas` nodes, which runs each command through its log
+#[derive(Default)]
+pub struct Raft {
+    me: String,
+    keyspaces: Vec<String>,
+    shards: u32,
+    snapshot_entries: usize,
+    // the nodes replicating every shard
+    replicas: Vec<Vec<ClusterNode>>,
+    groups: HashMap<u32, Arc<Shard>>,
+}
+
+impl Raft {
+    // shard `s` is replicated by the nodes following the `s`-th one in id order
+    pub fn new(
+        me: &str,
+        mut nodes: Vec<ClusterNode>,
+        keyspaces: Vec<String>,
+        shards: u32,
+        replicas: usize,
+        snapshot_entries: usize,
+    ) -> Raft {
+        nodes.sort_by(|a, b| a._id.cmp(&b._id));
+        let count = replicas.min(nodes.len());
+        let replicas: Vec<Vec<ClusterNode>> = (0..shards as usize)
+            .map(|shard| {
+                (0..count)
+                    .map(|i| nodes[(shard + i) % nodes.len()].clone())
+                    .collect()
+            })
+            .collect();
+
+        let now = Instant::now();
+        let mut groups = HashMap::new();
+        for (id, members) in replicas.iter().enumerate() {
+            if !members.iter().any(|node| node._id == me) {
+                continue;
+            }
+
+            let peers: Vec<ClusterNode> = members
+                .iter()
+                .filter(|node| node._id != me)
+                .cloned()
+                .collect();
+            let ids = peers.iter().map(|node| node._id.clone()).collect();
+            groups.insert(
+                id as u32,
+                Arc::new(Shard {
+                    id: id as u32,
+                    peers,
+                    group: Mutex::new(Group::new(me, ids, now)),
+                    changed: Condvar::new(),
+                    journal: Mutex::new(Journal::default()),
+                    restored: Mutex::new(Vec::new()),
+                }),
+            );
+        }
+
+        Raft {
+            me: me.to_string(),
+            keyspaces,
+            shards,
+            snapshot_entries,
+            replicas,
+            groups,
+        }
+    }
+
+    // like `new`, keeping the state of every shard in the journal `<path>.<shard>`
+    pub fn open(
+        me: &str,
+        nodes: Vec<ClusterNode>,
+        keyspaces: Vec<String>,
+        shards: u32,
+        replicas: usize,
+        snapshot_entries: usize,
+        path: &str,
+    ) -> io::Result<Raft> {
+        let raft = Raft::new(me, nodes, keyspaces, shards, replicas, snapshot_entries);
+        for (id, shard) in &raft.groups {
+            let (journal, mut saved) = Journal::open(&format!("{}.{}", path, id))?;
+            *shard.restored.lock().unwrap() = std::mem::take(&mut saved.snapshot);
+            shard.group.lock().unwrap().restore(saved);
+            *shard.journal.lock().unwrap() = journal;
+        }
+        Ok(raft)
+    }
+
+    pub fn consistent(&self, key: &str) -> bool {
+        self.keyspaces.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    // shards split the hash space in equal token ranges
+    pub fn shard_of(&self, key: &str) -> u32 {
+        ((HashRing::hash(key) as u64 * self.shards as u64) >> 32) as u32
+    }
+
+    pub fn replicas(&self, shard: u32) -> &[ClusterNode] {
+        self.replicas
+            .get(shard as usize)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    // keys of consistent keyspaces are stored by every replica of their shard, ranges and scans
+    // list them on the first replica only
+    pub fn lists(&self, key: &str) -> bool {
+        !self.consistent(key)
+            || self
+                .replicas(self.shard_of(key))
+                .first()
+                .is_some_and(|node| node._id == self.me)
+    }
+}
+
+// a random election timeout, so replicas rarely time out together
+fn election_timeout(me: &str) -> Duration {
+    let mut hasher = DefaultHasher::new();
+    (me, Instant::now(), std::thread::current().id()).hash(&mut hasher);
+    let jitter = hasher.finish() % ELECTION_TIMEOUT.as_millis() as u64;
+    ELECTION_TIMEOUT + Duration::from_millis(jitter)
+}
+
+// runs a replica for every shard of this node, starting from the keys of its snapshot
+pub fn start(ctx: Arc<NodeContext>) {
+    for shard in ctx.raft.groups.values() {
+        let restored = std::mem::take(&mut *shard.restored.lock().unwrap());
+        if !restored.is_empty()
+            && let Err(e) = ctx.storage.lock().unwrap().restore(restored)
+        {
+            eprintln!(
+                "Failed to restore the snapshot of shard {}: {}",
+                shard.id, e
+            );
+        }
+
+        let (ctx, shard) = (ctx.clone(), shard.clone());
+        std::thread::spawn(move || run(&ctx, &shard));
+    }
+}
+
+// elects a leader when none is heard of, replicates the log of a leader and applies committed
+// entries to the storage
+fn run(ctx: &NodeContext, shard: &Shard) {
+    loop {
+        let now = Instant::now();
+        let mut group = shard.group.lock().unwrap();
+
+        if group.election_due(now) {
+            let vote = group.start_election(shard.id, now);
+            log(
+                &format!(
+                    "Starting an election for shard {} in term {}",
+                    shard.id, group.term
+                ),
+                ctx.log_enabled,
+            );
+            // a vote for itself that is not saved could go to another candidate after a restart
+            let saved = shard.save(&mut group);
+            drop(group);
+            if saved.is_ok() {
+                request_votes(ctx, shard, vote);
+            }
+        } else if group.replication_due(now) {
+            drop(group);
+            replicate(ctx, shard);
+        } else {
+            drop(shard.changed.wait_timeout(group, TICK).unwrap());
+        }
+
+        apply_committed(ctx, shard);
+    }
+}
+
+fn request_votes(ctx: &NodeContext, shard: &Shard, vote: RaftMessage) {
+    let requests = shard
+        .peers
+        .iter()
+        .map(|peer| (peer.clone(), Command::Raft(vote.clone())))
+        .collect();
+    let replies = send_all(ctx, requests);
+
+    let mut group = shard.group.lock().unwrap();
+    for (peer, reply) in replies {
+        if let &[term, granted] = numbers(&reply).as_slice() {
+            group.on_vote(&peer, term, granted == 1);
+        }
+    }
+    let _ = shard.save(&mut group);
+
+    if group.role == Role::Leader {
+        log(
+            &format!(
+                "Elected leader of shard {} in term {}",
+                shard.id, group.term
+            ),
+            ctx.log_enabled,
+        );
+    }
+    shard.changed.notify_all();
+}
+
+// sends every peer the entries it is missing, or a snapshot; answers the replicas, this one
+// included, that acknowledged the leader in the term the round started in
+fn replicate(ctx: &NodeContext, shard: &Shard) -> usize {
+    let (term, snapshots, requests) = {
+        let mut group = shard.group.lock().unwrap();
+        if group.role != Role::Leader {
+            return 0;
+        }
+        group.replication_started(Instant::now());
+
+        let mut snapshots = HashMap::new();
+        let mut requests = Vec::new();
+        for peer in &shard.peers {
+            let message = match group.append_for(shard.id, &peer._id) {
+                Some(append) => append,
+                None => {
+                    let snapshot = snapshot(ctx, shard.id, &group);
+                    snapshots.insert(peer._id.clone(), group.last_applied);
+                    snapshot
+                }
+            };
+            requests.push((peer.clone(), Command::Raft(message)));
+        }
+        (group.term, snapshots, requests)
+    };
+
+    let replies = send_all(ctx, requests);
+
+    let mut group = shard.group.lock().unwrap();
+    let mut acknowledged = 1;
+    for (peer, reply) in replies {
+        match (numbers(&reply).as_slice(), snapshots.get(&peer)) {
+            (&[reply_term], Some(&last_index)) => {
+                group.on_install(&peer, reply_term, last_index);
+                acknowledged += usize::from(reply_term == term);
+            }
+            (&[reply_term, success, matched], None) => {
+                group.on_append(&peer, reply_term, success == 1, matched);
+                acknowledged += usize::from(reply_term == term && success == 1);
+            }
+            _ => {}
+        }
+    }
+    let _ = shard.save(&mut group);
+    shard.changed.notify_all();
+
+    if group.term == term { acknowledged } else { 0 }
+}
+
+// the entries of the shard as applied up to the last applied index, taken under the group lock
+// so nothing is applied meanwhile
+fn snapshot(ctx: &NodeContext, shard: u32, group: &Group) -> RaftMessage {
+    let storage = ctx.storage.lock().unwrap();
+    RaftMessage::Snapshot {
+        shard,
+        term: group.term,
+        leader: group.me.clone(),
+        last_index: group.last_applied,
+        last_term: group.term_at(group.last_applied).unwrap_or_default(),
+        entries: shard_entries(&ctx.raft, storage.as_ref(), shard),
+    }
+}
+
+// the keys of the shard with their expirations
+fn shard_entries(raft: &Raft, storage: &dyn Storage, shard: u32) -> Vec<StoredEntry> {
+    let mut entries = BTreeMap::new();
+    for prefix in &raft.keyspaces {
+        let Ok((keys, _)) = storage.scan(None, prefix, None, usize::MAX) else {
+            continue;
+        };
+        for key in keys.into_iter().filter(|key| raft.shard_of(key) == shard) {
+            if let Some(entry) = storage
+                .read_entries(&key, &key)
+                .ok()
+                .and_then(|mut e| e.pop())
+            {
+                entries.insert(key, entry);
+            }
+        }
+    }
+    entries.into_values().collect()
+}
+
+// sends the messages to their replicas at once, answers the replies by replica id
+fn send_all(ctx: &NodeContext, requests: Vec<(ClusterNode, Command)>) -> Vec<(String, String)> {
+    let deadline = Instant::now() + MESSAGE_TIMEOUT;
+    let credential = || ctx.auth.node_credential(&ctx.me_id);
+
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = requests
+            .into_iter()
+            .map(|(node, cmd)| {
+                scope.spawn(move || {
+                    let reply = ctx
+                        .peers
+                        .request(&node, &cmd, Some(deadline), ctx.tls.as_deref(), credential)
+                        .unwrap_or_else(|e| e.response());
+                    (node._id, reply)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .filter_map(|handle| handle.join().ok())
+            .collect()
+    })
+}
+
+// the numbers of a reply, none for an error
+fn numbers(reply: &str) -> Vec<u64> {
+    reply
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .unwrap_or_default()
+}
+
+fn apply_committed(ctx: &NodeContext, shard: &Shard) {
+    let mut group = shard.group.lock().unwrap();
+    let entries = group.committed();
+    if entries.is_empty() {
+        return;
+    }
+
+    let mut storage = ctx.storage.lock().unwrap();
+    for (index, entry) in entries {
+        if let Some(cmd) = &entry.command {
+            let answer = networking::apply(storage.as_mut(), cmd);
+            group.answer(index, entry.term, answer);
+        }
+    }
+
+    if group.compact(ctx.raft.snapshot_entries) {
+        let snapshot = shard_entries(&ctx.raft, storage.as_ref(), shard.id);
+        let _ = shard.journal.lock().unwrap().rewrite(&mut group, &snapshot);
+    }
+    drop(storage);
+    shard.changed.notify_all();
+}
+
+// runs a single key command of a consistent keyspace on the leader of its shard
+pub fn execute(ctx: &NodeContext, cmd: Command) -> String {
+    let Some(key) = cmd.key() else {
+        return Error::BadRequest(format!("{} has no key", cmd.name())).response();
+    };
+
+    // versions are counted by every replica on its own
+    let versioned = match &cmd {
+        Command::ReadVersion(_) => true,
+        Command::PutIfVersion(_, _, version) | Command::UpdateIfVersion(_, _, version) => {
+            *version != 0
+        }
+        Command::PutEx(_, _, _, version) => version.is_some_and(|version| version != 0),
+        _ => false,
+    };
+    if versioned {
+        return Error::BadRequest(
+            "Versions are not available in consistent keyspaces, use CAS".to_string(),
+        )
+        .response();
+    }
+
+    let shard = ctx.raft.shard_of(key);
+    if let Some(answer) = lead(ctx, shard, &cmd) {
+        return answer;
+    }
+
+    // the leader known to this replica is asked first, then the other replicas
+    let leader = ctx
+        .raft
+        .groups
+        .get(&shard)
+        .and_then(|shard| shard.group.lock().unwrap().leader.clone());
+    let mut replicas: Vec<ClusterNode> = ctx
+        .raft
+        .replicas(shard)
+        .iter()
+        .filter(|node| node._id != ctx.me_id)
+        .cloned()
+        .collect();
+    replicas.sort_by_key(|node| leader.as_ref() != Some(&node._id));
+
+    // a write sent to a replica that could not be reached may have run, only a replica saying it
+    // does not lead has certainly not run it
+    let retry = |response: &str| {
+        response == not_leader(shard).response()
+            || (cmd.is_idempotent()
+                && matches!(
+                    networking::parse_reply(response.to_string()),
+                    Err(Error::Unavailable(_))
+                ))
+    };
+    for node in replicas {
+        let submit = Command::Raft(RaftMessage::Submit(shard, Box::new(cmd.clone())));
+        let response = networking::forward_command(submit, node, None, ctx);
+        if !retry(&response) {
+            return response;
+        }
+    }
+
+    Error::Unavailable(format!("No leader for shard {}", shard)).response()
+}
+
+fn not_leader(shard: u32) -> Error {
+    Error::Unavailable(format!("Not the leader of shard {}", shard))
+}
+
+// answers the messages of the other replicas of a shard
+pub fn handle(ctx: &NodeContext, message: RaftMessage) -> String {
+    let shard_id = match &message {
+        RaftMessage::Vote { shard, .. }
+        | RaftMessage::Append { shard, .. }
+        | RaftMessage::Snapshot { shard, .. }
+        | RaftMessage::Submit(shard, _) => *shard,
+    };
+    let Some(shard) = ctx.raft.groups.get(&shard_id) else {
+        return Error::NotFound(format!("Not a replica of shard {}", shard_id)).response();
+    };
+    let now = Instant::now();
+
+    match message {
+        RaftMessage::Vote {
+            term,
+            candidate,
+            last_index,
+            last_term,
+            ..
+        } => {
+            let mut group = shard.group.lock().unwrap();
+            let (term, granted) = group.handle_vote(term, &candidate, last_index, last_term, now);
+            if let Err(e) = shard.save(&mut group) {
+                return e.response();
+            }
+            format!("{} {}\n", term, u8::from(granted))
+        }
+        RaftMessage::Append {
+            term,
+            leader,
+            prev_index,
+            prev_term,
+            commit,
+            entries,
+            ..
+        } => {
+            let mut group = shard.group.lock().unwrap();
+            let (term, success, matched) =
+                group.handle_append(term, &leader, prev_index, prev_term, commit, entries, now);
+            if let Err(e) = shard.save(&mut group) {
+                return e.response();
+            }
+            shard.changed.notify_all();
+            format!("{} {} {}\n", term, u8::from(success), matched)
+        }
+        RaftMessage::Snapshot {
+            term,
+            leader,
+            last_index,
+            last_term,
+            entries,
+            ..
+        } => {
+            let mut group = shard.group.lock().unwrap();
+            let (term, install) = group.install(term, &leader, last_index, last_term, now);
+            let saved = match install {
+                true => shard.journal.lock().unwrap().rewrite(&mut group, &entries),
+                false => shard.save(&mut group),
+            };
+            if let Err(e) = saved {
+                return e.response();
+            }
+            if install {
+                let mut storage = ctx.storage.lock().unwrap();
+                for entry in shard_entries(&ctx.raft, storage.as_ref(), shard_id) {
+                    let _ = storage.delete(&entry.key);
+                }
+                if let Err(e) = storage.restore(entries) {
+                    return e.response();
+                }
+                log(
+                    &format!(
+                        "Installed the snapshot of shard {} up to entry {}",
+                        shard_id, last_index
+                    ),
+                    ctx.log_enabled,
+                );
+            }
+            format!("{}\n", term)
+        }
+        RaftMessage::Submit(_, cmd) => {
+            lead(ctx, shard_id, &cmd).unwrap_or_else(|| not_leader(shard_id).response())
+        }
+    }
+}
+
+// runs the command if this node leads the shard, None otherwise
+fn lead(ctx: &NodeContext, shard_id: u32, cmd: &Command) -> Option<String> {
+    let shard = ctx.raft.groups.get(&shard_id)?;
+    if matches!(cmd, Command::Read(_) | Command::StrLen(_)) {
+        return read(ctx, shard, cmd);
+    }
+
+    let mut group = shard.group.lock().unwrap();
+    let (index, term) = group.propose(cmd.clone())?;
+    // the leader counts its own entry towards the quorum once it is saved
+    if let Err(e) = shard.save(&mut group) {
+        group.waiting.remove(&(index, term));
+        return Some(e.response());
+    }
+    shard.changed.notify_all();
+
+    let deadline = Instant::now() + COMMIT_TIMEOUT;
+    loop {
+        if let Some(answer) = group.answers.remove(&(index, term)) {
+            return Some(answer);
+        }
+        // another leader replaced the entry before it was committed
+        if group.last_applied >= index {
+            group.waiting.remove(&(index, term));
+            return Some(
+                Error::Unavailable(format!(
+                    "Leadership of shard {} changed, the command was not run",
+                    shard_id
+                ))
+                .response(),
+            );
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            group.waiting.remove(&(index, term));
+            return Some(
+                Error::Timeout(format!(
+                    "The command was not committed in time by shard {}, it may still run",
+                    shard_id
+                ))
+                .response(),
+            );
+        }
+        group = shard.changed.wait_timeout(group, deadline - now).unwrap().0;
+    }
+}
+
+// a linearizable read: the leader notes its commit index, confirms with a quorum that it still
+// leads, and reads once the entries up to that index are applied
+fn read(ctx: &NodeContext, shard: &Shard, cmd: &Command) -> Option<String> {
+    let deadline = Instant::now() + COMMIT_TIMEOUT;
+    let not_in_time =
+        || Some(Error::Timeout(format!("Shard {} did not commit in time", shard.id)).response());
+
+    let mut group = shard.group.lock().unwrap();
+    let (read_index, quorum) = loop {
+        if group.role != Role::Leader {
+            return None;
+        }
+        // the commit index is current once the leader committed an entry of its own term
+        if group.term_at(group.commit_index) == Some(group.term) {
+            break (group.commit_index, group.quorum());
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return not_in_time();
+        }
+        group = shard.changed.wait_timeout(group, deadline - now).unwrap().0;
+    };
+    drop(group);
+
+    if replicate(ctx, shard) < quorum {
+        return Some(
+            Error::Unavailable(format!("Leadership of shard {} not confirmed", shard.id))
+                .response(),
+        );
+    }
+
+    let mut group = shard.group.lock().unwrap();
+    while group.last_applied < read_index {
+        let now = Instant::now();
+        if now >= deadline {
+            return not_in_time();
+        }
+        group = shard.changed.wait_timeout(group, deadline - now).unwrap().0;
+    }
+    drop(group);
+
+    let mut storage = ctx.storage.lock().unwrap();
+    Some(networking::apply(storage.as_mut(), cmd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(key: &str, value: &str) -> Command {
+        Command::Put(key.to_string(), value.as_bytes().to_vec())
+    }
+
+    fn entry(key: &str, value: &str, expires_in: Option<Duration>) -> StoredEntry {
+        StoredEntry {
+            key: key.to_string(),
+            value: value.as_bytes().to_vec(),
+            version: 1,
+            expires_in,
+        }
+    }
+
+    // delivers the appends of the leader to the other groups until they have its log
+    fn replicate_to(leader: &mut Group, followers: &mut [&mut Group], now: Instant) {
+        for follower in followers {
+            for _ in 0..10 {
+                let Some(RaftMessage::Append {
+                    term,
+                    leader: id,
+                    prev_index,
+                    prev_term,
+                    commit,
+                    entries,
+                    ..
+                }) = leader.append_for(0, &follower.me)
+                else {
+                    panic!("entries missing from the log");
+                };
+                let (term, success, matched) =
+                    follower.handle_append(term, &id, prev_index, prev_term, commit, entries, now);
+                leader.on_append(&follower.me, term, success, matched);
+                if success {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_election_and_replication() {
+        let now = Instant::now();
+        let mut a = Group::new("1", vec!["2".into(), "3".into()], now);
+        let mut b = Group::new("2", vec!["1".into(), "3".into()], now);
+        let mut c = Group::new("3", vec!["1".into(), "2".into()], now);
+
+        let Some(RaftMessage::Vote {
+            term,
+            candidate,
+            last_index,
+            last_term,
+            ..
+        }) = Some(a.start_election(0, now))
+        else {
+            unreachable!();
+        };
+        let (reply_term, granted) = b.handle_vote(term, &candidate, last_index, last_term, now);
+        assert!(granted);
+        // one vote per term
+        assert!(!b.handle_vote(term, "3", last_index, last_term, now).1);
+
+        a.on_vote("2", reply_term, granted);
+        assert_eq!(a.role, Role::Leader);
+        assert_eq!(a.commit_index, 0);
+
+        let (index, term) = a.propose(put("k", "v")).unwrap();
+        assert_eq!((index, term), (2, 1));
+        assert!(b.propose(put("k", "w")).is_none());
+
+        replicate_to(&mut a, &mut [&mut b], now);
+        assert_eq!(a.commit_index, 2);
+        let applied = a.committed();
+        assert_eq!(applied.len(), 2);
+        assert!(applied[0].1.command.is_none());
+        a.answer(index, term, "OK\n".into());
+        assert_eq!(a.answers.remove(&(index, term)), Some("OK\n".into()));
+
+        // the commit index reaches the followers with the next append
+        replicate_to(&mut a, &mut [&mut b, &mut c], now);
+        assert_eq!(b.commit_index, 2);
+        assert_eq!(c.last_index(), 2);
+        assert_eq!(b.leader.as_deref(), Some("1"));
+
+        // a candidate with a shorter log gets no votes
+        let mut stale = Group::new("3", vec!["1".into(), "2".into()], now);
+        let RaftMessage::Vote {
+            term, last_index, ..
+        } = stale.start_election(0, now)
+        else {
+            unreachable!();
+        };
+        assert!(!b.handle_vote(term + 1, "3", last_index, 0, now).1);
+    }
+
+    #[test]
+    fn test_conflicting_entries_are_replaced() {
+        let now = Instant::now();
+        let mut follower = Group::new("2", vec!["1".into(), "3".into()], now);
+
+        // entries of a leader of term 1 that were never committed
+        let stale = vec![
+            LogEntry {
+                term: 1,
+                command: Some(put("k", "1")),
+            },
+            LogEntry {
+                term: 1,
+                command: Some(put("k", "2")),
+            },
+        ];
+        assert_eq!(
+            follower.handle_append(1, "1", 0, 0, 0, stale, now),
+            (1, true, 2)
+        );
+
+        // the leader of term 2 does not know entry 2, the follower points it back
+        assert_eq!(
+            follower.handle_append(2, "3", 2, 2, 0, vec![], now),
+            (2, false, 1)
+        );
+        let replacing = vec![LogEntry {
+            term: 2,
+            command: Some(put("k", "3")),
+        }];
+        assert_eq!(
+            follower.handle_append(2, "3", 1, 1, 2, replacing, now),
+            (2, true, 2)
+        );
+        assert_eq!(follower.term_at(2), Some(2));
+        assert_eq!(follower.commit_index, 2);
+
+        // a leader of an older term is refused
+        assert_eq!(
+            follower.handle_append(1, "1", 2, 2, 2, vec![], now),
+            (2, false, 2)
+        );
+    }
+
+    #[test]
+    fn test_compaction_and_snapshots() {
+        let now = Instant::now();
+        let mut follower = Group::new("2", vec!["1".into()], now);
+        let entries = (0..5)
+            .map(|i| LogEntry {
+                term: 1,
+                command: Some(put("k", &i.to_string())),
+            })
+            .collect();
+        follower.handle_append(1, "1", 0, 0, 5, entries, now);
+        assert_eq!(follower.committed().len(), 5);
+
+        follower.compact(2);
+        assert_eq!(follower.last_index(), 5);
+        assert_eq!(follower.term_at(5), Some(1));
+        assert_eq!(follower.term_at(3), None);
+
+        // entries before the snapshot are skipped, later ones appended
+        let resent = (0..2)
+            .map(|_| LogEntry {
+                term: 1,
+                command: None,
+            })
+            .collect();
+        assert_eq!(
+            follower.handle_append(1, "1", 4, 1, 6, resent, now),
+            (1, true, 6)
+        );
+
+        // a snapshot ahead of the log replaces it
+        assert_eq!(follower.install(2, "1", 10, 2, now), (2, true));
+        assert_eq!(follower.last_index(), 10);
+        assert_eq!(follower.last_applied, 10);
+        assert!(follower.committed().is_empty());
+        assert_eq!(follower.install(2, "1", 8, 2, now), (2, false));
+    }
+
+    #[test]
+    fn test_journal_recovery() {
+        let path = std::env::temp_dir().join(format!("kava-test-{}.raft", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        let now = Instant::now();
+
+        let (mut journal, saved) = Journal::open(path).unwrap();
+        assert_eq!((saved.term, saved.log.len()), (0, 0));
+        let mut leader = Group::new("1", vec!["2".into()], now);
+        leader.start_election(0, now);
+        journal.save(&mut leader).unwrap();
+        leader.on_vote("2", 1, true);
+        leader.propose(put("k", "v")).unwrap();
+        journal.save(&mut leader).unwrap();
+        drop(journal);
+
+        let (mut journal, saved) = Journal::open(path).unwrap();
+        assert_eq!((saved.term, saved.voted_for.as_deref()), (1, Some("1")));
+        assert_eq!(saved.log.len(), 2);
+        assert!(matches!(&saved.log[1].command, Some(Command::Put(k, _)) if k == "k"));
+
+        // the restarted replica follows a new leader replacing its entry
+        let mut follower = Group::new("1", vec!["2".into()], now);
+        follower.restore(saved);
+        let replacing = vec![LogEntry {
+            term: 2,
+            command: Some(put("k", "w")),
+        }];
+        assert_eq!(
+            follower.handle_append(2, "2", 1, 1, 2, replacing, now),
+            (2, true, 2)
+        );
+        journal.save(&mut follower).unwrap();
+        drop(journal);
+
+        let (mut journal, saved) = Journal::open(path).unwrap();
+        assert_eq!((saved.term, saved.voted_for.as_deref()), (2, None));
+        assert_eq!(saved.log[1].term, 2);
+
+        // a compacted log is saved as the keys it was applied to
+        follower.restore(saved);
+        follower.commit_index = 2;
+        assert_eq!(follower.committed().len(), 2);
+        assert!(follower.compact(0));
+        journal
+            .rewrite(&mut follower, &[entry("k", "w", None)])
+            .unwrap();
+        drop(journal);
+
+        // a line torn by a crash is dropped
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(b"ENTRY 3 2 \"PUT").unwrap();
+        let (_, saved) = Journal::open(path).unwrap();
+        assert_eq!((saved.snapshot_index, saved.snapshot_term), (2, 2));
+        assert_eq!(saved.snapshot, [entry("k", "w", None)]);
+        assert!(saved.log.is_empty());
+        assert_eq!(fs::read_to_string(path).unwrap().lines().count(), 2);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshots_keep_expirations() {
+        let path = std::env::temp_dir().join(format!("kava-test-{}.ttl.raft", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        let now = Instant::now();
+
+        let mut leader = crate::storage::StorageBuilder::builder("memory").build();
+        leader.put("k", b"v".to_vec()).unwrap();
+        leader.put("kept", b"v".to_vec()).unwrap();
+        leader.expire("k", Duration::from_millis(300)).unwrap();
+
+        // sent to a follower
+        let snapshot = Command::Raft(RaftMessage::Snapshot {
+            shard: 0,
+            term: 1,
+            leader: "1".into(),
+            last_index: 2,
+            last_term: 1,
+            entries: leader.read_entries("k", "kept").unwrap(),
+        });
+        let Ok(Command::Raft(RaftMessage::Snapshot { entries, .. })) =
+            Command::try_from(snapshot.to_string().as_str())
+        else {
+            panic!("unexpected {}", snapshot);
+        };
+        let mut follower = crate::storage::StorageBuilder::builder("memory").build();
+        follower.restore(entries.clone()).unwrap();
+
+        // and journaled by a compacting replica
+        let (mut journal, _) = Journal::open(path).unwrap();
+        let mut group = Group::new("1", vec!["2".into()], now);
+        journal.rewrite(&mut group, &entries).unwrap();
+        drop(journal);
+        let (_, saved) = Journal::open(path).unwrap();
+        let mut restarted = crate::storage::StorageBuilder::builder("memory").build();
+        restarted.restore(saved.snapshot).unwrap();
+
+        for storage in [&follower, &restarted] {
+            assert_eq!(storage.read("k").unwrap(), b"v");
+        }
+        std::thread::sleep(Duration::from_millis(400));
+        for storage in [&follower, &restarted] {
+            assert_eq!(storage.read("k"), Err(Error::not_found()));
+            assert_eq!(storage.read("kept").unwrap(), b"v");
+        }
+
+        // a key that expired while the replica was down is not restored
+        let (_, saved) = Journal::open(path).unwrap();
+        assert_eq!(saved.snapshot.len(), 1);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_shards_and_replicas() {
+        let nodes: Vec<ClusterNode> = ["3", "1", "2"]
+            .iter()
+            .map(|id| ClusterNode {
+                _id: id.to_string(),
+                host: "localhost".into(),
+                port: "0".into(),
+                gossip_port: "0".into(),
+            })
+            .collect();
+        let raft = Raft::new("1", nodes, vec!["acct:".into()], 4, 2, 100);
+
+        let ids = |shard| -> Vec<&str> {
+            raft.replicas(shard)
+                .iter()
+                .map(|node| node._id.as_str())
+                .collect()
+        };
+        assert_eq!(ids(0), ["1", "2"]);
+        assert_eq!(ids(2), ["3", "1"]);
+        // node 1 replicates shards 0, 2 and 3
+        assert_eq!(raft.groups.len(), 3);
+        assert!(!raft.groups.contains_key(&1));
+
+        assert!(raft.consistent("acct:1") && !raft.consistent("user:1"));
+        assert!(raft.shard_of("acct:1") < 4);
+        assert!(raft.lists("user:1"));
+    }
+}
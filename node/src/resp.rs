@@ -0,0 +1,665 @@
+use std::{
+    collections::HashSet,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, atomic::Ordering},
+};
+
+use crate::{
+    auth::Identity,
+    commands::{Command, Scan, Scope},
+    error::Error,
+    log::log,
+    networking::{
+        self, NodeContext, POOLED_CLIENT_READ_TIMEOUT, invalid_data, parse_reply, parse_value,
+        read_exactly,
+    },
+};
+
+// upper bound for a single bulk string or the number of arguments of a request
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const MAX_ARGS: usize = 1024 * 1024;
+// an inline command or the header of a request or bulk string, as in Redis
+const MAX_INLINE_LEN: usize = 64 * 1024;
+
+// a reply in the RESP data model, RESP2 has no null and map types of its own
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+impl Value {
+    fn encode(&self, protocol: u8, out: &mut Vec<u8>) {
+        match self {
+            Value::Simple(s) => out.extend(format!("+{}\r\n", single_line(s)).as_bytes()),
+            Value::Error(e) => out.extend(format!("-{}\r\n", single_line(e)).as_bytes()),
+            Value::Integer(i) => out.extend(format!(":{}\r\n", i).as_bytes()),
+            Value::Bulk(s) => {
+                out.extend(format!("${}\r\n", s.len()).as_bytes());
+                out.extend(s);
+                out.extend(b"\r\n");
+            }
+            Value::Null if protocol >= 3 => out.extend(b"_\r\n"),
+            Value::Null => out.extend(b"$-1\r\n"),
+            Value::Array(items) => {
+                out.extend(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(protocol, out);
+                }
+            }
+            Value::Map(pairs) => {
+                if protocol >= 3 {
+                    out.extend(format!("%{}\r\n", pairs.len()).as_bytes());
+                } else {
+                    out.extend(format!("*{}\r\n", pairs.len() * 2).as_bytes());
+                }
+                for (k, v) in pairs {
+                    k.encode(protocol, out);
+                    v.encode(protocol, out);
+                }
+            }
+        }
+    }
+}
+
+// simple strings and errors end at the first line break, so line breaks of the text, which may
+// echo what the client sent, become spaces
+fn single_line(s: &str) -> String {
+    s.replace(['\r', '\n'], " ")
+}
+
+// per connection state of a Redis client
+struct RespSession {
+    protocol: u8,
+    identity: Identity,
+}
+
+pub fn start_resp(host: &str, port: u16, max_connections: usize, ctx: Arc<NodeContext>) {
+    let addr = format!("{}:{}", host, port);
+    let listener = TcpListener::bind(&addr).expect("Failed to bind RESP address");
+
+    log(
+        &format!("RESP listener started on {}", addr),
+        ctx.log_enabled,
+    );
+
+    std::thread::spawn(move || {
+        networking::serve(listener, max_connections, ctx, handle_connection, |e| {
+            format!("-ERR {}\r\n", e).into_bytes()
+        });
+    });
+}
+
+fn handle_connection(tcp_stream: TcpStream, ctx: &NodeContext) {
+    let _ = tcp_stream.set_read_timeout(Some(POOLED_CLIENT_READ_TIMEOUT));
+
+    let Some(stream) = networking::accept_client(tcp_stream, ctx) else {
+        return;
+    };
+
+    // responses are collected and written once no pipelined request is pending
+    let mut reader = BufReader::new(stream);
+    let mut pending = Vec::new();
+    let mut session = RespSession {
+        protocol: 2,
+        identity: Identity::Anonymous,
+    };
+
+    loop {
+        let args = match read_request(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(e) => {
+                let mut out = Vec::new();
+                Value::Error(format!("ERR Protocol error: {}", e))
+                    .encode(session.protocol, &mut out);
+                let _ = pending.write_all(&out);
+                break;
+            }
+        };
+
+        if args.is_empty() {
+            continue;
+        }
+
+        ctx.request_count.fetch_add(1, Ordering::Relaxed);
+        log(
+            &format!(
+                "Received RESP command: {:?}",
+                args.iter()
+                    // keep credentials out of the log
+                    .take(if args[0].eq_ignore_ascii_case(b"AUTH") {
+                        1
+                    } else {
+                        args.len()
+                    })
+                    .map(|a| String::from_utf8_lossy(a))
+                    .collect::<Vec<_>>()
+            ),
+            ctx.log_enabled,
+        );
+
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        let reply = dispatch(&args, &mut session, ctx);
+
+        let mut out = Vec::new();
+        reply.encode(session.protocol, &mut out);
+        if pending.write_all(&out).is_err() || quit {
+            break;
+        }
+
+        // pipelined requests are answered in one go, flush once no request is pending
+        if reader.buffer().is_empty() && networking::flush(reader.get_mut(), &mut pending).is_err()
+        {
+            break;
+        }
+    }
+
+    networking::close(reader.get_mut(), &mut pending);
+}
+
+// reads one request: an array of bulk strings, or an inline command as typed into telnet
+fn read_request(reader: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = networking::read_line(reader, MAX_INLINE_LEN)? else {
+        return Ok(None);
+    };
+
+    let line = line.trim_end_matches(['\r', '\n']);
+
+    let Some(count) = line.strip_prefix('*') else {
+        return Ok(Some(
+            line.split_whitespace()
+                .map(|a| a.as_bytes().to_vec())
+                .collect(),
+        ));
+    };
+
+    let count = parse_len(count, MAX_ARGS)?;
+    let mut args = Vec::new();
+
+    for _ in 0..count {
+        let header = networking::read_line(reader, MAX_INLINE_LEN)?.unwrap_or_default();
+
+        let len = header
+            .trim_end_matches(['\r', '\n'])
+            .strip_prefix('$')
+            .ok_or_else(|| invalid_data("expected bulk string"))
+            .and_then(|len| parse_len(len, MAX_BULK_LEN))?;
+
+        let data = read_exactly(reader, len + 2)?;
+        if !data.ends_with(b"\r\n") {
+            return Err(invalid_data("expected CRLF after bulk string"));
+        }
+
+        args.push(data[..len].to_vec());
+    }
+
+    Ok(Some(args))
+}
+
+fn parse_len(s: &str, max: usize) -> io::Result<usize> {
+    match s.parse::<usize>() {
+        Ok(len) if len <= max => Ok(len),
+        _ => Err(invalid_data("invalid length")),
+    }
+}
+
+fn dispatch(args: &[Vec<u8>], session: &mut RespSession, ctx: &NodeContext) -> Value {
+    command(args, session, ctx).unwrap_or_else(|e| e)
+}
+
+fn command(args: &[Vec<u8>], session: &mut RespSession, ctx: &NodeContext) -> Result<Value, Value> {
+    let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+    let args = &args[1..];
+
+    // like Redis only the handshake is answered before the client authenticated
+    if ctx.auth.enabled()
+        && session.identity == Identity::Anonymous
+        && !matches!(name.as_str(), "AUTH" | "HELLO" | "QUIT")
+    {
+        return Err(Value::Error("NOAUTH Authentication required.".into()));
+    }
+    let identity = session.identity.clone();
+
+    Ok(match (name.as_str(), args) {
+        ("PING", []) => Value::Simple("PONG".into()),
+        ("PING", [message]) | ("ECHO", [message]) => Value::Bulk(message.clone()),
+        ("HELLO", rest) => hello(rest, session, ctx),
+        // a single argument is the password of the default user
+        ("AUTH", [password]) => auth(b"default", password, session, ctx),
+        ("AUTH", [user, password]) => auth(user, password, session, ctx),
+        ("QUIT", []) => Value::Simple("OK".into()),
+        ("SELECT", [db]) if db == b"0" => Value::Simple("OK".into()),
+        ("SELECT", [_]) => Value::Error("ERR DB index is out of range".into()),
+        // client libraries announce themselves and fetch command docs on connect
+        ("CLIENT", [_, ..]) => Value::Simple("OK".into()),
+        ("COMMAND", _) => Value::Array(Vec::new()),
+
+        ("GET", [key]) => match parse_value(networking::execute_as(
+            &identity,
+            Command::Read(text(key)?),
+            ctx,
+        )) {
+            Ok(Some(value)) => Value::Bulk(value),
+            Ok(None) => Value::Null,
+            Err(e) => error(e),
+        },
+
+        ("SET", [key, value, options @ ..]) => set(&text(key)?, value, options, &identity, ctx),
+
+        ("APPEND", [key, suffix]) => {
+            integer(Command::Append(text(key)?, suffix.clone()), &identity, ctx)
+        }
+        ("GETSET", [key, value]) => {
+            bulk(Command::GetSet(text(key)?, value.clone()), &identity, ctx)
+        }
+        ("GETDEL", [key]) => bulk(Command::GetDel(text(key)?), &identity, ctx),
+        ("STRLEN", [key]) => integer(Command::StrLen(text(key)?), &identity, ctx),
+
+        ("SETNX", [key, value]) => match parse_reply(networking::execute_as(
+            &identity,
+            Command::PutIfVersion(text(key)?, value.clone(), 0),
+            ctx,
+        )) {
+            Ok(_) => Value::Integer(1),
+            Err(Error::Conflict(_)) => Value::Integer(0),
+            Err(e) => error(e),
+        },
+
+        ("DEL", keys) if !keys.is_empty() => {
+            let keys = keys
+                .iter()
+                .map(|key| text(key))
+                .collect::<Result<Vec<_>, _>>()?;
+            let response =
+                networking::execute_as(&identity, Command::BatchDelete(keys.clone()), ctx);
+
+            // a key given twice is deleted once
+            let mut deleted = HashSet::new();
+            for (key, result) in keys.iter().zip(networking::parse_batch(&response, &keys)) {
+                match result {
+                    Ok(()) => {
+                        deleted.insert(key);
+                    }
+                    Err(Error::NotFound(_)) => {}
+                    Err(e) => return Err(error(e)),
+                }
+            }
+            Value::Integer(deleted.len() as i64)
+        }
+
+        ("MSET", pairs) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+            let mut entries = Vec::new();
+            for pair in pairs.chunks_exact(2) {
+                entries.push((text(&pair[0])?, pair[1].clone()));
+            }
+            match parse_reply(networking::execute_as(
+                &identity,
+                Command::BatchPut(entries),
+                ctx,
+            )) {
+                Ok(Some(ok)) if ok == "OK" => Value::Simple("OK".into()),
+                Ok(Some(other)) => Value::Error(format!("ERR {}", other)),
+                Ok(None) => Value::Error("ERR Key not found".into()),
+                Err(e) => error(e),
+            }
+        }
+
+        ("MGET", keys) if !keys.is_empty() => {
+            let keys = keys
+                .iter()
+                .map(|key| text(key))
+                .collect::<Result<Vec<_>, _>>()?;
+            let response = networking::execute_as(&identity, Command::BatchRead(keys.clone()), ctx);
+            if let Err(e) = parse_reply(response.clone()) {
+                return Err(error(e));
+            }
+
+            // keys that are missing or could not be read are nil, as in Redis
+            let values = networking::parse_values(&response, &keys)
+                .into_iter()
+                .map(|result| result.map_or(Value::Null, Value::Bulk))
+                .collect();
+            Value::Array(values)
+        }
+
+        ("EXISTS", keys) if !keys.is_empty() => {
+            let mut found = 0;
+            for key in keys {
+                match parse_reply(networking::execute_as(
+                    &identity,
+                    Command::Read(text(key)?),
+                    ctx,
+                )) {
+                    Ok(Some(_)) => found += 1,
+                    Ok(None) => {}
+                    Err(e) => return Err(error(e)),
+                }
+            }
+            Value::Integer(found)
+        }
+
+        ("EXPIRE", [key, seconds]) => match text(seconds)?.parse::<i64>() {
+            // a non-positive timeout deletes the key right away
+            Ok(seconds) if seconds <= 0 => {
+                match parse_reply(networking::execute_as(
+                    &identity,
+                    Command::Delete(text(key)?),
+                    ctx,
+                )) {
+                    Ok(Some(_)) => Value::Integer(1),
+                    Ok(None) => Value::Integer(0),
+                    Err(e) => error(e),
+                }
+            }
+            Ok(seconds) => match parse_reply(networking::execute_as(
+                &identity,
+                Command::Expire(text(key)?, seconds as u64),
+                ctx,
+            )) {
+                Ok(Some(_)) => Value::Integer(1),
+                Ok(None) => Value::Integer(0),
+                Err(e) => error(e),
+            },
+            Err(_) => Value::Error("ERR value is not an integer or out of range".into()),
+        },
+
+        ("INCR", [key]) => integer(Command::Incr(text(key)?, 1), &identity, ctx),
+        ("DECR", [key]) => integer(Command::Incr(text(key)?, -1), &identity, ctx),
+        ("INCRBY" | "DECRBY", [key, by]) => {
+            let by = text(by)?.parse::<i64>().ok();
+            let by = if name == "DECRBY" {
+                by.and_then(i64::checked_neg)
+            } else {
+                by
+            };
+            match by {
+                Some(by) => integer(Command::Incr(text(key)?, by), &identity, ctx),
+                None => Value::Error("ERR value is not an integer or out of range".into()),
+            }
+        }
+        ("INCRBYFLOAT", [key, by]) => match text(by)?.parse::<f64>() {
+            Ok(by) if by.is_finite() => bulk(Command::IncrFloat(text(key)?, by), &identity, ctx),
+            _ => Value::Error("ERR value is not a valid float".into()),
+        },
+
+        ("SCAN", [cursor, options @ ..]) => {
+            let options = options
+                .iter()
+                .map(|o| text(o))
+                .collect::<Result<Vec<_>, _>>()?;
+            scan(&text(cursor)?, &options, &identity, ctx)
+        }
+
+        _ => Value::Error(format!(
+            "ERR unknown command or wrong number of arguments for '{}'",
+            name.to_lowercase()
+        )),
+    })
+}
+
+// errors are prefixed with their code, ACL and authentication errors as in Redis
+fn error(e: Error) -> Value {
+    let prefix = match e {
+        Error::Forbidden(_) => "NOPERM",
+        Error::Unauthorized(_) => "NOAUTH",
+        Error::BadRequest(_) | Error::Internal(_) => "ERR",
+        // the remaining codes have no Redis counterpart and are passed on as they are
+        _ => e.code(),
+    };
+    Value::Error(format!("{} {}", prefix, e.message()))
+}
+
+// keys and options are text, only values are stored as they are
+fn text(arg: &[u8]) -> Result<String, Value> {
+    String::from_utf8(arg.to_vec()).map_err(|_| Value::Error("ERR keys must be valid UTF-8".into()))
+}
+
+fn auth(user: &[u8], password: &[u8], session: &mut RespSession, ctx: &NodeContext) -> Value {
+    if !ctx.auth.enabled() {
+        return Value::Error("ERR AUTH called without any users configured".into());
+    }
+
+    let user = String::from_utf8_lossy(user);
+    let password = String::from_utf8_lossy(password);
+    match ctx.auth.login(&user, &password) {
+        Ok(identity) => {
+            session.identity = identity;
+            Value::Simple("OK".into())
+        }
+        Err(e) => {
+            // write to the stderr regardless of log setting
+            eprintln!("Failed authentication: {}", e.message());
+            Value::Error("WRONGPASS invalid username-password pair".into())
+        }
+    }
+}
+
+// HELLO [protover [AUTH username password]]
+fn hello(args: &[Vec<u8>], session: &mut RespSession, ctx: &NodeContext) -> Value {
+    let protocol = match args.first().map(Vec::as_slice) {
+        None => session.protocol,
+        Some(b"2") => 2,
+        Some(b"3") => 3,
+        Some(_) => return Value::Error("NOPROTO unsupported protocol version".into()),
+    };
+
+    match args.get(1..).unwrap_or_default() {
+        [] => {}
+        [option, user, password] if option.eq_ignore_ascii_case(b"AUTH") => {
+            if let error @ Value::Error(_) = auth(user, password, session, ctx) {
+                return error;
+            }
+        }
+        _ => return Value::Error("ERR syntax error".into()),
+    }
+
+    if ctx.auth.enabled() && session.identity == Identity::Anonymous {
+        return Value::Error(
+            "NOAUTH HELLO must be called with AUTH <username> <password> before authenticating"
+                .into(),
+        );
+    }
+    session.protocol = protocol;
+
+    Value::Map(vec![
+        (Value::Bulk("server".into()), Value::Bulk("kavadb".into())),
+        (
+            Value::Bulk("version".into()),
+            Value::Bulk(env!("CARGO_PKG_VERSION").into()),
+        ),
+        (
+            Value::Bulk("proto".into()),
+            Value::Integer(session.protocol as i64),
+        ),
+        (
+            Value::Bulk("id".into()),
+            Value::Bulk(ctx.me_id.clone().into()),
+        ),
+        (Value::Bulk("mode".into()), Value::Bulk("standalone".into())),
+        (Value::Bulk("role".into()), Value::Bulk("master".into())),
+        (Value::Bulk("modules".into()), Value::Array(Vec::new())),
+    ])
+}
+
+// SET key value [EX seconds | PX milliseconds]
+fn set(
+    key: &str,
+    value: &[u8],
+    options: &[Vec<u8>],
+    identity: &Identity,
+    ctx: &NodeContext,
+) -> Value {
+    let ttl = match options {
+        [] => None,
+        [unit, amount] => match (
+            String::from_utf8_lossy(unit).to_uppercase().as_str(),
+            String::from_utf8_lossy(amount).parse::<u64>(),
+        ) {
+            ("EX", Ok(seconds)) if seconds > 0 => Some(seconds),
+            ("PX", Ok(millis)) if millis > 0 => Some(millis.div_ceil(1000)),
+            _ => return Value::Error("ERR syntax error".into()),
+        },
+        _ => return Value::Error("ERR syntax error".into()),
+    };
+
+    let put = match ttl {
+        Some(seconds) => Command::PutEx(key.to_string(), value.to_vec(), seconds, None),
+        None => Command::Put(key.to_string(), value.to_vec()),
+    };
+    match parse_reply(networking::execute_as(identity, put, ctx)) {
+        Ok(_) => Value::Simple("OK".into()),
+        Err(e) => error(e),
+    }
+}
+
+// for commands answering a number, such as INCR and STRLEN
+fn integer(cmd: Command, identity: &Identity, ctx: &NodeContext) -> Value {
+    match parse_reply(networking::execute_as(identity, cmd, ctx)) {
+        Ok(Some(value)) => match value.parse() {
+            Ok(value) => Value::Integer(value),
+            Err(_) => Value::Error(format!("ERR {}", value)),
+        },
+        Ok(None) => Value::Error("ERR Key not found".into()),
+        Err(e) => error(e),
+    }
+}
+
+// for commands answering a value, nil for a missing key or a NIL answer
+fn bulk(cmd: Command, identity: &Identity, ctx: &NodeContext) -> Value {
+    match parse_value(networking::execute_as(identity, cmd, ctx)) {
+        Ok(Some(value)) => Value::Bulk(value),
+        Ok(None) => Value::Null,
+        Err(e) => error(e),
+    }
+}
+
+// SCAN cursor [MATCH pattern] [COUNT n], walking the cluster like the text protocol SCAN
+fn scan(cursor: &str, options: &[String], identity: &Identity, ctx: &NodeContext) -> Value {
+    let mut scan = Scan {
+        cursor: cursor.to_string(),
+        pattern: None,
+        prefix: String::new(),
+        count: 10,
+        scope: Scope::Cluster,
+    };
+    for option in options.chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case("MATCH") => {
+                scan.pattern = Some(value.clone())
+            }
+            [name, value] if name.eq_ignore_ascii_case("COUNT") => match value.parse() {
+                Ok(count) if count > 0 => scan.count = count,
+                _ => return Value::Error("ERR value is not an integer or out of range".into()),
+            },
+            _ => return Value::Error("ERR syntax error".into()),
+        }
+    }
+
+    match networking::parse_scan(&networking::execute_as(identity, Command::Scan(scan), ctx)) {
+        Ok((cursor, keys)) => Value::Array(vec![
+            Value::Bulk(cursor.into_bytes()),
+            Value::Array(
+                keys.into_iter()
+                    .map(|key| Value::Bulk(key.into_bytes()))
+                    .collect(),
+            ),
+        ]),
+        Err(e) => error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ClusterNode, hashing::HashRing, partition::Partitioner};
+    use std::{collections::HashMap, sync::Mutex};
+
+    #[test]
+    fn test_read_request_array_and_inline() {
+        let mut input: &[u8] = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\nPING\r\n";
+
+        assert_eq!(
+            read_request(&mut input).unwrap().unwrap(),
+            vec![b"SET".to_vec(), b"key".to_vec(), b"value".to_vec()]
+        );
+        assert_eq!(
+            read_request(&mut input).unwrap().unwrap(),
+            vec![b"PING".to_vec()]
+        );
+        assert!(read_request(&mut input).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_request_rejects_invalid_length() {
+        let mut input: &[u8] = b"*1\r\n$abc\r\n";
+        assert!(read_request(&mut input).is_err());
+
+        // the announced length is not trusted up front
+        let mut input: &[u8] = b"*1000000\r\n$536870912\r\nshort\r\n";
+        assert!(read_request(&mut input).is_err());
+
+        let mut input: &[u8] = b"*1\r\n$3\r\nSETXX";
+        assert!(read_request(&mut input).is_err());
+
+        let inline = vec![b'a'; MAX_INLINE_LEN + 1];
+        assert!(read_request(&mut inline.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_value_encode() {
+        let value = Value::Array(vec![
+            Value::Bulk("v".into()),
+            Value::Null,
+            Value::Integer(2),
+        ]);
+
+        let mut resp2 = Vec::new();
+        value.encode(2, &mut resp2);
+        assert_eq!(resp2, b"*3\r\n$1\r\nv\r\n$-1\r\n:2\r\n");
+
+        let mut resp3 = Vec::new();
+        value.encode(3, &mut resp3);
+        assert_eq!(resp3, b"*3\r\n$1\r\nv\r\n_\r\n:2\r\n");
+
+        let mut map = Vec::new();
+        Value::Map(vec![(Value::Simple("a".into()), Value::Integer(1))]).encode(3, &mut map);
+        assert_eq!(map, b"%1\r\n+a\r\n:1\r\n");
+    }
+
+    #[test]
+    fn test_error_echoing_line_breaks() {
+        let node = ClusterNode {
+            _id: "1".into(),
+            host: "127.0.0.1".into(),
+            port: "0".into(),
+            gossip_port: "0".into(),
+        };
+        let ctx = NodeContext::new(
+            "1".into(),
+            "memory",
+            false,
+            Partitioner::Hash(HashRing::build(vec![node], 16)),
+            &Arc::new(Mutex::new(HashMap::new())),
+            &Arc::new(Mutex::new(HashMap::new())),
+            None,
+        );
+        let mut session = RespSession {
+            protocol: 2,
+            identity: Identity::Anonymous,
+        };
+
+        let mut out = Vec::new();
+        dispatch(&[b"FOO\r\n+OK".to_vec()], &mut session, &ctx).encode(2, &mut out);
+        assert_eq!(
+            out,
+            b"-ERR unknown command or wrong number of arguments for 'foo  +ok'\r\n"
+        );
+    }
+}
@@ -1,66 +1,322 @@
-pub trait Storage {
-    fn put(&mut self, key: &String, value: String) -> Result<(), String>;
-    fn read(&self, key: &String) -> Result<String, String>;
-    fn read_key_by_range(
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    time::{Duration, Instant},
+};
+
+use crate::error::Error;
+
+// keys are text, values are arbitrary bytes
+pub trait Storage: Send {
+    fn put(&mut self, key: &str, value: Vec<u8>) -> Result<(), Error>;
+    // like put, but the key keeps its ttl; for writes derived from the current value
+    fn update(&mut self, key: &str, value: Vec<u8>) -> Result<(), Error>;
+    fn read(&self, key: &str) -> Result<Vec<u8>, Error>;
+    fn read_key_by_range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>, Error>;
+    fn batch_put(&mut self, entries: Vec<(String, Vec<u8>)>) -> Result<(), Error>;
+    fn delete(&mut self, key: &str) -> Result<(), Error>;
+    // the key is removed once the ttl has passed, putting the key clears the ttl
+    fn expire(&mut self, key: &str, ttl: Duration) -> Result<(), Error>;
+    // every write gives the key a new version, versions start at 1
+    fn read_versioned(&self, key: &str) -> Result<(Vec<u8>, u64), Error>;
+    // writes only if the key is at the given version, version 0 means the key must not exist
+    fn put_if_version(&mut self, key: &str, value: Vec<u8>, version: u64) -> Result<(), Error>;
+    // the entries between start and end (both inclusive) with their versions and expirations
+    fn read_entries(&self, start: &str, end: &str) -> Result<Vec<StoredEntry>, Error>;
+    // writes entries moved from another node, keeping their versions and expirations
+    fn restore(&mut self, entries: Vec<StoredEntry>) -> Result<(), Error>;
+    // up to `count` keys after `after` in key order that start with `prefix` and match the glob
+    // `pattern`, and whether no such keys are left after them
+    fn scan(
         &self,
-        start: &String,
-        end: &String,
-    ) -> Result<Vec<(String, String)>, String>;
-    fn batch_put(&mut self, entries: Vec<(String, String)>) -> Result<(), String>;
-    fn delete(&mut self, key: &String) -> Result<(), String>;
+        after: Option<&str>,
+        prefix: &str,
+        pattern: Option<&str>,
+        count: usize,
+    ) -> Result<(Vec<String>, bool), Error>;
+    fn key_count(&self) -> usize;
+    fn size_bytes(&self) -> usize;
+}
+
+// a key as moved between nodes: its value, version and the time it has left if it expires
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredEntry {
+    pub key: String,
+    pub value: Vec<u8>,
+    pub version: u64,
+    pub expires_in: Option<Duration>,
 }
 
+// the store is ordered by key so that scans resume after their cursor
 pub struct InMemoryStorage {
-    store: std::collections::HashMap<String, String>,
+    store: BTreeMap<String, Vec<u8>>,
+    expirations: HashMap<String, Instant>,
+    versions: HashMap<String, u64>,
+    last_version: u64,
 }
 
 impl InMemoryStorage {
     fn new() -> Self {
         InMemoryStorage {
-            store: std::collections::HashMap::new(),
+            store: BTreeMap::new(),
+            expirations: HashMap::new(),
+            versions: HashMap::new(),
+            last_version: 0,
+        }
+    }
+
+    fn insert(&mut self, key: String, value: Vec<u8>) {
+        self.last_version += 1;
+        self.expirations.remove(&key);
+        self.versions.insert(key.clone(), self.last_version);
+        self.store.insert(key, value);
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Vec<u8>> {
+        self.expirations.remove(key);
+        self.versions.remove(key);
+        self.store.remove(key)
+    }
+
+    fn is_live(&self, key: &str) -> bool {
+        self.expirations
+            .get(key)
+            .is_none_or(|deadline| *deadline > Instant::now())
+    }
+
+    // expired keys are skipped by reads and dropped on the next write
+    fn purge_expired(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .expirations
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in expired {
+            self.remove(&key);
         }
     }
 }
 
 impl Storage for InMemoryStorage {
-    fn put(&mut self, key: &String, value: String) -> Result<(), String> {
-        self.store.insert(key.clone(), value);
+    fn put(&mut self, key: &str, value: Vec<u8>) -> Result<(), Error> {
+        self.purge_expired();
+        self.insert(key.to_string(), value);
         Ok(())
     }
 
-    fn read(&self, key: &String) -> Result<String, String> {
+    fn update(&mut self, key: &str, value: Vec<u8>) -> Result<(), Error> {
+        self.purge_expired();
+        let deadline = self.expirations.get(key).copied();
+        self.insert(key.to_string(), value);
+        if let Some(deadline) = deadline {
+            self.expirations.insert(key.to_string(), deadline);
+        }
+        Ok(())
+    }
+
+    fn read(&self, key: &str) -> Result<Vec<u8>, Error> {
         self.store
             .get(key)
+            .filter(|_| self.is_live(key))
             .cloned()
-            .ok_or_else(|| "Key not found".to_string())
+            .ok_or_else(Error::not_found)
     }
 
-    fn read_key_by_range(
-        &self,
-        start: &String,
-        end: &String,
-    ) -> Result<Vec<(String, String)>, String> {
+    fn read_key_by_range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
         let mut result = Vec::new();
         for (key, value) in &self.store {
-            if key >= start && key <= end {
+            if key.as_str() >= start && key.as_str() <= end && self.is_live(key) {
                 result.push((key.clone(), value.clone()));
             }
         }
         Ok(result)
     }
 
-    fn batch_put(&mut self, entries: Vec<(String, String)>) -> Result<(), String> {
+    fn batch_put(&mut self, entries: Vec<(String, Vec<u8>)>) -> Result<(), Error> {
+        self.purge_expired();
         for (key, value) in entries {
-            self.store.insert(key, value);
+            self.insert(key, value);
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), Error> {
+        self.purge_expired();
+        self.remove(key).map(|_| ()).ok_or_else(Error::not_found)
+    }
+
+    fn expire(&mut self, key: &str, ttl: Duration) -> Result<(), Error> {
+        self.purge_expired();
+        if !self.store.contains_key(key) {
+            return Err(Error::not_found());
+        }
+
+        self.expirations
+            .insert(key.to_string(), Instant::now() + ttl);
+        Ok(())
+    }
+
+    fn read_versioned(&self, key: &str) -> Result<(Vec<u8>, u64), Error> {
+        let value = self.read(key)?;
+        Ok((value, self.versions.get(key).copied().unwrap_or_default()))
+    }
+
+    fn put_if_version(&mut self, key: &str, value: Vec<u8>, version: u64) -> Result<(), Error> {
+        self.purge_expired();
+        match (self.versions.get(key), version) {
+            (None, 0) => {}
+            (None, _) => return Err(Error::not_found()),
+            (Some(_), 0) => return Err(Error::Conflict("Key exists".to_string())),
+            (Some(current), _) if *current != version => {
+                return Err(Error::Conflict("Version mismatch".to_string()));
+            }
+            (Some(_), _) => {}
         }
+
+        self.insert(key.to_string(), value);
         Ok(())
     }
 
-    fn delete(&mut self, key: &String) -> Result<(), String> {
+    fn read_entries(&self, start: &str, end: &str) -> Result<Vec<StoredEntry>, Error> {
+        if start > end {
+            return Ok(Vec::new());
+        }
+
+        let now = Instant::now();
+        Ok(self
+            .store
+            .range::<str, _>((Bound::Included(start), Bound::Included(end)))
+            .filter(|(key, _)| self.is_live(key))
+            .map(|(key, value)| StoredEntry {
+                key: key.clone(),
+                value: value.clone(),
+                version: self.versions.get(key).copied().unwrap_or_default(),
+                expires_in: self
+                    .expirations
+                    .get(key)
+                    .map(|deadline| deadline.saturating_duration_since(now)),
+            })
+            .collect())
+    }
+
+    fn restore(&mut self, entries: Vec<StoredEntry>) -> Result<(), Error> {
+        self.purge_expired();
+        let now = Instant::now();
+        for entry in entries {
+            // later writes on this node must still raise the version
+            self.last_version = self.last_version.max(entry.version);
+            self.versions.insert(entry.key.clone(), entry.version);
+            match entry.expires_in {
+                Some(ttl) => self.expirations.insert(entry.key.clone(), now + ttl),
+                None => self.expirations.remove(&entry.key),
+            };
+            self.store.insert(entry.key, entry.value);
+        }
+        Ok(())
+    }
+
+    fn scan(
+        &self,
+        after: Option<&str>,
+        prefix: &str,
+        pattern: Option<&str>,
+        count: usize,
+    ) -> Result<(Vec<String>, bool), Error> {
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix),
+        };
+
+        let mut keys: Vec<String> = self
+            .store
+            .range::<str, _>((start, Bound::Unbounded))
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .filter(|key| self.is_live(key))
+            .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+            .take(count + 1)
+            .cloned()
+            .collect();
+
+        let done = keys.len() <= count;
+        keys.truncate(count);
+        Ok((keys, done))
+    }
+
+    fn key_count(&self) -> usize {
+        self.store.keys().filter(|key| self.is_live(key)).count()
+    }
+
+    fn size_bytes(&self) -> usize {
         self.store
-            .remove(key)
-            .map(|_| ())
-            .ok_or_else(|| "Key not found".to_string())
+            .iter()
+            .filter(|(key, _)| self.is_live(key))
+            .map(|(k, v)| k.len() + v.len())
+            .sum()
+    }
+}
+
+// glob style pattern matching as used by SCAN MATCH: `*`, `?`, `[abc]`, `[a-z]`, `[^a]` and `\` escapes
+pub fn glob_match(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+
+    // on a mismatch the last `*` takes one more character of the key, earlier stars never need to
+    let (mut p, mut k) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            star = Some((p, k));
+        } else if let Some(next) = match_char(&pattern, p, key[k]) {
+            p = next;
+            k += 1;
+        } else if let Some((star_p, star_k)) = star {
+            p = star_p;
+            k = star_k + 1;
+            star = Some((star_p, k));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// where the pattern continues if its element at `p` matches the character
+fn match_char(pattern: &[char], p: usize, c: char) -> Option<usize> {
+    match pattern.get(p)? {
+        '?' => Some(p + 1),
+        '[' => {
+            let Some(close) = pattern.iter().skip(p + 2).position(|x| *x == ']') else {
+                return (c == '[').then_some(p + 1);
+            };
+            let close = close + p + 2;
+            let class = &pattern[p + 1..close];
+            let (negated, class) = match class.first() {
+                Some('^') => (true, &class[1..]),
+                _ => (false, class),
+            };
+
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == '-' {
+                    matched |= class[i] <= c && c <= class[i + 2];
+                    i += 3;
+                } else {
+                    matched |= class[i] == c;
+                    i += 1;
+                }
+            }
+
+            (matched != negated).then_some(close + 1)
+        }
+        '\\' if p + 1 < pattern.len() => (pattern[p + 1] == c).then_some(p + 2),
+        x => (*x == c).then_some(p + 1),
     }
 }
 
@@ -71,19 +327,17 @@ pub struct StorageBuilder {
 impl StorageBuilder {
     pub fn builder(storage_type: &str) -> Self {
         match storage_type {
-            "memory" => {
-                return StorageBuilder {
-                    storage_type: "memory".to_string(),
-                };
-            }
+            "memory" => StorageBuilder {
+                storage_type: "memory".to_string(),
+            },
             _ => {
                 eprintln!(
                     "Unknown storage type '{}', defaulting to 'memory'",
                     storage_type
                 );
-                return StorageBuilder {
+                StorageBuilder {
                     storage_type: "memory".to_string(),
-                };
+                }
             }
         }
     }
@@ -97,6 +351,11 @@ impl StorageBuilder {
 }
 
 #[cfg(test)]
+#[allow(
+    clippy::unnecessary_to_owned,
+    clippy::useless_vec,
+    clippy::bool_assert_comparison
+)]
 mod tests {
     use super::*;
 
@@ -104,23 +363,23 @@ mod tests {
     fn test_in_memory_storage_put_and_read() {
         let mut storage = InMemoryStorage::new();
         storage
-            .put(&"key1".to_string(), "value1".to_string())
+            .put(&"key1".to_string(), b"value1".to_vec())
             .unwrap();
         let value = storage.read(&"key1".to_string()).unwrap();
-        assert_eq!(value, "value1");
+        assert_eq!(value, b"value1");
     }
 
     #[test]
     fn test_in_memory_storage_read_key_by_range() {
         let mut storage = InMemoryStorage::new();
         storage
-            .put(&"key1".to_string(), "value1".to_string())
+            .put(&"key1".to_string(), b"value1".to_vec())
             .unwrap();
         storage
-            .put(&"key2".to_string(), "value2".to_string())
+            .put(&"key2".to_string(), b"value2".to_vec())
             .unwrap();
         storage
-            .put(&"key3".to_string(), "value3".to_string())
+            .put(&"key3".to_string(), b"value3".to_vec())
             .unwrap();
 
         let result = storage
@@ -128,8 +387,8 @@ mod tests {
             .unwrap();
 
         let expected = vec![
-            ("key1".to_string(), "value1".to_string()),
-            ("key2".to_string(), "value2".to_string()),
+            ("key1".to_string(), b"value1".to_vec()),
+            ("key2".to_string(), b"value2".to_vec()),
         ];
 
         result.iter().for_each(|(k, v)| {
@@ -141,25 +400,183 @@ mod tests {
     fn test_in_memory_storage_batch_put() {
         let mut storage = InMemoryStorage::new();
         let entries = vec![
-            ("key1".to_string(), "value1".to_string()),
-            ("key2".to_string(), "value2".to_string()),
+            ("key1".to_string(), b"value1".to_vec()),
+            ("key2".to_string(), b"value2".to_vec()),
         ];
         storage.batch_put(entries).unwrap();
 
         let value1 = storage.read(&"key1".to_string()).unwrap();
         let value2 = storage.read(&"key2".to_string()).unwrap();
-        assert_eq!(value1, "value1");
-        assert_eq!(value2, "value2");
+        assert_eq!(value1, b"value1");
+        assert_eq!(value2, b"value2");
     }
 
     #[test]
     fn test_in_memory_storage_delete() {
         let mut storage = InMemoryStorage::new();
         storage
-            .put(&"key1".to_string(), "value1".to_string())
+            .put(&"key1".to_string(), b"value1".to_vec())
             .unwrap();
         storage.delete(&"key1".to_string()).unwrap();
         let result = storage.read(&"key1".to_string());
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_in_memory_storage_scan() {
+        let mut storage = InMemoryStorage::new();
+        for key in ["user:3", "user:1", "user:2", "order:1", "user:10"] {
+            storage.put(key, b"value".to_vec()).unwrap();
+        }
+
+        let (keys, done) = storage.scan(None, "user:", None, 2).unwrap();
+        assert_eq!(keys, ["user:1", "user:10"]);
+        assert!(!done);
+
+        // a key written behind the cursor does not move it
+        storage.put("user:0", b"value".to_vec()).unwrap();
+        let (keys, done) = storage.scan(Some("user:10"), "user:", None, 2).unwrap();
+        assert_eq!(keys, ["user:2", "user:3"]);
+        assert!(done);
+
+        let (keys, done) = storage.scan(None, "", Some("*:1*"), 10).unwrap();
+        assert_eq!(keys, ["order:1", "user:1", "user:10"]);
+        assert!(done);
+    }
+
+    #[test]
+    fn test_in_memory_storage_stats() {
+        let mut storage = InMemoryStorage::new();
+        storage.put("key1", b"value1".to_vec()).unwrap();
+        storage.put("k2", b"v2".to_vec()).unwrap();
+
+        assert_eq!(storage.key_count(), 2);
+        assert_eq!(storage.size_bytes(), 14);
+    }
+
+    #[test]
+    fn test_in_memory_storage_expire() {
+        let mut storage = InMemoryStorage::new();
+        storage.put("key1", b"value1".to_vec()).unwrap();
+        storage.put("key2", b"value2".to_vec()).unwrap();
+
+        storage.expire("key1", Duration::ZERO).unwrap();
+        storage.expire("key2", Duration::ZERO).unwrap();
+        storage.put("key2", b"value2".to_vec()).unwrap();
+
+        assert!(storage.read("key1").is_err());
+        assert_eq!(storage.read("key2").unwrap(), b"value2");
+        assert_eq!(storage.key_count(), 1);
+        assert!(storage.expire("key1", Duration::from_secs(1)).is_err());
+        assert!(storage.expire("missing", Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn test_in_memory_storage_versions() {
+        let mut storage = InMemoryStorage::new();
+
+        assert!(storage.read_versioned("key1").is_err());
+        assert_eq!(
+            storage.put_if_version("key1", b"value1".to_vec(), 3),
+            Err(Error::not_found())
+        );
+
+        storage
+            .put_if_version("key1", b"value1".to_vec(), 0)
+            .unwrap();
+        let (value, version) = storage.read_versioned("key1").unwrap();
+        assert_eq!(value, b"value1");
+
+        assert_eq!(
+            storage.put_if_version("key1", b"value2".to_vec(), 0),
+            Err(Error::Conflict("Key exists".to_string()))
+        );
+        assert_eq!(
+            storage.put_if_version("key1", b"value2".to_vec(), version + 1),
+            Err(Error::Conflict("Version mismatch".to_string()))
+        );
+
+        storage
+            .put_if_version("key1", b"value2".to_vec(), version)
+            .unwrap();
+        let (value, new_version) = storage.read_versioned("key1").unwrap();
+        assert_eq!(value, b"value2");
+        assert!(new_version > version);
+
+        storage.delete("key1").unwrap();
+        storage
+            .put_if_version("key1", b"value3".to_vec(), 0)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_in_memory_storage_update() {
+        let mut storage = InMemoryStorage::new();
+        storage.put("a", b"1".to_vec()).unwrap();
+        storage.expire("a", Duration::from_secs(60)).unwrap();
+
+        storage.update("a", b"2".to_vec()).unwrap();
+        assert_eq!(storage.read_versioned("a").unwrap(), (b"2".to_vec(), 2));
+        assert!(storage.expirations.contains_key("a"));
+
+        storage.put("a", b"3".to_vec()).unwrap();
+        assert!(!storage.expirations.contains_key("a"));
+        storage.update("b", b"4".to_vec()).unwrap();
+        assert!(!storage.expirations.contains_key("b"));
+    }
+
+    #[test]
+    fn test_in_memory_storage_restore() {
+        let mut storage = InMemoryStorage::new();
+        storage.put("a", b"1".to_vec()).unwrap();
+        storage.put("b", b"2".to_vec()).unwrap();
+        storage.put("b", b"3".to_vec()).unwrap();
+        storage.expire("b", Duration::from_secs(60)).unwrap();
+
+        let entries = storage.read_entries("a", "z").unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].version, 3);
+        assert!(
+            entries[1]
+                .expires_in
+                .is_some_and(|ttl| ttl <= Duration::from_secs(60))
+        );
+        assert!(entries[0].expires_in.is_none());
+        assert!(storage.read_entries("z", "a").unwrap().is_empty());
+
+        let mut other = InMemoryStorage::new();
+        other.restore(entries).unwrap();
+        assert_eq!(other.read_versioned("b").unwrap(), (b"3".to_vec(), 3));
+        assert!(other.expirations.contains_key("b"));
+        assert!(!other.expirations.contains_key("a"));
+
+        // a write after the restore gets a newer version than the restored ones
+        other.put("c", b"4".to_vec()).unwrap();
+        assert_eq!(other.read_versioned("c").unwrap().1, 4);
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("user:*", "user:42"));
+        assert!(!glob_match("user:*", "order:42"));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("key[0-9]", "key7"));
+        assert!(glob_match("a\\*b", "a*b"));
+        assert!(!glob_match("a\\*b", "axb"));
+        assert!(glob_match("*a*b", "xxaxxb"));
+        assert!(!glob_match("a*", ""));
+        assert!(glob_match("**", ""));
+    }
+
+    #[test]
+    fn test_glob_match_many_stars() {
+        let key = "a".repeat(200);
+        let pattern = format!("{}b", "a*".repeat(30));
+        assert!(!glob_match(&pattern, &key));
+        assert!(glob_match(&pattern, &format!("{}b", key)));
+    }
 }
@@ -0,0 +1,306 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::Arc,
+};
+
+use rustls::{
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, pem::PemObject},
+    server::WebPkiClientVerifier,
+};
+
+// a node uses one certificate to serve clients and peers and to connect to peers,
+// peers have to present a certificate issued by the cluster CA
+#[derive(Debug)]
+pub struct TlsConfig {
+    // client port: certificates are optional, nodes forwarding commands present theirs
+    client_server: Arc<ServerConfig>,
+    // gossip port: only nodes of the cluster are accepted
+    peer_server: Arc<ServerConfig>,
+    // connections to other nodes
+    peer_client: Arc<ClientConfig>,
+}
+
+impl TlsConfig {
+    pub fn load(cert_path: &str, key_path: &str, ca_path: &str) -> Result<TlsConfig, String> {
+        if cert_path.is_empty() || key_path.is_empty() || ca_path.is_empty() {
+            return Err("tls.cert, tls.key and tls.ca are required".to_string());
+        }
+
+        let certs = CertificateDer::pem_file_iter(cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("Failed to read certificate {}: {}", cert_path, e))?;
+        let key = PrivateKeyDer::from_pem_file(key_path)
+            .map_err(|e| format!("Failed to read private key {}: {}", key_path, e))?;
+
+        let mut roots = RootCertStore::empty();
+        let ca_certs = CertificateDer::pem_file_iter(ca_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("Failed to read CA certificate {}: {}", ca_path, e))?;
+        for ca_cert in ca_certs {
+            roots
+                .add(ca_cert)
+                .map_err(|e| format!("Invalid CA certificate {}: {}", ca_path, e))?;
+        }
+        let roots = Arc::new(roots);
+
+        let optional_client_auth = WebPkiClientVerifier::builder(roots.clone())
+            .allow_unauthenticated()
+            .build()
+            .map_err(|e| e.to_string())?;
+        let client_server = ServerConfig::builder()
+            .with_client_cert_verifier(optional_client_auth)
+            .with_single_cert(certs.clone(), key.clone_key())
+            .map_err(|e| format!("Invalid certificate or key: {}", e))?;
+
+        let client_auth = WebPkiClientVerifier::builder(roots.clone())
+            .build()
+            .map_err(|e| e.to_string())?;
+        let peer_server = ServerConfig::builder()
+            .with_client_cert_verifier(client_auth)
+            .with_single_cert(certs.clone(), key.clone_key())
+            .map_err(|e| format!("Invalid certificate or key: {}", e))?;
+
+        let peer_client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(certs, key)
+            .map_err(|e| format!("Invalid certificate or key: {}", e))?;
+
+        Ok(TlsConfig {
+            client_server: Arc::new(client_server),
+            peer_server: Arc::new(peer_server),
+            peer_client: Arc::new(peer_client),
+        })
+    }
+}
+
+// a connection that is plain TCP or TLS depending on the node's configuration,
+// the handshake happens on first use
+pub enum Stream {
+    Plain(TcpStream),
+    Server(Box<StreamOwned<ServerConnection, TcpStream>>),
+    Client(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Stream {
+    // a connection on the client port
+    pub fn accept_client(stream: TcpStream, tls: Option<&TlsConfig>) -> Result<Stream, String> {
+        match tls {
+            Some(tls) => Self::accept(stream, &tls.client_server),
+            None => Ok(Stream::Plain(stream)),
+        }
+    }
+
+    // a connection on the gossip port, requires a certificate of the cluster
+    pub fn accept_peer(stream: TcpStream, tls: Option<&TlsConfig>) -> Result<Stream, String> {
+        match tls {
+            Some(tls) => Self::accept(stream, &tls.peer_server),
+            None => Ok(Stream::Plain(stream)),
+        }
+    }
+
+    fn accept(stream: TcpStream, config: &Arc<ServerConfig>) -> Result<Stream, String> {
+        let connection = ServerConnection::new(config.clone()).map_err(|e| e.to_string())?;
+        Ok(Stream::Server(Box::new(StreamOwned::new(
+            connection, stream,
+        ))))
+    }
+
+    // a connection to another node, its certificate has to be valid for `host`
+    pub fn connect_peer(addr: &str, host: &str, tls: Option<&TlsConfig>) -> Result<Stream, String> {
+        let stream = TcpStream::connect(addr).map_err(|e| e.to_string())?;
+        Self::peer(stream, host, tls)
+    }
+
+    // like `connect_peer` for a socket the caller connected itself
+    pub fn peer(stream: TcpStream, host: &str, tls: Option<&TlsConfig>) -> Result<Stream, String> {
+        let Some(tls) = tls else {
+            return Ok(Stream::Plain(stream));
+        };
+
+        let server_name = ServerName::try_from(host.to_string()).map_err(|e| e.to_string())?;
+        let connection = ClientConnection::new(tls.peer_client.clone(), server_name)
+            .map_err(|e| e.to_string())?;
+        Ok(Stream::Client(Box::new(StreamOwned::new(
+            connection, stream,
+        ))))
+    }
+
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::Server(stream) => &stream.sock,
+            Stream::Client(stream) => &stream.sock,
+        }
+    }
+
+    // signals the end of the data sent on this connection, the other side can still answer
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.shutdown(Shutdown::Write),
+            Stream::Server(stream) => {
+                stream.conn.send_close_notify();
+                stream.flush()
+            }
+            Stream::Client(stream) => {
+                stream.conn.send_close_notify();
+                stream.flush()
+            }
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Server(stream) => stream.read(buf),
+            Stream::Client(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Server(stream) => stream.write(buf),
+            Stream::Client(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Server(stream) => stream.flush(),
+            Stream::Client(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{
+        BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+    };
+    use std::{fs, net::TcpListener, path::PathBuf, thread};
+
+    // writes a CA and a node certificate for localhost signed by it, returns cert, key and CA paths
+    fn generate_certificates(name: &str) -> (String, String, String) {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("kava-tls-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+        let issuer = Issuer::new(ca_params, ca_key);
+
+        let node_key = KeyPair::generate().unwrap();
+        let mut node_params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        node_params.extended_key_usages = vec![
+            ExtendedKeyUsagePurpose::ServerAuth,
+            ExtendedKeyUsagePurpose::ClientAuth,
+        ];
+        let node_cert = node_params.signed_by(&node_key, &issuer).unwrap();
+
+        let path = |file: &str| dir.join(file).to_string_lossy().into_owned();
+        fs::write(path("node.pem"), node_cert.pem()).unwrap();
+        fs::write(path("node.key"), node_key.serialize_pem()).unwrap();
+        fs::write(path("ca.pem"), ca_cert.pem()).unwrap();
+
+        (path("node.pem"), path("node.key"), path("ca.pem"))
+    }
+
+    fn load(name: &str) -> Arc<TlsConfig> {
+        let (cert, key, ca) = generate_certificates(name);
+        Arc::new(TlsConfig::load(&cert, &key, &ca).unwrap())
+    }
+
+    // answers one line with the line it received, reports whether the exchange worked
+    fn echo_server(
+        tls: Arc<TlsConfig>,
+        accept: fn(TcpStream, Option<&TlsConfig>) -> Result<Stream, String>,
+    ) -> (String, thread::JoinHandle<bool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let handle = thread::spawn(move || {
+            let (tcp_stream, _) = listener.accept().unwrap();
+            let mut stream = accept(tcp_stream, Some(&tls)).unwrap();
+            let mut request = String::new();
+            if stream.read_to_string(&mut request).is_err() {
+                return false;
+            }
+            stream.write_all(request.as_bytes()).is_ok() && stream.finish().is_ok()
+        });
+
+        (addr, handle)
+    }
+
+    #[test]
+    fn test_mutual_tls_between_nodes() {
+        let tls = load("mutual");
+        let (addr, server) = echo_server(tls.clone(), Stream::accept_peer);
+
+        let mut stream = Stream::connect_peer(&addr, "localhost", Some(&tls)).unwrap();
+        stream.write_all(b"READ key\n").unwrap();
+        stream.finish().unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "READ key\n");
+        assert!(server.join().unwrap());
+    }
+
+    #[test]
+    fn test_peer_without_certificate_is_rejected() {
+        let (cert, key, ca) = generate_certificates("anonymous");
+        let tls = Arc::new(TlsConfig::load(&cert, &key, &ca).unwrap());
+        let (addr, server) = echo_server(tls, Stream::accept_peer);
+
+        // a client trusting the CA but without a certificate of its own
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(&ca).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connection =
+            ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(&addr).unwrap());
+
+        let _ = stream.write_all(b"READ key\n");
+        stream.conn.send_close_notify();
+        let _ = stream.flush();
+
+        let mut response = String::new();
+        assert!(stream.read_to_string(&mut response).is_err() || response.is_empty());
+        assert!(!server.join().unwrap());
+    }
+
+    #[test]
+    fn test_certificate_of_another_cluster_is_rejected() {
+        let tls = load("cluster-a");
+        let other = load("cluster-b");
+        let (addr, server) = echo_server(tls, Stream::accept_client);
+
+        let mut stream = Stream::connect_peer(&addr, "localhost", Some(&other)).unwrap();
+        let _ = stream.write_all(b"READ key\n");
+        let _ = stream.finish();
+
+        let mut response = String::new();
+        assert!(stream.read_to_string(&mut response).is_err());
+        assert!(!server.join().unwrap());
+    }
+
+    #[test]
+    fn test_load_requires_all_paths() {
+        assert!(TlsConfig::load("cert.pem", "key.pem", "").is_err());
+        assert!(TlsConfig::load("missing.pem", "missing.key", "missing.pem").is_err());
+    }
+}
@@ -0,0 +1,410 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{commands::Transaction, error::Error};
+
+// how long a participant remembers the outcome of a share, a coordinator delivering the decision
+// again within it gets the same answer
+const OUTCOME_RETENTION: Duration = Duration::from_secs(3600);
+
+// the outcome of a transaction spanning nodes, as recorded by its coordinator
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    // the participants are being prepared
+    Pending,
+    Commit,
+    Abort,
+}
+
+impl Decision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Decision::Pending => "PENDING",
+            Decision::Commit => "COMMIT",
+            Decision::Abort => "ABORT",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Decision> {
+        match s {
+            "PENDING" => Some(Decision::Pending),
+            "COMMIT" => Some(Decision::Commit),
+            "ABORT" => Some(Decision::Abort),
+            _ => None,
+        }
+    }
+}
+
+// a transaction coordinated by this node whose decision not every participant has yet
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub decision: Decision,
+    pub participants: Vec<String>,
+}
+
+// a share of a transaction prepared on this node, its keys stay locked until the decision of
+// the coordinator arrives
+struct Prepared {
+    coordinator: String,
+    transaction: Transaction,
+    keys: Vec<String>,
+    deadline: Instant,
+}
+
+// the decision on a share of a transaction and the answers of its commands when committed
+struct Outcome {
+    decision: Decision,
+    answers: String,
+    at: Instant,
+}
+
+// two-phase commit state of a node: the transactions it coordinates, kept in an append-only
+// log so their decisions survive a crash, and the transactions it takes part in
+pub struct TwoPhase {
+    lock_timeout: Duration,
+    log: Mutex<Option<File>>,
+    next_id: AtomicU64,
+    records: Mutex<HashMap<String, Record>>,
+    // coordinated transactions whose decision is still delivered by the client's connection
+    delivering: Mutex<HashSet<String>>,
+    prepared: Mutex<HashMap<String, Prepared>>,
+    outcomes: Mutex<HashMap<String, Outcome>>,
+    // the transaction holding the lock of a key
+    locks: Mutex<HashMap<String, String>>,
+}
+
+impl Default for TwoPhase {
+    fn default() -> Self {
+        TwoPhase::new(Duration::from_secs(10))
+    }
+}
+
+impl TwoPhase {
+    // without a log, decisions are lost when the node stops
+    pub fn new(lock_timeout: Duration) -> TwoPhase {
+        TwoPhase {
+            lock_timeout,
+            log: Mutex::new(None),
+            next_id: AtomicU64::new(0),
+            records: Mutex::new(HashMap::new()),
+            delivering: Mutex::new(HashSet::new()),
+            prepared: Mutex::new(HashMap::new()),
+            outcomes: Mutex::new(HashMap::new()),
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // reads the log back: decided transactions are delivered again, the ones the node stopped
+    // in before deciding are aborted; the log is then rewritten with these only
+    pub fn open(path: &str, lock_timeout: Duration) -> std::io::Result<TwoPhase> {
+        let mut records = HashMap::new();
+        if let Ok(file) = File::open(path) {
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                let mut parts = line.split(' ');
+                let (Some(txid), Some(state)) = (parts.next(), parts.next()) else {
+                    continue;
+                };
+
+                if state == "DONE" {
+                    records.remove(txid);
+                } else if let Some(decision) = Decision::parse(state) {
+                    let participants = parts
+                        .next()
+                        .unwrap_or_default()
+                        .split(',')
+                        .filter(|id| !id.is_empty())
+                        .map(String::from)
+                        .collect();
+                    records.insert(
+                        txid.to_string(),
+                        Record {
+                            decision,
+                            participants,
+                        },
+                    );
+                }
+            }
+        }
+
+        for record in records.values_mut() {
+            if record.decision == Decision::Pending {
+                record.decision = Decision::Abort;
+            }
+        }
+
+        // written next to the log and renamed, a crash leaves either log behind
+        let compacted = format!("{}.tmp", path);
+        let mut file = File::create(&compacted)?;
+        for (txid, record) in &records {
+            writeln!(file, "{}", record_line(txid, record))?;
+        }
+        file.sync_all()?;
+        fs::rename(&compacted, path)?;
+
+        let two_phase = TwoPhase::new(lock_timeout);
+        *two_phase.log.lock().unwrap() = Some(OpenOptions::new().append(true).open(path)?);
+        *two_phase.records.lock().unwrap() = records;
+        Ok(two_phase)
+    }
+
+    // unique across restarts of the node; the transaction is left to its coordinator's
+    // connection until `delivered`
+    pub fn begin(&self, me_id: &str) -> String {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let n = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let txid = format!("{}-{}-{}", me_id, millis, n);
+        self.delivering.lock().unwrap().insert(txid.clone());
+        txid
+    }
+
+    // the coordinator's connection is done with the transaction, a decision it could not
+    // deliver is delivered by recovery
+    pub fn delivered(&self, txid: &str) {
+        self.delivering.lock().unwrap().remove(txid);
+    }
+
+    // durably records the state of a coordinated transaction before it is acted on
+    pub fn record(
+        &self,
+        txid: &str,
+        decision: Decision,
+        participants: &[String],
+    ) -> Result<(), Error> {
+        let record = Record {
+            decision,
+            participants: participants.to_vec(),
+        };
+        let line = record_line(txid, &record);
+        self.records
+            .lock()
+            .unwrap()
+            .insert(txid.to_string(), record);
+        self.write(&line)
+    }
+
+    // every participant has the decision, the transaction is forgotten
+    pub fn done(&self, txid: &str) {
+        self.records.lock().unwrap().remove(txid);
+        // failing to write this only delivers the decision once more after a restart
+        let _ = self.write(&format!("{} DONE", txid));
+    }
+
+    // the decided transactions not every participant has acknowledged yet, without the ones
+    // still delivered by their coordinator's connection
+    pub fn unresolved(&self) -> Vec<(String, Record)> {
+        let delivering = self.delivering.lock().unwrap();
+        self.records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(txid, _)| !delivering.contains(*txid))
+            .filter(|(_, record)| record.decision != Decision::Pending)
+            .map(|(txid, record)| (txid.clone(), record.clone()))
+            .collect()
+    }
+
+    // a transaction without a record was either never decided or acknowledged by every
+    // participant, a participant asking can only be in the first case
+    pub fn status(&self, txid: &str) -> Decision {
+        self.records
+            .lock()
+            .unwrap()
+            .get(txid)
+            .map_or(Decision::Abort, |record| record.decision)
+    }
+
+    fn write(&self, line: &str) -> Result<(), Error> {
+        let mut log = self.log.lock().unwrap();
+        let Some(file) = log.as_mut() else {
+            return Ok(());
+        };
+
+        writeln!(file, "{}", line)
+            .and_then(|_| file.sync_data())
+            .map_err(|e| Error::Internal(format!("Failed to write the transaction log: {}", e)))
+    }
+
+    pub fn locked_by(&self, key: &str) -> Option<String> {
+        self.locks.lock().unwrap().get(key).cloned()
+    }
+
+    // locks the keys of a share of a transaction until it is committed or aborted
+    pub fn prepare(
+        &self,
+        txid: &str,
+        coordinator: &str,
+        transaction: Transaction,
+        keys: Vec<String>,
+    ) {
+        let mut locks = self.locks.lock().unwrap();
+        for key in &keys {
+            locks.insert(key.clone(), txid.to_string());
+        }
+
+        self.prepared.lock().unwrap().insert(
+            txid.to_string(),
+            Prepared {
+                coordinator: coordinator.to_string(),
+                transaction,
+                keys,
+                deadline: Instant::now() + self.lock_timeout,
+            },
+        );
+    }
+
+    // removes a prepared transaction and releases its locks
+    pub fn take(&self, txid: &str) -> Option<Transaction> {
+        let prepared = self.prepared.lock().unwrap().remove(txid)?;
+
+        let mut locks = self.locks.lock().unwrap();
+        for key in &prepared.keys {
+            if locks.get(key).is_some_and(|holder| holder == txid) {
+                locks.remove(key);
+            }
+        }
+        Some(prepared.transaction)
+    }
+
+    // remembers the decision on a share taken from the prepared transactions
+    pub fn decided(&self, txid: &str, decision: Decision, answers: String) {
+        let now = Instant::now();
+        let mut outcomes = self.outcomes.lock().unwrap();
+        outcomes.retain(|_, outcome| now.duration_since(outcome.at) < OUTCOME_RETENTION);
+        outcomes.insert(
+            txid.to_string(),
+            Outcome {
+                decision,
+                answers,
+                at: now,
+            },
+        );
+    }
+
+    // the decision on a share decided before, with the answers of its commands
+    pub fn outcome(&self, txid: &str) -> Option<(Decision, String)> {
+        self.outcomes
+            .lock()
+            .unwrap()
+            .get(txid)
+            .map(|outcome| (outcome.decision, outcome.answers.clone()))
+    }
+
+    // the prepared transactions whose locks timed out, with their coordinators
+    pub fn expired(&self) -> Vec<(String, String)> {
+        let now = Instant::now();
+        self.prepared
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, prepared)| prepared.deadline <= now)
+            .map(|(txid, prepared)| (txid.clone(), prepared.coordinator.clone()))
+            .collect()
+    }
+
+    // the coordinator has not decided yet, the locks are held for another timeout
+    pub fn extend(&self, txid: &str) {
+        if let Some(prepared) = self.prepared.lock().unwrap().get_mut(txid) {
+            prepared.deadline = Instant::now() + self.lock_timeout;
+        }
+    }
+}
+
+// `<txid> <decision> <participant ids, comma separated>`
+fn record_line(txid: &str, record: &Record) -> String {
+    format!(
+        "{} {} {}",
+        txid,
+        record.decision.as_str(),
+        record.participants.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::Command;
+
+    #[test]
+    fn test_log_recovery() {
+        let path = std::env::temp_dir().join(format!("kava-test-{}.txlog", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        let participants = vec!["1".to_string(), "2".to_string()];
+
+        let two_phase = TwoPhase::open(path, Duration::from_secs(1)).unwrap();
+        let (committed, started, finished) = (
+            two_phase.begin("1"),
+            two_phase.begin("1"),
+            two_phase.begin("1"),
+        );
+        assert_ne!(committed, started);
+
+        two_phase
+            .record(&committed, Decision::Pending, &participants)
+            .unwrap();
+        two_phase
+            .record(&committed, Decision::Commit, &participants)
+            .unwrap();
+        two_phase
+            .record(&started, Decision::Pending, &participants)
+            .unwrap();
+        two_phase
+            .record(&finished, Decision::Abort, &participants)
+            .unwrap();
+        two_phase.done(&finished);
+        assert_eq!(two_phase.status(&started), Decision::Pending);
+        // decided, but still delivered by the connection that began it
+        assert!(two_phase.unresolved().is_empty());
+        two_phase.delivered(&committed);
+        assert_eq!(two_phase.unresolved().len(), 1);
+        drop(two_phase);
+
+        // a node stopped before deciding aborts, decided transactions are delivered again
+        let recovered = TwoPhase::open(path, Duration::from_secs(1)).unwrap();
+        assert_eq!(recovered.status(&committed), Decision::Commit);
+        assert_eq!(recovered.status(&started), Decision::Abort);
+        assert_eq!(recovered.status(&finished), Decision::Abort);
+        assert_eq!(recovered.unresolved().len(), 2);
+        assert_eq!(fs::read_to_string(path).unwrap().lines().count(), 2);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_locks_until_taken_or_expired() {
+        let two_phase = TwoPhase::new(Duration::ZERO);
+        let transaction = Transaction {
+            watched: vec![],
+            commands: vec![Command::Put("a".into(), b"1".to_vec())],
+        };
+
+        two_phase.prepare("t1", "2", transaction, vec!["a".into()]);
+        assert_eq!(two_phase.locked_by("a"), Some("t1".to_string()));
+        assert_eq!(two_phase.locked_by("b"), None);
+        assert_eq!(two_phase.expired(), [("t1".to_string(), "2".to_string())]);
+
+        assert_eq!(two_phase.take("t1").unwrap().commands.len(), 1);
+        assert!(two_phase.take("t1").is_none());
+        assert_eq!(two_phase.locked_by("a"), None);
+        assert!(two_phase.expired().is_empty());
+
+        assert_eq!(two_phase.outcome("t1"), None);
+        two_phase.decided("t1", Decision::Commit, "OK\n".to_string());
+        assert_eq!(
+            two_phase.outcome("t1"),
+            Some((Decision::Commit, "OK\n".to_string()))
+        );
+    }
+}